@@ -6,242 +6,741 @@
 /// => Traits
 ///
 /// Common things separate.
-use tauri::State;
+use futures_util::{pin_mut, TryStreamExt};
+use sql::SqlStatement;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tauri::{Manager, State};
 use tokio::select;
-use tokio_postgres::types::Type;
-use tokio_util::sync::CancellationToken;
-use types::{BasicTextField, BasicTextTable, DatabaseQueryResult, TypedField};
+use tokio::sync::{mpsc, oneshot};
+use tokio_postgres::types::ToSql;
+use types::{DatabaseQueryResult, TypedField};
+
+mod conninfo;
+mod convert;
+mod describe;
+mod edit;
+mod explain;
+mod export;
+mod history;
+mod import;
+mod libpq;
+mod params;
+mod pool;
+mod profiles;
+mod running;
+mod script;
+mod session;
+mod sql;
+mod tls;
+mod transactions;
+mod xlsx;
+
+pub use history::{history_dir, History};
+pub use session::Sessions;
+
+/// How often idle pooled connections are checked for their timeout
+const POOL_MAINTENANCE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Rows per event when streaming results to the frontend
+const DEFAULT_STREAM_BATCH_SIZE: usize = 500;
+/// Batches buffered between db task and frontend, the db task waits if exceeded
+const STREAM_BATCHES_IN_FLIGHT: usize = 4;
+/// Rows per batch when exporting, progress is reported after each
+const EXPORT_BATCH_SIZE: usize = 1000;
 
 /// Several things:
 ///
 /// * connection (a reference)
-/// * info_query (none -> database, database -> tables)
+/// * info_query (none -> databases, database -> schemas, schema -> tables)
 /// * data_query (database, table, filter)
 /// * custom_query (SELECT statement)
 ///
 /// Connection is used to route the query to the correct handler
-/// Query-ID is used to route the answer to the correct user
-/// Both are ignored for now
+/// The answer is routed back to the correct user by a reply channel
+/// that travels along with each query (see `types::DbRequest`)
 ///
-
-/** Thoughts on being stateful:
- *
- * keep selected things in mind (connection, database, table) - not rest
- *
- * Regarding position in breadcrumbs
- *
- * 1. specify desired position -> communicate that to backend
- * 2. afterwards, from position decide next default action  -> run query
+/** Being stateful:
  *
- * Example structure:
+ * Selected things (connection, database, schema, table) are kept in mind
+ * per window, see `session`. The rest is sent along with each query.
  *
- *       pub enum StatefulQuery {
- *           Connect(String),
- *           GetDatabases,
- *           SelectDatabase(String),
- *           GetTables,
- *           SetTable(String),
- *           GetTableContents,
- *       }
- *
- * pub enum DatabaseQuery {
- *          ConsecutiveQuery(StatefulQuery),
- *          OneShot(DatabasePath)
- * }
+ * 1. specify desired position -> communicate that to backend (`navigate_to`)
+ * 2. afterwards, from position decide next default action -> run query
+ *    (`default_next_action`)
  *
+ * Queries with the full path (`db_query`) keep working without a session.
  */
-
 pub mod types {
     use serde::{Deserialize, Serialize};
+    use std::collections::BTreeMap;
     use std::fmt;
-    use tokio::sync::mpsc;
     use tokio::sync::Mutex;
+    use tokio::sync::{mpsc, oneshot};
+
+    use super::conninfo;
+    use super::sql::{self, SqlStatement};
 
     pub type SomeDatabase = String;
+    pub type SomeSchema = String;
     pub type SomeTable = String;
 
-    #[derive(Debug, Deserialize, Serialize)]
+    fn default_schema() -> SomeSchema {
+        String::from("public")
+    }
+
+    /// Connection options, see the libpq documentation for their meaning
+    ///
+    /// Options without a field of their own (e.g. sslrootcert) are kept in `extras`.
+    #[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq)]
+    #[serde(default)]
+    pub struct ConnectionParams {
+        /// Host name, address or socket directory, several separated by comma
+        pub host: Option<String>,
+        /// Port, several separated by comma: one for each host, or one for all of them
+        #[serde(deserialize_with = "port_from_number_or_text")]
+        pub port: Option<String>,
+        pub user: Option<String>,
+        pub password: Option<String>,
+        pub dbname: Option<String>,
+        pub application_name: Option<String>,
+        pub sslmode: Option<String>,
+        /// Seconds
+        pub connect_timeout: Option<u64>,
+        pub extras: BTreeMap<String, String>,
+    }
+
+    /// Profiles saved before port lists were supported have the port as number
+    fn port_from_number_or_text<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Port {
+            Number(u16),
+            Text(String),
+        }
+        Ok(
+            Option::<Port>::deserialize(deserializer)?.map(|port| match port {
+                Port::Number(number) => number.to_string(),
+                Port::Text(text) => text,
+            }),
+        )
+    }
+
+    #[derive(Debug, Deserialize, Serialize, Clone)]
     pub enum Connection {
+        /// Connection string, keyword/value pairs or URI
         Stateless(String),
+        Params(ConnectionParams),
+    }
+
+    impl Connection {
+        pub fn to_params(&self) -> Result<ConnectionParams, String> {
+            match self {
+                Connection::Stateless(connection_str) => {
+                    conninfo::parse_connection_string(connection_str)
+                }
+                Connection::Params(params) => Ok(params.clone()),
+            }
+        }
+
+        /// Connection string for logs, with the password masked
+        pub fn redacted(&self) -> String {
+            match self {
+                Connection::Stateless(connection_str) => conninfo::redact_password(connection_str),
+                Connection::Params(params) => conninfo::to_redacted_string(params),
+            }
+        }
+    }
+
+    /// One connection in all its forms
+    #[derive(Debug, Serialize)]
+    pub struct ConnectionDescription {
+        pub params: ConnectionParams,
+        pub keywords: String,
+        pub uri: String,
+    }
+
+    /// Part of a table to read
+    #[derive(Debug, Deserialize, Serialize, Clone)]
+    pub enum Pagination {
+        /// Skip `offset` rows, then read at most `limit` rows ordered by `order_by`,
+        /// which is filled with the primary key (or ctid) of the table if empty
+        Offset {
+            limit: u64,
+            offset: u64,
+            #[serde(default)]
+            order_by: Vec<String>,
+        },
+        /// Read at most `limit` rows ordered by `key_columns`,
+        /// starting after the key values `after` (given as text)
+        Keyset {
+            limit: u64,
+            key_columns: Vec<String>,
+            after: Option<Vec<String>>,
+        },
+    }
+
+    #[derive(Debug, Deserialize, Serialize, Clone)]
+    pub struct DatabaseSchema {
+        pub database: Option<SomeDatabase>,
+        /// "public" if not given
+        #[serde(default = "default_schema")]
+        pub schema: SomeSchema,
     }
 
     #[derive(Debug, Deserialize, Serialize, Clone)]
     pub struct DatabaseTable {
         pub database: Option<SomeDatabase>,
+        /// "public" if not given
+        #[serde(default = "default_schema")]
+        pub schema: SomeSchema,
         pub table: SomeTable,
+        /// Whole table if not given
+        #[serde(default)]
+        pub pagination: Option<Pagination>,
     }
 
     #[derive(Debug, Deserialize, Serialize, Clone)]
     pub struct CustomQuery {
         pub database: Option<SomeDatabase>,
         pub sql_query: String,
+        /// Values for $1, $2, ... in text form, None is NULL
+        #[serde(default)]
+        pub params: Vec<Option<String>>,
     }
 
-    #[derive(Debug, Deserialize, Serialize)]
+    /// Plan of a custom query, see `explain`
+    #[derive(Debug, Deserialize, Serialize, Clone)]
+    pub struct ExplainQuery {
+        pub query: CustomQuery,
+        /// Run the statement for actual times and rows, its effects are rolled back
+        #[serde(default)]
+        pub analyze: bool,
+        /// Report blocks read and written
+        #[serde(default)]
+        pub buffers: bool,
+    }
+
+    #[derive(Debug, Deserialize, Serialize, Clone)]
     pub enum Query {
         CustomQuery(CustomQuery),
+        /// Plan of the query in the details
+        Explain(ExplainQuery),
         GetDatabases,
-        GetTables(Option<SomeDatabase>),
+        GetSchemas(Option<SomeDatabase>),
+        GetTables(DatabaseSchema),
         GetTableContents(DatabaseTable),
+        /// Columns of the table, with constraints, indexes and triggers in the details
+        DescribeTable(DatabaseTable),
     }
 
     impl Query {
         pub fn get_mentioned_database(&self) -> Option<SomeDatabase> {
             match self {
                 Self::CustomQuery(custom_query) => custom_query.database.clone(),
+                Self::Explain(explain) => explain.query.database.clone(),
                 Self::GetDatabases => None,
-                Self::GetTables(opt_db) => opt_db.clone(),
+                Self::GetSchemas(opt_db) => opt_db.clone(),
+                Self::GetTables(db_schema) => db_schema.database.clone(),
                 Self::GetTableContents(db_table) => db_table.database.clone(),
+                Self::DescribeTable(db_table) => db_table.database.clone(),
             }
         }
 
-        pub fn get_query_string(&self) -> String {
+        /// SQL to run, navigation queries are built from the names given
+        pub fn get_statement(&self) -> SqlStatement {
             match self {
-                Self::CustomQuery(custom_query) => custom_query.sql_query.clone(),
-                Self::GetDatabases => String::from("SELECT datname FROM pg_database;"),
-                Self::GetTables(_) => String::from(
-                    "SELECT table_name 
-            FROM information_schema.tables
-            WHERE table_schema = 'public';",
-                ),
-                Self::GetTableContents(db_and_table) => {
-                    format!("SELECT * FROM \"{}\";", &db_and_table.table)
+                Self::CustomQuery(custom_query) => {
+                    SqlStatement::with_values(&custom_query.sql_query, &custom_query.params)
                 }
+                Self::Explain(explain) => super::explain::explain_statement(explain),
+                Self::GetDatabases => sql::databases_query(),
+                Self::GetSchemas(_) => sql::schemas_query(),
+                Self::GetTables(db_schema) => sql::tables_query(&db_schema.schema),
+                Self::GetTableContents(db_and_table) => sql::table_contents_query(db_and_table),
+                Self::DescribeTable(db_and_table) => sql::table_columns_query(db_and_table),
             }
         }
     }
 
-    #[derive(Debug, Deserialize, Serialize)]
-    pub struct FullQuery {
-        pub connection: Connection,
-        pub query: Query,
+    /// Limits enforced by the server while running a query
+    #[derive(Debug, Deserialize, Serialize, Clone)]
+    pub struct QueryLimits {
+        /// Statement is aborted after this many milliseconds, no limit if not given
+        pub statement_timeout_ms: Option<u64>,
+        /// At most this many rows are read, no limit if not given
+        pub max_rows: Option<u64>,
     }
 
-    #[derive(Debug, Deserialize, Serialize)]
-    pub struct BasicTextField {
-        pub text: String,
-        pub column_index: usize,
+    impl Default for QueryLimits {
+        fn default() -> Self {
+            QueryLimits {
+                statement_timeout_ms: Some(30_000),
+                max_rows: Some(10_000),
+            }
+        }
     }
-    pub struct BasicTextTable {
-        pub columns: Vec<String>,
-        pub fields: Vec<Vec<BasicTextField>>,
+
+    #[derive(Debug, Deserialize, Serialize, Clone)]
+    pub struct FullQuery {
+        pub connection: Connection,
+        pub query: Query,
+        /// Defaults apply if not given
+        #[serde(default)]
+        pub limits: QueryLimits,
+        /// Name of the profile the connection belongs to, kept in the history
+        #[serde(default)]
+        pub profile: Option<String>,
     }
 
-    #[derive(Debug, Deserialize, Serialize)]
+    /// Single cell of a result table
+    ///
+    /// Null and failed conversions are distinct from any real value.
+    #[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
     pub enum TypedField {
+        Null,
         Text(String),
+        /// Within ±(2^53 - 1), so that javascript numbers hold it exactly, larger values are `Numeric`
+        Integer(i64),
+        Float(f64),
+        Bool(bool),
+        /// Exact decimal value (also used for NaN, infinite floats and large integers)
+        Numeric(String),
+        Timestamp(String),
+        Json(serde_json::Value),
         Database(String),
+        Schema(String),
         Table(String),
+        /// Value could not be converted, with reason
+        ConversionFailure(String),
+    }
+
+    /// Postgres type of a result column
+    #[derive(Debug, Deserialize, Serialize, Clone)]
+    pub struct ColumnType {
+        pub name: String,
+        pub oid: u32,
     }
 
     #[derive(Debug, Deserialize, Serialize)]
     pub struct TypedTable {
         pub columns: Vec<String>,
+        pub column_types: Vec<ColumnType>,
         pub fields: Vec<Vec<TypedField>>,
     }
-    pub type TypedTableResult = Result<TypedTable, ()>;
+    pub type TypedTableResult = Result<TypedTable, DatabaseError>;
+
+    /// TLS state of a connection, as negotiated with the server
+    #[derive(Debug, Deserialize, Serialize, Clone)]
+    pub struct TlsState {
+        /// As requested in the connection string
+        pub sslmode: String,
+        /// Unknown if the server cannot tell
+        pub encrypted: Option<bool>,
+        /// Protocol version, e.g. TLSv1.3
+        pub version: Option<String>,
+        pub cipher: Option<String>,
+    }
+
+    /// Result of a connection check
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct ConnectionCheck {
+        pub okay: bool,
+        pub tls: TlsState,
+    }
 
     #[derive(Debug, Deserialize, Serialize)]
     pub struct DatabaseQueryResult {
         pub database: Option<SomeDatabase>,
         pub sql_query: String,
         pub table: TypedTableResult,
+        /// Estimated number of rows of the whole table (table contents only)
+        pub row_estimate: Option<i64>,
+        /// More rows than `QueryLimits::max_rows` were available, the rest was not read
+        pub truncated: bool,
+        /// TLS state of the connection the query ran on, unknown if connecting failed
+        pub tls: Option<TlsState>,
+        /// Result that does not fit into a table, depending on the query
+        pub details: Option<ResultDetails>,
+        /// False for statements without result columns (e.g. UPDATE, CREATE TABLE)
+        pub returns_rows: bool,
+        /// Rows returned, inserted, updated, deleted ..., unknown if not all were read
+        pub rows_affected: Option<u64>,
+        /// Derived from the statement text and affected rows like postgres forms it,
+        /// e.g. "INSERT 0 5", "UPDATE 12", "CREATE TABLE"
+        pub command_tag: Option<String>,
+    }
+
+    #[derive(Debug, Deserialize, Serialize)]
+    pub enum ResultDetails {
+        TableStructure(Box<super::describe::TableStructure>),
+        Plan(Box<super::explain::QueryPlan>),
+    }
+
+    /// Rows of a streamed result, as sent to the frontend
+    #[derive(Debug, Serialize, Clone)]
+    pub struct RowBatch {
+        pub stream_id: String,
+        pub batch_index: usize,
+        pub fields: Vec<Vec<TypedField>>,
+    }
+
+    /// Where the db task sends the rows of a streamed result
+    pub struct RowStreaming {
+        pub sender: mpsc::Sender<Vec<Vec<TypedField>>>,
+        pub batch_size: usize,
+    }
+
+    /// Query as sent to the db task, together with the way back to the caller
+    pub struct DbRequest {
+        pub query: FullQuery,
+        /// Id chosen by the caller, needed to cancel the query
+        pub query_id: Option<String>,
+        pub reply: oneshot::Sender<DatabaseQueryResult>,
+        /// If given, rows are streamed in batches instead of being part of the reply
+        pub streaming: Option<RowStreaming>,
+        /// Window whose open transaction custom queries run in, if it has one
+        pub transaction: Option<String>,
+    }
+
+    /// Change of the transaction of a window, see `super::transactions`
+    pub enum TransactionAction {
+        Begin {
+            connection_str: String,
+            database: Option<SomeDatabase>,
+            limits: QueryLimits,
+        },
+        Commit,
+        Rollback,
+        GetState,
+    }
+
+    /// Message to the db task
+    pub enum DbMessage {
+        Query(Box<DbRequest>),
+        /// Apply edits to rows of a table
+        Edit {
+            edits: Box<super::edit::TableEdits>,
+            reply: oneshot::Sender<Result<super::edit::EditResult, DatabaseError>>,
+        },
+        /// Run statements of a script, in the window's transaction if it has one
+        Script {
+            script: Box<super::script::Script>,
+            window: String,
+            query_id: Option<String>,
+            reply: oneshot::Sender<Result<super::script::ScriptResult, DatabaseError>>,
+        },
+        /// Load file into a table, cancelled by the import id like a query
+        Import {
+            import: Box<super::import::TableImport>,
+            import_id: String,
+            progress: mpsc::Sender<super::import::ImportProgress>,
+            reply: oneshot::Sender<Result<super::import::ImportResult, DatabaseError>>,
+        },
+        /// Prepare query without running it, to learn the types of its parameters
+        Prepare {
+            query: Box<FullQuery>,
+            /// Window whose open transaction custom queries are prepared in, if it has one
+            transaction: Option<String>,
+            reply: oneshot::Sender<Result<super::params::PreparedQuery, DatabaseError>>,
+        },
+        Transaction {
+            window: String,
+            action: TransactionAction,
+            reply: oneshot::Sender<Result<super::transactions::TransactionState, DatabaseError>>,
+        },
+        /// Cancel running query, reply tells whether there was such a query
+        Cancel {
+            query_id: String,
+            reply: oneshot::Sender<bool>,
+        },
     }
 
     pub struct StateHalfpipeToDb {
-        pub inner: Mutex<mpsc::Sender<FullQuery>>,
+        pub inner: Mutex<mpsc::Sender<DbMessage>>,
     }
 
     impl StateHalfpipeToDb {
-        pub fn from(sender_to_db: mpsc::Sender<FullQuery>) -> StateHalfpipeToDb {
+        pub fn from(sender_to_db: mpsc::Sender<DbMessage>) -> StateHalfpipeToDb {
             StateHalfpipeToDb {
                 inner: Mutex::new(sender_to_db),
             }
         }
     }
 
-    pub struct StateHalfpipeToTauri {
-        pub inner: Mutex<mpsc::Receiver<DatabaseQueryResult>>,
+    pub type DatabaseQueryReceiver = mpsc::Receiver<DbMessage>;
+
+    /// Details of a failed query, as far as known
+    #[derive(Debug, Deserialize, Serialize, Clone, Default)]
+    pub struct ErrorDetails {
+        /// SQLSTATE code, only present for errors reported by the server
+        pub code: Option<String>,
+        pub message: String,
+        pub detail: Option<String>,
+        pub hint: Option<String>,
+        /// Position of the error inside the SQL text (1-based, in characters)
+        pub position: Option<u32>,
     }
 
-    impl StateHalfpipeToTauri {
-        pub fn from(receiver_from_db: mpsc::Receiver<DatabaseQueryResult>) -> StateHalfpipeToTauri {
-            StateHalfpipeToTauri {
-                inner: Mutex::new(receiver_from_db),
+    impl ErrorDetails {
+        pub fn from_message(message: String) -> ErrorDetails {
+            ErrorDetails {
+                message,
+                ..Default::default()
             }
         }
     }
 
-    pub type DatabaseQueryReceiver = mpsc::Receiver<FullQuery>;
-    pub type StringTableSender = mpsc::Sender<DatabaseQueryResult>;
+    impl From<&tokio_postgres::Error> for ErrorDetails {
+        fn from(e: &tokio_postgres::Error) -> Self {
+            match e.as_db_error() {
+                Some(db_error) => ErrorDetails {
+                    code: Some(String::from(db_error.code().code())),
+                    message: String::from(db_error.message()),
+                    detail: db_error.detail().map(String::from),
+                    hint: db_error.hint().map(String::from),
+                    position: match db_error.position() {
+                        Some(tokio_postgres::error::ErrorPosition::Original(position)) => {
+                            Some(*position)
+                        }
+                        _ => None,
+                    },
+                },
+                None => {
+                    // causes (e.g. of a failed TLS handshake) are only known from the source chain
+                    let mut message = e.to_string();
+                    let mut source = std::error::Error::source(e);
+                    while let Some(cause) = source {
+                        message = format!("{}: {}", message, cause);
+                        source = cause.source();
+                    }
+                    ErrorDetails::from_message(message)
+                }
+            }
+        }
+    }
 
-    #[derive(Deserialize, Serialize, Clone)]
-    pub struct WhateverError {}
+    /// Failure of a database query, by the phase it occurred in
+    #[derive(Debug, Deserialize, Serialize, Clone)]
+    pub enum DatabaseError {
+        Connect(ErrorDetails),
+        Auth(ErrorDetails),
+        Parse(ErrorDetails),
+        Execute(ErrorDetails),
+        Decode(ErrorDetails),
+        /// Query was cancelled on request of the user
+        Cancelled(ErrorDetails),
+    }
 
-    impl fmt::Debug for WhateverError {
-        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-            write!(f, "{{ file: {}, line: {} }}", file!(), line!()) // programmer-facing output
+    impl DatabaseError {
+        /// Error while establishing a connection
+        ///
+        /// Authentication failures (SQLSTATE class 28, missing password)
+        /// are told apart from other connection problems.
+        pub fn on_connect(e: &tokio_postgres::Error) -> DatabaseError {
+            let details = ErrorDetails::from(e);
+            let is_auth = match &details.code {
+                Some(code) => code.starts_with("28"),
+                None => details.message.contains("password missing"),
+            };
+            if is_auth {
+                DatabaseError::Auth(details)
+            } else {
+                DatabaseError::Connect(details)
+            }
         }
-    }
 
-    impl fmt::Display for WhateverError {
-        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-            write!(f, "An Error Occurred, Please Try Again!") // user-facing output
+        pub fn phase(&self) -> &'static str {
+            match self {
+                Self::Connect(_) => "connect",
+                Self::Auth(_) => "auth",
+                Self::Parse(_) => "parse",
+                Self::Execute(_) => "execute",
+                Self::Decode(_) => "decode",
+                Self::Cancelled(_) => "cancel",
+            }
+        }
+
+        pub fn details(&self) -> &ErrorDetails {
+            match self {
+                Self::Connect(details)
+                | Self::Auth(details)
+                | Self::Parse(details)
+                | Self::Execute(details)
+                | Self::Decode(details)
+                | Self::Cancelled(details) => details,
+            }
+        }
+
+        pub fn details_mut(&mut self) -> &mut ErrorDetails {
+            match self {
+                Self::Connect(details)
+                | Self::Auth(details)
+                | Self::Parse(details)
+                | Self::Execute(details)
+                | Self::Decode(details)
+                | Self::Cancelled(details) => details,
+            }
         }
     }
 
-    impl std::error::Error for WhateverError {
-        fn description(&self) -> &str {
-            "WhateverError Platzhalter Beschreibung"
+    impl fmt::Display for DatabaseError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            let details = self.details();
+            write!(f, "{} failed: {}", self.phase(), details.message)?;
+            if let Some(code) = &details.code {
+                write!(f, " (SQLSTATE {})", code)?;
+            }
+            Ok(())
         }
     }
-}
 
-fn suggest_connection_str() -> String {
-    format!("host=localhost user={}", whoami::username())
+    impl std::error::Error for DatabaseError {}
 }
 
 pub mod commands {
 
+    use tokio::sync::{mpsc, oneshot};
     use types::DatabaseQueryResult;
 
     use super::*;
 
+    /// Check connection, an error tells why connecting failed
     #[tauri::command]
-    pub async fn test_connection_string(connection_string: String) -> bool {
-        run_check_connection(connection_string).await
+    pub async fn test_connection_string(
+        connection_string: String,
+    ) -> Result<types::ConnectionCheck, types::DatabaseError> {
+        run_check_connection(types::Connection::Stateless(connection_string)).await
+    }
+
+    /// Check connection given as string or parameters
+    #[tauri::command]
+    pub async fn test_connection(
+        connection: types::Connection,
+    ) -> Result<types::ConnectionCheck, types::DatabaseError> {
+        run_check_connection(connection).await
+    }
+
+    /// Parameters, keyword/value string and URI of a connection
+    #[tauri::command]
+    pub fn describe_connection(
+        connection: types::Connection,
+    ) -> Result<types::ConnectionDescription, types::DatabaseError> {
+        let params = connection.to_params().map_err(connect_error)?;
+        Ok(types::ConnectionDescription {
+            keywords: conninfo::to_keyword_string(&params),
+            uri: conninfo::to_uri(&params),
+            params,
+        })
+    }
+
+    /// All profiles and the name of the one used last
+    #[tauri::command]
+    pub fn list_profiles(app: tauri::AppHandle) -> Result<profiles::Profiles, String> {
+        profiles::Profiles::load(&profiles::profiles_dir(&app)?)
+    }
+
+    #[tauri::command]
+    pub fn create_profile(app: tauri::AppHandle, profile: profiles::Profile) -> Result<(), String> {
+        profiles::modify(&app, |profiles| profiles.create(profile))
+    }
+
+    /// Replace profile `name`, renaming it if the profile has another name
+    #[tauri::command]
+    pub fn update_profile(
+        app: tauri::AppHandle,
+        name: String,
+        profile: profiles::Profile,
+    ) -> Result<(), String> {
+        profiles::modify(&app, |profiles| profiles.update(&name, profile))
+    }
+
+    #[tauri::command]
+    pub fn delete_profile(app: tauri::AppHandle, name: String) -> Result<(), String> {
+        profiles::modify(&app, |profiles| profiles.delete(&name))
+    }
+
+    /// Copy profile, returns the copy
+    #[tauri::command]
+    pub fn duplicate_profile(
+        app: tauri::AppHandle,
+        name: String,
+        new_name: Option<String>,
+    ) -> Result<profiles::Profile, String> {
+        profiles::modify(&app, |profiles| profiles.duplicate(&name, new_name))
+    }
+
+    /// Remember profile as used last, it is suggested on the next start
+    #[tauri::command]
+    pub fn use_profile(app: tauri::AppHandle, name: String) -> Result<(), String> {
+        profiles::modify(&app, |profiles| profiles.mark_used(&name))
     }
 
     /// Return a path and a name for the location to be used as "home"
     ///
+    /// The connection is the one of the profile used last, guessed if there is none.
     #[tauri::command]
-    pub async fn suggest_query() -> types::FullQuery {
+    pub async fn suggest_query(app: tauri::AppHandle) -> types::FullQuery {
+        let last_used = profiles::profiles_dir(&app)
+            .and_then(|dir| profiles::Profiles::load(&dir))
+            .map(|profiles| profiles.last_used().cloned());
+        let (connection, limits, profile) = match last_used {
+            Ok(Some(profile)) => (profile.connection, profile.limits, Some(profile.name)),
+            Ok(None) => (libpq::suggest(), types::QueryLimits::default(), None),
+            Err(message) => {
+                println!("Could not load profiles: {}", message);
+                (libpq::suggest(), types::QueryLimits::default(), None)
+            }
+        };
         types::FullQuery {
-            connection: types::Connection::Stateless(suggest_connection_str()),
+            connection: types::Connection::Params(connection),
+            limits,
+            profile,
             query: types::Query::GetDatabases, // Available:
                                                //
                                                // types::Query::CustomQuery(...)
                                                // types::Query::GetDatabases
-                                               // types::Query::GetTables(
+                                               // types::Query::GetSchemas(
                                                //     Some(String::from("myuser")))
+                                               // types::Query::GetTables(
+                                               //     types::DatabaseSchema{
+                                               //         database: Some(String::from("myuser")),
+                                               //         schema: String::from("public")})
                                                // types::Query::GetTableContents(
                                                //     types::DatabaseTable{
                                                //         database: Some(String::from("myuser")),
-                                               //         table: String::from("sometable")})
+                                               //         schema: String::from("public"),
+                                               //         table: String::from("sometable"),
+                                               //         pagination: None})
         }
     }
 
-    #[tauri::command]
-    pub async fn db_query(
+    /// Hand query to the db task and wait for its result
+    async fn request_from_db(
         query: types::FullQuery,
-        to_db: State<'_, types::StateHalfpipeToDb>,
-        from_db: State<'_, types::StateHalfpipeToTauri>,
+        query_id: Option<String>,
+        streaming: Option<types::RowStreaming>,
+        transaction: Option<String>,
+        to_db: &types::StateHalfpipeToDb,
     ) -> Result<DatabaseQueryResult, String> {
-        println!("Called: db_query");
+        let (reply_tx, reply_rx) = oneshot::channel();
         {
-            let sender = to_db.inner.lock().await;
+            let sender = to_db.inner.lock().await.clone();
+            let request = types::DbRequest {
+                query,
+                query_id,
+                reply: reply_tx,
+                streaming,
+                transaction,
+            };
 
-            match sender.send(query).await {
+            match sender
+                .send(types::DbMessage::Query(Box::new(request)))
+                .await
+            {
                 Ok(_) => {}
                 Err(_) => {
                     let failure_msg = String::from("db_query: Could not send query to task");
@@ -250,256 +749,1662 @@ pub mod commands {
                 }
             }
         }
-        {
-            let mut receiver = from_db.inner.lock().await;
-            if let Some(query_result) = receiver.recv().await {
+        match reply_rx.await {
+            Ok(query_result) => {
                 println!("Sending to frontend: {:?}", query_result);
                 Ok(query_result)
-            } else {
+            }
+            Err(_) => {
                 let failure_msg = String::from("db_query: Did not receive an answer from db task");
                 println!("{}", failure_msg);
-                return Err(failure_msg);
+                Err(failure_msg)
             }
         }
     }
-}
 
-fn get_resulting_connection_string(connection_string: &str, database: &Option<String>) -> String {
-    match database {
-        Some(dbname) => format!("{} dbname={}", connection_string, dbname),
-        None => String::from(connection_string),
+    /// Hand change of a window's transaction to the db task and wait for the new state
+    pub(super) async fn request_transaction(
+        window: &str,
+        action: types::TransactionAction,
+        to_db: &types::StateHalfpipeToDb,
+    ) -> Result<transactions::TransactionState, String> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        let sender = to_db.inner.lock().await.clone();
+        if sender
+            .send(types::DbMessage::Transaction {
+                window: String::from(window),
+                action,
+                reply: reply_tx,
+            })
+            .await
+            .is_err()
+        {
+            return Err(String::from("transaction: Could not send request to task"));
+        }
+        match reply_rx.await {
+            Ok(state) => state.map_err(|e| e.to_string()),
+            Err(_) => Err(String::from(
+                "transaction: Did not receive an answer from db task",
+            )),
+        }
     }
-}
 
-fn try_get_field_as_string(row: &tokio_postgres::Row, index: usize) -> Result<String, ()> {
-    let field_type: &Type = row.columns()[index].type_();
-    match field_type {
-        &Type::BOOL => match row.try_get::<usize, bool>(index) {
-            Ok(val) => Ok(format!("{}", val)),
-            Err(_) => Err(()),
-        },
-        &Type::INT2 => match row.try_get::<usize, i16>(index) {
-            Ok(val) => Ok(format!("{}", val)),
-            Err(_) => Err(()),
-        },
-        &Type::INT4 => match row.try_get::<usize, i32>(index) {
-            Ok(val) => Ok(format!("{}", val)),
-            Err(_) => Err(()),
-        },
-        &Type::INT8 => match row.try_get::<usize, i64>(index) {
-            Ok(val) => Ok(format!("{}", val)),
-            Err(_) => Err(()),
-        },
-        &Type::FLOAT4 => match row.try_get::<usize, f32>(index) {
-            Ok(val) => Ok(format!("{}", val)),
-            Err(_) => Err(()),
-        },
-        &Type::FLOAT8 => match row.try_get::<usize, f64>(index) {
-            Ok(val) => Ok(format!("{}", val)),
-            Err(_) => Err(()),
-        },
-        &Type::VARCHAR | &Type::NAME => match row.try_get::<usize, String>(index) {
-            Ok(val) => Ok(val),
-            Err(_) => Err(()),
-        },
-        some_type => {
-            print!("Could not convert column of type: {:?}", some_type);
-            Err(())
+    /// Run query, it can be cancelled by `cancel_query` using the given id
+    ///
+    /// Custom queries run in the window's transaction, if it has one.
+    #[tauri::command]
+    pub async fn db_query(
+        query: types::FullQuery,
+        query_id: Option<String>,
+        window: tauri::Window,
+        to_db: State<'_, types::StateHalfpipeToDb>,
+    ) -> Result<DatabaseQueryResult, String> {
+        println!("Called: db_query");
+        request_from_db(
+            query,
+            query_id,
+            None,
+            Some(String::from(window.label())),
+            &to_db,
+        )
+        .await
+    }
+
+    /// Prepare query in the db task, in the transaction of the given window if it has one
+    async fn request_prepare(
+        query: types::FullQuery,
+        transaction: Option<String>,
+        to_db: &types::StateHalfpipeToDb,
+    ) -> Result<params::PreparedQuery, String> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        let sender = to_db.inner.lock().await.clone();
+        if sender
+            .send(types::DbMessage::Prepare {
+                query: Box::new(query),
+                transaction,
+                reply: reply_tx,
+            })
+            .await
+            .is_err()
+        {
+            return Err(String::from("prepare_query: Could not send query to task"));
+        }
+        match reply_rx.await {
+            Ok(result) => result.map_err(|e| e.to_string()),
+            Err(_) => Err(String::from(
+                "prepare_query: Did not receive an answer from db task",
+            )),
+        }
+    }
+
+    /// Parameter and result types of a query, without running it
+    ///
+    /// Custom queries are prepared in the window's transaction, if it has one.
+    #[tauri::command]
+    pub async fn prepare_query(
+        query: types::FullQuery,
+        window: tauri::Window,
+        to_db: State<'_, types::StateHalfpipeToDb>,
+    ) -> Result<params::PreparedQuery, String> {
+        println!("Called: prepare_query");
+        request_prepare(query, Some(String::from(window.label())), &to_db).await
+    }
+
+    /// Run script statement by statement, each with its own result
+    ///
+    /// Cancelling with `query_id` stops the script.
+    #[tauri::command]
+    pub async fn db_run_script(
+        script: script::Script,
+        query_id: Option<String>,
+        window: tauri::Window,
+        to_db: State<'_, types::StateHalfpipeToDb>,
+    ) -> Result<script::ScriptResult, String> {
+        println!("Called: db_run_script");
+        let (reply_tx, reply_rx) = oneshot::channel();
+        let sender = to_db.inner.lock().await.clone();
+        if sender
+            .send(types::DbMessage::Script {
+                script: Box::new(script),
+                window: String::from(window.label()),
+                query_id,
+                reply: reply_tx,
+            })
+            .await
+            .is_err()
+        {
+            return Err(String::from("db_run_script: Could not send script to task"));
+        }
+        match reply_rx.await {
+            Ok(result) => result.map_err(|e| e.to_string()),
+            Err(_) => Err(String::from(
+                "db_run_script: Did not receive an answer from db task",
+            )),
+        }
+    }
+
+    /// Insert, update and delete rows of a table in one transaction
+    ///
+    /// Each edit reports whether it was applied. The edits are only kept
+    /// if all of them were applied.
+    #[tauri::command]
+    pub async fn edit_rows(
+        edits: edit::TableEdits,
+        to_db: State<'_, types::StateHalfpipeToDb>,
+    ) -> Result<edit::EditResult, String> {
+        println!("Called: edit_rows");
+        let (reply_tx, reply_rx) = oneshot::channel();
+        let sender = to_db.inner.lock().await.clone();
+        if sender
+            .send(types::DbMessage::Edit {
+                edits: Box::new(edits),
+                reply: reply_tx,
+            })
+            .await
+            .is_err()
+        {
+            return Err(String::from("edit_rows: Could not send edits to task"));
+        }
+        match reply_rx.await {
+            Ok(result) => result.map_err(|e| e.to_string()),
+            Err(_) => Err(String::from(
+                "edit_rows: Did not receive an answer from db task",
+            )),
+        }
+    }
+
+    /// Guess encoding, delimiter and header of a CSV file, with its first rows
+    #[tauri::command]
+    pub fn preview_import(
+        path: String,
+        rows: Option<usize>,
+    ) -> Result<import::ImportPreview, String> {
+        import::preview_file(&path, rows.unwrap_or(import::PREVIEW_ROWS))
+    }
+
+    /// Load CSV file into a table, reporting progress as "import-progress" events
+    ///
+    /// Each event carries an `import::ImportProgress`. The import id also
+    /// serves as id for `cancel_query`, nothing is imported then.
+    #[tauri::command]
+    pub async fn import_file(
+        import: import::TableImport,
+        import_id: String,
+        window: tauri::Window,
+        to_db: State<'_, types::StateHalfpipeToDb>,
+    ) -> Result<import::ImportResult, String> {
+        println!("Called: import_file");
+        let (progress_tx, mut progress_rx) = mpsc::channel(STREAM_BATCHES_IN_FLIGHT);
+        let forwarder = tokio::spawn(async move {
+            while let Some(progress) = progress_rx.recv().await {
+                if let Err(e) = window.emit("import-progress", progress) {
+                    println!("Could not emit import progress: {}", e);
+                }
+            }
+        });
+        let (reply_tx, reply_rx) = oneshot::channel();
+        let sender = to_db.inner.lock().await.clone();
+        if sender
+            .send(types::DbMessage::Import {
+                import: Box::new(import),
+                import_id,
+                progress: progress_tx,
+                reply: reply_tx,
+            })
+            .await
+            .is_err()
+        {
+            return Err(String::from("import_file: Could not send import to task"));
+        }
+        let result = match reply_rx.await {
+            Ok(result) => result.map_err(|e| e.to_string()),
+            Err(_) => Err(String::from(
+                "import_file: Did not receive an answer from db task",
+            )),
+        };
+        // the db task dropped its sender, so the forwarder ends after the last progress
+        if forwarder.await.is_err() {
+            println!("Forwarding import progress failed");
+        }
+        result
+    }
+
+    /// Begin transaction for the custom queries of the window
+    ///
+    /// They run in it until `commit_transaction` or `rollback_transaction`,
+    /// see `db_query`.
+    #[tauri::command]
+    pub async fn begin_transaction(
+        connection: types::Connection,
+        database: Option<types::SomeDatabase>,
+        limits: Option<types::QueryLimits>,
+        window: tauri::Window,
+        to_db: State<'_, types::StateHalfpipeToDb>,
+    ) -> Result<transactions::TransactionState, String> {
+        println!("Called: begin_transaction");
+        let connection_str = get_resulting_connection_string(&connection, &database)
+            .await
+            .map_err(|e| e.to_string())?;
+        let action = types::TransactionAction::Begin {
+            connection_str,
+            database,
+            limits: limits.unwrap_or_default(),
+        };
+        request_transaction(window.label(), action, &to_db).await
+    }
+
+    #[tauri::command]
+    pub async fn commit_transaction(
+        window: tauri::Window,
+        to_db: State<'_, types::StateHalfpipeToDb>,
+    ) -> Result<transactions::TransactionState, String> {
+        println!("Called: commit_transaction");
+        request_transaction(window.label(), types::TransactionAction::Commit, &to_db).await
+    }
+
+    #[tauri::command]
+    pub async fn rollback_transaction(
+        window: tauri::Window,
+        to_db: State<'_, types::StateHalfpipeToDb>,
+    ) -> Result<transactions::TransactionState, String> {
+        println!("Called: rollback_transaction");
+        request_transaction(window.label(), types::TransactionAction::Rollback, &to_db).await
+    }
+
+    /// Whether the window has an open transaction, and whether it failed
+    #[tauri::command]
+    pub async fn get_transaction_state(
+        window: tauri::Window,
+        to_db: State<'_, types::StateHalfpipeToDb>,
+    ) -> Result<transactions::TransactionState, String> {
+        request_transaction(window.label(), types::TransactionAction::GetState, &to_db).await
+    }
+
+    /// Session of the window, to restore the position after a reload
+    #[tauri::command]
+    pub fn get_session(window: tauri::Window, sessions: State<'_, Sessions>) -> session::Session {
+        sessions.get(window.label())
+    }
+
+    /// Change position of the window's session, returns the session
+    #[tauri::command]
+    pub fn navigate_to(
+        window: tauri::Window,
+        navigation: session::Navigation,
+        sessions: State<'_, Sessions>,
+    ) -> Result<session::Session, String> {
+        sessions.navigate(window.label(), navigation)
+    }
+
+    /// Run the query belonging to the window's position
+    ///
+    /// Pagination applies to table contents only.
+    #[tauri::command]
+    pub async fn default_next_action(
+        window: tauri::Window,
+        query_id: Option<String>,
+        pagination: Option<types::Pagination>,
+        sessions: State<'_, Sessions>,
+        to_db: State<'_, types::StateHalfpipeToDb>,
+    ) -> Result<session::NextAction, String> {
+        println!("Called: default_next_action");
+        let query = sessions.get(window.label()).next_query(pagination)?;
+        let result = request_from_db(query.clone(), query_id, None, None, &to_db).await?;
+        Ok(session::NextAction { query, result })
+    }
+
+    /// History entries matching the filter, most recent first
+    #[tauri::command]
+    pub fn list_history(
+        filter: Option<history::HistoryFilter>,
+        history: State<'_, Arc<History>>,
+    ) -> Result<Vec<history::HistoryEntry>, String> {
+        history.list(&filter.unwrap_or_default())
+    }
+
+    /// Run query of a history entry again
+    ///
+    /// The password is not stored, a connection to run the query on instead
+    /// can be given. Custom queries run in the window's transaction, if it has one.
+    #[tauri::command]
+    pub async fn rerun_history_entry(
+        id: i64,
+        connection: Option<types::Connection>,
+        query_id: Option<String>,
+        window: tauri::Window,
+        history: State<'_, Arc<History>>,
+        to_db: State<'_, types::StateHalfpipeToDb>,
+    ) -> Result<session::NextAction, String> {
+        println!("Called: rerun_history_entry");
+        let mut query = history.get(id)?.query;
+        if let Some(connection) = connection {
+            query.connection = connection;
+        }
+        let transaction = Some(String::from(window.label()));
+        let result = request_from_db(query.clone(), query_id, None, transaction, &to_db).await?;
+        Ok(session::NextAction { query, result })
+    }
+
+    /// Remove history entries, returns how many there were
+    #[tauri::command]
+    pub fn delete_history_entries(
+        ids: Vec<i64>,
+        history: State<'_, Arc<History>>,
+    ) -> Result<usize, String> {
+        history.delete(&ids)
+    }
+
+    #[tauri::command]
+    pub fn clear_history(history: State<'_, Arc<History>>) -> Result<usize, String> {
+        history.clear()
+    }
+
+    /// Cancel running query, returns false if no query with this id is running
+    ///
+    /// The cancelled query returns `types::DatabaseError::Cancelled`.
+    #[tauri::command]
+    pub async fn cancel_query(
+        query_id: String,
+        to_db: State<'_, types::StateHalfpipeToDb>,
+    ) -> Result<bool, String> {
+        println!("Called: cancel_query");
+        let (reply_tx, reply_rx) = oneshot::channel();
+        let sender = to_db.inner.lock().await.clone();
+        if sender
+            .send(types::DbMessage::Cancel {
+                query_id,
+                reply: reply_tx,
+            })
+            .await
+            .is_err()
+        {
+            return Err(String::from("cancel_query: Could not send request to task"));
+        }
+        reply_rx
+            .await
+            .map_err(|_| String::from("cancel_query: Did not receive an answer from db task"))
+    }
+
+    /// Run query and stream its rows to the window as "db-rows" events
+    ///
+    /// Each event carries a `types::RowBatch` tagged with the given stream id.
+    /// The returned result has the columns but no rows, it is returned
+    /// after the last batch has been emitted. The stream id also serves
+    /// as id for `cancel_query`. Custom queries run in the window's transaction, if it has one.
+    #[tauri::command]
+    pub async fn db_stream_query(
+        query: types::FullQuery,
+        stream_id: String,
+        batch_size: Option<usize>,
+        window: tauri::Window,
+        to_db: State<'_, types::StateHalfpipeToDb>,
+    ) -> Result<DatabaseQueryResult, String> {
+        println!("Called: db_stream_query");
+        let transaction = Some(String::from(window.label()));
+        let (batch_tx, mut batch_rx) = mpsc::channel(STREAM_BATCHES_IN_FLIGHT);
+        let query_id = stream_id.clone();
+        let forwarder = tokio::spawn(async move {
+            let mut batch_index = 0;
+            while let Some(fields) = batch_rx.recv().await {
+                let batch = types::RowBatch {
+                    stream_id: stream_id.clone(),
+                    batch_index,
+                    fields,
+                };
+                if let Err(e) = window.emit("db-rows", batch) {
+                    println!("Could not emit row batch: {}", e);
+                }
+                batch_index += 1;
+            }
+        });
+        let streaming = types::RowStreaming {
+            sender: batch_tx,
+            batch_size: batch_size.unwrap_or(DEFAULT_STREAM_BATCH_SIZE).max(1),
+        };
+        let result =
+            request_from_db(query, Some(query_id), Some(streaming), transaction, &to_db).await;
+        // the db task dropped its sender, so the forwarder ends after the last batch
+        if forwarder.await.is_err() {
+            println!("Forwarding row batches failed");
+        }
+        result
+    }
+
+    /// Write all rows of the query to a file, reporting progress as "export-progress" events
+    ///
+    /// Each event carries an `export::ExportProgress`. The query runs on a
+    /// client of the pool, not in the window's transaction. The export id
+    /// also serves as id for `cancel_query`. A failed or cancelled export
+    /// removes the file again.
+    #[tauri::command]
+    pub async fn export_query(
+        query: types::FullQuery,
+        path: String,
+        options: export::ExportOptions,
+        export_id: String,
+        window: tauri::Window,
+        to_db: State<'_, types::StateHalfpipeToDb>,
+    ) -> Result<export::ExportSummary, String> {
+        println!("Called: export_query");
+        let table = match &query.query {
+            types::Query::Explain(_) => return Err(String::from("Plans cannot be exported")),
+            types::Query::GetTableContents(table) => {
+                Some((table.schema.clone(), table.table.clone()))
+            }
+            _ => None,
+        };
+        let prepared = request_prepare(query.clone(), None, &to_db).await?;
+        if prepared.columns.is_empty() {
+            return Err(String::from(
+                "Statement returns no rows, there is nothing to export",
+            ));
+        }
+        let file_path = path.clone();
+        let file = tokio::task::spawn_blocking(move || std::fs::File::create(file_path))
+            .await
+            .map_err(|e| e.to_string())?
+            .map_err(|e| format!("Could not create {}: {}", path, e))?;
+
+        let (batch_tx, mut batch_rx) =
+            mpsc::channel::<Vec<Vec<TypedField>>>(STREAM_BATCHES_IN_FLIGHT);
+        let streaming = types::RowStreaming {
+            sender: batch_tx,
+            batch_size: EXPORT_BATCH_SIZE,
+        };
+        let progress_id = export_id.clone();
+        // writing and compressing block, so they run on a thread of their own;
+        // returning early drops the receiver, which stops the query from sending more rows
+        let writing = tokio::task::spawn_blocking(move || {
+            let table = table
+                .as_ref()
+                .map(|(schema, table)| (schema.as_str(), table.as_str()));
+            let mut writer = export::row_writer(
+                std::io::BufWriter::new(file),
+                &prepared.columns,
+                &options,
+                table,
+            )?;
+            let mut rows = 0;
+            while let Some(batch) = batch_rx.blocking_recv() {
+                writer.write_rows(&batch)?;
+                rows += batch.len() as u64;
+                let progress = export::ExportProgress {
+                    export_id: progress_id.clone(),
+                    rows,
+                };
+                if let Err(e) = window.emit("export-progress", progress) {
+                    println!("Could not emit export progress: {}", e);
+                }
+            }
+            writer.finish()?;
+            Ok::<u64, std::io::Error>(rows)
+        });
+        let (result, written) = tokio::join!(
+            request_from_db(query, Some(export_id), Some(streaming), None, &to_db),
+            writing
+        );
+        let written =
+            written.unwrap_or_else(|e| Err(std::io::Error::new(std::io::ErrorKind::Other, e)));
+        let rows = match (result, written) {
+            (Err(e), _) => Err(e),
+            (Ok(DatabaseQueryResult { table: Err(e), .. }), _) => Err(e.to_string()),
+            (Ok(_), Err(e)) => Err(format!("Could not write {}: {}", path, e)),
+            (Ok(_), Ok(rows)) => Ok(rows),
+        }
+        .map_err(|message| remove_export(&path, message))?;
+        println!("Exported {} rows to {}", rows, path);
+        Ok(export::ExportSummary { path, rows })
+    }
+
+    /// Remove file of a failed export, returns the reason it failed
+    fn remove_export(path: &str, message: String) -> String {
+        if let Err(e) = std::fs::remove_file(path) {
+            println!("Could not remove {}: {}", path, e);
         }
+        message
     }
 }
 
-/// Extract string from given column of row
-fn get_field_from_row(row: &tokio_postgres::Row, index: usize) -> String {
-    let res = try_get_field_as_string(row, index);
-    match res {
-        Ok(s) => s,
-        Err(_) => String::from("?"),
+/// Forget everything about a closed window, its open transaction is rolled back
+pub fn window_closed(window: &tauri::Window) {
+    window.state::<Sessions>().remove(window.label());
+    let window = window.clone();
+    tauri::async_runtime::spawn(async move {
+        let to_db = window.state::<types::StateHalfpipeToDb>();
+        let state = commands::request_transaction(
+            window.label(),
+            types::TransactionAction::GetState,
+            &to_db,
+        )
+        .await;
+        if let Ok(transactions::TransactionState { open: true, .. }) = state {
+            if let Err(e) = commands::request_transaction(
+                window.label(),
+                types::TransactionAction::Rollback,
+                &to_db,
+            )
+            .await
+            {
+                println!("Could not roll back transaction of closed window: {}", e);
+            }
+        }
+    });
+}
+
+fn connect_error(message: String) -> types::DatabaseError {
+    types::DatabaseError::Connect(types::ErrorDetails::from_message(message))
+}
+
+/// Connection string for tokio-postgres, with the database set if given
+///
+/// Options not given are filled in like psql does, see `libpq`.
+///
+/// Resolving reads the password and service files, so it runs on a blocking thread.
+async fn get_resulting_connection_string(
+    connection: &types::Connection,
+    database: &Option<String>,
+) -> Result<String, types::DatabaseError> {
+    let mut params = connection.to_params().map_err(connect_error)?;
+    if let Some(dbname) = database {
+        params.dbname = Some(dbname.clone());
     }
+    let params = tokio::task::spawn_blocking(move || libpq::resolve(&mut params).map(|_| params))
+        .await
+        .map_err(|e| connect_error(e.to_string()))?
+        .map_err(connect_error)?;
+    Ok(conninfo::to_keyword_string(&params))
 }
 
 /// Print debug output of rows
-fn debug_rows(column_names: &Vec<String>, rows: &Vec<tokio_postgres::Row>) {
+fn debug_rows(column_names: &[String], fields: &[Vec<TypedField>]) {
     println!("Column names: {:?}", column_names);
 
-    println!("number of rows in result: {}", rows.len());
+    println!("number of rows in result: {}", fields.len());
 
-    for row in rows.iter() {
+    for row in fields.iter() {
         print!("# {}: ", row.len());
-        for i in 0..row.len() {
-            print!(" {}", get_field_from_row(row, i));
+        for field in row.iter() {
+            print!(" {:?}", field);
         }
-        println!("");
+        println!();
     }
 }
 
-/// Convert row to vector of str
-fn row_to_strings(row: &tokio_postgres::Row) -> Vec<String> {
-    (0..row.len())
-        .into_iter()
-        .map(|index| get_field_from_row(row, index))
-        .collect()
+async fn row_to_typed_fields(
+    client: &tokio_postgres::Client,
+    renderer: &mut convert::ServerRenderer,
+    row: &tokio_postgres::Row,
+) -> Vec<TypedField> {
+    let mut fields = Vec::with_capacity(row.len());
+    for column_index in 0..row.len() {
+        fields.push(convert::get_typed_field(client, renderer, row, column_index).await);
+    }
+    fields
 }
 
-fn row_to_basic_fields(row: &tokio_postgres::Row) -> Vec<BasicTextField> {
-    let strings = row_to_strings(row);
-    strings
-        .into_iter()
-        .enumerate()
-        .map(|(column_index, s)| BasicTextField {
-            text: s,
-            column_index: column_index,
-        })
+fn column_names(columns: &[tokio_postgres::Column]) -> Vec<String> {
+    columns
+        .iter()
+        .map(|column| String::from(column.name()))
         .collect()
 }
 
-fn get_column_name(row: &tokio_postgres::Row, column_index: usize) -> String {
-    String::from(row.columns()[column_index].name())
+/// Prepare query, errors in the SQL text are reported as parse errors
+async fn prepare_statement(
+    client: &tokio_postgres::Client,
+    query: &SqlStatement,
+) -> Result<tokio_postgres::Statement, types::DatabaseError> {
+    println!(
+        "Query: \"{}\" {:?} {:?}",
+        query.text, query.params, query.values
+    );
+    client
+        .prepare(&query.text)
+        .await
+        .map_err(|e| types::DatabaseError::Parse(types::ErrorDetails::from(&e)))
 }
 
-fn row_to_column_names(row: &tokio_postgres::Row) -> Vec<String> {
-    (0..row.len())
-        .map(|column_index| get_column_name(&row, column_index))
-        .collect()
+fn execute_error(e: tokio_postgres::Error) -> types::DatabaseError {
+    types::DatabaseError::Execute(types::ErrorDetails::from(&e))
 }
 
-/// Connect to database and run single query
+/// Result of a query before it is sent back
+struct QueryOutcome {
+    table: types::TypedTable,
+    truncated: bool,
+    rows_affected: Option<u64>,
+    command_tag: Option<String>,
+    row_estimate: Option<i64>,
+    tls: Option<types::TlsState>,
+    details: Option<types::ResultDetails>,
+}
+
+/// Set statement timeout of the session, the server aborts statements running longer
+async fn set_statement_timeout(
+    client: &tokio_postgres::Client,
+    limits: &types::QueryLimits,
+) -> Result<(), types::DatabaseError> {
+    match limits.statement_timeout_ms {
+        Some(timeout) => client
+            .batch_execute(&format!("SET statement_timeout = {}", timeout))
+            .await
+            .map_err(execute_error),
+        None => Ok(()),
+    }
+}
+
+/// Back to the server's statement timeout, before the client returns to the pool
+async fn reset_statement_timeout(client: &tokio_postgres::Client, limits: &types::QueryLimits) {
+    if limits.statement_timeout_ms.is_some() {
+        if let Err(e) = client.batch_execute("RESET statement_timeout").await {
+            println!("Could not reset statement timeout: {}", e);
+        }
+    }
+}
+
+/// Rows read by `fetch_rows`
+struct FetchedRows {
+    rows: Vec<tokio_postgres::Row>,
+    truncated: bool,
+    /// From the command tag, if the statement ran to completion
+    rows_affected: Option<u64>,
+}
+
+/// Run prepared statement, reading at most `max_rows` rows
+///
+/// The rows are fetched from a portal, so the server stops producing rows
+/// once the limit is reached. Portals only exist inside a transaction, which
+/// is committed afterwards. Statements without result columns run without
+/// a transaction, as some of them (e.g. VACUUM) must not run inside one.
+///
+/// Within a transaction of the user (`in_transaction`) there is no
+/// transaction of our own, rows beyond the limit are read and dropped.
+///
+async fn fetch_rows(
+    client: &mut tokio_postgres::Client,
+    statement: &tokio_postgres::Statement,
+    params: &[&(dyn ToSql + Sync)],
+    max_rows: Option<u64>,
+    in_transaction: bool,
+) -> Result<FetchedRows, types::DatabaseError> {
+    match max_rows {
+        Some(max_rows) if in_transaction => {
+            let rows = client
+                .query_raw(statement, params.iter().copied())
+                .await
+                .map_err(execute_error)?;
+            pin_mut!(rows);
+            let mut kept = Vec::new();
+            let mut truncated = false;
+            while let Some(row) = rows.try_next().await.map_err(execute_error)? {
+                if kept.len() as u64 == max_rows {
+                    truncated = true;
+                } else {
+                    kept.push(row);
+                }
+            }
+            Ok(FetchedRows {
+                rows: kept,
+                truncated,
+                rows_affected: rows.rows_affected(),
+            })
+        }
+        Some(max_rows) if !statement.columns().is_empty() => {
+            let transaction = client.transaction().await.map_err(execute_error)?;
+            let portal = transaction
+                .bind(statement, params)
+                .await
+                .map_err(execute_error)?;
+            // one more row than allowed tells whether the result was truncated
+            let fetch = i32::try_from(max_rows.saturating_add(1)).unwrap_or(i32::MAX);
+            let mut rows = transaction
+                .query_portal(&portal, fetch)
+                .await
+                .map_err(execute_error)?;
+            drop(portal);
+            transaction.commit().await.map_err(execute_error)?;
+
+            let truncated = rows.len() as u64 > max_rows;
+            if truncated {
+                rows.truncate(max_rows as usize);
+            }
+            // the server did not finish the statement, the number of rows is unknown
+            let rows_affected = if truncated {
+                None
+            } else {
+                Some(rows.len() as u64)
+            };
+            Ok(FetchedRows {
+                rows,
+                truncated,
+                rows_affected,
+            })
+        }
+        _ => {
+            if statement.columns().is_empty() {
+                let rows_affected = client
+                    .execute(statement, params)
+                    .await
+                    .map_err(execute_error)?;
+                return Ok(FetchedRows {
+                    rows: Vec::new(),
+                    truncated: false,
+                    rows_affected: Some(rows_affected),
+                });
+            }
+            let rows = client
+                .query(statement, params)
+                .await
+                .map_err(execute_error)?;
+            Ok(FetchedRows {
+                rows_affected: Some(rows.len() as u64),
+                rows,
+                truncated: false,
+            })
+        }
+    }
+}
+
+/// Run single query on an existing client
+///
+/// The statement is prepared first, so that errors in the SQL text
+/// are told apart from errors during execution.
+///
+async fn run_query(
+    client: &mut tokio_postgres::Client,
+    query: &SqlStatement,
+    max_rows: Option<u64>,
+    in_transaction: bool,
+) -> Result<QueryOutcome, types::DatabaseError> {
+    let statement = prepare_statement(client, query).await?;
+    let values = params::bind(query, statement.params())?;
+    let params: Vec<&(dyn ToSql + Sync)> =
+        values.iter().map(|p| p as &(dyn ToSql + Sync)).collect();
+    let FetchedRows {
+        rows,
+        truncated,
+        rows_affected,
+    } = fetch_rows(client, &statement, &params, max_rows, in_transaction).await?;
+
+    let column_names = column_names(statement.columns());
+    let column_types = convert::column_types(statement.columns());
+    let mut fields: Vec<Vec<TypedField>> = Vec::with_capacity(rows.len());
+    let mut renderer = convert::ServerRenderer::default();
+    for row in rows.iter() {
+        fields.push(row_to_typed_fields(client, &mut renderer, row).await);
+    }
+    debug_rows(&column_names, &fields);
+    if truncated {
+        println!("Result truncated to {} rows", fields.len());
+    }
+
+    Ok(QueryOutcome {
+        table: types::TypedTable {
+            columns: column_names,
+            column_types,
+            fields,
+        },
+        truncated,
+        rows_affected,
+        // unknown for statements that were stopped at the row limit
+        command_tag: rows_affected.map(|rows| script::command_tag(&query.text, Some(rows))),
+        row_estimate: None,
+        tls: None,
+        details: None,
+    })
+}
+
+/// Run EXPLAIN of a query and read its plan into the details
+///
+/// EXPLAIN ANALYZE executes the statement, so it runs in a transaction
+/// that is rolled back. Within an open transaction a savepoint takes its
+/// place, which also keeps a failing EXPLAIN from aborting the transaction.
+async fn run_explain(
+    client: &mut tokio_postgres::Client,
+    explain: &types::ExplainQuery,
+    query: &SqlStatement,
+    in_transaction: bool,
+) -> Result<QueryOutcome, types::DatabaseError> {
+    let enclosing = if in_transaction {
+        Some((
+            "SAVEPOINT explain",
+            "ROLLBACK TO SAVEPOINT explain; RELEASE SAVEPOINT explain",
+        ))
+    } else if explain.analyze {
+        Some(("BEGIN", "ROLLBACK"))
+    } else {
+        None
+    };
+    if let Some((begin, _)) = enclosing {
+        client.batch_execute(begin).await.map_err(execute_error)?;
+    }
+    let result = run_query(client, query, None, false).await;
+    if let Some((_, rollback)) = enclosing {
+        client
+            .batch_execute(rollback)
+            .await
+            .map_err(execute_error)?;
+    }
+    // positions of errors refer to the query, not to the EXPLAIN around it
+    let prefix_len = (query.text.chars().count() - explain.query.sql_query.chars().count()) as u32;
+    let outcome = result.map_err(|mut e| {
+        let details = e.details_mut();
+        details.position = details
+            .position
+            .map(|position| position.saturating_sub(prefix_len));
+        e
+    })?;
+    let plan = explain::plan_from_table(&outcome.table).map_err(|message| {
+        types::DatabaseError::Decode(types::ErrorDetails::from_message(message))
+    })?;
+    Ok(QueryOutcome {
+        details: Some(types::ResultDetails::Plan(Box::new(plan))),
+        ..outcome
+    })
+}
+
+/// Connect to database and run single query within the limits
+///
+/// The connection is closed afterwards, it is not taken from the pool.
 ///
 async fn run_standalone_query(
     connection_str: String,
-    query: &String,
-) -> Result<types::BasicTextTable, Box<dyn std::error::Error + Send + Sync>> {
-    println!("Connection-String: \"{}\"", connection_str);
-    let (client, connection) =
-        tokio_postgres::connect(&connection_str, tokio_postgres::NoTls).await?;
+    query: &SqlStatement,
+    limits: &types::QueryLimits,
+) -> Result<QueryOutcome, types::DatabaseError> {
+    let mut open = pool::connect(&connection_str).await?;
+    set_statement_timeout(&open.client, limits).await?;
+    let outcome = run_query(&mut open.client, query, limits.max_rows, false).await?;
+    Ok(QueryOutcome {
+        tls: Some(open.tls_state),
+        ..outcome
+    })
+}
 
-    let token = CancellationToken::new();
-    let cloned_token = token.clone();
+/// Run single query, sending its rows in batches instead of returning them
+///
+/// Values that need the server for their conversion are rendered on a
+/// second connection, as the first one is busy delivering rows. It is opened
+/// outside of the pool, so that streams never wait for each other's slots.
+///
+async fn stream_query(
+    connection_str: &str,
+    client: &tokio_postgres::Client,
+    query: &types::Query,
+    query_statement: &SqlStatement,
+    streaming: types::RowStreaming,
+) -> Result<QueryOutcome, types::DatabaseError> {
+    let statement = prepare_statement(client, query_statement).await?;
+    let values = params::bind(query_statement, statement.params())?;
+    let rows = client
+        .query_raw(&statement, values.iter())
+        .await
+        .map_err(execute_error)?;
+    pin_mut!(rows);
 
-    let db_connector_handle = tokio::spawn(async move {
-        select! {
-            _ = cloned_token.cancelled() => {}
-            _ = connection => {}
+    let mut renderer: Option<pool::OpenClient> = None;
+    let mut render_statements = convert::ServerRenderer::default();
+    let mut batch = Vec::with_capacity(streaming.batch_size);
+    let mut row_count = 0;
+    while let Some(row) = rows.try_next().await.map_err(execute_error)? {
+        let mut fields = Vec::with_capacity(row.len());
+        for index in 0..row.len() {
+            let field = match convert::get_typed_field_locally(&row, index) {
+                convert::LocalField::Done(field) => field,
+                convert::LocalField::NeedsServer(ty, raw) => {
+                    if renderer.is_none() {
+                        renderer = Some(pool::connect(connection_str).await?);
+                    }
+                    let renderer = renderer.as_ref().expect("renderer was connected above");
+                    render_statements
+                        .render_field(&renderer.client, ty, raw)
+                        .await
+                }
+            };
+            fields.push(convert_to_typed_cell(field, query));
         }
-    });
-    println!("Query: \"{}\"", query);
-    let rows = client.query(query, &[]).await?;
-    token.cancel();
-    db_connector_handle.await?;
+        batch.push(fields);
+        row_count += 1;
+        if batch.len() >= streaming.batch_size
+            && streaming
+                .sender
+                .send(std::mem::take(&mut batch))
+                .await
+                .is_err()
+        {
+            println!("Receiver of row batches is gone, stop streaming");
+            break;
+        }
+    }
+    if !batch.is_empty() && streaming.sender.send(batch).await.is_err() {
+        println!("Receiver of row batches is gone");
+    }
+    println!("Streamed {} rows", row_count);
 
-    let column_names = if rows.len() > 0 {
-        row_to_column_names(&rows[0])
-    } else {
-        Vec::new()
-    };
-    debug_rows(&column_names, &rows);
+    Ok(QueryOutcome {
+        table: types::TypedTable {
+            columns: column_names(statement.columns()),
+            column_types: convert::column_types(statement.columns()),
+            fields: Vec::new(),
+        },
+        truncated: false,
+        rows_affected: rows.rows_affected(),
+        command_tag: rows
+            .rows_affected()
+            .map(|affected| script::command_tag(&query_statement.text, Some(affected))),
+        row_estimate: None,
+        tls: None,
+        details: None,
+    })
+}
 
-    let fields: Vec<Vec<BasicTextField>> = rows
-        .into_iter()
-        .map(|row| row_to_basic_fields(&row))
-        .collect();
+/// Planner's estimate of the number of rows in the table
+async fn estimate_row_count(
+    client: &tokio_postgres::Client,
+    table: &types::DatabaseTable,
+) -> Option<i64> {
+    let row = client
+        .query_opt(
+            "SELECT reltuples::bigint FROM pg_class WHERE oid = to_regclass($1)",
+            &[&sql::qualified_name(&table.schema, &table.table)],
+        )
+        .await
+        .ok()??;
+    // tables never analyzed have no estimate (-1)
+    row.try_get::<usize, i64>(0)
+        .ok()
+        .filter(|count| *count >= 0)
+}
+
+/// Columns giving table pages a stable order: the primary key, else ctid for tables
+/// and materialized views, else none (views)
+async fn table_order(
+    client: &tokio_postgres::Client,
+    table: &types::DatabaseTable,
+) -> Result<Vec<String>, tokio_postgres::Error> {
+    let row = client
+        .query_one(
+            "SELECT COALESCE(
+                (SELECT array_agg(a.attname::text ORDER BY k.position)
+                FROM pg_catalog.pg_index i
+                CROSS JOIN LATERAL unnest(i.indkey) WITH ORDINALITY AS k(attnum, position)
+                JOIN pg_catalog.pg_attribute a ON a.attrelid = i.indrelid AND a.attnum = k.attnum
+                WHERE i.indrelid = c.oid AND i.indisprimary),
+                CASE WHEN c.relkind IN ('r', 'm') THEN ARRAY['ctid'] ELSE ARRAY[]::text[] END)
+            FROM pg_catalog.pg_class c WHERE c.oid = $1::text::regclass",
+            &[&sql::qualified_name(&table.schema, &table.table)],
+        )
+        .await?;
+    row.try_get(0)
+}
 
-    let basic_table = BasicTextTable {
-        columns: column_names,
-        fields: fields,
+/// Fill in the order of offset pages, so that the pages neither overlap nor skip rows
+///
+/// Without an order it is left to the plan, which may change from one page to the next.
+async fn order_table_pages(
+    pool: &pool::ConnectionPool,
+    connection_str: &str,
+    query: &mut types::Query,
+) {
+    let table = match query {
+        types::Query::GetTableContents(table) => table,
+        _ => return,
+    };
+    if !matches!(&table.pagination, Some(types::Pagination::Offset { order_by, .. }) if order_by.is_empty())
+    {
+        return;
+    }
+    let client = match pool.acquire(connection_str).await {
+        Ok(client) => client,
+        Err(e) => {
+            println!("Could not look up order of table pages: {}", e);
+            return;
+        }
     };
-    Ok(basic_table)
+    match table_order(&client, table).await {
+        Ok(columns) => {
+            if let Some(types::Pagination::Offset { order_by, .. }) = &mut table.pagination {
+                *order_by = columns;
+            }
+        }
+        Err(e) => println!("Could not look up order of table pages: {}", e),
+    }
+    pool.release(client).await;
 }
 
-async fn run_check_connection(connection_str: String) -> bool {
-    let q = String::from("SELECT 147 as a;");
-    if let Ok(table) = run_standalone_query(connection_str, &q).await {
-        let size_okay = (&table).fields.len() == 1 && (&table).fields[0].len() == 1;
-        if !size_okay {
-            false
-        } else {
-            let item = &table.fields[0][0];
-            item.text == String::from("147")
+/// Run single query on a client taken from the pool, within the limits
+///
+/// Table contents come with an estimate of the total number of rows,
+/// table descriptions with the structure of the table.
+/// Streamed results are not limited in rows, as they are not kept in memory.
+///
+async fn run_pooled_query(
+    pool: &pool::ConnectionPool,
+    connection_str: String,
+    query: &types::Query,
+    query_statement: &SqlStatement,
+    limits: &types::QueryLimits,
+    streaming: Option<types::RowStreaming>,
+    handle: Option<&running::QueryHandle>,
+) -> Result<QueryOutcome, types::DatabaseError> {
+    let mut client = pool.acquire(&connection_str).await?;
+    if let types::Query::CustomQuery(_) | types::Query::Explain(_) = query {
+        client.mark_session_changed();
+    }
+    if let Some(handle) = handle {
+        if !handle.attach(&client, client.connector().clone()) {
+            pool.release(client).await;
+            return Err(cancelled_before_start());
         }
-    } else {
-        false
     }
+    if let Err(e) = set_statement_timeout(&client, limits).await {
+        pool.release(client).await;
+        return Err(e);
+    }
+    let mut result = match (query, streaming) {
+        (types::Query::Explain(explain), _) => {
+            run_explain(&mut client, explain, query_statement, false).await
+        }
+        (_, None) => run_query(&mut client, query_statement, limits.max_rows, false)
+            .await
+            .map(|outcome| QueryOutcome {
+                table: convert_rows(outcome.table, query),
+                ..outcome
+            }),
+        (_, Some(streaming)) => {
+            stream_query(&connection_str, &client, query, query_statement, streaming).await
+        }
+    };
+    if let (types::Query::DescribeTable(db_table), Ok(_)) = (query, &result) {
+        result = match describe::describe_table(&client, db_table).await {
+            Ok(structure) => result.map(|outcome| QueryOutcome {
+                details: Some(types::ResultDetails::TableStructure(Box::new(structure))),
+                ..outcome
+            }),
+            Err(e) => Err(e),
+        };
+    }
+    reset_statement_timeout(&client, limits).await;
+    if let (types::Query::GetTableContents(db_table), Ok(outcome)) = (query, &mut result) {
+        outcome.row_estimate = estimate_row_count(&client, db_table).await;
+    }
+    if let Ok(outcome) = &mut result {
+        outcome.tls = Some(client.tls_state().clone());
+    }
+    pool.release(client).await;
+    result
+}
+
+async fn run_check_connection(
+    connection: types::Connection,
+) -> Result<types::ConnectionCheck, types::DatabaseError> {
+    let connection_str = get_resulting_connection_string(&connection, &None).await?;
+    let q = SqlStatement::verbatim("SELECT 147 as a;");
+    let outcome = run_standalone_query(connection_str, &q, &types::QueryLimits::default()).await?;
+    let table = outcome.table;
+    let size_okay = table.fields.len() == 1 && table.fields[0].len() == 1;
+    Ok(types::ConnectionCheck {
+        okay: size_okay && table.fields[0][0] == TypedField::Integer(147),
+        tls: outcome
+            .tls
+            .expect("standalone queries know their TLS state"),
+    })
 }
 
-/// Convert single text field to a typed field
+/// Give a cell its meaning in the context of the query
 ///
-/// This requires some context knowledge
+/// Names returned by navigation queries are databases, schemas or tables,
+/// everything else keeps its type.
 ///
-fn convert_to_typed_cell(text_cell: types::BasicTextField, query: &types::Query) -> TypedField {
-    let s = text_cell.text;
-    match query {
-        types::Query::CustomQuery(_) => TypedField::Text(s),
-        types::Query::GetDatabases => TypedField::Database(s),
-        types::Query::GetTables(_) => TypedField::Table(s),
-        types::Query::GetTableContents(_) => TypedField::Text(s),
+fn convert_to_typed_cell(cell: TypedField, query: &types::Query) -> TypedField {
+    match (query, cell) {
+        (types::Query::GetDatabases, TypedField::Text(s)) => TypedField::Database(s),
+        (types::Query::GetSchemas(_), TypedField::Text(s)) => TypedField::Schema(s),
+        (types::Query::GetTables(_), TypedField::Text(s)) => TypedField::Table(s),
+        (_, cell) => cell,
     }
 }
 
-/// Convert table so that elements from text to typed elements.
+/// Convert table so that navigation results become links.
 ///
-/// Originally all cells in the table are just text entries.
-/// Depending on context (query), they can be specified as being databases or tables.
+/// Depending on context (query), text cells can be specified as being databases, schemas or tables.
 ///
-fn convert_rows(table: types::BasicTextTable, query: &types::Query) -> types::TypedTable {
+fn convert_rows(table: types::TypedTable, query: &types::Query) -> types::TypedTable {
     let fields = table
         .fields
         .into_iter()
         .map(|row| {
             row.into_iter()
-                .map(|text_cell: BasicTextField| convert_to_typed_cell(text_cell, query))
+                .map(|cell| convert_to_typed_cell(cell, query))
                 .collect()
         })
         .collect();
     types::TypedTable {
         columns: table.columns,
-        fields: fields,
+        column_types: table.column_types,
+        fields,
+    }
+}
+
+fn cancelled_before_start() -> types::DatabaseError {
+    types::DatabaseError::Cancelled(types::ErrorDetails::from_message(String::from(
+        "query was cancelled before it started",
+    )))
+}
+
+/// Errors of a query cancelled by the user become `Cancelled`
+///
+/// The server reports a cancelled statement as an ordinary error
+/// (SQLSTATE 57014, which is also used for statement timeouts).
+///
+fn mark_cancelled(
+    error: types::DatabaseError,
+    handle: Option<&running::QueryHandle>,
+) -> types::DatabaseError {
+    match (handle, error) {
+        (_, error @ types::DatabaseError::Cancelled(_)) => error,
+        (Some(handle), error) if handle.was_cancelled() => {
+            types::DatabaseError::Cancelled(error.details().clone())
+        }
+        (_, error) => error,
+    }
+}
+
+/// Run custom query (or its EXPLAIN) on the client of an open transaction
+///
+/// The statement timeout was set when the transaction began.
+async fn run_in_transaction(
+    held: &mut transactions::HeldTransaction,
+    connection_str: &str,
+    db_query: &types::FullQuery,
+    query_statement: &SqlStatement,
+    streaming: Option<types::RowStreaming>,
+    handle: Option<&running::QueryHandle>,
+) -> Result<QueryOutcome, types::DatabaseError> {
+    transactions::refuse_transaction_control(&query_statement.text)?;
+    let query = &db_query.query;
+    let client = held.client(connection_str)?;
+    if let Some(handle) = handle {
+        if !handle.attach(client, client.connector().clone()) {
+            return Err(cancelled_before_start());
+        }
+    }
+    let result = match (query, streaming) {
+        (types::Query::Explain(explain), _) => {
+            run_explain(client, explain, query_statement, true).await
+        }
+        (_, None) => run_query(client, query_statement, db_query.limits.max_rows, true).await,
+        (_, Some(streaming)) => {
+            stream_query(connection_str, client, query, query_statement, streaming).await
+        }
+    }
+    .map(|outcome| QueryOutcome {
+        tls: Some(client.tls_state().clone()),
+        ..outcome
+    });
+    // a failing EXPLAIN is rolled back to its savepoint, it succeeds only if the transaction had not failed
+    if !matches!(query, types::Query::Explain(_)) || result.is_ok() {
+        held.record_outcome(result.is_ok());
+    }
+    result
+}
+
+/// Run query of a request and assemble its result
+///
+/// Custom queries run in the transaction if one is given, everything else
+/// on a client of the pool.
+async fn handle_query(
+    pool: &pool::ConnectionPool,
+    mut db_query: types::FullQuery,
+    streaming: Option<types::RowStreaming>,
+    transaction: Option<Arc<tokio::sync::Mutex<transactions::HeldTransaction>>>,
+    handle: Option<&running::QueryHandle>,
+) -> DatabaseQueryResult {
+    println!(
+        "Received db query: {:?} on \"{}\"",
+        db_query.query,
+        db_query.connection.redacted()
+    );
+
+    let database: Option<String> = db_query.query.get_mentioned_database();
+    let connection_str = get_resulting_connection_string(&db_query.connection, &database).await;
+    if let Ok(connection_str) = &connection_str {
+        order_table_pages(pool, connection_str, &mut db_query.query).await;
+    }
+    let statement = db_query.query.get_statement();
+
+    let table_data = async {
+        let connection_str = connection_str?;
+        match (&db_query.query, transaction) {
+            (types::Query::CustomQuery(_) | types::Query::Explain(_), Some(held)) => {
+                let mut held = held.lock().await;
+                run_in_transaction(
+                    &mut held,
+                    &connection_str,
+                    &db_query,
+                    &statement,
+                    streaming,
+                    handle,
+                )
+                .await
+            }
+            _ => {
+                run_pooled_query(
+                    pool,
+                    connection_str,
+                    &db_query.query,
+                    &statement,
+                    &db_query.limits,
+                    streaming,
+                    handle,
+                )
+                .await
+            }
+        }
+    }
+    .await
+    .map_err(|e| mark_cancelled(e, handle));
+    match table_data {
+        Ok(outcome) => DatabaseQueryResult {
+            database,
+            sql_query: statement.display,
+            row_estimate: outcome.row_estimate,
+            truncated: outcome.truncated,
+            tls: outcome.tls,
+            details: outcome.details,
+            returns_rows: !outcome.table.columns.is_empty(),
+            rows_affected: outcome.rows_affected,
+            command_tag: outcome.command_tag,
+            table: Ok(outcome.table),
+        },
+        Err(e) => {
+            println!("Error executing query - no results: {}", e);
+            DatabaseQueryResult {
+                database,
+                sql_query: statement.display,
+                table: Err(e),
+                row_estimate: None,
+                truncated: false,
+                tls: None,
+                details: None,
+                returns_rows: false,
+                rows_affected: None,
+                command_tag: None,
+            }
+        }
+    }
+}
+
+/// Run query in a task of its own, so that it does not block other requests
+///
+/// The query is recorded in the history after its result was returned,
+/// on a blocking thread as writing to the history file blocks.
+fn spawn_query(
+    pool: &Arc<pool::ConnectionPool>,
+    running_queries: &Arc<running::RunningQueries>,
+    transactions: &transactions::Transactions,
+    history: &Arc<History>,
+    request: types::DbRequest,
+) {
+    let pool = pool.clone();
+    let history = history.clone();
+    let handle = request.query_id.map(|id| running_queries.register(id));
+    let transaction = match (&request.query.query, &request.transaction) {
+        (types::Query::CustomQuery(_) | types::Query::Explain(_), Some(window)) => {
+            transactions.get(window)
+        }
+        _ => None,
+    };
+    tokio::spawn(async move {
+        let executed_at = SystemTime::now();
+        let started = Instant::now();
+        let query = request.query.clone();
+        let database_result = handle_query(
+            &pool,
+            request.query,
+            request.streaming,
+            transaction,
+            handle.as_ref(),
+        )
+        .await;
+        let record =
+            history::HistoryRecord::new(&query, &database_result, executed_at, started.elapsed());
+        if request.reply.send(database_result).is_err() {
+            println!("Could not return results to caller, caller is gone");
+        } else {
+            println!("Returned results to caller okay");
+        }
+        match tokio::task::spawn_blocking(move || history.record(&record)).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(message)) => println!("Could not record query in history: {}", message),
+            Err(e) => println!("Could not record query in history: {}", e),
+        }
+    });
+}
+
+/// Prepare statement of the query without running it
+///
+/// In a transaction the statement sees the tables created in it. As a
+/// failing prepare aborts the transaction, its outcome is recorded.
+async fn handle_prepare(
+    pool: &pool::ConnectionPool,
+    query: types::FullQuery,
+    transaction: Option<Arc<tokio::sync::Mutex<transactions::HeldTransaction>>>,
+) -> Result<params::PreparedQuery, types::DatabaseError> {
+    let connection_str =
+        get_resulting_connection_string(&query.connection, &query.query.get_mentioned_database())
+            .await?;
+    let statement = query.query.get_statement();
+    match transaction {
+        Some(held) => {
+            let mut held = held.lock().await;
+            let result = prepare_statement(held.client(&connection_str)?, &statement).await;
+            held.record_outcome(result.is_ok());
+            result.map(|prepared| params::PreparedQuery::from(&prepared))
+        }
+        None => {
+            let client = pool.acquire(&connection_str).await?;
+            let result = prepare_statement(&client, &statement).await;
+            pool.release(client).await;
+            result.map(|prepared| params::PreparedQuery::from(&prepared))
+        }
+    }
+}
+
+fn spawn_prepare(
+    pool: &Arc<pool::ConnectionPool>,
+    transactions: &transactions::Transactions,
+    query: types::FullQuery,
+    transaction: Option<String>,
+    reply: oneshot::Sender<Result<params::PreparedQuery, types::DatabaseError>>,
+) {
+    let pool = pool.clone();
+    let transaction = match (&query.query, &transaction) {
+        (types::Query::CustomQuery(_), Some(window)) => transactions.get(window),
+        _ => None,
+    };
+    tokio::spawn(async move {
+        let result = handle_prepare(&pool, query, transaction).await;
+        if reply.send(result).is_err() {
+            println!("Could not return prepared query to caller, caller is gone");
+        }
+    });
+}
+
+/// Import file on a client of the pool
+///
+/// After an error the state of the connection is unknown, so the client
+/// is closed instead of being returned to the pool.
+async fn handle_import(
+    pool: &pool::ConnectionPool,
+    import: import::TableImport,
+    import_id: &str,
+    progress: mpsc::Sender<import::ImportProgress>,
+    handle: &running::QueryHandle,
+) -> Result<import::ImportResult, types::DatabaseError> {
+    let connection_str =
+        get_resulting_connection_string(&import.connection, &import.table.database).await?;
+    let client = pool.acquire(&connection_str).await?;
+    if !handle.attach(&client, client.connector().clone()) {
+        return Err(cancelled_before_start());
+    }
+    let result = import::import_file(&client, &import, import_id, &progress, || {
+        handle.was_cancelled()
+    })
+    .await
+    .map_err(|e| mark_cancelled(e, Some(handle)))?;
+    pool.release(client).await;
+    Ok(result)
+}
+
+fn spawn_import(
+    pool: &Arc<pool::ConnectionPool>,
+    running_queries: &Arc<running::RunningQueries>,
+    import: import::TableImport,
+    import_id: String,
+    progress: mpsc::Sender<import::ImportProgress>,
+    reply: oneshot::Sender<Result<import::ImportResult, types::DatabaseError>>,
+) {
+    let pool = pool.clone();
+    let handle = running_queries.register(import_id.clone());
+    tokio::spawn(async move {
+        let result = handle_import(&pool, import, &import_id, progress, &handle).await;
+        if reply.send(result).is_err() {
+            println!("Could not return import result to caller, caller is gone");
+        }
+    });
+}
+
+/// Apply edits on a client of the pool
+///
+/// After an error the state of the connection is unknown, so the client
+/// is closed instead of being returned to the pool.
+async fn handle_edits(
+    pool: &pool::ConnectionPool,
+    edits: edit::TableEdits,
+) -> Result<edit::EditResult, types::DatabaseError> {
+    let connection_str =
+        get_resulting_connection_string(&edits.connection, &edits.table.database).await?;
+    let client = pool.acquire(&connection_str).await?;
+    set_statement_timeout(&client, &edits.limits).await?;
+    let result = edit::apply_edits(&client, &edits.table, &edits.edits).await?;
+    reset_statement_timeout(&client, &edits.limits).await;
+    pool.release(client).await;
+    Ok(result)
+}
+
+fn spawn_edits(
+    pool: &Arc<pool::ConnectionPool>,
+    edits: edit::TableEdits,
+    reply: oneshot::Sender<Result<edit::EditResult, types::DatabaseError>>,
+) {
+    let pool = pool.clone();
+    tokio::spawn(async move {
+        let result = handle_edits(&pool, edits).await;
+        if reply.send(result).is_err() {
+            println!("Could not return edit results to caller, caller is gone");
+        }
+    });
+}
+
+/// Run script on a client of its own, or on the client of the window's transaction
+///
+/// The client of its own is closed afterwards instead of being returned to
+/// the pool, as the script may have left a transaction or settings behind.
+async fn handle_script(
+    pool: &pool::ConnectionPool,
+    script: script::Script,
+    transaction: Option<Arc<tokio::sync::Mutex<transactions::HeldTransaction>>>,
+    handle: Option<&running::QueryHandle>,
+) -> Result<script::ScriptResult, types::DatabaseError> {
+    let connection_str =
+        get_resulting_connection_string(&script.connection, &script.database).await?;
+    let statements = script::split_statements(&script.sql_script);
+    let was_cancelled =
+        |_: &types::DatabaseError| handle.map_or(false, |handle| handle.was_cancelled());
+    let max_rows = script.limits.max_rows;
+
+    let mut held = match &transaction {
+        Some(held) => Some(held.lock().await),
+        None => None,
+    };
+    let mut own_client = None;
+    let client = match held.as_mut() {
+        Some(held) => {
+            for statement in &statements {
+                transactions::refuse_transaction_control(&statement.text)?;
+            }
+            held.client(&connection_str)?
+        }
+        None => {
+            let client = pool.acquire(&connection_str).await?;
+            set_statement_timeout(&client, &script.limits).await?;
+            own_client.insert(client)
+        }
+    };
+    if let Some(handle) = handle {
+        if !handle.attach(client, client.connector().clone()) {
+            return Err(cancelled_before_start());
+        }
+    }
+    let tls = client.tls_state().clone();
+    let (mut results, skipped) =
+        script::run_script(client, statements, script.on_error, max_rows, was_cancelled).await;
+
+    if let Some(last) = results.last_mut() {
+        if let Some(held) = held.as_mut() {
+            held.record_outcome(last.table.is_ok());
+        }
+        if let Err(e) = &last.table {
+            last.table = Err(mark_cancelled(e.clone(), handle));
+        }
+    }
+    Ok(script::ScriptResult {
+        database: script.database,
+        statements: results,
+        skipped,
+        tls: Some(tls),
+    })
+}
+
+fn spawn_script(
+    pool: &Arc<pool::ConnectionPool>,
+    running_queries: &Arc<running::RunningQueries>,
+    transactions: &transactions::Transactions,
+    script: script::Script,
+    window: String,
+    query_id: Option<String>,
+    reply: oneshot::Sender<Result<script::ScriptResult, types::DatabaseError>>,
+) {
+    let pool = pool.clone();
+    let handle = query_id.map(|id| running_queries.register(id));
+    let transaction = transactions.get(&window);
+    tokio::spawn(async move {
+        let result = handle_script(&pool, script, transaction, handle.as_ref()).await;
+        if reply.send(result).is_err() {
+            println!("Could not return script results to caller, caller is gone");
+        }
+    });
+}
+
+/// Change transaction of a window in a task of its own, a commit may wait for a running query
+fn spawn_transaction_action(
+    pool: &Arc<pool::ConnectionPool>,
+    transactions: &Arc<transactions::Transactions>,
+    window: String,
+    action: types::TransactionAction,
+    reply: oneshot::Sender<Result<transactions::TransactionState, types::DatabaseError>>,
+) {
+    let pool = pool.clone();
+    let transactions = transactions.clone();
+    tokio::spawn(async move {
+        let result = match action {
+            types::TransactionAction::Begin {
+                connection_str,
+                database,
+                limits,
+            } => {
+                transactions
+                    .begin(&pool, &window, connection_str, database, limits)
+                    .await
+            }
+            types::TransactionAction::Commit => transactions.end(&pool, &window, true).await,
+            types::TransactionAction::Rollback => transactions.end(&pool, &window, false).await,
+            types::TransactionAction::GetState => Ok(transactions.state(&window).await),
+        };
+        if reply.send(result).is_err() {
+            println!("Could not return transaction state to caller, caller is gone");
+        }
+    });
+}
+
+/// Ask the server to abort the query, answers whether the query was known
+fn cancel_query(
+    running_queries: &running::RunningQueries,
+    query_id: String,
+    reply: oneshot::Sender<bool>,
+) {
+    let known = match running_queries.cancel(&query_id) {
+        running::Cancellation::Unknown => false,
+        running::Cancellation::BeforeStart => true,
+        running::Cancellation::Running(token, connector) => {
+            tokio::spawn(async move {
+                if let Err(e) = token.cancel_query(connector).await {
+                    println!("Could not cancel query \"{}\": {}", query_id, e);
+                }
+            });
+            true
+        }
+    };
+    if reply.send(known).is_err() {
+        println!("Could not answer cancel request, caller is gone");
     }
 }
 
 /// Standalone task that handles database requests and returns responses
 ///
+/// Every query runs in a task of its own and its response is sent on the
+/// reply channel of its request, so concurrent callers cannot receive each
+/// other's results. Slow queries do not hold up later requests. Queries
+/// are recorded in the history.
+///
 pub async fn db_task(
     mut channel_to_db_rx: types::DatabaseQueryReceiver,
-    channel_to_tauri_tx: types::StringTableSender,
+    history: Arc<History>,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    while let Some(db_query) = channel_to_db_rx.recv().await {
-        println!("Received db query: {:?}", db_query);
+    let pool = Arc::new(pool::ConnectionPool::new(pool::PoolConfig::default()));
+    let running_queries = Arc::new(running::RunningQueries::default());
+    let transactions = Arc::new(transactions::Transactions::default());
+    let mut pool_maintenance = tokio::time::interval(POOL_MAINTENANCE_INTERVAL);
 
-        let connection_raw = match &db_query.connection {
-            types::Connection::Stateless(s) => s.as_str(),
+    loop {
+        let message = select! {
+            received = channel_to_db_rx.recv() => match received {
+                Some(message) => message,
+                None => break,
+            },
+            _ = pool_maintenance.tick() => {
+                pool.close_idle_clients();
+                continue;
+            }
         };
-        let database: Option<String> = db_query.query.get_mentioned_database();
-        let connection_str = get_resulting_connection_string(connection_raw, &database);
-        let query_string = db_query.query.get_query_string();
-
-        let table_data = run_standalone_query(connection_str, &query_string).await;
-        match table_data {
-            Ok(rows) => {
-                let converted_table = convert_rows(rows, &db_query.query);
-                let database_result = DatabaseQueryResult {
-                    database: database,
-                    sql_query: query_string,
-                    table: Ok(converted_table),
-                };
-                if let Err(_) = channel_to_tauri_tx.send(database_result).await {
-                    println!("Could not return results to caller. Exit sb task.");
-
-                    return Ok(());
-                }
-                println!("Returned results to caller okay");
+        match message {
+            types::DbMessage::Query(request) => {
+                spawn_query(&pool, &running_queries, &transactions, &history, *request)
             }
-            Err(_) => {
-                println!("Error executing query - no results");
-                if let Err(_) = channel_to_tauri_tx
-                    .send(DatabaseQueryResult {
-                        database: database,
-                        sql_query: query_string,
-                        table: Err(()),
-                    })
-                    .await
-                {
-                    return Ok(());
-                }
+            types::DbMessage::Edit { edits, reply } => spawn_edits(&pool, *edits, reply),
+            types::DbMessage::Script {
+                script,
+                window,
+                query_id,
+                reply,
+            } => spawn_script(
+                &pool,
+                &running_queries,
+                &transactions,
+                *script,
+                window,
+                query_id,
+                reply,
+            ),
+            types::DbMessage::Import {
+                import,
+                import_id,
+                progress,
+                reply,
+            } => spawn_import(&pool, &running_queries, *import, import_id, progress, reply),
+            types::DbMessage::Prepare {
+                query,
+                transaction,
+                reply,
+            } => spawn_prepare(&pool, &transactions, *query, transaction, reply),
+            types::DbMessage::Transaction {
+                window,
+                action,
+                reply,
+            } => spawn_transaction_action(&pool, &transactions, window, action, reply),
+            types::DbMessage::Cancel { query_id, reply } => {
+                cancel_query(&running_queries, query_id, reply)
             }
         }
     }
@@ -0,0 +1,1137 @@
+//! Conversion of database values to text
+//!
+//! Values arrive in the binary wire format. Built-in types are decoded here
+//! and rendered the way postgres renders them as text. Anything unknown
+//! (extension types, types without a decoder here) is handed back to the
+//! server to be cast to text, so every value has a faithful representation.
+//! This includes locale dependent types like money.
+//!
+//! Time stamps with time zone are shown in UTC.
+//!
+//! Scalars the frontend can use directly (numbers, booleans, json, ...)
+//! are passed on as typed fields instead of text.
+
+use fallible_iterator::FallibleIterator;
+use postgres_protocol::types as pg;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::net::IpAddr;
+use tokio_postgres::types::{to_sql_checked, FromSql, IsNull, Kind, ToSql, Type};
+
+use super::types::{ColumnType, TypedField};
+
+type BoxError = Box<dyn Error + Sync + Send>;
+
+/// Days between 1970-01-01 (unix epoch) and 2000-01-01 (postgres epoch)
+const POSTGRES_EPOCH_DAYS: i64 = 10_957;
+const MICROS_PER_SECOND: i64 = 1_000_000;
+const MICROS_PER_DAY: i64 = 86_400 * MICROS_PER_SECOND;
+
+/// Largest integer a javascript number holds exactly
+const MAX_SAFE_INTEGER: i64 = (1 << 53) - 1;
+
+#[derive(Debug)]
+pub enum ConversionError {
+    /// No decoder for this type, value has to be rendered by the server
+    Unsupported(Type),
+    /// Value could not be decoded
+    Invalid(String),
+}
+
+impl fmt::Display for ConversionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConversionError::Unsupported(ty) => write!(f, "unsupported type {}", ty),
+            ConversionError::Invalid(msg) => write!(f, "invalid value: {}", msg),
+        }
+    }
+}
+
+impl Error for ConversionError {}
+
+impl From<BoxError> for ConversionError {
+    fn from(e: BoxError) -> Self {
+        ConversionError::Invalid(e.to_string())
+    }
+}
+
+type ConversionResult = Result<String, ConversionError>;
+
+/// Undecoded value of any type as received from the database
+pub struct RawValue<'a>(pub &'a [u8]);
+
+impl<'a> FromSql<'a> for RawValue<'a> {
+    fn from_sql(_ty: &Type, raw: &'a [u8]) -> Result<Self, BoxError> {
+        Ok(RawValue(raw))
+    }
+
+    fn accepts(_ty: &Type) -> bool {
+        true
+    }
+}
+
+/// Value of any type sent to the database unchanged (binary format)
+#[derive(Debug)]
+struct RawParam<'a>(&'a [u8]);
+
+impl ToSql for RawParam<'_> {
+    fn to_sql(&self, _ty: &Type, out: &mut bytes::BytesMut) -> Result<IsNull, BoxError> {
+        out.extend_from_slice(self.0);
+        Ok(IsNull::No)
+    }
+
+    fn accepts(_ty: &Type) -> bool {
+        true
+    }
+
+    to_sql_checked!();
+}
+
+/// Get raw value of a column, `None` for SQL NULL
+pub fn get_raw<'a>(
+    row: &'a tokio_postgres::Row,
+    index: usize,
+) -> Result<Option<RawValue<'a>>, ConversionError> {
+    row.try_get::<usize, Option<RawValue>>(index)
+        .map_err(|e| ConversionError::Invalid(e.to_string()))
+}
+
+/// Lets the server render values using the text output function of their type
+///
+/// The statement doing so is prepared once per type. Statements belong to
+/// the client they were prepared on, a renderer is only used with one client.
+#[derive(Default)]
+pub struct ServerRenderer {
+    statements: HashMap<u32, tokio_postgres::Statement>,
+}
+
+impl ServerRenderer {
+    pub async fn render(
+        &mut self,
+        client: &tokio_postgres::Client,
+        ty: &Type,
+        raw: &[u8],
+    ) -> ConversionResult {
+        let statement = match self.statements.get(&ty.oid()) {
+            Some(statement) => statement.clone(),
+            None => {
+                let statement = client
+                    .prepare_typed("SELECT $1::text", std::slice::from_ref(ty))
+                    .await
+                    .map_err(|e| ConversionError::Invalid(e.to_string()))?;
+                self.statements.insert(ty.oid(), statement.clone());
+                statement
+            }
+        };
+        let row = client
+            .query_one(&statement, &[&RawParam(raw)])
+            .await
+            .map_err(|e| ConversionError::Invalid(e.to_string()))?;
+        row.try_get::<usize, String>(0)
+            .map_err(|e| ConversionError::Invalid(e.to_string()))
+    }
+
+    /// Typed field of a value rendered as text by the server
+    pub async fn render_field(
+        &mut self,
+        client: &tokio_postgres::Client,
+        ty: &Type,
+        raw: &[u8],
+    ) -> TypedField {
+        match self.render(client, ty, raw).await {
+            Ok(text) => TypedField::Text(text),
+            Err(e) => {
+                println!("Could not convert value of type {}: {}", ty, e);
+                TypedField::ConversionFailure(e.to_string())
+            }
+        }
+    }
+}
+
+/// Type information of all columns of a row
+pub fn column_types(columns: &[tokio_postgres::Column]) -> Vec<ColumnType> {
+    columns
+        .iter()
+        .map(|column| ColumnType {
+            name: String::from(column.type_().name()),
+            oid: column.type_().oid(),
+        })
+        .collect()
+}
+
+/// Column value as far as it can be converted without the server
+pub enum LocalField<'a> {
+    Done(TypedField),
+    /// No local decoder for the type, value has to be rendered by the server
+    NeedsServer(&'a Type, &'a [u8]),
+}
+
+/// Get typed value of a column
+///
+/// SQL NULL and values that cannot be converted are reported as such,
+/// types without a local decoder are rendered as text by the server.
+pub async fn get_typed_field(
+    client: &tokio_postgres::Client,
+    renderer: &mut ServerRenderer,
+    row: &tokio_postgres::Row,
+    index: usize,
+) -> TypedField {
+    match get_typed_field_locally(row, index) {
+        LocalField::Done(field) => field,
+        LocalField::NeedsServer(ty, raw) => renderer.render_field(client, ty, raw).await,
+    }
+}
+
+/// Get typed value of a column, without round trip to the server
+pub fn get_typed_field_locally(row: &tokio_postgres::Row, index: usize) -> LocalField<'_> {
+    let field_type = row.columns()[index].type_();
+    let raw = match get_raw(row, index) {
+        Ok(Some(raw)) => raw,
+        Ok(None) => return LocalField::Done(TypedField::Null),
+        Err(e) => return LocalField::Done(TypedField::ConversionFailure(e.to_string())),
+    };
+    match value_to_typed_field(field_type, raw.0) {
+        Ok(field) => LocalField::Done(field),
+        Err(ConversionError::Unsupported(_)) => LocalField::NeedsServer(field_type, raw.0),
+        Err(e) => {
+            println!("Could not convert column {}: {}", index, e);
+            LocalField::Done(TypedField::ConversionFailure(e.to_string()))
+        }
+    }
+}
+
+/// Convert value to typed field, everything without own variant becomes text
+pub fn value_to_typed_field(ty: &Type, raw: &[u8]) -> Result<TypedField, ConversionError> {
+    match *ty {
+        Type::BOOL => Ok(TypedField::Bool(pg::bool_from_sql(raw)?)),
+        Type::INT2 => Ok(TypedField::Integer(pg::int2_from_sql(raw)? as i64)),
+        Type::INT4 => Ok(TypedField::Integer(pg::int4_from_sql(raw)? as i64)),
+        Type::INT8 => Ok(integer_field(pg::int8_from_sql(raw)?)),
+        Type::OID => Ok(TypedField::Integer(pg::oid_from_sql(raw)? as i64)),
+        Type::FLOAT4 | Type::FLOAT8 => {
+            let text = value_to_string(ty, raw)?;
+            // shortest text representation keeps e.g. float4 0.1 from becoming 0.10000000149011612
+            match text.parse::<f64>() {
+                Ok(value) if value.is_finite() => Ok(TypedField::Float(value)),
+                _ => Ok(TypedField::Numeric(text)),
+            }
+        }
+        Type::NUMERIC => Ok(TypedField::Numeric(numeric_to_string(raw)?)),
+        Type::TIMESTAMP | Type::TIMESTAMPTZ => Ok(TypedField::Timestamp(value_to_string(ty, raw)?)),
+        // json is kept as written, with its key order, duplicate keys and exact numbers
+        Type::JSON => Ok(TypedField::Text(value_to_string(ty, raw)?)),
+        Type::JSONB => {
+            let text = value_to_string(ty, raw)?;
+            if has_inexact_numbers(&text) {
+                return Ok(TypedField::Text(text));
+            }
+            serde_json::from_str(&text)
+                .map(TypedField::Json)
+                .map_err(|e| ConversionError::Invalid(e.to_string()))
+        }
+        _ => match ty.kind() {
+            Kind::Domain(base) => value_to_typed_field(base, raw),
+            _ => Ok(TypedField::Text(value_to_string(ty, raw)?)),
+        },
+    }
+}
+
+/// Whether the json text has numbers a double cannot hold exactly
+///
+/// Up to 15 significant digits survive the trip through a double.
+fn has_inexact_numbers(json: &str) -> bool {
+    let mut chars = json.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '"' {
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => {
+                        chars.next();
+                    }
+                    '"' => break,
+                    _ => (),
+                }
+            }
+        } else if c.is_ascii_digit() {
+            let mut mantissa = String::from(c);
+            while let Some(&c) = chars.peek() {
+                if !(c.is_ascii_digit() || c == '.') {
+                    break;
+                }
+                mantissa.push(c);
+                chars.next();
+            }
+            let significant = mantissa.replace('.', "");
+            if significant.trim_start_matches('0').len() > 15 {
+                return true;
+            }
+        }
+    }
+    false
+}
+
+/// Integer as number, unless the frontend would get it wrong (bigint ids, ...)
+fn integer_field(value: i64) -> TypedField {
+    if (-MAX_SAFE_INTEGER..=MAX_SAFE_INTEGER).contains(&value) {
+        TypedField::Integer(value)
+    } else {
+        TypedField::Numeric(value.to_string())
+    }
+}
+
+/// Render value of given type as text, as postgres would
+pub fn value_to_string(ty: &Type, raw: &[u8]) -> ConversionResult {
+    match ty.kind() {
+        Kind::Simple => simple_to_string(ty, raw),
+        Kind::Enum(_) => Ok(String::from(pg::text_from_sql(raw)?)),
+        Kind::Domain(base) => value_to_string(base, raw),
+        Kind::Array(element) => array_to_string(element, raw),
+        Kind::Range(subtype) => range_to_string(subtype, raw),
+        Kind::Multirange(subtype) => multirange_to_string(subtype, raw),
+        Kind::Composite(fields) => composite_to_string(fields, raw),
+        Kind::Pseudo if *ty == Type::RECORD => composite_to_string(&[], raw),
+        _ => Err(ConversionError::Unsupported(ty.clone())),
+    }
+}
+
+fn simple_to_string(ty: &Type, raw: &[u8]) -> ConversionResult {
+    match *ty {
+        Type::BOOL => Ok(format!("{}", pg::bool_from_sql(raw)?)),
+        Type::INT2 => Ok(format!("{}", pg::int2_from_sql(raw)?)),
+        Type::INT4 => Ok(format!("{}", pg::int4_from_sql(raw)?)),
+        Type::INT8 => Ok(format!("{}", pg::int8_from_sql(raw)?)),
+        Type::OID | Type::XID | Type::CID => Ok(format!("{}", pg::oid_from_sql(raw)?)),
+        Type::XID8 => Ok(format!("{}", pg::int8_from_sql(raw)? as u64)),
+        Type::FLOAT4 => Ok(float_to_string(pg::float4_from_sql(raw)?, 6)),
+        Type::FLOAT8 => Ok(float_to_string(pg::float8_from_sql(raw)?, 15)),
+        Type::NUMERIC => numeric_to_string(raw),
+        Type::TEXT
+        | Type::VARCHAR
+        | Type::BPCHAR
+        | Type::NAME
+        | Type::UNKNOWN
+        | Type::XML
+        | Type::JSON => Ok(String::from(pg::text_from_sql(raw)?)),
+        Type::CHAR => Ok(char_to_string(pg::char_from_sql(raw)?)),
+        Type::JSONB | Type::JSONPATH => versioned_text_to_string(raw),
+        Type::BYTEA => Ok(bytea_to_string(pg::bytea_from_sql(raw))),
+        Type::UUID => Ok(uuid_to_string(&pg::uuid_from_sql(raw)?)),
+        Type::DATE => Ok(date_to_string(pg::date_from_sql(raw)?)),
+        Type::TIME => Ok(time_to_string(pg::time_from_sql(raw)?)),
+        Type::TIMETZ => timetz_to_string(raw),
+        Type::TIMESTAMP => Ok(timestamp_to_string(pg::timestamp_from_sql(raw)?, "")),
+        Type::TIMESTAMPTZ => Ok(timestamp_to_string(pg::timestamp_from_sql(raw)?, "+00")),
+        Type::INTERVAL => interval_to_string(raw),
+        Type::INET => Ok(inet_to_string(raw, false)?),
+        Type::CIDR => Ok(inet_to_string(raw, true)?),
+        Type::MACADDR => Ok(hex_octets(&pg::macaddr_from_sql(raw)?)),
+        Type::MACADDR8 => macaddr8_to_string(raw),
+        Type::BIT | Type::VARBIT => Ok(varbit_to_string(raw)?),
+        Type::PG_LSN => {
+            let lsn = pg::lsn_from_sql(raw)?;
+            Ok(format!("{:X}/{:X}", lsn >> 32, lsn & 0xFFFF_FFFF))
+        }
+        Type::POINT => Ok(point_to_string(&pg::point_from_sql(raw)?)),
+        Type::BOX => {
+            let b = pg::box_from_sql(raw)?;
+            Ok(format!(
+                "{},{}",
+                point_to_string(&b.upper_right()),
+                point_to_string(&b.lower_left())
+            ))
+        }
+        Type::LSEG => {
+            let values = floats(raw, 4)?;
+            Ok(format!("[{}]", points_to_string(&values)))
+        }
+        Type::LINE => {
+            let values = floats(raw, 3)?;
+            Ok(format!(
+                "{{{},{},{}}}",
+                float_to_string(values[0], 15),
+                float_to_string(values[1], 15),
+                float_to_string(values[2], 15)
+            ))
+        }
+        Type::CIRCLE => {
+            let values = floats(raw, 3)?;
+            Ok(format!(
+                "<({},{}),{}>",
+                float_to_string(values[0], 15),
+                float_to_string(values[1], 15),
+                float_to_string(values[2], 15)
+            ))
+        }
+        Type::PATH => {
+            let path = pg::path_from_sql(raw)?;
+            let points: Vec<String> = path.points().map(|p| Ok(point_to_string(&p))).collect()?;
+            if path.closed() {
+                Ok(format!("({})", points.join(",")))
+            } else {
+                Ok(format!("[{}]", points.join(",")))
+            }
+        }
+        Type::POLYGON => {
+            let count = read_i32(&mut &raw[..])?;
+            let values = floats(&raw[4..], count.max(0) as usize * 2)?;
+            Ok(format!("({})", points_to_string(&values)))
+        }
+        _ => Err(ConversionError::Unsupported(ty.clone())),
+    }
+}
+
+/// Format float like postgres' shortest exact output
+///
+/// Exponent notation is used below 1e-4 and from 10^`max_exponent` on.
+fn float_to_string<F>(value: F, max_exponent: i32) -> String
+where
+    F: fmt::Display + fmt::LowerExp + Into<f64> + Copy,
+{
+    let float: f64 = value.into();
+    if float.is_nan() {
+        return String::from("NaN");
+    }
+    if float.is_infinite() {
+        return String::from(if float > 0.0 { "Infinity" } else { "-Infinity" });
+    }
+    if float == 0.0 {
+        return String::from(if float.is_sign_negative() { "-0" } else { "0" });
+    }
+    let scientific = format!("{:e}", value);
+    let (mantissa, exponent) = scientific.split_once('e').unwrap_or((&scientific, "0"));
+    let exponent: i32 = exponent.parse().unwrap_or(0);
+    if exponent < -4 || exponent >= max_exponent {
+        let sign = if exponent < 0 { '-' } else { '+' };
+        format!("{}e{}{:02}", mantissa, sign, exponent.abs())
+    } else {
+        format!("{}", value)
+    }
+}
+
+fn numeric_to_string(raw: &[u8]) -> ConversionResult {
+    let mut buf = raw;
+    let ndigits = read_i16(&mut buf)?;
+    let weight = read_i16(&mut buf)? as i32;
+    let sign = read_i16(&mut buf)? as u16;
+    let dscale = read_i16(&mut buf)? as u16 as usize;
+    let digits = (0..ndigits.max(0))
+        .map(|_| read_i16(&mut buf))
+        .collect::<Result<Vec<i16>, ConversionError>>()?;
+
+    match sign {
+        0xC000 => return Ok(String::from("NaN")),
+        0xD000 => return Ok(String::from("Infinity")),
+        0xF000 => return Ok(String::from("-Infinity")),
+        _ => {}
+    }
+    let digit_at = |index: i32| -> i16 {
+        if index >= 0 && (index as usize) < digits.len() {
+            digits[index as usize]
+        } else {
+            0
+        }
+    };
+
+    let mut text = String::new();
+    if sign == 0x4000 {
+        text.push('-');
+    }
+    if weight < 0 {
+        text.push('0');
+    } else {
+        text.push_str(&format!("{}", digit_at(0)));
+        for index in 1..=weight {
+            text.push_str(&format!("{:04}", digit_at(index)));
+        }
+    }
+    if dscale > 0 {
+        let mut fraction = String::new();
+        let mut index = weight + 1;
+        while fraction.len() < dscale {
+            fraction.push_str(&format!("{:04}", digit_at(index)));
+            index += 1;
+        }
+        fraction.truncate(dscale);
+        text.push('.');
+        text.push_str(&fraction);
+    }
+    Ok(text)
+}
+
+fn char_to_string(c: i8) -> String {
+    if c == 0 {
+        String::new()
+    } else {
+        String::from(c as u8 as char)
+    }
+}
+
+/// Text prefixed by a format version byte (jsonb, jsonpath)
+fn versioned_text_to_string(raw: &[u8]) -> ConversionResult {
+    match raw.split_first() {
+        Some((1, text)) => Ok(String::from(pg::text_from_sql(text)?)),
+        Some((version, _)) => Err(ConversionError::Invalid(format!(
+            "unknown format version {}",
+            version
+        ))),
+        None => Err(ConversionError::Invalid(String::from("empty value"))),
+    }
+}
+
+fn bytea_to_string(bytes: &[u8]) -> String {
+    let mut text = String::with_capacity(2 + bytes.len() * 2);
+    text.push_str("\\x");
+    for byte in bytes {
+        text.push_str(&format!("{:02x}", byte));
+    }
+    text
+}
+
+fn uuid_to_string(bytes: &[u8; 16]) -> String {
+    let hex: Vec<String> = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        hex[0..4].concat(),
+        hex[4..6].concat(),
+        hex[6..8].concat(),
+        hex[8..10].concat(),
+        hex[10..16].concat()
+    )
+}
+
+fn hex_octets(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<String>>()
+        .join(":")
+}
+
+fn macaddr8_to_string(raw: &[u8]) -> ConversionResult {
+    if raw.len() != 8 {
+        return Err(ConversionError::Invalid(String::from(
+            "invalid macaddr8 length",
+        )));
+    }
+    Ok(hex_octets(raw))
+}
+
+/// Convert days since 1970-01-01 to (year, month, day) of the proleptic gregorian calendar
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// Date part and era suffix, days counted from 2000-01-01
+fn format_date(days: i64) -> (String, &'static str) {
+    let (year, month, day) = civil_from_days(days + POSTGRES_EPOCH_DAYS);
+    if year <= 0 {
+        (format!("{:04}-{:02}-{:02}", 1 - year, month, day), " BC")
+    } else {
+        (format!("{:04}-{:02}-{:02}", year, month, day), "")
+    }
+}
+
+/// Seconds fraction with trailing zeros removed, empty if there is none
+fn format_fraction(micros: i64) -> String {
+    if micros == 0 {
+        String::new()
+    } else {
+        let digits = format!(".{:06}", micros);
+        String::from(digits.trim_end_matches('0'))
+    }
+}
+
+fn format_time_of_day(micros: i64) -> String {
+    let seconds = micros / MICROS_PER_SECOND;
+    format!(
+        "{:02}:{:02}:{:02}{}",
+        seconds / 3600,
+        (seconds / 60) % 60,
+        seconds % 60,
+        format_fraction(micros % MICROS_PER_SECOND)
+    )
+}
+
+fn date_to_string(days: i32) -> String {
+    match days {
+        i32::MAX => String::from("infinity"),
+        i32::MIN => String::from("-infinity"),
+        _ => {
+            let (date, era) = format_date(days as i64);
+            format!("{}{}", date, era)
+        }
+    }
+}
+
+fn time_to_string(micros: i64) -> String {
+    format_time_of_day(micros)
+}
+
+/// Offset east of UTC as shown by postgres: +HH[:MM[:SS]]
+fn format_utc_offset(seconds_east: i32) -> String {
+    let sign = if seconds_east < 0 { '-' } else { '+' };
+    let seconds = seconds_east.abs();
+    let (hours, minutes, secs) = (seconds / 3600, (seconds / 60) % 60, seconds % 60);
+    if secs != 0 {
+        format!("{}{:02}:{:02}:{:02}", sign, hours, minutes, secs)
+    } else if minutes != 0 {
+        format!("{}{:02}:{:02}", sign, hours, minutes)
+    } else {
+        format!("{}{:02}", sign, hours)
+    }
+}
+
+fn timetz_to_string(raw: &[u8]) -> ConversionResult {
+    let mut buf = raw;
+    let micros = read_i64(&mut buf)?;
+    // zone is stored as seconds west of UTC
+    let zone = read_i32(&mut buf)?;
+    Ok(format!(
+        "{}{}",
+        format_time_of_day(micros),
+        format_utc_offset(-zone)
+    ))
+}
+
+/// Timestamp (microseconds since 2000-01-01) in UTC, with given zone suffix
+fn timestamp_to_string(micros: i64, zone: &str) -> String {
+    match micros {
+        i64::MAX => String::from("infinity"),
+        i64::MIN => String::from("-infinity"),
+        _ => {
+            let days = micros.div_euclid(MICROS_PER_DAY);
+            let time = micros.rem_euclid(MICROS_PER_DAY);
+            let (date, era) = format_date(days);
+            format!("{} {}{}{}", date, format_time_of_day(time), zone, era)
+        }
+    }
+}
+
+/// Interval in postgres' default output style, e.g. "1 year 2 mons -3 days +04:05:06"
+fn interval_to_string(raw: &[u8]) -> ConversionResult {
+    let mut buf = raw;
+    let micros = read_i64(&mut buf)?;
+    let days = read_i32(&mut buf)?;
+    let months = read_i32(&mut buf)?;
+
+    let mut text = String::new();
+    let mut is_before = false;
+    let mut is_zero = true;
+    let mut add_part = |text: &mut String, value: i32, unit: &str| {
+        if value != 0 {
+            text.push_str(&format!(
+                "{}{}{} {}{}",
+                if is_zero { "" } else { " " },
+                if is_before && value > 0 { "+" } else { "" },
+                value,
+                unit,
+                if value != 1 { "s" } else { "" }
+            ));
+            is_before = value < 0;
+            is_zero = false;
+        }
+    };
+    add_part(&mut text, months / 12, "year");
+    add_part(&mut text, months % 12, "mon");
+    add_part(&mut text, days, "day");
+
+    if is_zero || micros != 0 {
+        let sign = if micros < 0 {
+            "-"
+        } else if is_before {
+            "+"
+        } else {
+            ""
+        };
+        let abs = micros.unsigned_abs() as i64;
+        let seconds = abs / MICROS_PER_SECOND;
+        text.push_str(&format!(
+            "{}{}{:02}:{:02}:{:02}{}",
+            if is_zero { "" } else { " " },
+            sign,
+            seconds / 3600,
+            (seconds / 60) % 60,
+            seconds % 60,
+            format_fraction(abs % MICROS_PER_SECOND)
+        ));
+    }
+    Ok(text)
+}
+
+fn inet_to_string(raw: &[u8], is_cidr: bool) -> ConversionResult {
+    let inet = pg::inet_from_sql(raw)?;
+    let max_bits = match inet.addr() {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    };
+    if is_cidr || inet.netmask() != max_bits {
+        Ok(format!("{}/{}", inet.addr(), inet.netmask()))
+    } else {
+        Ok(format!("{}", inet.addr()))
+    }
+}
+
+fn varbit_to_string(raw: &[u8]) -> ConversionResult {
+    let varbit = pg::varbit_from_sql(raw)?;
+    let bytes = varbit.bytes();
+    Ok((0..varbit.len())
+        .map(|i| {
+            if bytes[i / 8] & (0x80 >> (i % 8)) != 0 {
+                '1'
+            } else {
+                '0'
+            }
+        })
+        .collect())
+}
+
+fn point_to_string(point: &pg::Point) -> String {
+    format!(
+        "({},{})",
+        float_to_string(point.x(), 15),
+        float_to_string(point.y(), 15)
+    )
+}
+
+/// Render sequence of x/y values as list of points
+fn points_to_string(values: &[f64]) -> String {
+    values
+        .chunks(2)
+        .map(|xy| {
+            format!(
+                "({},{})",
+                float_to_string(xy[0], 15),
+                float_to_string(xy[1], 15)
+            )
+        })
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+fn floats(raw: &[u8], count: usize) -> Result<Vec<f64>, ConversionError> {
+    if raw.len() != count * 8 {
+        return Err(ConversionError::Invalid(String::from(
+            "invalid geometric value length",
+        )));
+    }
+    raw.chunks(8)
+        .map(|chunk| Ok(pg::float8_from_sql(chunk)?))
+        .collect()
+}
+
+/// Quote element if required, as done by postgres for arrays, ranges and records
+///
+/// Arrays escape with a backslash, ranges and records double the character.
+fn quote_if_needed(text: &str, special: &str, doubled_escape: bool, quote_null: bool) -> String {
+    let needs_quotes = text.is_empty()
+        || (quote_null && text.eq_ignore_ascii_case("NULL"))
+        || text
+            .chars()
+            .any(|c| c.is_whitespace() || c == '"' || c == '\\' || special.contains(c));
+    if !needs_quotes {
+        return String::from(text);
+    }
+    let mut quoted = String::with_capacity(text.len() + 2);
+    quoted.push('"');
+    for c in text.chars() {
+        if c == '"' || c == '\\' {
+            quoted.push(if doubled_escape { c } else { '\\' });
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn array_to_string(element_type: &Type, raw: &[u8]) -> ConversionResult {
+    let array = pg::array_from_sql(raw)?;
+    let dimensions: Vec<pg::ArrayDimension> = array.dimensions().collect()?;
+    let delimiter = if *element_type == Type::BOX { ';' } else { ',' };
+    let special = format!("{{}}{}", delimiter);
+
+    let mut values = array.values();
+    let mut elements = Vec::new();
+    while let Some(value) = values.next()? {
+        elements.push(match value {
+            None => String::from("NULL"),
+            Some(bytes) => quote_if_needed(
+                &value_to_string(element_type, bytes)?,
+                &special,
+                false,
+                true,
+            ),
+        });
+    }
+
+    if dimensions.is_empty() {
+        return Ok(String::from("{}"));
+    }
+    let mut text = String::new();
+    if dimensions.iter().any(|d| d.lower_bound != 1) {
+        for d in dimensions.iter() {
+            text.push_str(&format!(
+                "[{}:{}]",
+                d.lower_bound,
+                d.lower_bound + d.len - 1
+            ));
+        }
+        text.push('=');
+    }
+    let mut remaining = elements.into_iter();
+    text.push_str(&nest_array(&dimensions, &mut remaining, delimiter));
+    Ok(text)
+}
+
+fn nest_array(
+    dimensions: &[pg::ArrayDimension],
+    elements: &mut impl Iterator<Item = String>,
+    delimiter: char,
+) -> String {
+    let (first, inner) = match dimensions.split_first() {
+        Some(split) => split,
+        None => return elements.next().unwrap_or_default(),
+    };
+    let parts: Vec<String> = (0..first.len)
+        .map(|_| nest_array(inner, elements, delimiter))
+        .collect();
+    format!("{{{}}}", parts.join(&delimiter.to_string()))
+}
+
+fn range_bound_to_string(subtype: &Type, bound: Option<&[u8]>) -> ConversionResult {
+    match bound {
+        None => Ok(String::new()),
+        Some(bytes) => Ok(quote_if_needed(
+            &value_to_string(subtype, bytes)?,
+            "()[],",
+            true,
+            false,
+        )),
+    }
+}
+
+fn range_to_string(subtype: &Type, raw: &[u8]) -> ConversionResult {
+    match pg::range_from_sql(raw)? {
+        pg::Range::Empty => Ok(String::from("empty")),
+        pg::Range::Nonempty(lower, upper) => {
+            let (open, lower) = match lower {
+                pg::RangeBound::Inclusive(v) => ('[', v),
+                pg::RangeBound::Exclusive(v) => ('(', v),
+                pg::RangeBound::Unbounded => ('(', None),
+            };
+            let (close, upper) = match upper {
+                pg::RangeBound::Inclusive(v) => (']', v),
+                pg::RangeBound::Exclusive(v) => (')', v),
+                pg::RangeBound::Unbounded => (')', None),
+            };
+            Ok(format!(
+                "{}{},{}{}",
+                open,
+                range_bound_to_string(subtype, lower)?,
+                range_bound_to_string(subtype, upper)?,
+                close
+            ))
+        }
+    }
+}
+
+/// Multirange, its kind names the subtype of the ranges (not the range type)
+fn multirange_to_string(subtype: &Type, raw: &[u8]) -> ConversionResult {
+    let mut buf = raw;
+    let count = read_i32(&mut buf)?;
+    let mut ranges = Vec::new();
+    for _ in 0..count {
+        let len = read_i32(&mut buf)? as usize;
+        let bytes = take(&mut buf, len)?;
+        ranges.push(range_to_string(subtype, bytes)?);
+    }
+    Ok(format!("{{{}}}", ranges.join(",")))
+}
+
+fn composite_to_string(fields: &[tokio_postgres::types::Field], raw: &[u8]) -> ConversionResult {
+    let mut buf = raw;
+    let count = read_i32(&mut buf)?.max(0) as usize;
+    let mut parts = Vec::with_capacity(count);
+    for index in 0..count {
+        let oid = read_i32(&mut buf)? as u32;
+        let len = read_i32(&mut buf)?;
+        if len < 0 {
+            parts.push(String::new());
+            continue;
+        }
+        let bytes = take(&mut buf, len as usize)?;
+        let field_type = match fields.get(index) {
+            Some(field) => field.type_().clone(),
+            None => Type::from_oid(oid)
+                .ok_or_else(|| ConversionError::Invalid(format!("unknown field type {}", oid)))?,
+        };
+        parts.push(quote_if_needed(
+            &value_to_string(&field_type, bytes)?,
+            "(),",
+            true,
+            false,
+        ));
+    }
+    Ok(format!("({})", parts.join(",")))
+}
+
+fn take<'a>(buf: &mut &'a [u8], len: usize) -> Result<&'a [u8], ConversionError> {
+    if buf.len() < len {
+        return Err(ConversionError::Invalid(String::from("value too short")));
+    }
+    let (head, tail) = buf.split_at(len);
+    *buf = tail;
+    Ok(head)
+}
+
+fn read_i16(buf: &mut &[u8]) -> Result<i16, ConversionError> {
+    let bytes = take(buf, 2)?;
+    Ok(i16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+fn read_i32(buf: &mut &[u8]) -> Result<i32, ConversionError> {
+    let bytes = take(buf, 4)?;
+    Ok(i32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+fn read_i64(buf: &mut &[u8]) -> Result<i64, ConversionError> {
+    let bytes = take(buf, 8)?;
+    let mut array = [0u8; 8];
+    array.copy_from_slice(bytes);
+    Ok(i64::from_be_bytes(array))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from_hex(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    /// Wire bytes as sent by `<type>send`, text as printed by postgres for the same value
+    #[test]
+    fn renders_like_postgres() {
+        let cases = [
+            (Type::NUMERIC, "0000000000000000", "0"),
+            (Type::NUMERIC, "0002000000000002007b1194", "123.45"),
+            (Type::NUMERIC, "0001ffff400000040001", "-0.0001"),
+            (Type::NUMERIC, "00010001000000000001", "10000"),
+            (Type::NUMERIC, "00010005000000000001", "100000000000000000000"),
+            (Type::NUMERIC, "00000000c0000000", "NaN"),
+            (Type::NUMERIC, "0000000000000003", "0.000"),
+            (Type::NUMERIC, "000300010000000304d2162e2332", "12345678.901"),
+            (Type::FLOAT4, "3dcccccd", "0.1"),
+            (Type::FLOAT4, "3727c5ac", "1e-05"),
+            (Type::FLOAT4, "49742400", "1e+06"),
+            (Type::FLOAT4, "47f12000", "123456"),
+            (Type::FLOAT4, "ff800000", "-Infinity"),
+            (Type::FLOAT8, "430c6bf526340000", "1e+15"),
+            (Type::FLOAT8, "3fd3333333333334", "0.30000000000000004"),
+            (Type::FLOAT8, "8000000000000000", "-0"),
+            (Type::FLOAT8, "42dc12218377de40", "123456789012345"),
+            (Type::FLOAT8, "7ff8000000000000", "NaN"),
+            (Type::DATE, "00000000", "2000-01-01"),
+            (Type::DATE, "fff49d7b", "0044-03-15 BC"),
+            (Type::DATE, "7fffffff", "infinity"),
+            (Type::DATE, "ffffffff", "1999-12-31"),
+            (Type::DATE, "00002279", "2024-02-29"),
+            (Type::TIME, "0000000a8be62608", "12:34:56.789"),
+            (Type::TIME, "0000000000000000", "00:00:00"),
+            (Type::TIMETZ, "0000000a0eebb000ffffb2a8", "12:00:00+05:30"),
+            (Type::TIMETZ, "00000000dde878c000007080", "01:02:03-08"),
+            (Type::TIMESTAMP, "0002b58cd35c1ee0", "2024-02-29 23:59:59.5"),
+            (Type::TIMESTAMP, "ff1fc63d1bb12000", "0001-01-01 00:00:00 BC"),
+            (Type::TIMESTAMP, "8000000000000000", "-infinity"),
+            (Type::TIMESTAMPTZ, "0002bccfee8ea800", "2024-06-01 10:00:00+00"),
+            (Type::INTERVAL, "000000036c966ee0fffffffd0000000e", "1 year 2 mons -3 days +04:05:06.7"),
+            (Type::INTERVAL, "00000001ad274800ffffffff00000000", "-1 days +02:00:00"),
+            (Type::INTERVAL, "00000000000000000000000000000000", "00:00:00"),
+            (Type::INTERVAL, "fffffffffff0bdc00000000000000000", "-00:00:01"),
+            (Type::INTERVAL, "0000000000000000000000000000000e", "1 year 2 mons"),
+            (Type::INTERVAL, "00000000000000000000000100000000", "1 day"),
+            (
+                Type::TEXT_ARRAY,
+                concat!(
+                    "000000010000000100000019000000060000000100000003612062ffffffff00000000000000044e",
+                    "554c4c0000000378227900000005706c61696e",
+                ),
+                "{\"a b\",NULL,\"\",\"NULL\",\"x\\\"y\",plain}",
+            ),
+            (
+                Type::INT4_ARRAY,
+                concat!(
+                    "00000002000000000000001700000002000000010000000200000001000000040000000100000004",
+                    "0000000200000004000000030000000400000004",
+                ),
+                "{{1,2},{3,4}}",
+            ),
+            (
+                Type::INT4_ARRAY,
+                "000000010000000000000017000000020000000000000004000000010000000400000002",
+                "[0:1]={1,2}",
+            ),
+            (Type::INT4_ARRAY, "000000000000000000000017", "{}"),
+            (
+                Type::BOX_ARRAY,
+                concat!(
+                    "00000001000000000000025b0000000200000001000000203ff00000000000003ff0000000000000",
+                    "0000000000000000000000000000000000000020400000000000000040000000000000003ff00000",
+                    "000000003ff0000000000000",
+                ),
+                "{(1,1),(0,0);(2,2),(1,1)}",
+            ),
+            (Type::INT4_RANGE, "0200000004000000010000000400000005", "[1,5)"),
+            (Type::INT4_RANGE, "01", "empty"),
+            (Type::NUM_RANGE, "0c0000000c000200000000000100031388", "(,3.5]"),
+            (
+                Type::TS_RANGE,
+                "020000000800023e0786c260000000000800025aca30ada000",
+                "[\"2020-01-01 00:00:00\",\"2021-01-01 00:00:00\")",
+            ),
+            (
+                Type::INT4MULTI_RANGE,
+                concat!(
+                    "00000002000000110200000004000000010000000400000003000000110200000004000000050000",
+                    "000400000007",
+                ),
+                "{[1,3),[5,7)}",
+            ),
+            (Type::BYTEA, "deadbeef", "\\xdeadbeef"),
+            (Type::UUID, "a0eebc999c0b4ef8bb6d6bb9bd380a11", "a0eebc99-9c0b-4ef8-bb6d-6bb9bd380a11"),
+            (Type::INET, "02180004c0a80001", "192.168.0.1/24"),
+            (Type::INET, "0380001000000000000000000000000000000001", "::1"),
+            (Type::CIDR, "020801040a000000", "10.0.0.0/8"),
+            (Type::MACADDR, "08002b010203", "08:00:2b:01:02:03"),
+            (Type::MACADDR8, "08002b0102030405", "08:00:2b:01:02:03:04:05"),
+            (Type::BIT, "0000000180", "1"),
+            (Type::VARBIT, "0000000accc0", "1100110011"),
+            (Type::POINT, "3ff8000000000000c000000000000000", "(1.5,-2)"),
+            (Type::BOX, "400800000000000040100000000000003ff00000000000004000000000000000", "(3,4),(1,2)"),
+            (Type::CIRCLE, "3ff000000000000040000000000000004008000000000000", "<(1,2),3>"),
+            (
+                Type::PATH,
+                "0000000002000000000000000000000000000000003ff00000000000003ff0000000000000",
+                "[(0,0),(1,1)]",
+            ),
+            (
+                Type::PATH,
+                concat!(
+                    "0100000003000000000000000000000000000000003ff00000000000003ff00000000000003ff000",
+                    "00000000000000000000000000",
+                ),
+                "((0,0),(1,1),(1,0))",
+            ),
+            (
+                Type::POLYGON,
+                concat!(
+                    "00000003000000000000000000000000000000003ff00000000000003ff00000000000003ff00000",
+                    "000000000000000000000000",
+                ),
+                "((0,0),(1,1),(1,0))",
+            ),
+            (Type::LSEG, "000000000000000000000000000000003ff00000000000003ff0000000000000", "[(0,0),(1,1)]"),
+            (Type::LINE, "3ff0000000000000bff00000000000000000000000000000", "{1,-1,0}"),
+            (Type::JSONB, "017b2261223a205b312c20322e352c206e756c6c5d7d", "{\"a\": [1, 2.5, null]}"),
+            (Type::PG_LSN, "00000016b374d848", "16/B374D848"),
+            (Type::CHAR, "78", "x"),
+            (Type::OID, "ffffffff", "4294967295"),
+            (Type::INT8, "8000000000000000", "-9223372036854775808"),
+            (
+                Type::RECORD,
+                concat!(
+                    "00000004000000170000000400000001000000190000000361206200000017ffffffff0000001900",
+                    "000003782c79",
+                ),
+                "(1,\"a b\",,\"x,y\")",
+            ),
+        ];
+        for (ty, hex, expected) in cases.iter() {
+            let rendered = value_to_string(ty, &from_hex(hex));
+            assert_eq!(rendered.ok().as_deref(), Some(*expected), "{} {}", ty, hex);
+        }
+    }
+
+    #[test]
+    fn typed_fields() {
+        let field = |ty: &Type, hex: &str| value_to_typed_field(ty, &from_hex(hex)).unwrap();
+        assert_eq!(field(&Type::FLOAT4, "3dcccccd"), TypedField::Float(0.1));
+        assert_eq!(
+            field(&Type::FLOAT8, "7ff8000000000000"),
+            TypedField::Numeric(String::from("NaN"))
+        );
+        assert_eq!(
+            field(&Type::NUMERIC, "0002000000000002007b1194"),
+            TypedField::Numeric(String::from("123.45"))
+        );
+        assert_eq!(field(&Type::BOOL, "01"), TypedField::Bool(true));
+        assert_eq!(
+            field(&Type::INT8, "001fffffffffffff"),
+            TypedField::Integer(9007199254740991)
+        );
+        assert_eq!(
+            field(&Type::INT8, "0020000000000000"),
+            TypedField::Numeric(String::from("9007199254740992"))
+        );
+        assert_eq!(
+            field(&Type::INT8, "ffe0000000000000"),
+            TypedField::Numeric(String::from("-9007199254740992"))
+        );
+        assert_eq!(
+            field(&Type::JSONB, "017b2261223a20317d"),
+            TypedField::Json(serde_json::json!({ "a": 1 }))
+        );
+    }
+
+    #[test]
+    fn json_keeps_keys_and_numbers() {
+        let json = "{\"b\": 1, \"a\": 2, \"b\": 123456789012345678901234567890}";
+        assert_eq!(
+            value_to_typed_field(&Type::JSON, json.as_bytes()).unwrap(),
+            TypedField::Text(String::from(json))
+        );
+
+        let jsonb = |text: &str| {
+            value_to_typed_field(&Type::JSONB, &[&[1], text.as_bytes()].concat()).unwrap()
+        };
+        let big = "{\"a\": 123456789012345678901234567890}";
+        assert_eq!(jsonb(big), TypedField::Text(String::from(big)));
+        assert!(matches!(
+            jsonb("{\"x\": \"123456789012345678901234567890\"}"),
+            TypedField::Json(_)
+        ));
+        match jsonb("{\"b\": 1, \"aa\": 0.000000000000001}") {
+            TypedField::Json(value) => assert_eq!(value.to_string(), "{\"b\":1,\"aa\":1e-15}"),
+            other => panic!("expected json, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn rejects_broken_values() {
+        assert!(matches!(
+            value_to_string(&Type::NUMERIC, &from_hex("0002")),
+            Err(ConversionError::Invalid(_))
+        ));
+        assert!(matches!(
+            value_to_string(&Type::JSONB, &from_hex("027b7d")),
+            Err(ConversionError::Invalid(_))
+        ));
+        assert!(matches!(
+            value_to_string(&Type::TS_VECTOR, &[]),
+            Err(ConversionError::Unsupported(_))
+        ));
+    }
+}
@@ -0,0 +1,261 @@
+//! Structure of a table: columns, constraints, indexes and triggers
+//!
+//! Everything is read from `pg_catalog`, the definitions are the ones
+//! postgres itself prints (`pg_get_constraintdef` and friends).
+
+use futures_util::TryStreamExt;
+use serde::{Deserialize, Serialize};
+use tokio_postgres::{Client, Row};
+
+use super::types::{DatabaseError, DatabaseTable, ErrorDetails, SomeSchema, SomeTable};
+use super::{execute_error, params, sql};
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct ColumnInfo {
+    pub name: String,
+    /// Type as written in SQL, e.g. "character varying(20)"
+    pub data_type: String,
+    pub nullable: bool,
+    pub default: Option<String>,
+    /// "always" or "by default" for identity columns
+    pub identity: Option<String>,
+    /// Expression of a generated column
+    pub generated: Option<String>,
+    pub comment: Option<String>,
+}
+
+/// Primary key, unique or check constraint
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct Constraint {
+    pub name: String,
+    pub columns: Vec<String>,
+    pub definition: String,
+}
+
+/// Foreign key, from the referencing table to the referenced one
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct ForeignKey {
+    pub name: String,
+    pub from_schema: SomeSchema,
+    pub from_table: SomeTable,
+    pub from_columns: Vec<String>,
+    pub to_schema: SomeSchema,
+    pub to_table: SomeTable,
+    pub to_columns: Vec<String>,
+    pub definition: String,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct Index {
+    pub name: String,
+    pub definition: String,
+    pub unique: bool,
+    pub primary: bool,
+    /// False while being built concurrently, or if building failed
+    pub valid: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct Trigger {
+    pub name: String,
+    pub definition: String,
+    pub enabled: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct TableStructure {
+    pub columns: Vec<ColumnInfo>,
+    pub primary_key: Option<Constraint>,
+    /// Foreign keys of this table
+    pub foreign_keys: Vec<ForeignKey>,
+    /// Foreign keys of other tables (or this one) referencing this table
+    pub referenced_by: Vec<ForeignKey>,
+    pub unique_constraints: Vec<Constraint>,
+    pub check_constraints: Vec<Constraint>,
+    pub indexes: Vec<Index>,
+    pub triggers: Vec<Trigger>,
+}
+
+fn decode_error(e: tokio_postgres::Error) -> DatabaseError {
+    DatabaseError::Decode(ErrorDetails::from(&e))
+}
+
+/// Constraints of the table, and foreign keys of other tables referencing it
+///
+/// The column numbers of a constraint are turned into names, in their order.
+const CONSTRAINTS_QUERY: &str = "SELECT c.conname::text AS name, c.contype::text AS kind,
+        c.conrelid, fn.nspname::text AS from_schema, f.relname::text AS from_table,
+        ARRAY(SELECT a.attname::text FROM unnest(c.conkey) WITH ORDINALITY AS k(attnum, n)
+            JOIN pg_catalog.pg_attribute a ON a.attrelid = c.conrelid AND a.attnum = k.attnum
+            ORDER BY k.n) AS from_columns,
+        c.confrelid, tn.nspname::text AS to_schema, t.relname::text AS to_table,
+        ARRAY(SELECT a.attname::text FROM unnest(c.confkey) WITH ORDINALITY AS k(attnum, n)
+            JOIN pg_catalog.pg_attribute a ON a.attrelid = c.confrelid AND a.attnum = k.attnum
+            ORDER BY k.n) AS to_columns,
+        pg_catalog.pg_get_constraintdef(c.oid, true) AS definition
+    FROM pg_catalog.pg_constraint c
+    JOIN pg_catalog.pg_class f ON f.oid = c.conrelid
+    JOIN pg_catalog.pg_namespace fn ON fn.oid = f.relnamespace
+    LEFT JOIN pg_catalog.pg_class t ON t.oid = c.confrelid
+    LEFT JOIN pg_catalog.pg_namespace tn ON tn.oid = t.relnamespace
+    WHERE c.conrelid = $1 OR (c.contype = 'f' AND c.confrelid = $1)
+    ORDER BY c.conname";
+
+const INDEXES_QUERY: &str =
+    "SELECT i.relname::text AS name, pg_catalog.pg_get_indexdef(x.indexrelid) AS definition,
+        x.indisunique AS unique, x.indisprimary AS primary, x.indisvalid AS valid
+    FROM pg_catalog.pg_index x
+    JOIN pg_catalog.pg_class i ON i.oid = x.indexrelid
+    WHERE x.indrelid = $1
+    ORDER BY i.relname";
+
+const TRIGGERS_QUERY: &str =
+    "SELECT t.tgname::text AS name, pg_catalog.pg_get_triggerdef(t.oid, true) AS definition,
+        t.tgenabled <> 'D' AS enabled
+    FROM pg_catalog.pg_trigger t
+    WHERE t.tgrelid = $1 AND NOT t.tgisinternal
+    ORDER BY t.tgname";
+
+fn column_info(row: &Row) -> Result<ColumnInfo, tokio_postgres::Error> {
+    Ok(ColumnInfo {
+        name: row.try_get("column_name")?,
+        data_type: row.try_get("data_type")?,
+        nullable: row.try_get("nullable")?,
+        default: row.try_get("column_default")?,
+        identity: row.try_get("identity")?,
+        generated: row.try_get("generated")?,
+        comment: row.try_get("comment")?,
+    })
+}
+
+fn constraint(row: &Row) -> Result<Constraint, tokio_postgres::Error> {
+    Ok(Constraint {
+        name: row.try_get("name")?,
+        columns: row.try_get("from_columns")?,
+        definition: row.try_get("definition")?,
+    })
+}
+
+fn foreign_key(row: &Row) -> Result<ForeignKey, tokio_postgres::Error> {
+    Ok(ForeignKey {
+        name: row.try_get("name")?,
+        from_schema: row.try_get("from_schema")?,
+        from_table: row.try_get("from_table")?,
+        from_columns: row.try_get("from_columns")?,
+        to_schema: row.try_get("to_schema")?,
+        to_table: row.try_get("to_table")?,
+        to_columns: row.try_get("to_columns")?,
+        definition: row.try_get("definition")?,
+    })
+}
+
+fn index(row: &Row) -> Result<Index, tokio_postgres::Error> {
+    Ok(Index {
+        name: row.try_get("name")?,
+        definition: row.try_get("definition")?,
+        unique: row.try_get("unique")?,
+        primary: row.try_get("primary")?,
+        valid: row.try_get("valid")?,
+    })
+}
+
+fn trigger(row: &Row) -> Result<Trigger, tokio_postgres::Error> {
+    Ok(Trigger {
+        name: row.try_get("name")?,
+        definition: row.try_get("definition")?,
+        enabled: row.try_get("enabled")?,
+    })
+}
+
+/// Read structure of the table
+pub async fn describe_table(
+    client: &Client,
+    db_table: &DatabaseTable,
+) -> Result<TableStructure, DatabaseError> {
+    let oid: u32 = client
+        .query_one(
+            "SELECT $1::text::regclass::oid",
+            &[&sql::qualified_name(&db_table.schema, &db_table.table)],
+        )
+        .await
+        .map_err(execute_error)?
+        .try_get(0)
+        .map_err(decode_error)?;
+
+    let columns_statement = sql::table_columns_query(db_table);
+    let column_rows: Vec<Row> = client
+        .query_raw(
+            &columns_statement.text,
+            params::text_params(&columns_statement).iter(),
+        )
+        .await
+        .map_err(execute_error)?
+        .try_collect()
+        .await
+        .map_err(execute_error)?;
+    let columns = column_rows
+        .iter()
+        .map(column_info)
+        .collect::<Result<_, _>>()
+        .map_err(decode_error)?;
+
+    let mut structure = TableStructure {
+        columns,
+        primary_key: None,
+        foreign_keys: Vec::new(),
+        referenced_by: Vec::new(),
+        unique_constraints: Vec::new(),
+        check_constraints: Vec::new(),
+        indexes: Vec::new(),
+        triggers: Vec::new(),
+    };
+
+    for row in client
+        .query(CONSTRAINTS_QUERY, &[&oid])
+        .await
+        .map_err(execute_error)?
+    {
+        let kind: String = row.try_get("kind").map_err(decode_error)?;
+        let from: u32 = row.try_get("conrelid").map_err(decode_error)?;
+        let to: u32 = row.try_get("confrelid").map_err(decode_error)?;
+        match kind.as_str() {
+            // a foreign key of the table referencing itself is both
+            "f" => {
+                let key = foreign_key(&row).map_err(decode_error)?;
+                if to == oid {
+                    structure.referenced_by.push(key.clone());
+                }
+                if from == oid {
+                    structure.foreign_keys.push(key);
+                }
+            }
+            "p" => structure.primary_key = Some(constraint(&row).map_err(decode_error)?),
+            "u" => structure
+                .unique_constraints
+                .push(constraint(&row).map_err(decode_error)?),
+            "c" => structure
+                .check_constraints
+                .push(constraint(&row).map_err(decode_error)?),
+            // exclusion and constraint triggers show up as indexes and triggers
+            _ => {}
+        }
+    }
+
+    for row in client
+        .query(INDEXES_QUERY, &[&oid])
+        .await
+        .map_err(execute_error)?
+    {
+        structure.indexes.push(index(&row).map_err(decode_error)?);
+    }
+    for row in client
+        .query(TRIGGERS_QUERY, &[&oid])
+        .await
+        .map_err(execute_error)?
+    {
+        structure
+            .triggers
+            .push(trigger(&row).map_err(decode_error)?);
+    }
+    Ok(structure)
+}
@@ -0,0 +1,336 @@
+//! Editing rows of a table: insert, update and delete by primary key
+//!
+//! All edits of a batch run in one transaction, each of them within a
+//! savepoint, so a failing row does not hide the outcome of the others.
+//! The transaction is only committed if every edit was applied.
+//!
+//! Updates and deletes are optimistic: besides the key, the values the row
+//! had when it was read are part of the condition. If someone else changed
+//! or deleted the row in the meantime, nothing matches and the edit is
+//! reported as a conflict.
+
+use futures_util::TryStreamExt;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use tokio_postgres::{Client, Row};
+
+use super::convert::ServerRenderer;
+use super::sql::{self, SqlStatement};
+use super::types::{
+    Connection, DatabaseError, DatabaseTable, ErrorDetails, QueryLimits, TypedField,
+};
+use super::{execute_error, params, row_to_typed_fields};
+
+/// Primary key of a row, values in text form by column
+pub type RowKey = BTreeMap<String, String>;
+
+/// Column values in text form, None is NULL
+pub type RowValues = BTreeMap<String, Option<String>>;
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub enum RowEdit {
+    /// Columns not given get their default
+    Insert {
+        values: RowValues,
+    },
+    /// `original` holds the values as read (key columns included), for the
+    /// columns that must not have changed
+    Update {
+        key: RowKey,
+        original: RowValues,
+        changes: RowValues,
+    },
+    Delete {
+        key: RowKey,
+        original: RowValues,
+    },
+}
+
+impl RowEdit {
+    /// Statement of the edit, `text_compared` are the columns compared as text
+    fn statement(
+        &self,
+        table: &DatabaseTable,
+        text_compared: &BTreeSet<String>,
+    ) -> Result<SqlStatement, String> {
+        match self {
+            RowEdit::Insert { values } => {
+                Ok(sql::insert_statement(&table.schema, &table.table, values))
+            }
+            RowEdit::Update {
+                key,
+                original,
+                changes,
+            } => sql::update_statement(
+                &table.schema,
+                &table.table,
+                key,
+                original,
+                text_compared,
+                changes,
+            ),
+            RowEdit::Delete { key, original } => {
+                sql::delete_statement(&table.schema, &table.table, key, original, text_compared)
+            }
+        }
+    }
+}
+
+/// Edits of one table, as sent by the frontend
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct TableEdits {
+    pub connection: Connection,
+    #[serde(default)]
+    pub limits: QueryLimits,
+    /// Pagination is ignored
+    pub table: DatabaseTable,
+    pub edits: Vec<RowEdit>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub enum RowOutcome {
+    /// Row as it is now (none for deletes)
+    Applied {
+        row: Option<Vec<TypedField>>,
+    },
+    /// Row was changed or deleted since it was read
+    Conflict,
+    Failed(DatabaseError),
+}
+
+/// Outcome of each edit, in the order of the edits
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct EditResult {
+    /// False if any edit was not applied, then none of them is kept
+    pub committed: bool,
+    /// Names of the columns of the returned rows
+    pub columns: Vec<String>,
+    pub rows: Vec<RowOutcome>,
+}
+
+async fn run_statement(
+    client: &Client,
+    statement: &SqlStatement,
+) -> Result<Vec<Row>, DatabaseError> {
+    println!("Edit: \"{}\" {:?}", statement.text, statement.params);
+    client
+        .query_raw(&statement.text, params::text_params(statement).iter())
+        .await
+        .map_err(execute_error)?
+        .try_collect()
+        .await
+        .map_err(execute_error)
+}
+
+/// Columns of types without equality operator (json, point, xml, ...), arrays by their element type
+///
+/// Enums and ranges have generic operators. Types found here by mistake
+/// (varchar, cidr) are still compared correctly, only as text.
+async fn columns_without_equality(
+    client: &Client,
+    table: &DatabaseTable,
+) -> Result<BTreeSet<String>, DatabaseError> {
+    let rows = client
+        .query(
+            "SELECT a.attname::text
+            FROM pg_catalog.pg_attribute a
+            JOIN pg_catalog.pg_type d ON d.oid = a.atttypid
+            JOIN pg_catalog.pg_type t ON t.oid = COALESCE(NULLIF(d.typbasetype, 0), d.oid)
+            JOIN pg_catalog.pg_type e ON e.oid = CASE WHEN t.typcategory = 'A' THEN t.typelem ELSE t.oid END
+            WHERE a.attrelid = $1::text::regclass AND a.attnum > 0 AND NOT a.attisdropped
+            AND NOT EXISTS (
+                SELECT 1 FROM pg_catalog.pg_operator o
+                WHERE o.oprname = '=' AND o.oprleft = o.oprright
+                AND o.oprleft IN (e.oid, CASE e.typtype
+                    WHEN 'e' THEN to_regtype('anyenum')
+                    WHEN 'r' THEN to_regtype('anyrange')
+                    WHEN 'm' THEN to_regtype('anymultirange') END))",
+            &[&sql::qualified_name(&table.schema, &table.table)],
+        )
+        .await
+        .map_err(execute_error)?;
+    rows.iter()
+        .map(|row| row.try_get(0).map_err(execute_error))
+        .collect()
+}
+
+/// Apply single edit within a savepoint, rolled back unless exactly one row was affected
+async fn apply_edit(
+    client: &Client,
+    table: &DatabaseTable,
+    edit: &RowEdit,
+    text_compared: &BTreeSet<String>,
+    columns: &mut Vec<String>,
+    renderer: &mut ServerRenderer,
+) -> Result<RowOutcome, DatabaseError> {
+    let statement = match edit.statement(table, text_compared) {
+        Ok(statement) => statement,
+        Err(message) => {
+            return Ok(RowOutcome::Failed(DatabaseError::Parse(
+                ErrorDetails::from_message(message),
+            )))
+        }
+    };
+    client
+        .batch_execute("SAVEPOINT row_edit")
+        .await
+        .map_err(execute_error)?;
+    let outcome = match run_statement(client, &statement).await {
+        Ok(rows) if rows.len() == 1 => {
+            let row = match edit {
+                RowEdit::Delete { .. } => None,
+                _ => {
+                    if columns.is_empty() {
+                        columns.extend(
+                            rows[0]
+                                .columns()
+                                .iter()
+                                .map(|column| String::from(column.name())),
+                        );
+                    }
+                    Some(row_to_typed_fields(client, renderer, &rows[0]).await)
+                }
+            };
+            RowOutcome::Applied { row }
+        }
+        Ok(rows) if rows.is_empty() => RowOutcome::Conflict,
+        Ok(rows) => RowOutcome::Failed(DatabaseError::Execute(ErrorDetails::from_message(
+            format!("key matches {} rows instead of one", rows.len()),
+        ))),
+        Err(e) => RowOutcome::Failed(e),
+    };
+    let end = match outcome {
+        RowOutcome::Applied { .. } => "RELEASE SAVEPOINT row_edit",
+        _ => "ROLLBACK TO SAVEPOINT row_edit",
+    };
+    client.batch_execute(end).await.map_err(execute_error)?;
+    Ok(outcome)
+}
+
+/// Apply edits in one transaction, committed only if all of them were applied
+///
+/// The error is about the transaction itself, e.g. the connection was lost.
+pub async fn apply_edits(
+    client: &Client,
+    table: &DatabaseTable,
+    edits: &[RowEdit],
+) -> Result<EditResult, DatabaseError> {
+    let text_compared = columns_without_equality(client, table).await?;
+    client.batch_execute("BEGIN").await.map_err(execute_error)?;
+    let mut columns = Vec::new();
+    let mut renderer = ServerRenderer::default();
+    let mut rows = Vec::with_capacity(edits.len());
+    for edit in edits {
+        match apply_edit(
+            client,
+            table,
+            edit,
+            &text_compared,
+            &mut columns,
+            &mut renderer,
+        )
+        .await
+        {
+            Ok(outcome) => rows.push(outcome),
+            Err(e) => {
+                if let Err(rollback_error) = client.batch_execute("ROLLBACK").await {
+                    println!("Could not roll back edits: {}", rollback_error);
+                }
+                return Err(e);
+            }
+        }
+    }
+    let committed = rows
+        .iter()
+        .all(|outcome| matches!(outcome, RowOutcome::Applied { .. }));
+    let end = if committed { "COMMIT" } else { "ROLLBACK" };
+    client.batch_execute(end).await.map_err(execute_error)?;
+    Ok(EditResult {
+        committed,
+        columns,
+        rows,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values(list: &[(&str, Option<&str>)]) -> RowValues {
+        list.iter()
+            .map(|(column, value)| (String::from(*column), value.map(String::from)))
+            .collect()
+    }
+
+    fn table() -> DatabaseTable {
+        DatabaseTable {
+            database: None,
+            schema: String::from("public"),
+            table: String::from("customers"),
+            pagination: None,
+        }
+    }
+
+    fn params(list: &[&str]) -> Vec<String> {
+        list.iter().map(|param| String::from(*param)).collect()
+    }
+
+    #[test]
+    fn update_selects_row_by_key_and_original_values() {
+        let edit = RowEdit::Update {
+            key: [(String::from("id"), String::from("1"))]
+                .into_iter()
+                .collect(),
+            original: values(&[
+                ("id", Some("1")),
+                ("email", Some("a@x")),
+                ("info", Some("{}")),
+                ("note", None),
+            ]),
+            changes: values(&[("email", Some("b@x"))]),
+        };
+        let text_compared = [String::from("info")].into_iter().collect();
+        let statement = edit.statement(&table(), &text_compared).unwrap();
+        assert_eq!(
+            statement.text,
+            "UPDATE \"public\".\"customers\" SET \"email\" = $1 WHERE \"id\" = $2 \
+             AND \"email\" IS NOT DISTINCT FROM $3 AND \"info\"::text IS NOT DISTINCT FROM $4::text \
+             AND \"note\" IS NULL RETURNING *"
+        );
+        assert_eq!(statement.params, params(&["b@x", "1", "a@x", "{}"]));
+    }
+
+    #[test]
+    fn delete_selects_row_by_key_and_original_values() {
+        let edit = RowEdit::Delete {
+            key: [
+                (String::from("a"), String::from("1")),
+                (String::from("b"), String::from("2")),
+            ]
+            .into_iter()
+            .collect(),
+            original: values(&[("a", Some("1")), ("b", Some("2")), ("pos", Some("(1,2)"))]),
+        };
+        let text_compared = [String::from("pos")].into_iter().collect();
+        let statement = edit.statement(&table(), &text_compared).unwrap();
+        assert_eq!(
+            statement.text,
+            "DELETE FROM \"public\".\"customers\" WHERE \"a\" = $1 AND \"b\" = $2 \
+             AND \"pos\"::text IS NOT DISTINCT FROM $3::text RETURNING *"
+        );
+        assert_eq!(statement.params, params(&["1", "2", "(1,2)"]));
+    }
+
+    #[test]
+    fn original_values_are_required() {
+        let edit: Result<RowEdit, _> = serde_json::from_str(r#"{"Delete": {"key": {"id": "1"}}}"#);
+        assert!(edit.is_err());
+        let edit = RowEdit::Delete {
+            key: [(String::from("id"), String::from("1"))]
+                .into_iter()
+                .collect(),
+            original: RowValues::new(),
+        };
+        assert!(edit.statement(&table(), &BTreeSet::new()).is_err());
+    }
+}
@@ -0,0 +1,467 @@
+//! Query plans: EXPLAIN (FORMAT JSON) read into a tree of plan nodes
+//!
+//! Besides what postgres reports, each node gets the cost and time spent
+//! in the node itself (without its children), and flags pointing out
+//! where to look first: the most expensive node, the slowest one and
+//! nodes whose row estimate is far off.
+//!
+//! Buffers are reported the way postgres does, including the children.
+//! Times of parallel workers are averages, so for nodes below a Gather
+//! the exclusive times are approximate.
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use super::sql::SqlStatement;
+use super::types::{ExplainQuery, TypedField, TypedTable};
+
+/// Actual rows that many times more (or less) than estimated count as misestimate
+const MISESTIMATE_FACTOR: f64 = 10.0;
+
+/// Blocks read and written, as reported with BUFFERS
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq)]
+pub struct Buffers {
+    pub shared_hit: u64,
+    pub shared_read: u64,
+    pub shared_dirtied: u64,
+    pub shared_written: u64,
+    pub local_hit: u64,
+    pub local_read: u64,
+    pub local_dirtied: u64,
+    pub local_written: u64,
+    pub temp_read: u64,
+    pub temp_written: u64,
+}
+
+/// What happened when the node ran, only known with ANALYZE
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct ActualRun {
+    /// Milliseconds until the first row, average per loop
+    pub startup_time_ms: f64,
+    /// Milliseconds until the last row, average per loop
+    pub total_time_ms: f64,
+    /// Average per loop
+    pub rows: f64,
+    /// Zero if the node never ran
+    pub loops: u64,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct PlanNode {
+    /// e.g. "Seq Scan", "Hash Join"
+    pub node_type: String,
+    /// Role below the parent, e.g. "Outer", "Inner", "InitPlan"
+    pub parent_relationship: Option<String>,
+    pub relation_name: Option<String>,
+    pub alias: Option<String>,
+    pub index_name: Option<String>,
+    pub join_type: Option<String>,
+    pub startup_cost: f64,
+    pub total_cost: f64,
+    /// Estimated rows per loop
+    pub plan_rows: f64,
+    pub plan_width: u64,
+    pub actual: Option<ActualRun>,
+    pub buffers: Option<Buffers>,
+    /// Total cost without the total cost of the children
+    pub exclusive_cost: f64,
+    /// Time spent in the node itself over all loops, with ANALYZE
+    pub exclusive_time_ms: Option<f64>,
+    pub most_expensive: bool,
+    pub slowest: bool,
+    /// Actual rows are off the estimate by `MISESTIMATE_FACTOR` or more
+    pub misestimated: bool,
+    /// Everything else postgres reports for the node, e.g. "Filter", "Sort Key"
+    pub properties: Map<String, Value>,
+    pub children: Vec<PlanNode>,
+}
+
+/// Time spent in a trigger, with ANALYZE
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct TriggerTime {
+    pub name: String,
+    pub relation: Option<String>,
+    pub time_ms: f64,
+    pub calls: u64,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct QueryPlan {
+    pub root: PlanNode,
+    /// The statement ran, its effects were rolled back
+    pub analyzed: bool,
+    pub planning_time_ms: Option<f64>,
+    pub execution_time_ms: Option<f64>,
+    /// Buffers used for planning, with BUFFERS
+    pub planning_buffers: Option<Buffers>,
+    pub triggers: Vec<TriggerTime>,
+}
+
+/// EXPLAIN statement for the query, the values are bound as for the query itself
+pub fn explain_statement(explain: &ExplainQuery) -> SqlStatement {
+    let mut options = String::from("FORMAT JSON");
+    if explain.analyze {
+        options.push_str(", ANALYZE");
+    }
+    if explain.buffers {
+        options.push_str(", BUFFERS");
+    }
+    let sql = format!("EXPLAIN ({}) {}", options, explain.query.sql_query);
+    SqlStatement::with_values(&sql, &explain.query.params)
+}
+
+fn take_string(map: &mut Map<String, Value>, key: &str) -> Option<String> {
+    match map.remove(key) {
+        Some(Value::String(text)) => Some(text),
+        _ => None,
+    }
+}
+
+fn take_f64(map: &mut Map<String, Value>, key: &str) -> Option<f64> {
+    map.remove(key).and_then(|value| value.as_f64())
+}
+
+fn take_u64(map: &mut Map<String, Value>, key: &str) -> Option<u64> {
+    map.remove(key).and_then(|value| value.as_u64())
+}
+
+fn take_buffers(map: &mut Map<String, Value>) -> Option<Buffers> {
+    if !map.contains_key("Shared Hit Blocks") {
+        return None;
+    }
+    let mut blocks = |key: &str| take_u64(map, key).unwrap_or(0);
+    Some(Buffers {
+        shared_hit: blocks("Shared Hit Blocks"),
+        shared_read: blocks("Shared Read Blocks"),
+        shared_dirtied: blocks("Shared Dirtied Blocks"),
+        shared_written: blocks("Shared Written Blocks"),
+        local_hit: blocks("Local Hit Blocks"),
+        local_read: blocks("Local Read Blocks"),
+        local_dirtied: blocks("Local Dirtied Blocks"),
+        local_written: blocks("Local Written Blocks"),
+        temp_read: blocks("Temp Read Blocks"),
+        temp_written: blocks("Temp Written Blocks"),
+    })
+}
+
+/// Whether the actual rows are far off the estimate, nodes that never ran are not
+fn is_misestimated(plan_rows: f64, actual: &ActualRun) -> bool {
+    if actual.loops == 0 {
+        return false;
+    }
+    // postgres never estimates less than one row, zero actual rows count as one
+    let ratio = actual.rows.max(1.0) / plan_rows.max(1.0);
+    ratio >= MISESTIMATE_FACTOR || ratio <= 1.0 / MISESTIMATE_FACTOR
+}
+
+fn parse_node(value: Value) -> Result<PlanNode, String> {
+    let mut map = match value {
+        Value::Object(map) => map,
+        _ => return Err(String::from("plan node is not an object")),
+    };
+    let node_type = take_string(&mut map, "Node Type").ok_or("plan node without \"Node Type\"")?;
+    let children = match map.remove("Plans") {
+        Some(Value::Array(plans)) => plans
+            .into_iter()
+            .map(parse_node)
+            .collect::<Result<Vec<_>, _>>()?,
+        _ => Vec::new(),
+    };
+    let startup_cost = take_f64(&mut map, "Startup Cost").unwrap_or(0.0);
+    let total_cost = take_f64(&mut map, "Total Cost").unwrap_or(0.0);
+    let plan_rows = take_f64(&mut map, "Plan Rows").unwrap_or(0.0);
+    let actual = take_u64(&mut map, "Actual Loops").map(|loops| ActualRun {
+        startup_time_ms: take_f64(&mut map, "Actual Startup Time").unwrap_or(0.0),
+        total_time_ms: take_f64(&mut map, "Actual Total Time").unwrap_or(0.0),
+        rows: take_f64(&mut map, "Actual Rows").unwrap_or(0.0),
+        loops,
+    });
+
+    let children_cost: f64 = children.iter().map(|child| child.total_cost).sum();
+    let exclusive_time_ms = actual.as_ref().map(|actual| {
+        let children_time: f64 = children
+            .iter()
+            .filter_map(|child| child.actual.as_ref())
+            .map(|child| child.total_time_ms * child.loops as f64)
+            .sum();
+        (actual.total_time_ms * actual.loops as f64 - children_time).max(0.0)
+    });
+    Ok(PlanNode {
+        node_type,
+        parent_relationship: take_string(&mut map, "Parent Relationship"),
+        relation_name: take_string(&mut map, "Relation Name"),
+        alias: take_string(&mut map, "Alias"),
+        index_name: take_string(&mut map, "Index Name"),
+        join_type: take_string(&mut map, "Join Type"),
+        startup_cost,
+        total_cost,
+        plan_rows,
+        plan_width: take_u64(&mut map, "Plan Width").unwrap_or(0),
+        misestimated: actual
+            .as_ref()
+            .map_or(false, |actual| is_misestimated(plan_rows, actual)),
+        actual,
+        buffers: take_buffers(&mut map),
+        exclusive_cost: (total_cost - children_cost).max(0.0),
+        exclusive_time_ms,
+        most_expensive: false,
+        slowest: false,
+        properties: map,
+        children,
+    })
+}
+
+/// Nodes of the tree, parents before their children
+fn preorder<'a>(node: &'a PlanNode, nodes: &mut Vec<&'a PlanNode>) {
+    nodes.push(node);
+    for child in node.children.iter() {
+        preorder(child, nodes);
+    }
+}
+
+/// Position (in pre-order) of the first node with the highest value
+fn position_of_max(nodes: &[&PlanNode], key: impl Fn(&PlanNode) -> Option<f64>) -> Option<usize> {
+    let mut best: Option<(usize, f64)> = None;
+    for (position, node) in nodes.iter().enumerate() {
+        if let Some(value) = key(node) {
+            if best.map_or(true, |(_, best_value)| value > best_value) {
+                best = Some((position, value));
+            }
+        }
+    }
+    best.map(|(position, _)| position)
+}
+
+fn set_flags(
+    node: &mut PlanNode,
+    position: &mut usize,
+    most_expensive: Option<usize>,
+    slowest: Option<usize>,
+) {
+    node.most_expensive = most_expensive == Some(*position);
+    node.slowest = slowest == Some(*position);
+    *position += 1;
+    for child in node.children.iter_mut() {
+        set_flags(child, position, most_expensive, slowest);
+    }
+}
+
+/// Flag the node with the highest exclusive cost, and the one with the highest exclusive time
+fn mark_hot_spots(root: &mut PlanNode) {
+    let mut nodes = Vec::new();
+    preorder(root, &mut nodes);
+    let most_expensive = position_of_max(&nodes, |node| Some(node.exclusive_cost));
+    let slowest = position_of_max(&nodes, |node| node.exclusive_time_ms);
+    set_flags(root, &mut 0, most_expensive, slowest);
+}
+
+fn parse_triggers(value: Option<Value>) -> Vec<TriggerTime> {
+    let triggers = match value {
+        Some(Value::Array(triggers)) => triggers,
+        _ => return Vec::new(),
+    };
+    triggers
+        .into_iter()
+        .filter_map(|trigger| match trigger {
+            Value::Object(mut map) => Some(TriggerTime {
+                name: take_string(&mut map, "Trigger Name")?,
+                relation: take_string(&mut map, "Relation"),
+                time_ms: take_f64(&mut map, "Time").unwrap_or(0.0),
+                calls: take_u64(&mut map, "Calls").unwrap_or(0),
+            }),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Read the output of EXPLAIN (FORMAT JSON)
+pub fn parse_plan(value: Value) -> Result<QueryPlan, String> {
+    let mut map = match value {
+        Value::Array(mut statements) if statements.len() == 1 => match statements.remove(0) {
+            Value::Object(map) => map,
+            _ => return Err(String::from("plan is not an object")),
+        },
+        _ => return Err(String::from("expected a plan for exactly one statement")),
+    };
+    let mut root = parse_node(
+        map.remove("Plan")
+            .ok_or("no \"Plan\" in the output of EXPLAIN")?,
+    )?;
+    mark_hot_spots(&mut root);
+    let planning_buffers = match map.remove("Planning") {
+        Some(Value::Object(mut planning)) => take_buffers(&mut planning),
+        _ => None,
+    };
+    Ok(QueryPlan {
+        analyzed: root.actual.is_some(),
+        root,
+        planning_time_ms: take_f64(&mut map, "Planning Time"),
+        execution_time_ms: take_f64(&mut map, "Execution Time"),
+        planning_buffers,
+        triggers: parse_triggers(map.remove("Triggers")),
+    })
+}
+
+/// Plan from the result of EXPLAIN, a single row with a single json value
+pub fn plan_from_table(table: &TypedTable) -> Result<QueryPlan, String> {
+    match table.fields.first().and_then(|row| row.first()) {
+        Some(TypedField::Json(value)) => parse_plan(value.clone()),
+        Some(TypedField::Text(text)) => {
+            parse_plan(serde_json::from_str(text).map_err(|e| e.to_string())?)
+        }
+        _ => Err(String::from("EXPLAIN returned no plan")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn scan(
+        node_type: &str,
+        total_cost: f64,
+        plan_rows: f64,
+        actual: Option<(f64, f64, u64)>,
+    ) -> Value {
+        let mut node = json!({
+            "Node Type": node_type,
+            "Parent Relationship": "Outer",
+            "Relation Name": "orders",
+            "Alias": "o",
+            "Startup Cost": 0.0,
+            "Total Cost": total_cost,
+            "Plan Rows": plan_rows,
+            "Plan Width": 8,
+            "Filter": "(amount > 100)",
+        });
+        if let Some((time, rows, loops)) = actual {
+            let map = node.as_object_mut().unwrap();
+            map.insert(String::from("Actual Startup Time"), json!(0.0));
+            map.insert(String::from("Actual Total Time"), json!(time));
+            map.insert(String::from("Actual Rows"), json!(rows));
+            map.insert(String::from("Actual Loops"), json!(loops));
+        }
+        node
+    }
+
+    #[test]
+    fn plan_without_analyze() {
+        let output = json!([{
+            "Plan": {
+                "Node Type": "Hash Join",
+                "Join Type": "Inner",
+                "Startup Cost": 10.0,
+                "Total Cost": 50.0,
+                "Plan Rows": 100,
+                "Plan Width": 16,
+                "Hash Cond": "(o.customer = c.id)",
+                "Plans": [scan("Seq Scan", 30.0, 1000.0, None), scan("Hash", 12.5, 10.0, None)]
+            },
+            "Planning Time": 0.25
+        }]);
+        let plan = parse_plan(output).unwrap();
+        assert!(!plan.analyzed);
+        assert_eq!(plan.planning_time_ms, Some(0.25));
+        assert_eq!(plan.execution_time_ms, None);
+
+        let root = &plan.root;
+        assert_eq!(root.node_type, "Hash Join");
+        assert_eq!(root.join_type.as_deref(), Some("Inner"));
+        assert_eq!(root.exclusive_cost, 7.5);
+        assert_eq!(
+            root.properties.get("Hash Cond"),
+            Some(&json!("(o.customer = c.id)"))
+        );
+        assert!(!root.properties.contains_key("Node Type"));
+        assert_eq!(root.children.len(), 2);
+        assert_eq!(root.children[0].relation_name.as_deref(), Some("orders"));
+        assert!(root.children[0].most_expensive);
+        assert!(!root.most_expensive && !root.children[1].most_expensive);
+        assert!(root
+            .children
+            .iter()
+            .all(|child| !child.slowest && !child.misestimated));
+    }
+
+    #[test]
+    fn plan_with_analyze_and_buffers() {
+        let mut outer = scan("Seq Scan", 30.0, 1000.0, Some((0.5, 3.0, 1)));
+        let mut inner = scan("Index Scan", 5.0, 1.0, Some((0.2, 1.0, 20)));
+        inner["Shared Hit Blocks"] = json!(40);
+        inner["Shared Read Blocks"] = json!(2);
+        outer["Plans"] = json!([]);
+        let output = json!([{
+            "Plan": {
+                "Node Type": "Nested Loop",
+                "Startup Cost": 0.0,
+                "Total Cost": 150.0,
+                "Plan Rows": 1000,
+                "Plan Width": 16,
+                "Actual Startup Time": 0.1,
+                "Actual Total Time": 6.0,
+                "Actual Rows": 3,
+                "Actual Loops": 1,
+                "Plans": [outer, inner, scan("Seq Scan", 1.0, 5.0, Some((0.0, 0.0, 0)))]
+            },
+            "Planning": {"Shared Hit Blocks": 7},
+            "Planning Time": 0.1,
+            "Triggers": [{"Trigger Name": "orders_audit", "Relation": "orders", "Time": 0.3, "Calls": 3}],
+            "Execution Time": 6.5
+        }]);
+        let plan = parse_plan(output).unwrap();
+        assert!(plan.analyzed);
+        assert_eq!(plan.execution_time_ms, Some(6.5));
+        assert_eq!(
+            plan.planning_buffers
+                .as_ref()
+                .map(|buffers| buffers.shared_hit),
+            Some(7)
+        );
+        assert_eq!(plan.triggers[0].name, "orders_audit");
+        assert_eq!(plan.triggers[0].calls, 3);
+
+        let root = &plan.root;
+        // 6 ms in total, 0.5 ms in the outer scan, 20 loops of 0.2 ms in the inner one
+        assert!((root.exclusive_time_ms.unwrap() - 1.5).abs() < 1e-9);
+        assert!(root.most_expensive);
+        let [outer, inner, never_ran] = [&root.children[0], &root.children[1], &root.children[2]];
+        assert!(inner.slowest && !outer.slowest && !root.slowest);
+        // 3 rows instead of 1000
+        assert!(outer.misestimated && root.misestimated);
+        assert!(!inner.misestimated && !never_ran.misestimated);
+        assert_eq!(
+            inner
+                .buffers
+                .as_ref()
+                .map(|buffers| (buffers.shared_hit, buffers.shared_read)),
+            Some((40, 2))
+        );
+        assert_eq!(outer.buffers, None);
+    }
+
+    #[test]
+    fn explain_options() {
+        let mut explain = ExplainQuery {
+            query: super::super::types::CustomQuery {
+                database: None,
+                sql_query: String::from("DELETE FROM t WHERE id = $1"),
+                params: vec![Some(String::from("5"))],
+            },
+            analyze: false,
+            buffers: false,
+        };
+        assert_eq!(
+            explain_statement(&explain).text,
+            "EXPLAIN (FORMAT JSON) DELETE FROM t WHERE id = $1"
+        );
+        explain.analyze = true;
+        explain.buffers = true;
+        let statement = explain_statement(&explain);
+        assert_eq!(
+            statement.text,
+            "EXPLAIN (FORMAT JSON, ANALYZE, BUFFERS) DELETE FROM t WHERE id = $1"
+        );
+        assert_eq!(statement.values, vec![Some(String::from("5"))]);
+    }
+}
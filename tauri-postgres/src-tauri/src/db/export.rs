@@ -0,0 +1,472 @@
+//! Export of query results to files: CSV, JSON, SQL INSERT statements and XLSX
+//!
+//! Rows are written as their batches arrive, the result is never held in
+//! memory as a whole. CSV follows RFC 4180 (quotes doubled, lines ended by
+//! CRLF), NULL is written as the text chosen for it and never quoted, so
+//! it stays distinct from a quoted value with the same text.
+//!
+//! JSON is an array with one object per row. Numbers that fit JSON are
+//! written as numbers, other values (NaN, timestamps, ...) as strings.
+
+use serde::{Deserialize, Serialize};
+use std::io::{self, Write};
+
+use super::sql;
+use super::types::TypedField;
+use super::xlsx::{Cell, XlsxWriter};
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub enum ExportFormat {
+    Csv,
+    Json,
+    /// One INSERT statement per row
+    SqlInsert,
+    Xlsx,
+}
+
+/// Which CSV values are put in quotes
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub enum Quoting {
+    /// Only values that need it: with delimiter, quote or line break, or equal to the NULL text
+    Minimal,
+    All,
+    /// All but numbers
+    NonNumeric,
+}
+
+/// Options of an export, defaults give CSV with header
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct ExportOptions {
+    pub format: ExportFormat,
+    /// CSV only
+    pub delimiter: char,
+    /// Column names as first row (CSV and XLSX)
+    pub header: bool,
+    /// How NULL is written in CSV
+    pub null_text: String,
+    pub quoting: Quoting,
+    /// Target of the INSERT statements, the exported table or "export" if not given
+    pub table_name: Option<String>,
+    /// Schema of the target table, unqualified name if not given
+    pub schema_name: Option<String>,
+}
+
+impl Default for ExportOptions {
+    fn default() -> Self {
+        ExportOptions {
+            format: ExportFormat::Csv,
+            delimiter: ',',
+            header: true,
+            null_text: String::new(),
+            quoting: Quoting::Minimal,
+            table_name: None,
+            schema_name: None,
+        }
+    }
+}
+
+/// Rows written to a file
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ExportSummary {
+    pub path: String,
+    pub rows: u64,
+}
+
+/// Rows written so far, as sent to the frontend during an export
+#[derive(Debug, Serialize, Clone)]
+pub struct ExportProgress {
+    pub export_id: String,
+    pub rows: u64,
+}
+
+/// Writes rows in one of the formats
+pub trait RowWriter<W> {
+    fn write_rows(&mut self, rows: &[Vec<TypedField>]) -> io::Result<()>;
+    /// Complete the file, returns the underlying writer
+    fn finish(self: Box<Self>) -> io::Result<W>;
+}
+
+/// Writer for the format of the options, the header (if any) is written right away
+///
+/// `table` is the exported table, as default target of INSERT statements.
+pub fn row_writer<W: Write + Send + 'static>(
+    mut out: W,
+    columns: &[String],
+    options: &ExportOptions,
+    table: Option<(&str, &str)>,
+) -> io::Result<Box<dyn RowWriter<W> + Send>> {
+    match options.format {
+        ExportFormat::Csv => {
+            if matches!(options.delimiter, '"' | '\r' | '\n') {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "delimiter cannot be a quote or a line break",
+                ));
+            }
+            let writer = CsvWriter {
+                out,
+                delimiter: options.delimiter,
+                null_text: options.null_text.clone(),
+                quoting: options.quoting,
+            };
+            let mut writer = Box::new(writer);
+            if options.header {
+                let names: Vec<(Option<&str>, bool)> = columns
+                    .iter()
+                    .map(|name| (Some(name.as_str()), false))
+                    .collect();
+                writer.write_line(&names)?;
+            }
+            Ok(writer)
+        }
+        ExportFormat::Json => {
+            out.write_all(b"[")?;
+            let columns = columns
+                .iter()
+                .map(serde_json::to_string)
+                .collect::<Result<_, _>>()?;
+            Ok(Box::new(JsonWriter {
+                out,
+                columns,
+                first: true,
+            }))
+        }
+        ExportFormat::SqlInsert => {
+            let target = match (&options.table_name, &options.schema_name, table) {
+                (Some(name), Some(schema), _) => sql::qualified_name(schema, name),
+                (Some(name), None, _) => sql::quote_identifier(name),
+                (None, _, Some((schema, name))) => sql::qualified_name(schema, name),
+                (None, Some(schema), None) => sql::qualified_name(schema, "export"),
+                (None, None, None) => sql::quote_identifier("export"),
+            };
+            let names: Vec<String> = columns
+                .iter()
+                .map(|name| sql::quote_identifier(name))
+                .collect();
+            Ok(Box::new(InsertWriter {
+                out,
+                prefix: format!("INSERT INTO {} ({}) VALUES (", target, names.join(", ")),
+            }))
+        }
+        ExportFormat::Xlsx => {
+            let mut writer = XlsxWriter::new(out)?;
+            if options.header {
+                let names: Vec<Cell> = columns.iter().map(|name| Cell::Text(name)).collect();
+                writer.write_row(&names)?;
+            }
+            Ok(Box::new(writer))
+        }
+    }
+}
+
+fn conversion_failed(reason: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("value could not be converted: {}", reason),
+    )
+}
+
+/// Value as text, None for NULL
+fn field_text(field: &TypedField) -> io::Result<Option<String>> {
+    let text = match field {
+        TypedField::Null => return Ok(None),
+        TypedField::Integer(value) => value.to_string(),
+        TypedField::Float(value) => value.to_string(),
+        TypedField::Bool(value) => value.to_string(),
+        TypedField::Json(value) => value.to_string(),
+        TypedField::Text(text)
+        | TypedField::Numeric(text)
+        | TypedField::Timestamp(text)
+        | TypedField::Database(text)
+        | TypedField::Schema(text)
+        | TypedField::Table(text) => text.clone(),
+        TypedField::ConversionFailure(reason) => return Err(conversion_failed(reason)),
+    };
+    Ok(Some(text))
+}
+
+fn is_number(field: &TypedField) -> bool {
+    match field {
+        TypedField::Integer(_) => true,
+        TypedField::Float(value) => value.is_finite(),
+        TypedField::Numeric(text) => is_plain_decimal(text),
+        _ => false,
+    }
+}
+
+/// Digits with optional sign and decimal point, valid in JSON, SQL and XLSX alike
+fn is_plain_decimal(text: &str) -> bool {
+    let digits = text.strip_prefix('-').unwrap_or(text);
+    let (whole, fraction) = match digits.split_once('.') {
+        Some((whole, fraction)) => (whole, Some(fraction)),
+        None => (digits, None),
+    };
+    let all_digits = |part: &str| !part.is_empty() && part.bytes().all(|b| b.is_ascii_digit());
+    all_digits(whole) && fraction.map_or(true, all_digits)
+}
+
+struct CsvWriter<W> {
+    out: W,
+    delimiter: char,
+    null_text: String,
+    quoting: Quoting,
+}
+
+impl<W: Write> CsvWriter<W> {
+    fn needs_quotes(&self, text: &str, is_number: bool) -> bool {
+        match self.quoting {
+            Quoting::All => true,
+            Quoting::NonNumeric if !is_number => true,
+            _ => text == self.null_text || text.contains(&[self.delimiter, '"', '\r', '\n'][..]),
+        }
+    }
+
+    /// Values with whether they are numbers, None is NULL
+    fn write_line(&mut self, values: &[(Option<&str>, bool)]) -> io::Result<()> {
+        let mut line = String::new();
+        for (index, (value, is_number)) in values.iter().enumerate() {
+            if index > 0 {
+                line.push(self.delimiter);
+            }
+            match value {
+                None => line.push_str(&self.null_text),
+                Some(text) if self.needs_quotes(text, *is_number) => {
+                    line.push('"');
+                    line.push_str(&text.replace('"', "\"\""));
+                    line.push('"');
+                }
+                Some(text) => line.push_str(text),
+            }
+        }
+        line.push_str("\r\n");
+        self.out.write_all(line.as_bytes())
+    }
+}
+
+impl<W: Write> RowWriter<W> for CsvWriter<W> {
+    fn write_rows(&mut self, rows: &[Vec<TypedField>]) -> io::Result<()> {
+        for row in rows {
+            let texts = row.iter().map(field_text).collect::<io::Result<Vec<_>>>()?;
+            let values: Vec<(Option<&str>, bool)> = texts
+                .iter()
+                .zip(row.iter())
+                .map(|(text, field)| (text.as_deref(), is_number(field)))
+                .collect();
+            self.write_line(&values)?;
+        }
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> io::Result<W> {
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+struct JsonWriter<W> {
+    out: W,
+    /// Column names, already encoded as JSON strings
+    columns: Vec<String>,
+    first: bool,
+}
+
+fn json_value(field: &TypedField) -> io::Result<String> {
+    let value = match field {
+        TypedField::Null => String::from("null"),
+        TypedField::Json(value) => value.to_string(),
+        TypedField::Bool(value) => value.to_string(),
+        field if is_number(field) => field_text(field)?.unwrap_or_default(),
+        field => serde_json::to_string(&field_text(field)?.unwrap_or_default())?,
+    };
+    Ok(value)
+}
+
+impl<W: Write> RowWriter<W> for JsonWriter<W> {
+    fn write_rows(&mut self, rows: &[Vec<TypedField>]) -> io::Result<()> {
+        for row in rows {
+            let mut object = String::from(if self.first { "\n{" } else { ",\n{" });
+            self.first = false;
+            for (index, (name, field)) in self.columns.iter().zip(row.iter()).enumerate() {
+                if index > 0 {
+                    object.push(',');
+                }
+                object.push_str(name);
+                object.push(':');
+                object.push_str(&json_value(field)?);
+            }
+            object.push('}');
+            self.out.write_all(object.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> io::Result<W> {
+        self.out.write_all(b"\n]\n")?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+struct InsertWriter<W> {
+    out: W,
+    /// INSERT INTO ... VALUES (
+    prefix: String,
+}
+
+fn sql_value(field: &TypedField) -> io::Result<String> {
+    let value = match field {
+        TypedField::Null => String::from("NULL"),
+        TypedField::Bool(true) => String::from("TRUE"),
+        TypedField::Bool(false) => String::from("FALSE"),
+        field if is_number(field) => field_text(field)?.unwrap_or_default(),
+        field => sql::quote_literal(&field_text(field)?.unwrap_or_default()),
+    };
+    Ok(value)
+}
+
+impl<W: Write> RowWriter<W> for InsertWriter<W> {
+    fn write_rows(&mut self, rows: &[Vec<TypedField>]) -> io::Result<()> {
+        for row in rows {
+            let values = row.iter().map(sql_value).collect::<io::Result<Vec<_>>>()?;
+            let statement = format!("{}{});\n", self.prefix, values.join(", "));
+            self.out.write_all(statement.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> io::Result<W> {
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+impl<W: Write> RowWriter<W> for XlsxWriter<W> {
+    fn write_rows(&mut self, rows: &[Vec<TypedField>]) -> io::Result<()> {
+        for row in rows {
+            let texts = row.iter().map(field_text).collect::<io::Result<Vec<_>>>()?;
+            let cells: Vec<Cell> = texts
+                .iter()
+                .zip(row.iter())
+                .map(|(text, field)| match (text, field) {
+                    (None, _) => Cell::Empty,
+                    (_, TypedField::Bool(value)) => Cell::Bool(*value),
+                    (Some(text), field) if is_number(field) => Cell::Number(text),
+                    (Some(text), _) => Cell::Text(text),
+                })
+                .collect();
+            self.write_row(&cells)?;
+        }
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> io::Result<W> {
+        XlsxWriter::finish(*self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn export(options: &ExportOptions, table: Option<(&str, &str)>) -> String {
+        let columns = vec![
+            String::from("id"),
+            String::from("name"),
+            String::from("price"),
+        ];
+        let rows = [
+            vec![
+                TypedField::Integer(1),
+                TypedField::Text(String::from("say \"hi\", bye")),
+                TypedField::Numeric(String::from("-2.50")),
+            ],
+            vec![
+                TypedField::Integer(2),
+                TypedField::Text(String::new()),
+                TypedField::Null,
+            ],
+            vec![
+                TypedField::Integer(3),
+                TypedField::Text(String::from("it's")),
+                TypedField::Numeric(String::from("NaN")),
+            ],
+        ];
+        let mut writer = row_writer(Vec::new(), &columns, options, table).unwrap();
+        writer.write_rows(&rows[..1]).unwrap();
+        writer.write_rows(&rows[1..]).unwrap();
+        String::from_utf8(writer.finish().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn csv_quotes_where_needed() {
+        assert_eq!(
+            export(&ExportOptions::default(), None),
+            "id,name,price\r\n1,\"say \"\"hi\"\", bye\",-2.50\r\n2,\"\",\r\n3,it's,NaN\r\n"
+        );
+        let options = ExportOptions {
+            delimiter: ';',
+            header: false,
+            null_text: String::from("NULL"),
+            quoting: Quoting::NonNumeric,
+            ..ExportOptions::default()
+        };
+        assert_eq!(
+            export(&options, None),
+            "1;\"say \"\"hi\"\", bye\";-2.50\r\n2;\"\";NULL\r\n3;\"it's\";\"NaN\"\r\n"
+        );
+    }
+
+    #[test]
+    fn json_keeps_numbers() {
+        let options = ExportOptions {
+            format: ExportFormat::Json,
+            ..ExportOptions::default()
+        };
+        assert_eq!(
+            export(&options, None),
+            concat!(
+                "[\n{\"id\":1,\"name\":\"say \\\"hi\\\", bye\",\"price\":-2.50},\n",
+                "{\"id\":2,\"name\":\"\",\"price\":null},\n",
+                "{\"id\":3,\"name\":\"it's\",\"price\":\"NaN\"}\n]\n"
+            )
+        );
+        let empty = row_writer(Vec::new(), &[], &options, None)
+            .unwrap()
+            .finish()
+            .unwrap();
+        assert_eq!(String::from_utf8(empty).unwrap(), "[\n]\n");
+    }
+
+    #[test]
+    fn inserts_go_to_the_exported_table() {
+        let options = ExportOptions {
+            format: ExportFormat::SqlInsert,
+            ..ExportOptions::default()
+        };
+        let statements = export(&options, Some(("public", "items")));
+        assert_eq!(
+            statements.lines().collect::<Vec<_>>(),
+            vec![
+                "INSERT INTO \"public\".\"items\" (\"id\", \"name\", \"price\") VALUES (1, 'say \"hi\", bye', -2.50);",
+                "INSERT INTO \"public\".\"items\" (\"id\", \"name\", \"price\") VALUES (2, '', NULL);",
+                "INSERT INTO \"public\".\"items\" (\"id\", \"name\", \"price\") VALUES (3, 'it''s', 'NaN');",
+            ]
+        );
+        let options = ExportOptions {
+            table_name: Some(String::from("Copy")),
+            ..options
+        };
+        assert!(export(&options, Some(("public", "items"))).starts_with("INSERT INTO \"Copy\" ("));
+    }
+
+    #[test]
+    fn plain_decimals() {
+        for number in ["0", "-12", "3.25", "100.0"] {
+            assert!(is_plain_decimal(number), "{}", number);
+        }
+        for other in ["", "-", "1.", ".5", "1e5", "NaN", "Infinity", "+1", "1.2.3"] {
+            assert!(!is_plain_decimal(other), "{}", other);
+        }
+    }
+}
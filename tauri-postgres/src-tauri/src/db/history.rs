@@ -0,0 +1,452 @@
+//! History of the queries run, stored in a SQLite file in the app's data directory
+//!
+//! The db task records every query with its outcome. Passwords are removed
+//! before a query is stored, a query run again from the history therefore
+//! may need the connection given anew.
+
+use rusqlite::{params, OptionalExtension, Row};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::conninfo;
+use super::types::{Connection, DatabaseQueryResult, FullQuery, Query, SomeDatabase};
+
+const HISTORY_FILE: &str = "history.sqlite3";
+
+/// Oldest entries are removed beyond this number
+const MAX_ENTRIES: i64 = 10_000;
+
+/// Entries listed if the filter has no limit
+const DEFAULT_LIMIT: u32 = 200;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS history (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        executed_at INTEGER NOT NULL,
+        profile TEXT,
+        connection TEXT NOT NULL,
+        database TEXT,
+        kind TEXT NOT NULL,
+        sql TEXT NOT NULL,
+        query TEXT NOT NULL,
+        duration_ms INTEGER NOT NULL,
+        rows INTEGER,
+        error TEXT
+    );
+";
+
+const COLUMNS: &str =
+    "id, executed_at, profile, connection, database, kind, sql, query, duration_ms, rows, error";
+
+#[derive(Debug, Serialize, Clone)]
+pub struct HistoryEntry {
+    pub id: i64,
+    /// Milliseconds since the unix epoch
+    pub executed_at: i64,
+    pub profile: Option<String>,
+    /// Keyword/value string, without password
+    pub connection: String,
+    pub database: Option<String>,
+    /// Variant of the query, e.g. "CustomQuery" or "GetTables"
+    pub kind: String,
+    pub sql: String,
+    /// Query as run, without password
+    pub query: FullQuery,
+    pub duration_ms: u64,
+    /// Rows returned or affected, unknown if not all were read
+    pub rows: Option<u64>,
+    /// Message of the error, none if the query succeeded
+    pub error: Option<String>,
+}
+
+/// Which entries to list, all conditions given must hold
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct HistoryFilter {
+    /// Part of the SQL, ignoring case
+    pub search: Option<String>,
+    pub profile: Option<String>,
+    pub database: Option<String>,
+    /// Only successful (true) or failed (false) queries
+    pub succeeded: Option<bool>,
+    /// Only custom queries and explains, no navigation
+    pub custom_only: bool,
+    pub limit: Option<u32>,
+    pub offset: u32,
+}
+
+/// What is recorded of a query, taken before its result is handed on
+pub struct HistoryRecord {
+    executed_at: SystemTime,
+    duration: Duration,
+    /// Without password
+    query: FullQuery,
+    database: Option<SomeDatabase>,
+    sql: String,
+    rows: Option<u64>,
+    error: Option<String>,
+}
+
+impl HistoryRecord {
+    pub fn new(
+        query: &FullQuery,
+        result: &DatabaseQueryResult,
+        executed_at: SystemTime,
+        duration: Duration,
+    ) -> HistoryRecord {
+        HistoryRecord {
+            executed_at,
+            duration,
+            query: without_password(query),
+            database: result.database.clone(),
+            sql: result.sql_query.clone(),
+            rows: row_count(result),
+            error: result.table.as_ref().err().map(|e| e.to_string()),
+        }
+    }
+}
+
+/// Store of the history, nothing is recorded until it is opened
+#[derive(Default)]
+pub struct History {
+    db: Mutex<Option<rusqlite::Connection>>,
+}
+
+impl History {
+    /// Open (or create) the history file in the directory
+    pub fn open(&self, dir: &Path) -> Result<(), String> {
+        let path = dir.join(HISTORY_FILE);
+        let open_error = |e: &dyn std::fmt::Display| {
+            format!("could not open history \"{}\": {}", path.display(), e)
+        };
+        fs::create_dir_all(dir).map_err(|e| open_error(&e))?;
+        let db = rusqlite::Connection::open(&path).map_err(|e| open_error(&e))?;
+        self.attach(db).map_err(|e| open_error(&e))
+    }
+
+    fn attach(&self, db: rusqlite::Connection) -> Result<(), String> {
+        db.execute_batch(SCHEMA).map_err(history_error)?;
+        *self.db.lock().unwrap() = Some(db);
+        Ok(())
+    }
+
+    fn with_db<T>(
+        &self,
+        f: impl FnOnce(&rusqlite::Connection) -> rusqlite::Result<T>,
+    ) -> Result<T, String> {
+        match self.db.lock().unwrap().as_ref() {
+            Some(db) => f(db).map_err(history_error),
+            None => Err(String::from("history is not available")),
+        }
+    }
+
+    /// Add query that was run
+    pub fn record(&self, record: &HistoryRecord) -> Result<i64, String> {
+        let stored = &record.query;
+        let connection = match stored.connection.to_params() {
+            Ok(params) => conninfo::to_keyword_string(&params),
+            Err(_) => String::new(),
+        };
+        let query_json = serde_json::to_string(stored)
+            .map_err(|e| format!("could not serialize query: {}", e))?;
+        self.with_db(|db| {
+            db.execute(
+                "INSERT INTO history
+                     (executed_at, profile, connection, database, kind, sql, query, duration_ms, rows, error)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+                params![
+                    millis_since_epoch(record.executed_at),
+                    stored.profile,
+                    connection,
+                    record.database,
+                    kind(&stored.query),
+                    record.sql,
+                    query_json,
+                    record.duration.as_millis() as i64,
+                    record.rows.map(|rows| rows as i64),
+                    record.error,
+                ],
+            )?;
+            let id = db.last_insert_rowid();
+            db.execute("DELETE FROM history WHERE id <= ?1", params![id - MAX_ENTRIES])?;
+            Ok(id)
+        })
+    }
+
+    /// Entries matching the filter, most recent first
+    pub fn list(&self, filter: &HistoryFilter) -> Result<Vec<HistoryEntry>, String> {
+        let sql = format!(
+            "SELECT {} FROM history
+             WHERE (?1 IS NULL OR instr(lower(sql), lower(?1)) > 0)
+               AND (?2 IS NULL OR profile = ?2)
+               AND (?3 IS NULL OR database = ?3)
+               AND (?4 IS NULL OR (error IS NULL) = ?4)
+               AND (NOT ?5 OR kind IN ('CustomQuery', 'Explain'))
+             ORDER BY id DESC LIMIT ?6 OFFSET ?7",
+            COLUMNS
+        );
+        self.with_db(|db| {
+            let mut statement = db.prepare(&sql)?;
+            let rows = statement.query_map(
+                params![
+                    filter.search,
+                    filter.profile,
+                    filter.database,
+                    filter.succeeded,
+                    filter.custom_only,
+                    filter.limit.unwrap_or(DEFAULT_LIMIT),
+                    filter.offset,
+                ],
+                entry_from_row,
+            )?;
+            rows.collect()
+        })
+    }
+
+    pub fn get(&self, id: i64) -> Result<HistoryEntry, String> {
+        let sql = format!("SELECT {} FROM history WHERE id = ?1", COLUMNS);
+        self.with_db(|db| db.query_row(&sql, params![id], entry_from_row).optional())?
+            .ok_or_else(|| format!("no history entry {}", id))
+    }
+
+    /// Remove entries, returns how many there were
+    pub fn delete(&self, ids: &[i64]) -> Result<usize, String> {
+        self.with_db(|db| {
+            let mut statement = db.prepare("DELETE FROM history WHERE id = ?1")?;
+            ids.iter().map(|id| statement.execute(params![id])).sum()
+        })
+    }
+
+    /// Remove all entries, returns how many there were
+    pub fn clear(&self) -> Result<usize, String> {
+        self.with_db(|db| db.execute("DELETE FROM history", []))
+    }
+}
+
+pub fn history_dir(app: &tauri::AppHandle) -> Result<PathBuf, String> {
+    app.path_resolver()
+        .app_data_dir()
+        .ok_or_else(|| String::from("no data directory available"))
+}
+
+fn history_error(e: rusqlite::Error) -> String {
+    format!("history: {}", e)
+}
+
+fn entry_from_row(row: &Row) -> rusqlite::Result<HistoryEntry> {
+    let query_json: String = row.get(7)?;
+    let query = serde_json::from_str(&query_json).map_err(|e| {
+        rusqlite::Error::FromSqlConversionFailure(7, rusqlite::types::Type::Text, Box::new(e))
+    })?;
+    Ok(HistoryEntry {
+        id: row.get(0)?,
+        executed_at: row.get(1)?,
+        profile: row.get(2)?,
+        connection: row.get(3)?,
+        database: row.get(4)?,
+        kind: row.get(5)?,
+        sql: row.get(6)?,
+        query,
+        duration_ms: row.get::<_, i64>(8)? as u64,
+        rows: row.get::<_, Option<i64>>(9)?.map(|rows| rows as u64),
+        error: row.get(10)?,
+    })
+}
+
+/// Copy of the query that is fit to be stored
+///
+/// A connection string that cannot be parsed is dropped, as it might
+/// contain a password.
+fn without_password(query: &FullQuery) -> FullQuery {
+    let mut stored = query.clone();
+    stored.connection = match query.connection.to_params() {
+        Ok(mut params) => {
+            params.password = None;
+            Connection::Params(params)
+        }
+        Err(_) => Connection::Stateless(String::new()),
+    };
+    stored
+}
+
+fn kind(query: &Query) -> &'static str {
+    match query {
+        Query::CustomQuery(_) => "CustomQuery",
+        Query::Explain(_) => "Explain",
+        Query::GetDatabases => "GetDatabases",
+        Query::GetSchemas(_) => "GetSchemas",
+        Query::GetTables(_) => "GetTables",
+        Query::GetTableContents(_) => "GetTableContents",
+        Query::DescribeTable(_) => "DescribeTable",
+    }
+}
+
+fn row_count(result: &DatabaseQueryResult) -> Option<u64> {
+    match &result.table {
+        Ok(table) if result.rows_affected.is_none() && result.returns_rows && !result.truncated => {
+            Some(table.fields.len() as u64)
+        }
+        Ok(_) => result.rows_affected,
+        Err(_) => None,
+    }
+}
+
+fn millis_since_epoch(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::types::{ConnectionParams, CustomQuery, DatabaseError, QueryLimits, TypedTable};
+
+    fn full_query(sql: &str, profile: Option<&str>) -> FullQuery {
+        FullQuery {
+            connection: Connection::Stateless(String::from(
+                "host=localhost user=me password=secret",
+            )),
+            query: Query::CustomQuery(CustomQuery {
+                database: Some(String::from("shop")),
+                sql_query: String::from(sql),
+                params: Vec::new(),
+            }),
+            limits: QueryLimits::default(),
+            profile: profile.map(String::from),
+        }
+    }
+
+    fn result(sql: &str, table: Result<TypedTable, DatabaseError>) -> DatabaseQueryResult {
+        DatabaseQueryResult {
+            database: Some(String::from("shop")),
+            sql_query: String::from(sql),
+            returns_rows: table
+                .as_ref()
+                .map_or(false, |table| !table.columns.is_empty()),
+            table,
+            row_estimate: None,
+            truncated: false,
+            tls: None,
+            details: None,
+            rows_affected: None,
+            command_tag: None,
+        }
+    }
+
+    fn two_rows() -> TypedTable {
+        TypedTable {
+            columns: vec![String::from("n")],
+            column_types: Vec::new(),
+            fields: vec![Vec::new(), Vec::new()],
+        }
+    }
+
+    fn history() -> History {
+        let history = History::default();
+        history
+            .attach(rusqlite::Connection::open_in_memory().unwrap())
+            .unwrap();
+        history
+    }
+
+    fn record(
+        history: &History,
+        sql: &str,
+        profile: Option<&str>,
+        table: Result<TypedTable, DatabaseError>,
+    ) -> i64 {
+        history
+            .record(&HistoryRecord::new(
+                &full_query(sql, profile),
+                &result(sql, table),
+                SystemTime::now(),
+                Duration::from_millis(12),
+            ))
+            .unwrap()
+    }
+
+    #[test]
+    fn records_without_password() {
+        let history = history();
+        let id = record(&history, "SELECT n FROM t", Some("local"), Ok(two_rows()));
+        let entry = history.get(id).unwrap();
+        assert_eq!(entry.kind, "CustomQuery");
+        assert_eq!(entry.rows, Some(2));
+        assert_eq!(entry.duration_ms, 12);
+        assert_eq!(entry.error, None);
+        assert!(!entry.connection.contains("secret"));
+        match entry.query.connection {
+            Connection::Params(params) => assert_eq!(
+                params,
+                ConnectionParams {
+                    host: Some(String::from("localhost")),
+                    user: Some(String::from("me")),
+                    ..ConnectionParams::default()
+                }
+            ),
+            other => panic!("unexpected connection {:?}", other),
+        }
+    }
+
+    #[test]
+    fn filters_entries() {
+        let history = history();
+        record(
+            &history,
+            "SELECT n FROM orders",
+            Some("local"),
+            Ok(two_rows()),
+        );
+        let failed = DatabaseError::Execute(Default::default());
+        record(&history, "SELECT x FROM Orders", Some("prod"), Err(failed));
+        record(&history, "SELECT n FROM customers", None, Ok(two_rows()));
+
+        let sqls = |filter: HistoryFilter| -> Vec<String> {
+            history
+                .list(&filter)
+                .unwrap()
+                .into_iter()
+                .map(|entry| entry.sql)
+                .collect()
+        };
+        assert_eq!(sqls(HistoryFilter::default()).len(), 3);
+        let search = |text: &str| HistoryFilter {
+            search: Some(String::from(text)),
+            ..HistoryFilter::default()
+        };
+        assert_eq!(
+            sqls(search("ORDERS")),
+            ["SELECT x FROM Orders", "SELECT n FROM orders"]
+        );
+        let failed_only = HistoryFilter {
+            succeeded: Some(false),
+            ..HistoryFilter::default()
+        };
+        assert_eq!(sqls(failed_only), ["SELECT x FROM Orders"]);
+        let local = HistoryFilter {
+            profile: Some(String::from("local")),
+            ..HistoryFilter::default()
+        };
+        assert_eq!(sqls(local), ["SELECT n FROM orders"]);
+        let page = HistoryFilter {
+            limit: Some(1),
+            offset: 1,
+            ..HistoryFilter::default()
+        };
+        assert_eq!(sqls(page), ["SELECT x FROM Orders"]);
+    }
+
+    #[test]
+    fn deletes_entries() {
+        let history = history();
+        let first = record(&history, "SELECT 1", None, Ok(two_rows()));
+        record(&history, "SELECT 2", None, Ok(two_rows()));
+        assert_eq!(history.delete(&[first, first + 100]).unwrap(), 1);
+        assert!(history.get(first).is_err());
+        assert_eq!(history.clear().unwrap(), 1);
+        assert!(history.list(&HistoryFilter::default()).unwrap().is_empty());
+    }
+}
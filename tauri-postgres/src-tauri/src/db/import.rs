@@ -0,0 +1,793 @@
+//! Import of CSV files into a table with COPY FROM STDIN
+//!
+//! A preview guesses encoding (UTF-8, else Latin-1), delimiter and whether
+//! the first row is a header from the start of the file. The import then
+//! reads the file record by record (RFC 4180, quoted fields may span lines)
+//! and sends the mapped columns to the server as CSV in UTF-8, so the
+//! server sees neither the file's encoding nor its skipped columns.
+//!
+//! As with COPY, an unquoted field equal to the NULL text is NULL, a quoted
+//! one is the text itself. Empty lines are skipped. Rows with the wrong
+//! number of fields or invalid characters are bad rows: they end the import
+//! unless bad rows are skipped, then they are reported. A value the server
+//! rejects always ends the import, nothing of it is kept then.
+
+use futures_util::{pin_mut, SinkExt};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+use tokio::sync::mpsc;
+use tokio_postgres::Client;
+
+use super::sql;
+use super::types::{Connection, DatabaseError, DatabaseTable, ErrorDetails};
+
+/// Data rows shown by the preview, unless asked for more
+pub const PREVIEW_ROWS: usize = 10;
+/// Bytes read from the start of the file for the preview
+const SAMPLE_SIZE: u64 = 64 * 1024;
+/// Records looked at to guess the delimiter
+const SAMPLE_RECORDS: usize = 50;
+/// Bytes of CSV collected before they are sent to the server, progress is reported after each
+const COPY_CHUNK_SIZE: usize = 256 * 1024;
+/// Bad rows reported in detail, the rest is only counted
+const MAX_REPORTED_BAD_ROWS: usize = 100;
+/// Chunks read ahead of the ones sent to the server
+const CHUNKS_IN_FLIGHT: usize = 2;
+
+const DELIMITERS: [char; 4] = [',', ';', '\t', '|'];
+const UTF8_BOM: &[u8] = b"\xef\xbb\xbf";
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Utf8,
+    /// ISO 8859-1, each byte is a character
+    Latin1,
+}
+
+/// What the start of a file tells about it
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ImportPreview {
+    pub encoding: Encoding,
+    pub delimiter: char,
+    /// Guessed: the first row has distinct texts that are not numbers
+    pub header: bool,
+    /// From the header, column1, column2 ... without one
+    pub columns: Vec<String>,
+    /// First data rows as in the file, NULL is not told apart yet
+    pub rows: Vec<Vec<String>>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct ImportOptions {
+    pub encoding: Encoding,
+    pub delimiter: char,
+    /// First row holds column names and is not imported
+    pub header: bool,
+    /// Unquoted fields with this text are NULL
+    pub null_text: String,
+    /// Skip and report bad rows instead of ending the import
+    pub skip_bad_rows: bool,
+}
+
+impl Default for ImportOptions {
+    fn default() -> Self {
+        ImportOptions {
+            encoding: Encoding::Utf8,
+            delimiter: ',',
+            header: true,
+            null_text: String::new(),
+            skip_bad_rows: false,
+        }
+    }
+}
+
+/// Import of a file into a table, as sent by the frontend
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct TableImport {
+    pub connection: Connection,
+    /// Pagination is ignored
+    pub table: DatabaseTable,
+    pub path: String,
+    #[serde(default)]
+    pub options: ImportOptions,
+    /// Table column for each column of the file, None skips it
+    pub mapping: Vec<Option<String>>,
+}
+
+/// Row of the file that could not be imported
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct BadRow {
+    /// Data row, counted from 1 without the header
+    pub row: u64,
+    /// Line of the file the row starts on
+    pub line: u64,
+    pub reason: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ImportResult {
+    /// Rows imported
+    pub rows: u64,
+    /// The first bad rows that were skipped
+    pub bad_rows: Vec<BadRow>,
+    pub bad_row_count: u64,
+}
+
+/// State of a running import, as sent to the frontend
+#[derive(Debug, Serialize, Clone)]
+pub struct ImportProgress {
+    pub import_id: String,
+    pub rows: u64,
+    /// Bytes of the file read so far
+    pub bytes: u64,
+    pub total_bytes: u64,
+}
+
+#[derive(Debug, PartialEq)]
+struct Field {
+    text: String,
+    quoted: bool,
+}
+
+/// Splits the lines of a record into fields
+struct FieldParser {
+    delimiter: char,
+    fields: Vec<Field>,
+    current: String,
+    quoted: bool,
+    in_quotes: bool,
+}
+
+impl FieldParser {
+    fn new(delimiter: char) -> FieldParser {
+        FieldParser {
+            delimiter,
+            fields: Vec::new(),
+            current: String::new(),
+            quoted: false,
+            in_quotes: false,
+        }
+    }
+
+    fn end_field(&mut self) {
+        self.fields.push(Field {
+            text: std::mem::take(&mut self.current),
+            quoted: self.quoted,
+        });
+        self.quoted = false;
+    }
+
+    /// Parse line (with its line break), true if the record is complete
+    fn feed(&mut self, line: &str) -> bool {
+        let mut chars = line.chars().peekable();
+        while let Some(c) = chars.next() {
+            if self.in_quotes {
+                if c != '"' {
+                    self.current.push(c);
+                } else if chars.peek() == Some(&'"') {
+                    chars.next();
+                    self.current.push('"');
+                } else {
+                    self.in_quotes = false;
+                }
+            } else if c == self.delimiter {
+                self.end_field();
+            } else if c == '"' && self.current.is_empty() && !self.quoted {
+                self.in_quotes = true;
+                self.quoted = true;
+            } else if c == '\n' {
+                self.end_field();
+                return true;
+            } else if c != '\r' || chars.peek() != Some(&'\n') {
+                self.current.push(c);
+            }
+        }
+        // last line of the file, without line break
+        if self.in_quotes {
+            false
+        } else {
+            self.end_field();
+            true
+        }
+    }
+}
+
+enum ReadOutcome {
+    Record { line: u64, fields: Vec<Field> },
+    Bad { line: u64, reason: String },
+    End,
+}
+
+/// Reads records from CSV text
+struct CsvReader<R> {
+    input: R,
+    encoding: Encoding,
+    delimiter: char,
+    /// Lines read so far
+    line: u64,
+    /// Bytes read so far
+    bytes: u64,
+}
+
+impl<R: BufRead> CsvReader<R> {
+    fn new(input: R, encoding: Encoding, delimiter: char) -> CsvReader<R> {
+        CsvReader {
+            input,
+            encoding,
+            delimiter,
+            line: 0,
+            bytes: 0,
+        }
+    }
+
+    fn read_line(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let mut line = Vec::new();
+        let read = self.input.read_until(b'\n', &mut line)?;
+        if read == 0 {
+            return Ok(None);
+        }
+        self.bytes += read as u64;
+        self.line += 1;
+        if self.line == 1 && line.starts_with(UTF8_BOM) {
+            line.drain(..UTF8_BOM.len());
+        }
+        Ok(Some(line))
+    }
+
+    fn decode(&self, line: Vec<u8>) -> Option<String> {
+        match self.encoding {
+            Encoding::Utf8 => String::from_utf8(line).ok(),
+            Encoding::Latin1 => Some(line.iter().map(|b| *b as char).collect()),
+        }
+    }
+
+    fn read_record(&mut self) -> io::Result<ReadOutcome> {
+        let mut line = loop {
+            match self.read_line()? {
+                None => return Ok(ReadOutcome::End),
+                Some(line) if line == b"\n" || line == b"\r\n" => continue,
+                Some(line) => break line,
+            }
+        };
+        let start = self.line;
+        let mut parser = FieldParser::new(self.delimiter);
+        loop {
+            let text = match self.decode(line) {
+                Some(text) => text,
+                None => {
+                    return Ok(ReadOutcome::Bad {
+                        line: start,
+                        reason: String::from("invalid UTF-8"),
+                    })
+                }
+            };
+            if parser.feed(&text) {
+                return Ok(ReadOutcome::Record {
+                    line: start,
+                    fields: parser.fields,
+                });
+            }
+            line = match self.read_line()? {
+                Some(line) => line,
+                None => {
+                    return Ok(ReadOutcome::Bad {
+                        line: start,
+                        reason: String::from("quoted field is not closed"),
+                    })
+                }
+            };
+        }
+    }
+}
+
+/// UTF-8 if the sample is valid UTF-8 (it may end within a character), else Latin-1
+fn detect_encoding(sample: &[u8]) -> Result<Encoding, String> {
+    if sample.starts_with(b"\xff\xfe") || sample.starts_with(b"\xfe\xff") {
+        return Err(String::from("UTF-16 files are not supported"));
+    }
+    match std::str::from_utf8(sample) {
+        Ok(_) => Ok(Encoding::Utf8),
+        Err(e) if e.error_len().is_none() => Ok(Encoding::Utf8),
+        Err(_) => Ok(Encoding::Latin1),
+    }
+}
+
+/// Records of the sample, bad ones left out
+fn sample_records(
+    sample: &[u8],
+    encoding: Encoding,
+    delimiter: char,
+    limit: usize,
+) -> Vec<Vec<Field>> {
+    let mut reader = CsvReader::new(sample, encoding, delimiter);
+    let mut records = Vec::new();
+    while records.len() < limit {
+        match reader.read_record() {
+            Ok(ReadOutcome::Record { fields, .. }) => records.push(fields),
+            Ok(ReadOutcome::Bad { .. }) => continue,
+            Ok(ReadOutcome::End) | Err(_) => break,
+        }
+    }
+    records
+}
+
+/// Delimiter that splits all records into the same number (more than one) of fields
+///
+/// If none does, the one giving the first record the most fields.
+fn detect_delimiter(sample: &[u8], encoding: Encoding) -> char {
+    let counts: Vec<(char, Vec<usize>)> = DELIMITERS
+        .iter()
+        .map(|delimiter| {
+            let records = sample_records(sample, encoding, *delimiter, SAMPLE_RECORDS);
+            (
+                *delimiter,
+                records.iter().map(|fields| fields.len()).collect(),
+            )
+        })
+        .collect();
+    let consistent = counts
+        .iter()
+        .filter(|(_, counts)| {
+            counts.first().map_or(false, |first| {
+                *first > 1 && counts.iter().all(|n| n == first)
+            })
+        })
+        .max_by_key(|(delimiter, counts)| {
+            (
+                counts[0],
+                std::cmp::Reverse(DELIMITERS.iter().position(|d| d == delimiter)),
+            )
+        });
+    let most_fields = counts
+        .iter()
+        .filter(|(_, counts)| counts.first().map_or(false, |first| *first > 1))
+        .max_by_key(|(delimiter, counts)| {
+            (
+                counts[0],
+                std::cmp::Reverse(DELIMITERS.iter().position(|d| d == delimiter)),
+            )
+        });
+    consistent
+        .or(most_fields)
+        .map_or(',', |(delimiter, _)| *delimiter)
+}
+
+/// Header if all fields of the first row are distinct, not empty and not numbers
+fn looks_like_header(first: &[Field]) -> bool {
+    first.iter().enumerate().all(|(index, field)| {
+        let text = field.text.trim();
+        !text.is_empty()
+            && text.parse::<f64>().is_err()
+            && first[..index].iter().all(|other| other.text != field.text)
+    })
+}
+
+/// Guess how the file is to be read, with its first rows
+pub fn preview_file(path: &str, rows: usize) -> Result<ImportPreview, String> {
+    let mut sample = Vec::new();
+    File::open(path)
+        .and_then(|file| file.take(SAMPLE_SIZE).read_to_end(&mut sample))
+        .map_err(|e| format!("Could not read {}: {}", path, e))?;
+    let encoding = detect_encoding(&sample)?;
+    // the last record may be cut off
+    if sample.len() as u64 == SAMPLE_SIZE {
+        let end = sample
+            .iter()
+            .rposition(|b| *b == b'\n')
+            .map_or(0, |newline| newline + 1);
+        sample.truncate(end);
+    }
+    let delimiter = detect_delimiter(&sample, encoding);
+    let mut records = sample_records(&sample, encoding, delimiter, rows + 1).into_iter();
+    let first = records.next().unwrap_or_default();
+    let header = looks_like_header(&first);
+    let columns = if header {
+        first.iter().map(|field| field.text.clone()).collect()
+    } else {
+        (1..=first.len())
+            .map(|number| format!("column{}", number))
+            .collect()
+    };
+    let data = if header || first.is_empty() {
+        None
+    } else {
+        Some(first)
+    };
+    let rows = data
+        .into_iter()
+        .chain(records)
+        .take(rows)
+        .map(|fields| fields.into_iter().map(|field| field.text).collect())
+        .collect();
+    Ok(ImportPreview {
+        encoding,
+        delimiter,
+        header,
+        columns,
+        rows,
+    })
+}
+
+/// Append mapped fields as CSV line for COPY: NULL unquoted and empty, every value quoted
+fn append_copy_line(out: &mut Vec<u8>, fields: &[Field], columns: &[usize], null_text: &str) {
+    for (position, index) in columns.iter().enumerate() {
+        if position > 0 {
+            out.push(b',');
+        }
+        let field = &fields[*index];
+        if field.quoted || field.text != null_text {
+            out.push(b'"');
+            out.extend_from_slice(field.text.replace('"', "\"\"").as_bytes());
+            out.push(b'"');
+        }
+    }
+    out.push(b'\n');
+}
+
+/// Data row of the file for a row sent to the server, given the bad rows left out
+fn data_row(sent_row: u64, skipped_rows: &[u64]) -> u64 {
+    skipped_rows.iter().fold(
+        sent_row,
+        |row, skipped| if *skipped <= row { row + 1 } else { row },
+    )
+}
+
+/// Error of the server, naming the data row it reports (as "COPY t, line 3, column c: ...")
+fn copy_error(e: tokio_postgres::Error, skipped_rows: &[u64]) -> DatabaseError {
+    let mut details = ErrorDetails::from(&e);
+    let sent_row = e
+        .as_db_error()
+        .and_then(|db_error| db_error.where_())
+        .and_then(|context| {
+            context
+                .split(", ")
+                .find_map(|part| part.strip_prefix("line "))
+        })
+        .and_then(|line| line.split(|c: char| !c.is_ascii_digit()).next())
+        .and_then(|line| line.parse::<u64>().ok());
+    if let Some(sent_row) = sent_row {
+        details.message = format!(
+            "{} (data row {} of the file)",
+            details.message,
+            data_row(sent_row, skipped_rows)
+        );
+    }
+    DatabaseError::Execute(details)
+}
+
+fn import_error(message: String) -> DatabaseError {
+    DatabaseError::Execute(ErrorDetails::from_message(message))
+}
+
+/// COPY statement for the mapped columns, with indexes of the file columns to send
+fn copy_statement(import: &TableImport) -> Result<(String, Vec<usize>), DatabaseError> {
+    let mapped: Vec<(usize, &String)> = import
+        .mapping
+        .iter()
+        .enumerate()
+        .filter_map(|(index, column)| column.as_ref().map(|column| (index, column)))
+        .collect();
+    if mapped.is_empty() {
+        return Err(import_error(String::from(
+            "no column of the file is mapped to the table",
+        )));
+    }
+    let names: Vec<String> = mapped
+        .iter()
+        .map(|(_, column)| sql::quote_identifier(column))
+        .collect();
+    let statement = format!(
+        "COPY {} ({}) FROM STDIN WITH (FORMAT csv)",
+        sql::qualified_name(&import.table.schema, &import.table.table),
+        names.join(", ")
+    );
+    Ok((statement, mapped.iter().map(|(index, _)| *index).collect()))
+}
+
+/// Rows of the file as COPY data, read on a blocking thread
+struct Chunk {
+    data: Vec<u8>,
+    /// Rows in `data`
+    rows: u64,
+    /// Data rows left out since the previous chunk
+    skipped_rows: Vec<u64>,
+    /// The skipped rows that are reported
+    bad_rows: Vec<BadRow>,
+    /// Bytes of the file read so far
+    bytes: u64,
+}
+
+impl Chunk {
+    fn new() -> Chunk {
+        Chunk {
+            data: Vec::with_capacity(COPY_CHUNK_SIZE),
+            rows: 0,
+            skipped_rows: Vec::new(),
+            bad_rows: Vec::new(),
+            bytes: 0,
+        }
+    }
+}
+
+/// Read the file, sending each full chunk and the rest at the end
+///
+/// Reading stops as soon as the receiver is gone, the import has ended then.
+fn read_chunks(
+    import: &TableImport,
+    columns: &[usize],
+    chunks: &mpsc::Sender<Result<Chunk, DatabaseError>>,
+) -> Result<(), DatabaseError> {
+    let options = &import.options;
+    let read_error = |e: io::Error| import_error(format!("Could not read {}: {}", import.path, e));
+    let file = File::open(&import.path).map_err(read_error)?;
+    let mut reader = CsvReader::new(BufReader::new(file), options.encoding, options.delimiter);
+
+    let mut chunk = Chunk::new();
+    let mut data_rows = 0;
+    let mut reported_bad_rows = 0;
+    let mut header = options.header;
+    loop {
+        let (line, bad_reason) = match reader.read_record().map_err(read_error)? {
+            ReadOutcome::End => break,
+            ReadOutcome::Record { .. } | ReadOutcome::Bad { .. } if header => {
+                header = false;
+                continue;
+            }
+            ReadOutcome::Record { line, fields } if fields.len() == import.mapping.len() => {
+                append_copy_line(&mut chunk.data, &fields, columns, &options.null_text);
+                (line, None)
+            }
+            ReadOutcome::Record { line, fields } => (
+                line,
+                Some(format!(
+                    "{} fields instead of {}",
+                    fields.len(),
+                    import.mapping.len()
+                )),
+            ),
+            ReadOutcome::Bad { line, reason } => (line, Some(reason)),
+        };
+        data_rows += 1;
+        match bad_reason {
+            None => chunk.rows += 1,
+            Some(reason) if options.skip_bad_rows => {
+                chunk.skipped_rows.push(data_rows);
+                if reported_bad_rows < MAX_REPORTED_BAD_ROWS {
+                    reported_bad_rows += 1;
+                    chunk.bad_rows.push(BadRow {
+                        row: data_rows,
+                        line,
+                        reason,
+                    });
+                }
+            }
+            Some(reason) => {
+                return Err(import_error(format!(
+                    "data row {} (line {}) of the file: {}",
+                    data_rows, line, reason
+                )))
+            }
+        }
+        if chunk.data.len() >= COPY_CHUNK_SIZE {
+            chunk.bytes = reader.bytes;
+            if chunks
+                .blocking_send(Ok(std::mem::replace(&mut chunk, Chunk::new())))
+                .is_err()
+            {
+                return Ok(());
+            }
+        }
+    }
+    chunk.bytes = reader.bytes;
+    // the receiver being gone is no error here either
+    let _ = chunks.blocking_send(Ok(chunk));
+    Ok(())
+}
+
+/// Load the file into the table
+///
+/// The file is read on a blocking thread, its chunks are sent as they arrive.
+/// Progress is reported after each chunk sent, it is skipped while the receiver is behind.
+/// The server may not notice a cancel request while it waits for data, so `was_cancelled`
+/// is asked after each chunk as well.
+pub async fn import_file(
+    client: &Client,
+    import: &TableImport,
+    import_id: &str,
+    progress: &mpsc::Sender<ImportProgress>,
+    was_cancelled: impl Fn() -> bool,
+) -> Result<ImportResult, DatabaseError> {
+    if matches!(import.options.delimiter, '"' | '\r' | '\n') {
+        return Err(import_error(String::from(
+            "delimiter cannot be a quote or a line break",
+        )));
+    }
+    let (statement, columns) = copy_statement(import)?;
+    let read_error = |e: io::Error| import_error(format!("Could not read {}: {}", import.path, e));
+    let total_bytes = tokio::fs::metadata(&import.path)
+        .await
+        .map_err(read_error)?
+        .len();
+
+    println!("Import: \"{}\" from {}", statement, import.path);
+    let sink = client
+        .copy_in(statement.as_str())
+        .await
+        .map_err(|e| copy_error(e, &[]))?;
+    pin_mut!(sink);
+
+    let (chunk_tx, mut chunk_rx) = mpsc::channel(CHUNKS_IN_FLIGHT);
+    let reading = {
+        let import = import.clone();
+        tokio::task::spawn_blocking(move || {
+            if let Err(e) = read_chunks(&import, &columns, &chunk_tx) {
+                let _ = chunk_tx.blocking_send(Err(e));
+            }
+        })
+    };
+
+    let mut sent_rows = 0;
+    let mut bytes_read = 0;
+    let mut skipped_rows = Vec::new();
+    let mut bad_rows = Vec::new();
+    // returning early drops the sink, which aborts the COPY, and the receiver, which stops reading
+    while let Some(chunk) = chunk_rx.recv().await {
+        let chunk = chunk?;
+        sent_rows += chunk.rows;
+        bytes_read = chunk.bytes;
+        skipped_rows.extend(chunk.skipped_rows);
+        bad_rows.extend(chunk.bad_rows);
+        if chunk.data.is_empty() {
+            continue;
+        }
+        sink.send(bytes::Bytes::from(chunk.data))
+            .await
+            .map_err(|e| copy_error(e, &skipped_rows))?;
+        if was_cancelled() {
+            return Err(DatabaseError::Cancelled(ErrorDetails::from_message(
+                String::from("import was cancelled"),
+            )));
+        }
+        // a full channel only means the frontend has not shown the last state yet
+        let _ = progress.try_send(ImportProgress {
+            import_id: String::from(import_id),
+            rows: sent_rows,
+            bytes: bytes_read,
+            total_bytes,
+        });
+    }
+    // a reader that panicked ends the channel as well, the COPY must not be finished then
+    reading
+        .await
+        .map_err(|e| import_error(format!("Could not read {}: {}", import.path, e)))?;
+    let rows = sink
+        .as_mut()
+        .finish()
+        .await
+        .map_err(|e| copy_error(e, &skipped_rows))?;
+    let _ = progress.try_send(ImportProgress {
+        import_id: String::from(import_id),
+        rows,
+        bytes: bytes_read,
+        total_bytes,
+    });
+    println!(
+        "Imported {} rows, {} bad rows skipped",
+        rows,
+        skipped_rows.len()
+    );
+    Ok(ImportResult {
+        rows,
+        bad_rows,
+        bad_row_count: skipped_rows.len() as u64,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Records with the line they start on, bad rows by their line only
+    fn read_all(text: &[u8], delimiter: char) -> Vec<Result<(u64, Vec<Field>), u64>> {
+        let mut reader = CsvReader::new(text, Encoding::Utf8, delimiter);
+        let mut records = Vec::new();
+        loop {
+            match reader.read_record().unwrap() {
+                ReadOutcome::Record { line, fields } => records.push(Ok((line, fields))),
+                ReadOutcome::Bad { line, .. } => records.push(Err(line)),
+                ReadOutcome::End => return records,
+            }
+        }
+    }
+
+    fn field(text: &str, quoted: bool) -> Field {
+        Field {
+            text: String::from(text),
+            quoted,
+        }
+    }
+
+    #[test]
+    fn records_follow_rfc_4180() {
+        let text = b"\xef\xbb\xbfid;name\r\n1;\"a \"\"quoted\"\"; text\"\r\n\r\n2;\"two\nlines\"\n3;\n4;\"\"\n5;\"open";
+        assert_eq!(
+            read_all(text, ';'),
+            vec![
+                Ok((1, vec![field("id", false), field("name", false)])),
+                Ok((
+                    2,
+                    vec![field("1", false), field("a \"quoted\"; text", true)]
+                )),
+                Ok((4, vec![field("2", false), field("two\nlines", true)])),
+                Ok((6, vec![field("3", false), field("", false)])),
+                Ok((7, vec![field("4", false), field("", true)])),
+                Err(8),
+            ]
+        );
+    }
+
+    #[test]
+    fn latin1_and_invalid_utf8() {
+        let text = b"caf\xe9,1\n";
+        assert_eq!(detect_encoding(text).unwrap(), Encoding::Latin1);
+        assert_eq!(
+            detect_encoding("café,1\n".as_bytes()).unwrap(),
+            Encoding::Utf8
+        );
+        // cut within a character
+        assert_eq!(
+            detect_encoding(&"café".as_bytes()[..4]).unwrap(),
+            Encoding::Utf8
+        );
+        assert!(detect_encoding(b"\xff\xfei\x00d\x00").is_err());
+        assert_eq!(read_all(text, ','), vec![Err(1)]);
+        let mut reader = CsvReader::new(&text[..], Encoding::Latin1, ',');
+        match reader.read_record().unwrap() {
+            ReadOutcome::Record { fields, .. } => assert_eq!(fields[0].text, "café"),
+            _ => panic!("record expected"),
+        }
+    }
+
+    #[test]
+    fn delimiter_and_header_are_guessed() {
+        let semicolons = b"name;amount;note\nBob;1,5;\"a;b\"\nAnn;2;x\n";
+        assert_eq!(detect_delimiter(semicolons, Encoding::Utf8), ';');
+        let tabs = b"1\t2,5\t3\n4\t5\t6\n";
+        assert_eq!(detect_delimiter(tabs, Encoding::Utf8), '\t');
+        assert_eq!(detect_delimiter(b"single\ncolumn\n", Encoding::Utf8), ',');
+
+        let fields = |texts: &[&str]| -> Vec<Field> {
+            texts.iter().map(|text| field(text, false)).collect()
+        };
+        assert!(looks_like_header(&fields(&["id", "name"])));
+        assert!(!looks_like_header(&fields(&["1", "Bob"])));
+        assert!(!looks_like_header(&fields(&["name", ""])));
+        assert!(!looks_like_header(&fields(&["a", "a"])));
+    }
+
+    #[test]
+    fn copy_lines_keep_null_apart() {
+        let fields = vec![
+            field("NULL", false),
+            field("skipped", false),
+            field("NULL", true),
+            field("say \"hi\"", false),
+        ];
+        let mut out = Vec::new();
+        append_copy_line(&mut out, &fields, &[0, 2, 3], "NULL");
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            ",\"NULL\",\"say \"\"hi\"\"\"\n"
+        );
+    }
+
+    #[test]
+    fn sent_rows_map_to_data_rows() {
+        assert_eq!(data_row(3, &[]), 3);
+        assert_eq!(data_row(3, &[1, 5]), 4);
+        assert_eq!(data_row(4, &[1, 5]), 6);
+        assert_eq!(data_row(1, &[1, 2]), 3);
+    }
+}
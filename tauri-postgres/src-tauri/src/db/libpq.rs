@@ -0,0 +1,400 @@
+//! Filling in connection options the way libpq (and thereby psql) does
+//!
+//! Options not given explicitly are taken from, in this order:
+//!
+//! * the service named by `service` (or `PGSERVICE`) in `~/.pg_service.conf`
+//!   (`PGSERVICEFILE`) or `$PGSYSCONFDIR/pg_service.conf`
+//! * the environment variables `PGHOST`, `PGPORT`, `PGUSER`, `PGDATABASE`,
+//!   `PGPASSWORD` and friends
+//! * defaults: host localhost, the user name of the OS user
+//!
+//! A password still missing is then looked up in `~/.pgpass` (`PGPASSFILE`).
+//!
+//! Unlike libpq, the default host is localhost instead of a unix socket.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use super::conninfo;
+use super::types::ConnectionParams;
+
+/// Environment variables and the options they stand for
+const ENVIRONMENT: &[(&str, &str)] = &[
+    ("PGHOST", "host"),
+    ("PGHOSTADDR", "hostaddr"),
+    ("PGPORT", "port"),
+    ("PGUSER", "user"),
+    ("PGPASSWORD", "password"),
+    ("PGDATABASE", "dbname"),
+    ("PGAPPNAME", "application_name"),
+    ("PGOPTIONS", "options"),
+    ("PGSSLMODE", "sslmode"),
+    ("PGSSLROOTCERT", "sslrootcert"),
+    ("PGSSLCERT", "sslcert"),
+    ("PGSSLKEY", "sslkey"),
+    ("PGCONNECT_TIMEOUT", "connect_timeout"),
+    ("PGTARGETSESSIONATTRS", "target_session_attrs"),
+];
+
+const DEFAULT_HOST: &str = "localhost";
+const DEFAULT_PORT: u16 = 5432;
+
+/// Value of an environment variable, empty ones count as not set
+type Environment<'a> = &'a dyn Fn(&str) -> Option<String>;
+
+fn process_environment(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+/// Fill in all options libpq would fill in
+pub fn resolve(params: &mut ConnectionParams) -> Result<(), String> {
+    resolve_with(params, &process_environment)
+}
+
+/// Connection to suggest, from the environment as far as set
+///
+/// The password is left out, it is looked up again when connecting.
+pub fn suggest() -> ConnectionParams {
+    let mut params = ConnectionParams::default();
+    if let Err(message) = apply_environment(&mut params, &process_environment) {
+        println!("Ignoring environment: {}", message);
+    }
+    params.password = None;
+    apply_defaults(&mut params);
+    params
+}
+
+fn resolve_with(params: &mut ConnectionParams, env: Environment) -> Result<(), String> {
+    // tokio-postgres does not know about services
+    let service = params.extras.remove("service").or_else(|| env("PGSERVICE"));
+    if let Some(service) = service {
+        apply_service(params, &service, env)?;
+    }
+    apply_environment(params, env)?;
+    apply_defaults(params);
+    if params.password.is_none() {
+        params.password = lookup_password(params, env);
+    }
+    Ok(())
+}
+
+/// Take options from `defaults` that are not set in `params`
+fn fill_missing(params: &mut ConnectionParams, defaults: ConnectionParams) {
+    params.host = params.host.take().or(defaults.host);
+    params.port = params.port.take().or(defaults.port);
+    params.user = params.user.take().or(defaults.user);
+    params.password = params.password.take().or(defaults.password);
+    params.dbname = params.dbname.take().or(defaults.dbname);
+    params.application_name = params.application_name.take().or(defaults.application_name);
+    params.sslmode = params.sslmode.take().or(defaults.sslmode);
+    params.connect_timeout = params.connect_timeout.or(defaults.connect_timeout);
+    for (key, value) in defaults.extras {
+        params.extras.entry(key).or_insert(value);
+    }
+}
+
+fn apply_environment(params: &mut ConnectionParams, env: Environment) -> Result<(), String> {
+    let pairs = ENVIRONMENT
+        .iter()
+        .filter_map(|(variable, key)| env(variable).map(|value| (String::from(*key), value)))
+        .collect();
+    let from_environment = conninfo::params_from_pairs(pairs)
+        .map_err(|message| format!("environment: {}", message))?;
+    fill_missing(params, from_environment);
+    Ok(())
+}
+
+fn apply_defaults(params: &mut ConnectionParams) {
+    if params.host.is_none() && !params.extras.contains_key("hostaddr") {
+        params.host = Some(String::from(DEFAULT_HOST));
+    }
+    if params.user.is_none() {
+        params.user = Some(whoami::username());
+    }
+}
+
+/// File named by the variable, or else the file in the user's home directory
+///
+/// On windows the file is looked for in %APPDATA%\postgresql instead.
+fn user_file(
+    env: Environment,
+    variable: &str,
+    unix_name: &str,
+    windows_name: &str,
+) -> Option<PathBuf> {
+    if let Some(path) = env(variable) {
+        return Some(PathBuf::from(path));
+    }
+    if cfg!(windows) {
+        env("APPDATA").map(|dir| Path::new(&dir).join("postgresql").join(windows_name))
+    } else {
+        env("HOME").map(|dir| Path::new(&dir).join(unix_name))
+    }
+}
+
+/* Service file */
+
+fn apply_service(
+    params: &mut ConnectionParams,
+    service: &str,
+    env: Environment,
+) -> Result<(), String> {
+    let files = [
+        user_file(env, "PGSERVICEFILE", ".pg_service.conf", ".pg_service.conf"),
+        env("PGSYSCONFDIR").map(|dir| Path::new(&dir).join("pg_service.conf")),
+    ];
+    for path in files.iter().flatten() {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(_) => continue,
+        };
+        let name = path.display().to_string();
+        if let Some(pairs) =
+            find_service(&content, service).map_err(|message| format!("{}: {}", name, message))?
+        {
+            let from_service = conninfo::params_from_pairs(pairs)
+                .map_err(|message| format!("{}: {}", name, message))?;
+            fill_missing(params, from_service);
+            return Ok(());
+        }
+    }
+    Err(format!("definition of service \"{}\" not found", service))
+}
+
+/// Options of the service in a service file, none if it is not defined there
+fn find_service(content: &str, service: &str) -> Result<Option<Vec<(String, String)>>, String> {
+    let mut pairs: Option<Vec<(String, String)>> = None;
+    for (number, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(section) = line.strip_prefix('[') {
+            if pairs.is_some() {
+                // end of the service looked for
+                break;
+            }
+            let name = section
+                .strip_suffix(']')
+                .ok_or_else(|| format!("syntax error in line {}", number + 1))?;
+            if name == service {
+                pairs = Some(Vec::new());
+            }
+        } else if let Some(pairs) = &mut pairs {
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| format!("syntax error in line {}", number + 1))?;
+            let key = key.trim();
+            if key == "service" {
+                return Err(String::from(
+                    "nested service specifications are not supported",
+                ));
+            }
+            pairs.push((String::from(key), String::from(value.trim())));
+        }
+    }
+    Ok(pairs)
+}
+
+/* Password file */
+
+fn lookup_password(params: &ConnectionParams, env: Environment) -> Option<String> {
+    let path = user_file(env, "PGPASSFILE", ".pgpass", "pgpass.conf")?;
+    let content = read_password_file(&path)?;
+    let user = params.user.as_deref().unwrap_or("");
+    let dbname = params.dbname.as_deref().unwrap_or(user);
+    // tokio-postgres takes one password for all hosts, the first host with an entry wins
+    let hosts = params.host.as_deref().unwrap_or(DEFAULT_HOST);
+    hosts.split(',').enumerate().find_map(|(index, host)| {
+        // connections through unix sockets are looked up as localhost
+        let host = if host.is_empty() || host.starts_with('/') {
+            DEFAULT_HOST
+        } else {
+            host
+        };
+        let port = conninfo::port_of_host(params.port.as_deref(), index).unwrap_or(DEFAULT_PORT);
+        find_password(&content, [host, &port.to_string(), dbname, user])
+    })
+}
+
+/// Content of the password file, ignored like libpq does if others may read it
+fn read_password_file(path: &Path) -> Option<String> {
+    let metadata = fs::metadata(path).ok()?;
+    if !metadata.is_file() {
+        return None;
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        if metadata.permissions().mode() & 0o077 != 0 {
+            println!(
+                "Password file \"{}\" has group or world access; permissions should be u=rw (0600) or less",
+                path.display()
+            );
+            return None;
+        }
+    }
+    fs::read_to_string(path).ok()
+}
+
+/// Password of the first line matching host, port, database and user
+///
+/// Lines have the form `host:port:database:user:password`, `*` matches
+/// anything, `\:` and `\\` stand for `:` and `\`.
+fn find_password(content: &str, wanted: [&str; 4]) -> Option<String> {
+    content.lines().find_map(|line| {
+        if line.starts_with('#') {
+            return None;
+        }
+        let fields = split_password_line(line);
+        if fields.len() < 5 {
+            return None;
+        }
+        let matches = wanted
+            .iter()
+            .zip(&fields)
+            .all(|(wanted, field)| field == "*" || field == wanted);
+        if matches {
+            Some(fields[4..].join(":"))
+        } else {
+            None
+        }
+    })
+}
+
+fn split_password_line(line: &str) -> Vec<String> {
+    let mut fields = vec![String::new()];
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                if let Some(escaped) = chars.next() {
+                    fields.last_mut().unwrap().push(escaped);
+                }
+            }
+            ':' => fields.push(String::new()),
+            _ => fields.last_mut().unwrap().push(c),
+        }
+    }
+    fields
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn environment(variables: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let variables: HashMap<String, String> = variables
+            .iter()
+            .map(|(name, value)| (String::from(*name), String::from(*value)))
+            .collect();
+        move |name| variables.get(name).cloned()
+    }
+
+    #[test]
+    fn explicit_options_win_over_environment() {
+        let env = environment(&[
+            ("PGHOST", "db.example.com"),
+            ("PGPORT", "6432"),
+            ("PGUSER", "env"),
+            ("PGSSLMODE", "require"),
+        ]);
+        let mut params = conninfo::parse_connection_string("user=me dbname=shop").unwrap();
+        resolve_with(&mut params, &env).unwrap();
+        assert_eq!(
+            conninfo::to_keyword_string(&params),
+            "host=db.example.com port=6432 user=me dbname=shop sslmode=require"
+        );
+        assert!(resolve_with(
+            &mut ConnectionParams::default(),
+            &environment(&[("PGPORT", "x")])
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn passwords_match_with_wildcards_and_escapes() {
+        let content = "# comment\n\
+                       db:5432:shop:me:first\n\
+                       db:*:*:me:second\n\
+                       weird\\:host:*:*:*:pass\\:with\\\\colon\n\
+                       *:*:*:*:fallback:with:colons\n\
+                       too:short\n";
+        assert_eq!(
+            find_password(content, ["db", "5432", "shop", "me"]).as_deref(),
+            Some("first")
+        );
+        assert_eq!(
+            find_password(content, ["db", "6432", "other", "me"]).as_deref(),
+            Some("second")
+        );
+        assert_eq!(
+            find_password(content, ["weird:host", "1", "x", "y"]).as_deref(),
+            Some("pass:with\\colon")
+        );
+        assert_eq!(
+            find_password(content, ["other", "1", "x", "y"]).as_deref(),
+            Some("fallback:with:colons")
+        );
+        assert_eq!(
+            find_password("db:5432:shop:me:first", ["db", "5432", "shop", "you"]),
+            None
+        );
+    }
+
+    #[test]
+    fn services_are_found_by_name() {
+        let content = "# services\n[other]\nhost=elsewhere\n\n[shop]\nhost = db.example.com\nport=6432\nsslrootcert=/etc/ca.crt\n[last]\nuser=x\n";
+        assert_eq!(
+            find_service(content, "shop").unwrap().unwrap(),
+            [
+                ("host", "db.example.com"),
+                ("port", "6432"),
+                ("sslrootcert", "/etc/ca.crt")
+            ]
+            .iter()
+            .map(|(key, value)| (String::from(*key), String::from(*value)))
+            .collect::<Vec<_>>()
+        );
+        assert_eq!(find_service(content, "missing").unwrap(), None);
+        assert!(find_service("[shop]\nhost", "shop").is_err());
+        assert!(find_service("[shop]\nservice=other", "shop").is_err());
+    }
+
+    #[test]
+    fn service_and_password_file_are_used() {
+        let dir = std::env::temp_dir().join(format!("dingo-libpq-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let service_file = dir.join("services.conf");
+        let password_file = dir.join("pgpass");
+        fs::write(
+            &service_file,
+            "[shop]\nhost=db.example.com\ndbname=shop\nuser=service\n",
+        )
+        .unwrap();
+        fs::write(&password_file, "db.example.com:5432:shop:me:secret\n").unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&password_file, fs::Permissions::from_mode(0o600)).unwrap();
+        }
+        let env = environment(&[
+            ("PGSERVICEFILE", service_file.to_str().unwrap()),
+            ("PGPASSFILE", password_file.to_str().unwrap()),
+            ("PGHOST", "ignored"),
+            ("PGAPPNAME", "from environment"),
+        ]);
+
+        let mut params = conninfo::parse_connection_string("service=shop user=me").unwrap();
+        resolve_with(&mut params, &env).unwrap();
+        assert_eq!(
+            conninfo::to_keyword_string(&params),
+            "host=db.example.com user=me password=secret dbname=shop application_name='from environment'"
+        );
+
+        let mut params = conninfo::parse_connection_string("service=missing").unwrap();
+        assert!(resolve_with(&mut params, &env).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
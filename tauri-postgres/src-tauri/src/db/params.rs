@@ -0,0 +1,285 @@
+//! Parameters of custom queries ($1, $2, ...)
+//!
+//! Preparing a statement tells which types its parameters have, so the
+//! frontend can ask for fitting values. The values arrive as text and are
+//! converted to these types before the statement runs: booleans, integers
+//! and floating point numbers here, with an error naming the parameter if
+//! a value does not fit. Everything else is sent in text form and parsed
+//! by the server, just like a literal in the SQL text.
+//!
+//! Parameters of statements built here (see `sql`) are always sent as text.
+
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use tokio_postgres::types::{to_sql_checked, Format, IsNull, ToSql, Type};
+
+use super::convert;
+use super::sql::SqlStatement;
+use super::types::{ColumnType, DatabaseError, ErrorDetails};
+
+type BoxError = Box<dyn Error + Sync + Send>;
+
+/// What preparing a statement tells about it
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct PreparedQuery {
+    /// Types of $1, $2, ...
+    pub param_types: Vec<ColumnType>,
+    /// Columns of the result, none for statements that do not return rows
+    pub columns: Vec<String>,
+    pub column_types: Vec<ColumnType>,
+}
+
+impl From<&tokio_postgres::Statement> for PreparedQuery {
+    fn from(statement: &tokio_postgres::Statement) -> PreparedQuery {
+        PreparedQuery {
+            param_types: statement
+                .params()
+                .iter()
+                .map(|ty| ColumnType {
+                    name: String::from(ty.name()),
+                    oid: ty.oid(),
+                })
+                .collect(),
+            columns: statement
+                .columns()
+                .iter()
+                .map(|column| String::from(column.name()))
+                .collect(),
+            column_types: convert::column_types(statement.columns()),
+        }
+    }
+}
+
+/// Parameter value, converted to the type the statement expects
+#[derive(Debug, PartialEq)]
+pub enum ParamValue {
+    Null,
+    Bool(bool),
+    Int2(i16),
+    Int4(i32),
+    Int8(i64),
+    Oid(u32),
+    Float4(f32),
+    Float8(f64),
+    /// Parsed by the server
+    Text(String),
+}
+
+impl ToSql for ParamValue {
+    fn to_sql(&self, _ty: &Type, out: &mut bytes::BytesMut) -> Result<IsNull, BoxError> {
+        match self {
+            ParamValue::Null => return Ok(IsNull::Yes),
+            ParamValue::Bool(value) => postgres_protocol::types::bool_to_sql(*value, out),
+            ParamValue::Int2(value) => postgres_protocol::types::int2_to_sql(*value, out),
+            ParamValue::Int4(value) => postgres_protocol::types::int4_to_sql(*value, out),
+            ParamValue::Int8(value) => postgres_protocol::types::int8_to_sql(*value, out),
+            ParamValue::Oid(value) => postgres_protocol::types::oid_to_sql(*value, out),
+            ParamValue::Float4(value) => postgres_protocol::types::float4_to_sql(*value, out),
+            ParamValue::Float8(value) => postgres_protocol::types::float8_to_sql(*value, out),
+            ParamValue::Text(value) => out.extend_from_slice(value.as_bytes()),
+        }
+        Ok(IsNull::No)
+    }
+
+    fn accepts(_ty: &Type) -> bool {
+        true
+    }
+
+    fn encode_format(&self, _ty: &Type) -> Format {
+        match self {
+            ParamValue::Null | ParamValue::Text(_) => Format::Text,
+            _ => Format::Binary,
+        }
+    }
+
+    to_sql_checked!();
+}
+
+/// Boolean as postgres accepts it: true/false, yes/no, on/off, 1/0 and their prefixes
+fn parse_bool(text: &str) -> Option<bool> {
+    let text = text.to_lowercase();
+    let is_prefix =
+        |word: &str, min_len: usize| text.len() >= min_len && word.starts_with(text.as_str());
+    if is_prefix("true", 1) || is_prefix("yes", 1) || is_prefix("on", 2) || text == "1" {
+        Some(true)
+    } else if is_prefix("false", 1) || is_prefix("no", 1) || is_prefix("off", 2) || text == "0" {
+        Some(false)
+    } else {
+        None
+    }
+}
+
+/// Floating point number, also NaN and (-)Infinity, out of range values are rejected
+fn parse_float<F: std::str::FromStr + Into<f64> + Copy>(text: &str) -> Option<F> {
+    let value: F = text.parse().ok()?;
+    let is_infinite = value.into().is_infinite();
+    let says_infinite = text
+        .trim_start_matches(&['+', '-'][..])
+        .to_lowercase()
+        .starts_with("inf");
+    if is_infinite && !says_infinite {
+        None
+    } else {
+        Some(value)
+    }
+}
+
+/// Convert value given as text to the parameter's type
+///
+/// Surrounding whitespace is ignored for numbers and booleans, as postgres does.
+fn convert_value(ty: &Type, text: &str) -> Option<ParamValue> {
+    let trimmed = text.trim();
+    let value = match *ty {
+        Type::BOOL => ParamValue::Bool(parse_bool(trimmed)?),
+        Type::INT2 => ParamValue::Int2(trimmed.parse().ok()?),
+        Type::INT4 => ParamValue::Int4(trimmed.parse().ok()?),
+        Type::INT8 => ParamValue::Int8(trimmed.parse().ok()?),
+        Type::OID => ParamValue::Oid(trimmed.parse().ok()?),
+        Type::FLOAT4 => ParamValue::Float4(parse_float(trimmed)?),
+        Type::FLOAT8 => ParamValue::Float8(parse_float(trimmed)?),
+        _ => ParamValue::Text(String::from(text)),
+    };
+    Some(value)
+}
+
+/// Values are checked before the statement runs, like the SQL text is parsed
+fn param_error(message: String) -> DatabaseError {
+    DatabaseError::Parse(ErrorDetails::from_message(message))
+}
+
+/// Parameters of a statement built here, all of them in text form
+pub fn text_params(query: &SqlStatement) -> Vec<ParamValue> {
+    query.params.iter().cloned().map(ParamValue::Text).collect()
+}
+
+/// Parameters for the prepared statement
+///
+/// Values given by the user are converted to the parameter types,
+/// there has to be one for each parameter. Statements built here have
+/// parameters of their own and take no values.
+pub fn bind(query: &SqlStatement, types: &[Type]) -> Result<Vec<ParamValue>, DatabaseError> {
+    if !query.params.is_empty() {
+        if !query.values.is_empty() {
+            return Err(param_error(String::from(
+                "values cannot be given for a statement with parameters of its own",
+            )));
+        }
+        return Ok(text_params(query));
+    }
+    if query.values.len() != types.len() {
+        return Err(param_error(format!(
+            "{} values given for the {} parameters of the statement",
+            query.values.len(),
+            types.len()
+        )));
+    }
+    types
+        .iter()
+        .zip(query.values.iter())
+        .enumerate()
+        .map(|(index, (ty, value))| match value {
+            None => Ok(ParamValue::Null),
+            Some(text) => convert_value(ty, text).ok_or_else(|| {
+                param_error(format!(
+                    "invalid value for parameter ${} of type {}: \"{}\"",
+                    index + 1,
+                    ty,
+                    text
+                ))
+            }),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::sql::SqlBuilder;
+    use super::*;
+
+    #[test]
+    fn values_are_converted_to_parameter_types() {
+        let query = SqlStatement::with_values(
+            "SELECT $1, $2, $3, $4, $5, $6",
+            &[
+                Some(String::from(" 42 ")),
+                Some(String::from("yes")),
+                Some(String::from("-Infinity")),
+                None,
+                Some(String::from("2024-02-29")),
+                Some(String::from("1.5")),
+            ],
+        );
+        let types = [
+            Type::INT4,
+            Type::BOOL,
+            Type::FLOAT8,
+            Type::INT8,
+            Type::DATE,
+            Type::FLOAT4,
+        ];
+        assert_eq!(
+            bind(&query, &types).unwrap(),
+            vec![
+                ParamValue::Int4(42),
+                ParamValue::Bool(true),
+                ParamValue::Float8(f64::NEG_INFINITY),
+                ParamValue::Null,
+                ParamValue::Text(String::from("2024-02-29")),
+                ParamValue::Float4(1.5),
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_values_name_the_parameter() {
+        let invalid = [
+            (Type::INT2, "70000"),
+            (Type::INT4, "12abc"),
+            (Type::BOOL, "o"),
+            (Type::BOOL, "maybe"),
+            (Type::FLOAT4, "1e40"),
+            (Type::OID, "-1"),
+        ];
+        for (ty, text) in invalid.iter() {
+            let query = SqlStatement::with_values("SELECT $1", &[Some(String::from(*text))]);
+            let message = bind(&query, std::slice::from_ref(ty))
+                .unwrap_err()
+                .details()
+                .message
+                .clone();
+            assert!(message.contains("parameter $1"), "{}: {}", text, message);
+        }
+    }
+
+    #[test]
+    fn number_of_values_has_to_match() {
+        let query = SqlStatement::with_values("SELECT $1, $2", &[Some(String::from("1"))]);
+        assert!(matches!(
+            bind(&query, &[Type::INT4, Type::INT4]),
+            Err(DatabaseError::Parse(_))
+        ));
+        assert!(bind(&SqlStatement::verbatim("SELECT 1"), &[])
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn built_statements_keep_text_params() {
+        let built = SqlBuilder::new()
+            .push("SELECT 1 LIMIT ")
+            .push_param("5")
+            .build();
+        assert_eq!(
+            bind(&built, &[Type::INT8]).unwrap(),
+            vec![ParamValue::Text(String::from("5"))]
+        );
+        let with_values = SqlStatement {
+            values: vec![Some(String::from("6"))],
+            ..built
+        };
+        assert!(matches!(
+            bind(&with_values, &[Type::INT8]),
+            Err(DatabaseError::Parse(_))
+        ));
+    }
+}
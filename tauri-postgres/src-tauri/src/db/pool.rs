@@ -0,0 +1,361 @@
+//! Pool of live database clients
+//!
+//! Clients are kept per resolved connection string, so that navigating
+//! (databases, tables, contents) reuses an open connection instead of
+//! doing a new TCP and authentication handshake for every query.
+
+use std::collections::HashMap;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio_postgres::Client;
+
+use super::types::{DatabaseError, ErrorDetails, TlsState};
+use super::{conninfo, tls};
+
+/// Limits of the pool, applied per connection string
+pub struct PoolConfig {
+    /// Maximum number of clients (idle and in use) per connection string
+    pub max_size: usize,
+    /// Idle clients are closed after this duration
+    pub idle_timeout: Duration,
+    /// Idle clients are checked by a round trip before reuse after this duration
+    pub health_check_after: Duration,
+    /// Waiting for a free slot fails after this duration
+    pub acquire_timeout: Duration,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        PoolConfig {
+            max_size: 4,
+            idle_timeout: Duration::from_secs(300),
+            health_check_after: Duration::from_secs(30),
+            acquire_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Client with what it took to connect it
+pub struct OpenClient {
+    pub client: Client,
+    /// TLS state negotiated for the connection
+    pub tls_state: TlsState,
+    /// Needed to cancel queries, as the cancel request opens a connection of its own
+    pub connector: tls::MakeTlsConnector,
+}
+
+struct IdleClient {
+    open: OpenClient,
+    idle_since: Instant,
+}
+
+struct PoolEntry {
+    idle: Vec<IdleClient>,
+    slots: Arc<Semaphore>,
+}
+
+/// Client taken from the pool
+///
+/// Hand it back using `ConnectionPool::release`. If dropped instead,
+/// the connection is closed and its slot becomes available again.
+pub struct PooledClient {
+    key: String,
+    open: OpenClient,
+    session_changed: bool,
+    /// None for clients outside of the limit
+    _slot: Option<OwnedSemaphorePermit>,
+}
+
+impl PooledClient {
+    /// Mark the client as having run user SQL
+    ///
+    /// Such SQL may have left a transaction open or changed settings like
+    /// search_path or the role, so the session is reset on release.
+    pub fn mark_session_changed(&mut self) {
+        self.session_changed = true;
+    }
+
+    pub fn tls_state(&self) -> &TlsState {
+        &self.open.tls_state
+    }
+
+    pub fn connector(&self) -> &tls::MakeTlsConnector {
+        &self.open.connector
+    }
+}
+
+impl Deref for PooledClient {
+    type Target = Client;
+
+    fn deref(&self) -> &Client {
+        &self.open.client
+    }
+}
+
+impl DerefMut for PooledClient {
+    fn deref_mut(&mut self) -> &mut Client {
+        &mut self.open.client
+    }
+}
+
+pub struct ConnectionPool {
+    config: PoolConfig,
+    entries: Mutex<HashMap<String, PoolEntry>>,
+}
+
+impl ConnectionPool {
+    pub fn new(config: PoolConfig) -> ConnectionPool {
+        ConnectionPool {
+            config,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Get a client for the connection string, reusing an idle one if possible
+    ///
+    /// Waits if the maximum number of clients for this connection string is in use,
+    /// up to the acquire timeout.
+    pub async fn acquire(&self, connection_str: &str) -> Result<PooledClient, DatabaseError> {
+        let slots = self.entry_slots(connection_str);
+        let slot = tokio::time::timeout(self.config.acquire_timeout, slots.acquire_owned())
+            .await
+            .map_err(|_| {
+                DatabaseError::Connect(ErrorDetails::from_message(format!(
+                    "all {} connections of the pool are in use, gave up after {} seconds",
+                    self.config.max_size,
+                    self.config.acquire_timeout.as_secs()
+                )))
+            })?
+            .expect("pool semaphores are never closed");
+        self.take_client(connection_str, Some(slot)).await
+    }
+
+    /// Get a client that does not count towards the maximum number of clients
+    ///
+    /// For open transactions, which hold their client as long as the user
+    /// likes and must not leave navigation waiting for a slot.
+    pub async fn acquire_unlimited(
+        &self,
+        connection_str: &str,
+    ) -> Result<PooledClient, DatabaseError> {
+        self.entry_slots(connection_str);
+        self.take_client(connection_str, None).await
+    }
+
+    fn entry_slots(&self, connection_str: &str) -> Arc<Semaphore> {
+        let mut entries = self.entries.lock().unwrap();
+        self.close_expired(&mut entries);
+        entries
+            .entry(String::from(connection_str))
+            .or_insert_with(|| PoolEntry {
+                idle: Vec::new(),
+                slots: Arc::new(Semaphore::new(self.config.max_size)),
+            })
+            .slots
+            .clone()
+    }
+
+    async fn take_client(
+        &self,
+        connection_str: &str,
+        slot: Option<OwnedSemaphorePermit>,
+    ) -> Result<PooledClient, DatabaseError> {
+        while let Some(idle) = self.pop_idle(connection_str) {
+            if self.is_healthy(&idle).await {
+                return Ok(PooledClient {
+                    key: String::from(connection_str),
+                    open: idle.open,
+                    session_changed: false,
+                    _slot: slot,
+                });
+            }
+            println!("Pool: dropping unhealthy connection");
+        }
+
+        println!("Pool: opening new connection");
+        let open = connect(connection_str).await?;
+        Ok(PooledClient {
+            key: String::from(connection_str),
+            open,
+            session_changed: false,
+            _slot: slot,
+        })
+    }
+
+    /// Return a client to the pool, closed clients are discarded
+    ///
+    /// Clients marked by `PooledClient::mark_session_changed` get their
+    /// session reset first, and are discarded if that fails.
+    pub async fn release(&self, pooled: PooledClient) {
+        if pooled.open.client.is_closed() {
+            return;
+        }
+        if pooled.session_changed {
+            if let Err(e) = reset_session(&pooled.open.client).await {
+                println!("Pool: dropping connection, session reset failed: {}", e);
+                return;
+            }
+        }
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(entry) = entries.get_mut(&pooled.key) {
+            // clients outside of the limit must not grow the pool beyond it
+            if entry.idle.len() >= self.config.max_size {
+                return;
+            }
+            entry.idle.push(IdleClient {
+                open: pooled.open,
+                idle_since: Instant::now(),
+            });
+        }
+    }
+
+    /// Close all idle clients that exceeded the idle timeout
+    pub fn close_idle_clients(&self) {
+        let mut entries = self.entries.lock().unwrap();
+        self.close_expired(&mut entries);
+    }
+
+    fn close_expired(&self, entries: &mut HashMap<String, PoolEntry>) {
+        for entry in entries.values_mut() {
+            entry.idle.retain(|idle| {
+                !idle.open.client.is_closed()
+                    && idle.idle_since.elapsed() < self.config.idle_timeout
+            });
+        }
+    }
+
+    fn pop_idle(&self, connection_str: &str) -> Option<IdleClient> {
+        let mut entries = self.entries.lock().unwrap();
+        entries
+            .get_mut(connection_str)
+            .and_then(|entry| entry.idle.pop())
+    }
+
+    async fn is_healthy(&self, idle: &IdleClient) -> bool {
+        if idle.open.client.is_closed() {
+            false
+        } else if idle.idle_since.elapsed() < self.config.health_check_after {
+            true
+        } else {
+            idle.open.client.simple_query("SELECT 1").await.is_ok()
+        }
+    }
+}
+
+/// Roll back an open transaction and reset the session to its defaults
+///
+/// Same as `DISCARD ALL`, except for `DEALLOCATE ALL`: the client keeps
+/// the statements it prepared for type lookups and would fail on them.
+async fn reset_session(client: &Client) -> Result<(), tokio_postgres::Error> {
+    // Outside of a transaction, this only raises a warning
+    client.batch_execute("ROLLBACK").await?;
+    client
+        .batch_execute(
+            "CLOSE ALL; SET SESSION AUTHORIZATION DEFAULT; RESET ALL; UNLISTEN *; \
+             SELECT pg_advisory_unlock_all(); DISCARD PLANS; DISCARD TEMP; DISCARD SEQUENCES;",
+        )
+        .await
+}
+
+/// Open a new connection, the connection is driven by a task of its own
+///
+/// The task ends as soon as the client is dropped.
+pub async fn connect(connection_str: &str) -> Result<OpenClient, DatabaseError> {
+    println!(
+        "Connecting: \"{}\"",
+        conninfo::redact_password(connection_str)
+    );
+    let tls_error = |message: String| DatabaseError::Connect(ErrorDetails::from_message(message));
+    let (config_str, settings) = tls::split_tls_settings(connection_str).map_err(tls_error)?;
+    let connector = tls::make_connector(&settings).map_err(tls_error)?;
+    let (client, connection) = tokio_postgres::connect(&config_str, connector.clone())
+        .await
+        .map_err(|e| DatabaseError::on_connect(&e))?;
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            println!("Connection closed with error: {}", e);
+        }
+    });
+    let tls_state = tls::query_tls_state(&client, &settings).await;
+    println!("TLS: {:?}", tls_state);
+    Ok(OpenClient {
+        client,
+        tls_state,
+        connector,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Connection string of a server to test against, the test is skipped without one
+    fn test_connection() -> Option<String> {
+        std::env::var("PG_TEST_CONNECTION").ok()
+    }
+
+    #[tokio::test]
+    async fn acquire_gives_up_when_all_slots_are_taken() {
+        let pool = ConnectionPool::new(PoolConfig {
+            max_size: 0,
+            acquire_timeout: Duration::from_millis(10),
+            ..PoolConfig::default()
+        });
+        match pool.acquire("host=localhost").await {
+            Err(DatabaseError::Connect(details)) => assert!(details.message.contains("in use")),
+            _ => panic!("expected a connect error"),
+        }
+    }
+
+    #[tokio::test]
+    async fn released_client_has_a_clean_session() {
+        let connection_str = match test_connection() {
+            Some(connection_str) => connection_str,
+            None => return println!("PG_TEST_CONNECTION is not set, skipped"),
+        };
+        let pool = ConnectionPool::new(PoolConfig {
+            max_size: 1,
+            ..PoolConfig::default()
+        });
+        let show_search_path = "SELECT current_setting('search_path'), pg_backend_pid()";
+
+        let mut client = pool.acquire(&connection_str).await.unwrap();
+        let row = client.query_one(show_search_path, &[]).await.unwrap();
+        let (default_path, pid): (String, i32) = (row.get(0), row.get(1));
+        client
+            .batch_execute("SET search_path = pg_catalog")
+            .await
+            .unwrap();
+        client.batch_execute("BEGIN").await.unwrap();
+        client.mark_session_changed();
+        pool.release(client).await;
+
+        let client = pool.acquire(&connection_str).await.unwrap();
+        let row = client.query_one(show_search_path, &[]).await.unwrap();
+        assert_eq!(row.get::<_, i32>(1), pid, "the client is reused");
+        assert_eq!(row.get::<_, String>(0), default_path);
+        assert!(
+            client.batch_execute("SAVEPOINT s").await.is_err(),
+            "no transaction is open"
+        );
+    }
+
+    #[tokio::test]
+    async fn unlimited_clients_leave_the_slots_free() {
+        let connection_str = match test_connection() {
+            Some(connection_str) => connection_str,
+            None => return println!("PG_TEST_CONNECTION is not set, skipped"),
+        };
+        let pool = ConnectionPool::new(PoolConfig {
+            max_size: 1,
+            acquire_timeout: Duration::from_secs(1),
+            ..PoolConfig::default()
+        });
+        let unlimited = pool.acquire_unlimited(&connection_str).await.unwrap();
+        let limited = pool.acquire(&connection_str).await.unwrap();
+        pool.release(unlimited).await;
+        pool.release(limited).await;
+    }
+}
@@ -0,0 +1,269 @@
+//! Named connection profiles, stored as JSON in the app's config directory
+//!
+//! A profile is identified by its name. Passwords are stored as given, the
+//! file is therefore only readable by the user (on unix).
+
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use super::types::{ConnectionParams, QueryLimits};
+
+const PROFILES_FILE: &str = "profiles.json";
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct Profile {
+    pub name: String,
+    pub connection: ConnectionParams,
+    #[serde(default)]
+    pub limits: QueryLimits,
+    /// Color to tell connections apart, e.g. "#cc0000"
+    #[serde(default)]
+    pub color: Option<String>,
+    /// Environment label, e.g. "production"
+    #[serde(default)]
+    pub environment: Option<String>,
+}
+
+/// Content of the profiles file
+#[derive(Debug, Deserialize, Serialize, Default)]
+pub struct Profiles {
+    pub profiles: Vec<Profile>,
+    /// Name of the profile used last
+    #[serde(default)]
+    pub last_used: Option<String>,
+}
+
+impl Profiles {
+    /// Read profiles from directory, none if there is no file yet
+    pub fn load(dir: &Path) -> Result<Profiles, String> {
+        let path = dir.join(PROFILES_FILE);
+        match fs::read(&path) {
+            Ok(content) => serde_json::from_slice(&content)
+                .map_err(|e| format!("invalid profiles file \"{}\": {}", path.display(), e)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Profiles::default()),
+            Err(e) => Err(format!(
+                "could not read profiles file \"{}\": {}",
+                path.display(),
+                e
+            )),
+        }
+    }
+
+    /// Write profiles to directory, replacing the file only once it is complete
+    pub fn save(&self, dir: &Path) -> Result<(), String> {
+        let path = dir.join(PROFILES_FILE);
+        let write_error = |e: std::io::Error| {
+            format!(
+                "could not write profiles file \"{}\": {}",
+                path.display(),
+                e
+            )
+        };
+        fs::create_dir_all(dir).map_err(write_error)?;
+        let content = serde_json::to_vec_pretty(self)
+            .map_err(|e| format!("could not serialize profiles: {}", e))?;
+
+        let temp_path = dir.join(format!("{}.tmp", PROFILES_FILE));
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options.open(&temp_path).map_err(write_error)?;
+        file.write_all(&content).map_err(write_error)?;
+        file.sync_all().map_err(write_error)?;
+        fs::rename(&temp_path, &path).map_err(write_error)
+    }
+
+    fn position(&self, name: &str) -> Result<usize, String> {
+        self.profiles
+            .iter()
+            .position(|profile| profile.name == name)
+            .ok_or_else(|| format!("no profile named \"{}\"", name))
+    }
+
+    fn check_name_is_free(&self, name: &str) -> Result<(), String> {
+        if name.trim().is_empty() {
+            return Err(String::from("profile name must not be empty"));
+        }
+        match self.position(name) {
+            Ok(_) => Err(format!("there is a profile named \"{}\" already", name)),
+            Err(_) => Ok(()),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Result<&Profile, String> {
+        Ok(&self.profiles[self.position(name)?])
+    }
+
+    /// Profile used last, if it still exists
+    pub fn last_used(&self) -> Option<&Profile> {
+        self.last_used.as_ref().and_then(|name| self.get(name).ok())
+    }
+
+    pub fn create(&mut self, profile: Profile) -> Result<(), String> {
+        self.check_name_is_free(&profile.name)?;
+        self.profiles.push(profile);
+        Ok(())
+    }
+
+    /// Replace profile, it may be renamed as long as the new name is free
+    pub fn update(&mut self, name: &str, profile: Profile) -> Result<(), String> {
+        let index = self.position(name)?;
+        if profile.name != name {
+            self.check_name_is_free(&profile.name)?;
+            if self.last_used.as_deref() == Some(name) {
+                self.last_used = Some(profile.name.clone());
+            }
+        }
+        self.profiles[index] = profile;
+        Ok(())
+    }
+
+    pub fn delete(&mut self, name: &str) -> Result<(), String> {
+        let index = self.position(name)?;
+        self.profiles.remove(index);
+        if self.last_used.as_deref() == Some(name) {
+            self.last_used = None;
+        }
+        Ok(())
+    }
+
+    /// Copy profile under a new name, "<name> (copy)" if none is given
+    pub fn duplicate(&mut self, name: &str, new_name: Option<String>) -> Result<Profile, String> {
+        let mut copy = self.get(name)?.clone();
+        copy.name = match new_name {
+            Some(new_name) => new_name,
+            None => (1..)
+                .map(|number| match number {
+                    1 => format!("{} (copy)", name),
+                    _ => format!("{} (copy {})", name, number),
+                })
+                .find(|candidate| self.position(candidate).is_err())
+                .expect("some number is free"),
+        };
+        self.create(copy.clone())?;
+        Ok(copy)
+    }
+
+    pub fn mark_used(&mut self, name: &str) -> Result<(), String> {
+        self.position(name)?;
+        self.last_used = Some(String::from(name));
+        Ok(())
+    }
+}
+
+/// Directory the profiles file is kept in
+pub fn profiles_dir(app: &tauri::AppHandle) -> Result<PathBuf, String> {
+    app.path_resolver()
+        .app_config_dir()
+        .ok_or_else(|| String::from("no config directory available"))
+}
+
+/// Load profiles, apply change and save them again
+pub fn modify<T>(
+    app: &tauri::AppHandle,
+    change: impl FnOnce(&mut Profiles) -> Result<T, String>,
+) -> Result<T, String> {
+    let dir = profiles_dir(app)?;
+    let mut profiles = Profiles::load(&dir)?;
+    let result = change(&mut profiles)?;
+    profiles.save(&dir)?;
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profile(name: &str) -> Profile {
+        Profile {
+            name: String::from(name),
+            connection: ConnectionParams {
+                host: Some(String::from("localhost")),
+                ..ConnectionParams::default()
+            },
+            limits: QueryLimits::default(),
+            color: None,
+            environment: Some(String::from("development")),
+        }
+    }
+
+    fn names(profiles: &Profiles) -> Vec<&str> {
+        profiles
+            .profiles
+            .iter()
+            .map(|profile| profile.name.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn names_stay_unique() {
+        let mut profiles = Profiles::default();
+        profiles.create(profile("local")).unwrap();
+        profiles.create(profile("prod")).unwrap();
+        assert!(profiles.create(profile("local")).is_err());
+        assert!(profiles.create(profile(" ")).is_err());
+        assert!(profiles.update("local", profile("prod")).is_err());
+        assert!(profiles.update("missing", profile("x")).is_err());
+
+        assert_eq!(
+            profiles.duplicate("local", None).unwrap().name,
+            "local (copy)"
+        );
+        assert_eq!(
+            profiles.duplicate("local", None).unwrap().name,
+            "local (copy 2)"
+        );
+        assert!(profiles
+            .duplicate("local", Some(String::from("prod")))
+            .is_err());
+        assert_eq!(
+            names(&profiles),
+            ["local", "prod", "local (copy)", "local (copy 2)"]
+        );
+    }
+
+    #[test]
+    fn last_used_follows_rename_and_delete() {
+        let mut profiles = Profiles::default();
+        profiles.create(profile("local")).unwrap();
+        assert!(profiles.mark_used("missing").is_err());
+        profiles.mark_used("local").unwrap();
+        profiles.update("local", profile("renamed")).unwrap();
+        assert_eq!(profiles.last_used().unwrap().name, "renamed");
+        profiles.delete("renamed").unwrap();
+        assert!(profiles.last_used().is_none());
+        assert_eq!(profiles.last_used, None);
+    }
+
+    #[test]
+    fn survive_save_and_load() {
+        let dir = std::env::temp_dir().join(format!("dingo-profiles-test-{}", std::process::id()));
+        assert!(Profiles::load(&dir).unwrap().profiles.is_empty());
+
+        let mut profiles = Profiles::default();
+        profiles.create(profile("local")).unwrap();
+        profiles.mark_used("local").unwrap();
+        profiles.save(&dir).unwrap();
+
+        let loaded = Profiles::load(&dir).unwrap();
+        assert_eq!(names(&loaded), ["local"]);
+        assert_eq!(
+            loaded.last_used().unwrap().connection,
+            profile("local").connection
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reads_port_saved_as_number() {
+        let params: ConnectionParams =
+            serde_json::from_str(r#"{"host": "localhost", "port": 5433}"#).unwrap();
+        assert_eq!(params.port.as_deref(), Some("5433"));
+    }
+}
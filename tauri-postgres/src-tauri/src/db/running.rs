@@ -0,0 +1,103 @@
+//! Registry of running queries, so that they can be cancelled
+//!
+//! A query is registered under the id chosen by the frontend as soon as
+//! it is received. Once it has a client, the client's cancel token is
+//! attached. Cancelling before that point keeps the query from running.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio_postgres::{CancelToken, Client};
+
+use super::tls::MakeTlsConnector;
+
+#[derive(Default)]
+struct RunningQuery {
+    cancel_token: Option<(CancelToken, MakeTlsConnector)>,
+    cancelled: bool,
+}
+
+#[derive(Default)]
+pub struct RunningQueries {
+    queries: Mutex<HashMap<String, RunningQuery>>,
+}
+
+/// Outcome of a cancel request
+pub enum Cancellation {
+    /// No running query with that id
+    Unknown,
+    /// Query has not started on the server yet, it will not be started
+    BeforeStart,
+    /// Query runs on the server, the token aborts it there
+    Running(CancelToken, MakeTlsConnector),
+}
+
+impl RunningQueries {
+    /// Register query, it stays registered until the handle is dropped
+    pub fn register(self: &Arc<Self>, query_id: String) -> QueryHandle {
+        let mut queries = self.queries.lock().unwrap();
+        if queries
+            .insert(query_id.clone(), RunningQuery::default())
+            .is_some()
+        {
+            println!(
+                "Query id \"{}\" is already in use, only the latest can be cancelled",
+                query_id
+            );
+        }
+        QueryHandle {
+            queries: self.clone(),
+            query_id,
+        }
+    }
+
+    /// Mark query as cancelled
+    pub fn cancel(&self, query_id: &str) -> Cancellation {
+        let mut queries = self.queries.lock().unwrap();
+        match queries.get_mut(query_id) {
+            None => Cancellation::Unknown,
+            Some(query) => {
+                query.cancelled = true;
+                match &query.cancel_token {
+                    None => Cancellation::BeforeStart,
+                    Some((token, connector)) => {
+                        Cancellation::Running(token.clone(), connector.clone())
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Registration of a single running query
+pub struct QueryHandle {
+    queries: Arc<RunningQueries>,
+    query_id: String,
+}
+
+impl QueryHandle {
+    /// Attach the client the query runs on, with the connector for cancel requests
+    ///
+    /// Returns false if the query was cancelled already and must not be run.
+    pub fn attach(&self, client: &Client, connector: MakeTlsConnector) -> bool {
+        let mut queries = self.queries.queries.lock().unwrap();
+        match queries.get_mut(&self.query_id) {
+            Some(query) if !query.cancelled => {
+                query.cancel_token = Some((client.cancel_token(), connector));
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn was_cancelled(&self) -> bool {
+        let queries = self.queries.queries.lock().unwrap();
+        matches!(queries.get(&self.query_id), Some(query) if query.cancelled)
+    }
+}
+
+impl Drop for QueryHandle {
+    fn drop(&mut self) {
+        let mut queries = self.queries.queries.lock().unwrap();
+        queries.remove(&self.query_id);
+    }
+}
@@ -0,0 +1,608 @@
+//! Scripts: several statements separated by semicolons, run one after the other
+//!
+//! Splitting follows the lexical rules of postgres, so semicolons in string
+//! literals ('', E'', $tag$ $tag$), quoted identifiers, comments (nested
+//! /* */ too) and bodies of `BEGIN ATOMIC ... END` do not end a statement.
+//!
+//! tokio-postgres only passes on the number of affected rows, not the
+//! command tag itself. The tag is derived from the statement's text and
+//! that number instead, the way postgres forms it; it may differ from the
+//! server's for statements it does not know.
+
+use futures_util::{pin_mut, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use std::time::Instant;
+use tokio_postgres::Client;
+
+use super::params::ParamValue;
+use super::sql::SqlStatement;
+use super::types::{
+    Connection, DatabaseError, QueryLimits, SomeDatabase, TlsState, TypedTable, TypedTableResult,
+};
+use super::{column_names, convert, execute_error, prepare_statement, row_to_typed_fields};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TokenKind {
+    /// Keyword, name or number
+    Word,
+    /// String literal or quoted identifier
+    Quoted,
+    Symbol(char),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Token {
+    kind: TokenKind,
+    /// Byte range in the text
+    start: usize,
+    end: usize,
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '$'
+}
+
+/// Tag of a dollar quote starting at `start` ("$tag$" or "$$"), if there is one
+fn dollar_quote_tag(text: &str, start: usize) -> Option<&str> {
+    let rest = &text[start + 1..];
+    let end = rest.find('$')?;
+    let tag = &rest[..end];
+    let valid = match tag.chars().next() {
+        None => true,
+        Some(first) => {
+            !first.is_ascii_digit() && tag.chars().all(|c| c.is_alphanumeric() || c == '_')
+        }
+    };
+    if valid {
+        Some(&text[start..start + end + 2])
+    } else {
+        None
+    }
+}
+
+/// Split text into tokens, leaving out whitespace and comments
+///
+/// Unterminated literals and comments extend to the end of the text,
+/// the server reports them when the statement is run.
+fn tokenize(text: &str) -> Vec<Token> {
+    let bytes = text.as_bytes();
+    let mut tokens = Vec::new();
+    let mut position = 0;
+    while let Some(c) = text[position..].chars().next() {
+        let start = position;
+        let next = start + c.len_utf8();
+        let rest = &text[start..];
+        if c.is_whitespace() {
+            position = next;
+        } else if rest.starts_with("--") {
+            position = rest.find('\n').map_or(text.len(), |end| start + end + 1);
+        } else if rest.starts_with("/*") {
+            let mut depth = 0;
+            position = start;
+            while position < text.len() {
+                if text[position..].starts_with("/*") {
+                    depth += 1;
+                    position += 2;
+                } else if text[position..].starts_with("*/") {
+                    depth -= 1;
+                    position += 2;
+                    if depth == 0 {
+                        break;
+                    }
+                } else {
+                    position += text[position..].chars().next().map_or(1, char::len_utf8);
+                }
+            }
+        } else if c == '\'' || c == '"' {
+            // E'...' allows backslash escapes, the E was read as a word before
+            let is_escape_prefix = |token: &Token| {
+                token.kind == TokenKind::Word
+                    && token.end == start
+                    && text[token.start..token.end].eq_ignore_ascii_case("e")
+            };
+            let escapes = c == '\'' && tokens.last().map_or(false, is_escape_prefix);
+            if escapes {
+                tokens.pop();
+            }
+            position = next;
+            loop {
+                match bytes.get(position) {
+                    None => break,
+                    Some(b'\\') if escapes => position += 2,
+                    Some(&b) if b == c as u8 => {
+                        position += 1;
+                        // doubled quote stands for the quote itself
+                        if bytes.get(position) != Some(&(c as u8)) {
+                            break;
+                        }
+                        position += 1;
+                    }
+                    Some(_) => position += 1,
+                }
+            }
+            position = position.min(text.len());
+            tokens.push(Token {
+                kind: TokenKind::Quoted,
+                start: if escapes { start - 1 } else { start },
+                end: position,
+            });
+        } else if c == '$' && dollar_quote_tag(text, start).is_some() {
+            let tag = dollar_quote_tag(text, start).expect("checked above");
+            let body = start + tag.len();
+            position = text[body..]
+                .find(tag)
+                .map_or(text.len(), |end| body + end + tag.len());
+            tokens.push(Token {
+                kind: TokenKind::Quoted,
+                start,
+                end: position,
+            });
+        } else if is_word_char(c) {
+            position = rest
+                .char_indices()
+                .find(|(_, c)| !is_word_char(*c))
+                .map_or(text.len(), |(end, _)| start + end);
+            tokens.push(Token {
+                kind: TokenKind::Word,
+                start,
+                end: position,
+            });
+        } else {
+            position = next;
+            tokens.push(Token {
+                kind: TokenKind::Symbol(c),
+                start,
+                end: position,
+            });
+        }
+    }
+    tokens
+}
+
+/// Upper case keywords at the start of the tokens
+fn keywords(text: &str, tokens: &[Token]) -> Vec<String> {
+    tokens
+        .iter()
+        .take_while(|token| token.kind == TokenKind::Word)
+        .map(|token| text[token.start..token.end].to_ascii_uppercase())
+        .collect()
+}
+
+/// Statement of a script
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScriptStatement {
+    pub text: String,
+    /// Offset of the first character in the script, in characters
+    pub start: usize,
+}
+
+/// Split script into statements, empty ones (only comments) are left out
+pub fn split_statements(script: &str) -> Vec<ScriptStatement> {
+    let tokens = tokenize(script);
+    let mut statements = Vec::new();
+    let mut first = 0;
+    // nesting of BEGIN ATOMIC ... END (and CASE ... END within)
+    let mut atomic_depth = 0;
+    for (index, token) in tokens.iter().enumerate() {
+        let word = match token.kind {
+            TokenKind::Word => script[token.start..token.end].to_ascii_uppercase(),
+            _ => String::new(),
+        };
+        let previous_word = index
+            .checked_sub(1)
+            .map(|previous| &tokens[previous])
+            .filter(|previous| previous.kind == TokenKind::Word)
+            .map(|previous| script[previous.start..previous.end].to_ascii_uppercase());
+        let opens = match word.as_str() {
+            "ATOMIC" => previous_word.as_deref() == Some("BEGIN"),
+            "CASE" => atomic_depth > 0,
+            _ => false,
+        };
+        if opens {
+            atomic_depth += 1;
+        } else if atomic_depth > 0 && word == "END" {
+            atomic_depth -= 1;
+        }
+        let is_end = token.kind == TokenKind::Symbol(';') && atomic_depth == 0;
+        if is_end || index + 1 == tokens.len() {
+            let last = if is_end { index } else { index + 1 };
+            if first < last {
+                let start = tokens[first].start;
+                statements.push(ScriptStatement {
+                    text: String::from(&script[start..tokens[last - 1].end]),
+                    start: script[..start].chars().count(),
+                });
+            }
+            first = index + 1;
+        }
+    }
+    statements
+}
+
+/// Main keyword of a statement with common table expressions: the first one after them
+fn keyword_after_with(text: &str, tokens: &[Token]) -> Option<String> {
+    let mut depth = 0;
+    for token in tokens {
+        match token.kind {
+            TokenKind::Symbol('(') => depth += 1,
+            TokenKind::Symbol(')') => depth -= 1,
+            TokenKind::Word if depth == 0 => {
+                let word = text[token.start..token.end].to_ascii_uppercase();
+                if [
+                    "SELECT", "INSERT", "UPDATE", "DELETE", "MERGE", "VALUES", "TABLE",
+                ]
+                .contains(&word.as_str())
+                {
+                    return Some(word);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Whether AS appears outside parentheses, e.g. not the one of a generated column
+fn has_top_level_as(text: &str, tokens: &[Token]) -> bool {
+    let mut depth = 0;
+    tokens.iter().any(|token| {
+        match token.kind {
+            TokenKind::Symbol('(') => depth += 1,
+            TokenKind::Symbol(')') => depth -= 1,
+            _ => {}
+        }
+        depth == 0
+            && token.kind == TokenKind::Word
+            && text[token.start..token.end].eq_ignore_ascii_case("AS")
+    })
+}
+
+/// Command tag derived from the statement text, as postgres would report it, e.g. "INSERT 0 5", "UPDATE 3"
+pub fn command_tag(statement: &str, rows_affected: Option<u64>) -> String {
+    let tokens = tokenize(statement);
+    let words = keywords(statement, &tokens);
+    let word = |index: usize| words.get(index).map(String::as_str).unwrap_or("");
+    let rows = rows_affected.unwrap_or(0);
+    let main = match word(0) {
+        "WITH" => keyword_after_with(statement, &tokens).unwrap_or_else(|| String::from("SELECT")),
+        // VALUES and TABLE are short for SELECT
+        "VALUES" | "TABLE" => String::from("SELECT"),
+        other => String::from(other),
+    };
+    match main.as_str() {
+        "INSERT" => format!("INSERT 0 {}", rows),
+        "VALUES" | "TABLE" => format!("SELECT {}", rows),
+        "SELECT" | "UPDATE" | "DELETE" | "MERGE" | "FETCH" | "MOVE" | "COPY" => {
+            format!("{} {}", main, rows)
+        }
+        "CREATE" | "DROP" | "ALTER" => {
+            // modifiers are not part of the tag: CREATE OR REPLACE VIEW, CREATE UNIQUE INDEX, ...
+            let modifiers = [
+                "OR",
+                "REPLACE",
+                "TEMP",
+                "TEMPORARY",
+                "UNLOGGED",
+                "UNIQUE",
+                "GLOBAL",
+                "LOCAL",
+                "RECURSIVE",
+                "TRUSTED",
+                "PROCEDURAL",
+                "DEFAULT",
+                "CONSTRAINT",
+            ];
+            let object: Vec<&str> = words[1..]
+                .iter()
+                .map(String::as_str)
+                .skip_while(|word| modifiers.contains(word))
+                .collect();
+            let length = match (object.first(), object.get(1)) {
+                (Some(&"FOREIGN"), Some(&"DATA")) | (Some(&"TEXT"), _) => 3,
+                (Some(&"MATERIALIZED"), _)
+                | (Some(&"FOREIGN"), _)
+                | (Some(&"EVENT"), _)
+                | (Some(&"ACCESS"), _) => 2,
+                (Some(&"USER"), Some(&"MAPPING")) | (Some(&"OPERATOR"), Some(&"CLASS")) => 2,
+                (Some(&"OPERATOR"), Some(&"FAMILY")) => 2,
+                _ => 1,
+            };
+            // CREATE TABLE ... AS and CREATE MATERIALIZED VIEW report the rows they wrote
+            let creates_from_query = main == "CREATE"
+                && (object.first() == Some(&"TABLE") || object.first() == Some(&"MATERIALIZED"))
+                && has_top_level_as(statement, &tokens);
+            if creates_from_query {
+                format!("SELECT {}", rows)
+            } else {
+                let object: Vec<&str> = object.into_iter().take(length).collect();
+                format!("{} {}", main, object.join(" "))
+                    .trim_end()
+                    .to_string()
+            }
+        }
+        "BEGIN" | "START" => String::from("BEGIN"),
+        "END" => String::from("COMMIT"),
+        "ABORT" => String::from("ROLLBACK"),
+        _ => main,
+    }
+}
+
+/// Whether the statement begins or ends a transaction, savepoints do not count
+pub fn controls_transaction(statement: &str) -> bool {
+    let tokens = tokenize(statement);
+    let words = keywords(statement, &tokens);
+    match command_tag(statement, None).as_str() {
+        "BEGIN" | "COMMIT" => true,
+        // ROLLBACK [WORK | TRANSACTION] TO [SAVEPOINT] name stays within the transaction
+        "ROLLBACK" => !words.iter().any(|word| word == "TO"),
+        "PREPARE" => words.get(1).map(String::as_str) == Some("TRANSACTION"),
+        _ => false,
+    }
+}
+
+/// What to do with the remaining statements after one failed
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub enum OnError {
+    Stop,
+    Continue,
+}
+
+impl Default for OnError {
+    fn default() -> Self {
+        OnError::Stop
+    }
+}
+
+/// Script as sent by the frontend
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct Script {
+    pub connection: Connection,
+    pub database: Option<SomeDatabase>,
+    pub sql_script: String,
+    #[serde(default)]
+    pub on_error: OnError,
+    /// Limits apply to each statement
+    #[serde(default)]
+    pub limits: QueryLimits,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct StatementResult {
+    pub sql: String,
+    /// Offset of the statement in the script, in characters
+    pub start: usize,
+    /// Rows, without columns for statements that return none
+    pub table: TypedTableResult,
+    pub returns_rows: bool,
+    /// More rows than `QueryLimits::max_rows` were returned, the rest was dropped
+    pub truncated: bool,
+    pub rows_affected: Option<u64>,
+    pub command_tag: Option<String>,
+    pub duration_ms: u64,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ScriptResult {
+    pub database: Option<SomeDatabase>,
+    /// Results of the statements run, in order
+    pub statements: Vec<StatementResult>,
+    /// Statements not run, as an earlier one failed
+    pub skipped: usize,
+    pub tls: Option<TlsState>,
+}
+
+struct StatementOutcome {
+    table: TypedTable,
+    truncated: bool,
+    rows_affected: Option<u64>,
+}
+
+async fn run_statement(
+    client: &Client,
+    text: &str,
+    max_rows: Option<u64>,
+) -> Result<StatementOutcome, DatabaseError> {
+    let statement = prepare_statement(client, &SqlStatement::verbatim(text)).await?;
+    let rows = client
+        .query_raw(&statement, std::iter::empty::<ParamValue>())
+        .await
+        .map_err(execute_error)?;
+    pin_mut!(rows);
+    let mut fields = Vec::new();
+    let mut truncated = false;
+    let mut renderer = convert::ServerRenderer::default();
+    while let Some(row) = rows.try_next().await.map_err(execute_error)? {
+        if max_rows.map_or(false, |max_rows| fields.len() as u64 >= max_rows) {
+            truncated = true;
+        } else {
+            fields.push(row_to_typed_fields(client, &mut renderer, &row).await);
+        }
+    }
+    Ok(StatementOutcome {
+        table: TypedTable {
+            columns: column_names(statement.columns()),
+            column_types: convert::column_types(statement.columns()),
+            fields,
+        },
+        truncated,
+        rows_affected: rows.rows_affected(),
+    })
+}
+
+/// Run statements one after the other on the client
+///
+/// The statement timeout must be set already. Stops after the first
+/// failure if asked to, or if the statement was cancelled (`stop_early`).
+pub async fn run_script(
+    client: &Client,
+    statements: Vec<ScriptStatement>,
+    on_error: OnError,
+    max_rows: Option<u64>,
+    stop_early: impl Fn(&DatabaseError) -> bool,
+) -> (Vec<StatementResult>, usize) {
+    let total = statements.len();
+    let mut results = Vec::with_capacity(total);
+    for statement in statements {
+        let started = Instant::now();
+        let outcome = run_statement(client, &statement.text, max_rows).await;
+        let duration_ms = started.elapsed().as_millis() as u64;
+        let stop = match &outcome {
+            Ok(_) => false,
+            Err(e) => on_error == OnError::Stop || stop_early(e),
+        };
+        results.push(match outcome {
+            Ok(outcome) => StatementResult {
+                returns_rows: !outcome.table.columns.is_empty(),
+                command_tag: Some(command_tag(&statement.text, outcome.rows_affected)),
+                sql: statement.text,
+                start: statement.start,
+                table: Ok(outcome.table),
+                truncated: outcome.truncated,
+                rows_affected: outcome.rows_affected,
+                duration_ms,
+            },
+            Err(e) => StatementResult {
+                sql: statement.text,
+                start: statement.start,
+                table: Err(e),
+                returns_rows: false,
+                truncated: false,
+                rows_affected: None,
+                command_tag: None,
+                duration_ms,
+            },
+        });
+        if stop {
+            break;
+        }
+    }
+    let skipped = total - results.len();
+    (results, skipped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texts(script: &str) -> Vec<String> {
+        split_statements(script)
+            .into_iter()
+            .map(|statement| statement.text)
+            .collect()
+    }
+
+    #[test]
+    fn splits_at_semicolons_outside_literals() {
+        assert_eq!(
+            texts("select 1; select 2;\n\n select 3"),
+            ["select 1", "select 2", "select 3"]
+        );
+        assert_eq!(
+            texts("select 'a;''b'; select \"x;\"\"y\" from t; select E'\\';' ;"),
+            [
+                "select 'a;''b'",
+                "select \"x;\"\"y\" from t",
+                "select E'\\';'"
+            ]
+        );
+        assert_eq!(
+            texts("select $$ ; $$; select $fn$ $$; $fn$, $1, a$b$c;"),
+            ["select $$ ; $$", "select $fn$ $$; $fn$, $1, a$b$c"]
+        );
+        // backslash ends a normal literal
+        assert_eq!(
+            texts("select 'a\\'; select 2"),
+            ["select 'a\\'", "select 2"]
+        );
+    }
+
+    #[test]
+    fn comments_do_not_split() {
+        assert_eq!(
+            texts("-- first; still comment\nselect 1 /* a; /* nested; */ b; */ + 1; /* only comment; */ ;; select 2 -- end;"),
+            ["select 1 /* a; /* nested; */ b; */ + 1", "select 2"]
+        );
+        let statements = split_statements("  /* x */ select 1;\n  select 'ä';\nselect 3");
+        assert_eq!(statements[0].start, 10);
+        assert_eq!(statements[2].start, 34);
+    }
+
+    #[test]
+    fn atomic_bodies_stay_together() {
+        let script = "create function f() returns int language sql begin atomic select 1; \
+                      select case when true then 2 end; end; select f();";
+        assert_eq!(
+            texts(script),
+            [
+                "create function f() returns int language sql begin atomic select 1; \
+                 select case when true then 2 end; end",
+                "select f()"
+            ]
+        );
+        assert_eq!(texts("begin; commit; end;"), ["begin", "commit", "end"]);
+    }
+
+    #[test]
+    fn unterminated_literal_runs_to_the_end() {
+        assert_eq!(
+            texts("select 1; select 'abc; select 2"),
+            ["select 1", "select 'abc; select 2"]
+        );
+        assert_eq!(texts("select $x$ ; "), ["select $x$ ; "]);
+    }
+
+    #[test]
+    fn tags_like_postgres() {
+        assert_eq!(
+            command_tag("insert into t values (1), (2)", Some(2)),
+            "INSERT 0 2"
+        );
+        assert_eq!(command_tag("Update t set a = 1", Some(3)), "UPDATE 3");
+        assert_eq!(command_tag("values (1)", Some(1)), "SELECT 1");
+        assert_eq!(
+            command_tag("with x as (select 1) delete from t using x", Some(4)),
+            "DELETE 4"
+        );
+        assert_eq!(
+            command_tag("with recursive x(n) as (select 1) select * from x", Some(1)),
+            "SELECT 1"
+        );
+        assert_eq!(
+            command_tag("create table t (a int)", Some(0)),
+            "CREATE TABLE"
+        );
+        assert_eq!(
+            command_tag("CREATE OR REPLACE VIEW v AS SELECT 1", Some(0)),
+            "CREATE VIEW"
+        );
+        assert_eq!(
+            command_tag("create unique index on t (a)", None),
+            "CREATE INDEX"
+        );
+        assert_eq!(
+            command_tag("create temp table t as select 1", Some(1)),
+            "SELECT 1"
+        );
+        assert_eq!(
+            command_tag(
+                "create table t (a int, b int generated always as (a * 2) stored)",
+                Some(0)
+            ),
+            "CREATE TABLE"
+        );
+        assert_eq!(
+            command_tag("drop materialized view v", None),
+            "DROP MATERIALIZED VIEW"
+        );
+        assert_eq!(command_tag("-- comment\nvacuum t", None), "VACUUM");
+        assert_eq!(command_tag("start transaction", None), "BEGIN");
+        assert!(controls_transaction("commit"));
+        assert!(controls_transaction("ROLLBACK WORK"));
+        assert!(controls_transaction("abort"));
+        assert!(controls_transaction("PREPARE TRANSACTION 'x'"));
+        assert!(!controls_transaction("rollback to savepoint a"));
+        assert!(!controls_transaction("SAVEPOINT a"));
+        assert!(!controls_transaction("PREPARE q AS SELECT 1"));
+        assert!(!controls_transaction("SELECT 'commit'"));
+    }
+}
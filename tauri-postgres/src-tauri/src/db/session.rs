@@ -0,0 +1,205 @@
+//! Navigation state kept per window
+//!
+//! The backend remembers the selected connection, database, schema and
+//! table. The frontend only says where to go (`Navigation`), the backend
+//! then knows which query to run there (`Position::next_query`). After a
+//! reload the frontend asks for its session to get back to where it was.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use super::types::{
+    Connection, DatabaseQueryResult, DatabaseSchema, DatabaseTable, FullQuery, Pagination, Query,
+    QueryLimits, SomeDatabase, SomeSchema, SomeTable,
+};
+
+/// Selected path below the connection
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct Position {
+    pub database: Option<SomeDatabase>,
+    pub schema: Option<SomeSchema>,
+    pub table: Option<SomeTable>,
+}
+
+impl Position {
+    /// Query to run at this position: the contents of the deepest thing selected
+    pub fn next_query(&self, pagination: Option<Pagination>) -> Query {
+        match (&self.database, &self.schema, &self.table) {
+            (None, _, _) => Query::GetDatabases,
+            (database, None, _) => Query::GetSchemas(database.clone()),
+            (database, Some(schema), None) => Query::GetTables(DatabaseSchema {
+                database: database.clone(),
+                schema: schema.clone(),
+            }),
+            (database, Some(schema), Some(table)) => Query::GetTableContents(DatabaseTable {
+                database: database.clone(),
+                schema: schema.clone(),
+                table: table.clone(),
+                pagination,
+            }),
+        }
+    }
+}
+
+/// Step of navigation
+#[derive(Debug, Deserialize, Serialize)]
+pub enum Navigation {
+    /// Use connection, starting at its top
+    Connect {
+        connection: Connection,
+        limits: QueryLimits,
+        /// Name of the profile the connection belongs to, if any
+        #[serde(default)]
+        profile: Option<String>,
+    },
+    /// Go to position on the current connection
+    To(Position),
+}
+
+#[derive(Debug, Serialize, Clone, Default)]
+pub struct Session {
+    pub connection: Option<Connection>,
+    pub limits: QueryLimits,
+    pub profile: Option<String>,
+    pub position: Position,
+}
+
+impl Session {
+    pub fn navigate(&mut self, navigation: Navigation) -> Result<(), String> {
+        match navigation {
+            Navigation::Connect {
+                connection,
+                limits,
+                profile,
+            } => {
+                self.connection = Some(connection);
+                self.limits = limits;
+                self.profile = profile;
+                self.position = Position::default();
+            }
+            Navigation::To(position) => {
+                if self.connection.is_none() {
+                    return Err(String::from("not connected"));
+                }
+                if position.table.is_some() && position.schema.is_none() {
+                    return Err(String::from("a table needs a schema"));
+                }
+                self.position = position;
+            }
+        }
+        Ok(())
+    }
+
+    /// Query to run at the current position
+    pub fn next_query(&self, pagination: Option<Pagination>) -> Result<FullQuery, String> {
+        let connection = self
+            .connection
+            .clone()
+            .ok_or_else(|| String::from("not connected"))?;
+        Ok(FullQuery {
+            connection,
+            query: self.position.next_query(pagination),
+            limits: self.limits.clone(),
+            profile: self.profile.clone(),
+        })
+    }
+}
+
+/// Query run by `default_next_action` (or again from the history) and its result
+#[derive(Debug, Serialize)]
+pub struct NextAction {
+    pub query: FullQuery,
+    pub result: DatabaseQueryResult,
+}
+
+/// Sessions by window label
+#[derive(Default)]
+pub struct Sessions {
+    sessions: Mutex<HashMap<String, Session>>,
+}
+
+impl Sessions {
+    /// Session of the window, a new one if there is none yet
+    pub fn get(&self, window: &str) -> Session {
+        let sessions = self.sessions.lock().unwrap();
+        sessions.get(window).cloned().unwrap_or_default()
+    }
+
+    pub fn navigate(&self, window: &str, navigation: Navigation) -> Result<Session, String> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let session = sessions.entry(String::from(window)).or_default();
+        session.navigate(navigation)?;
+        Ok(session.clone())
+    }
+
+    /// Forget session, e.g. when its window is closed
+    pub fn remove(&self, window: &str) {
+        self.sessions.lock().unwrap().remove(window);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn position(database: Option<&str>, schema: Option<&str>, table: Option<&str>) -> Position {
+        Position {
+            database: database.map(String::from),
+            schema: schema.map(String::from),
+            table: table.map(String::from),
+        }
+    }
+
+    #[test]
+    fn next_query_follows_position() {
+        let kind = |position: Position| match position.next_query(None) {
+            Query::GetDatabases => "databases",
+            Query::GetSchemas(_) => "schemas",
+            Query::GetTables(_) => "tables",
+            Query::GetTableContents(_) => "contents",
+            Query::DescribeTable(_) => "structure",
+            Query::CustomQuery(_) => "custom",
+            Query::Explain(_) => "explain",
+        };
+        assert_eq!(kind(position(None, None, None)), "databases");
+        assert_eq!(kind(position(Some("db"), None, None)), "schemas");
+        assert_eq!(kind(position(Some("db"), Some("public"), None)), "tables");
+        assert_eq!(
+            kind(position(Some("db"), Some("public"), Some("t"))),
+            "contents"
+        );
+    }
+
+    #[test]
+    fn connecting_starts_at_the_top() {
+        let sessions = Sessions::default();
+        let to_table = || Navigation::To(position(Some("db"), Some("public"), Some("t")));
+        assert!(sessions.navigate("main", to_table()).is_err());
+
+        let connect = || Navigation::Connect {
+            connection: Connection::Stateless(String::from("host=localhost")),
+            limits: QueryLimits::default(),
+            profile: None,
+        };
+        sessions.navigate("main", connect()).unwrap();
+        sessions.navigate("main", to_table()).unwrap();
+        assert!(sessions
+            .navigate(
+                "main",
+                Navigation::To(position(Some("db"), None, Some("t")))
+            )
+            .is_err());
+        assert_eq!(
+            sessions.get("main").position,
+            position(Some("db"), Some("public"), Some("t"))
+        );
+        assert_eq!(sessions.get("other").position, Position::default());
+
+        sessions.navigate("main", connect()).unwrap();
+        assert_eq!(sessions.get("main").position, Position::default());
+        sessions.remove("main");
+        assert!(sessions.get("main").connection.is_none());
+    }
+}
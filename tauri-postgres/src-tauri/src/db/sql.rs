@@ -0,0 +1,726 @@
+//! Building SQL from names and values given by the user
+//!
+//! Names (databases, schemas, tables, columns) are quoted as identifiers,
+//! values are sent as parameters. Nothing given by the user becomes part
+//! of the SQL text unquoted.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use super::types::{DatabaseTable, Pagination};
+
+/// Quote identifier (schema, table, column) for use in SQL
+pub fn quote_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+/// Quote text as SQL string literal
+///
+/// Backslashes are escaped as well, so the literal is the same
+/// whatever `standard_conforming_strings` is set to.
+pub fn quote_literal(text: &str) -> String {
+    let quoted = text.replace('\'', "''");
+    if quoted.contains('\\') {
+        format!("E'{}'", quoted.replace('\\', "\\\\"))
+    } else {
+        format!("'{}'", quoted)
+    }
+}
+
+/// Table name qualified by its schema, quoted for use in SQL
+pub fn qualified_name(schema: &str, table: &str) -> String {
+    format!("{}.{}", quote_identifier(schema), quote_identifier(table))
+}
+
+/// SQL text with its parameters
+#[derive(Debug, Clone, PartialEq)]
+pub struct SqlStatement {
+    /// SQL with placeholders ($1, $2, ...)
+    pub text: String,
+    pub params: Vec<String>,
+    /// Values given by the user for the placeholders of a custom statement, None is NULL
+    ///
+    /// Unlike `params` they are converted to the types the prepared statement expects.
+    pub values: Vec<Option<String>>,
+    /// SQL with the parameters as literals, to be shown to the user
+    pub display: String,
+}
+
+impl SqlStatement {
+    /// Statement given by the user as it is, without parameters
+    pub fn verbatim(sql: &str) -> SqlStatement {
+        SqlStatement {
+            text: String::from(sql),
+            params: Vec::new(),
+            values: Vec::new(),
+            display: String::from(sql),
+        }
+    }
+
+    /// Statement given by the user with values for its placeholders
+    pub fn with_values(sql: &str, values: &[Option<String>]) -> SqlStatement {
+        SqlStatement {
+            values: values.to_vec(),
+            ..SqlStatement::verbatim(sql)
+        }
+    }
+}
+
+/// Assembles a statement piece by piece
+#[derive(Default)]
+pub struct SqlBuilder {
+    text: String,
+    params: Vec<String>,
+    display: String,
+}
+
+impl SqlBuilder {
+    pub fn new() -> SqlBuilder {
+        SqlBuilder::default()
+    }
+
+    /// Append SQL as it is, only for SQL written here (never for user input)
+    pub fn push(&mut self, sql: &str) -> &mut SqlBuilder {
+        self.text.push_str(sql);
+        self.display.push_str(sql);
+        self
+    }
+
+    pub fn push_identifier(&mut self, name: &str) -> &mut SqlBuilder {
+        self.push(&quote_identifier(name))
+    }
+
+    /// Append identifiers separated by commas
+    pub fn push_identifiers(&mut self, names: &[String]) -> &mut SqlBuilder {
+        for (index, name) in names.iter().enumerate() {
+            if index > 0 {
+                self.push(", ");
+            }
+            self.push_identifier(name);
+        }
+        self
+    }
+
+    pub fn push_qualified_name(&mut self, schema: &str, table: &str) -> &mut SqlBuilder {
+        self.push(&qualified_name(schema, table))
+    }
+
+    /// Append value as parameter
+    pub fn push_param(&mut self, value: &str) -> &mut SqlBuilder {
+        self.params.push(String::from(value));
+        self.text.push_str(&format!("${}", self.params.len()));
+        self.display.push_str(&quote_literal(value));
+        self
+    }
+
+    /// Append values as parameters separated by commas
+    pub fn push_params(&mut self, values: &[String]) -> &mut SqlBuilder {
+        for (index, value) in values.iter().enumerate() {
+            if index > 0 {
+                self.push(", ");
+            }
+            self.push_param(value);
+        }
+        self
+    }
+
+    /// Append value as parameter, NULL if there is none
+    pub fn push_value(&mut self, value: Option<&str>) -> &mut SqlBuilder {
+        match value {
+            Some(value) => self.push_param(value),
+            None => self.push("NULL"),
+        }
+    }
+
+    /// Append condition selecting the row by its key, if it still has the original values
+    ///
+    /// Original values of key columns are already covered by the key. Columns
+    /// of types without equality operator (json, point, ...) are compared as text.
+    pub fn push_row_condition(
+        &mut self,
+        key: &BTreeMap<String, String>,
+        original: &BTreeMap<String, Option<String>>,
+        text_compared: &BTreeSet<String>,
+    ) -> &mut SqlBuilder {
+        self.push(" WHERE ");
+        for (index, (column, value)) in key.iter().enumerate() {
+            if index > 0 {
+                self.push(" AND ");
+            }
+            self.push_identifier(column).push(" = ").push_param(value);
+        }
+        for (column, value) in original
+            .iter()
+            .filter(|(column, _)| !key.contains_key(*column))
+        {
+            self.push(" AND ").push_identifier(column);
+            match value {
+                Some(value) if text_compared.contains(column) => self
+                    .push("::text IS NOT DISTINCT FROM ")
+                    .push_param(value)
+                    .push("::text"),
+                Some(value) => self.push(" IS NOT DISTINCT FROM ").push_param(value),
+                None => self.push(" IS NULL"),
+            };
+        }
+        self
+    }
+
+    pub fn build(&self) -> SqlStatement {
+        SqlStatement {
+            text: self.text.clone(),
+            params: self.params.clone(),
+            values: Vec::new(),
+            display: self.display.clone(),
+        }
+    }
+}
+
+/// All databases of the server
+pub fn databases_query() -> SqlStatement {
+    SqlStatement::verbatim("SELECT datname FROM pg_catalog.pg_database ORDER BY datname;")
+}
+
+/// All schemas of the database, except those of temporary tables and toast
+pub fn schemas_query() -> SqlStatement {
+    SqlStatement::verbatim(
+        "SELECT nspname AS schema_name
+            FROM pg_catalog.pg_namespace
+            WHERE nspname <> 'pg_toast' AND nspname NOT LIKE 'pg\\_%temp\\_%'
+            ORDER BY nspname;",
+    )
+}
+
+/// Tables, views and foreign tables of a schema
+pub fn tables_query(schema: &str) -> SqlStatement {
+    let mut builder = SqlBuilder::new();
+    builder
+        .push(
+            "SELECT c.relname AS table_name
+            FROM pg_catalog.pg_class c
+            JOIN pg_catalog.pg_namespace n ON n.oid = c.relnamespace
+            WHERE c.relkind IN ('r', 'p', 'v', 'm', 'f') AND n.nspname = ",
+        )
+        .push_param(schema)
+        .push("\n            ORDER BY c.relname;");
+    builder.build()
+}
+
+/// Columns of a table with type, nullability, default, identity, generation and comment
+///
+/// Needs postgres 12 or later (generated columns).
+pub fn table_columns_query(db_and_table: &DatabaseTable) -> SqlStatement {
+    let mut builder = SqlBuilder::new();
+    builder
+        .push(
+            "SELECT a.attname AS column_name,
+                pg_catalog.format_type(a.atttypid, a.atttypmod) AS data_type,
+                NOT a.attnotnull AS nullable,
+                CASE WHEN a.attgenerated = '' THEN pg_catalog.pg_get_expr(d.adbin, d.adrelid) END AS column_default,
+                CASE a.attidentity WHEN 'a' THEN 'always' WHEN 'd' THEN 'by default' END AS identity,
+                CASE WHEN a.attgenerated <> '' THEN pg_catalog.pg_get_expr(d.adbin, d.adrelid) END AS generated,
+                pg_catalog.col_description(a.attrelid, a.attnum) AS comment
+            FROM pg_catalog.pg_attribute a
+            LEFT JOIN pg_catalog.pg_attrdef d ON d.adrelid = a.attrelid AND d.adnum = a.attnum
+            WHERE a.attrelid = ",
+        )
+        .push_param(&qualified_name(&db_and_table.schema, &db_and_table.table))
+        .push("::regclass AND a.attnum > 0 AND NOT a.attisdropped\n            ORDER BY a.attnum;");
+    builder.build()
+}
+
+/// Contents of a table, or the part of it selected by the pagination
+pub fn table_contents_query(db_and_table: &DatabaseTable) -> SqlStatement {
+    let mut builder = SqlBuilder::new();
+    builder
+        .push("SELECT * FROM ")
+        .push_qualified_name(&db_and_table.schema, &db_and_table.table);
+    match &db_and_table.pagination {
+        None => {}
+        Some(Pagination::Offset {
+            limit,
+            offset,
+            order_by,
+        }) => {
+            if !order_by.is_empty() {
+                builder.push(" ORDER BY ").push_identifiers(order_by);
+            }
+            builder.push(&format!(" LIMIT {} OFFSET {}", limit, offset));
+        }
+        Some(Pagination::Keyset {
+            limit,
+            key_columns,
+            after,
+        }) => {
+            if !key_columns.is_empty() {
+                if let Some(values) = after {
+                    builder
+                        .push(" WHERE (")
+                        .push_identifiers(key_columns)
+                        .push(") > (")
+                        .push_params(values)
+                        .push(")");
+                }
+                builder.push(" ORDER BY ").push_identifiers(key_columns);
+            }
+            builder.push(&format!(" LIMIT {}", limit));
+        }
+    }
+    builder.push(";");
+    builder.build()
+}
+
+/// Insert row, columns not given get their default
+pub fn insert_statement(
+    schema: &str,
+    table: &str,
+    values: &BTreeMap<String, Option<String>>,
+) -> SqlStatement {
+    let mut builder = SqlBuilder::new();
+    builder
+        .push("INSERT INTO ")
+        .push_qualified_name(schema, table);
+    if values.is_empty() {
+        builder.push(" DEFAULT VALUES");
+    } else {
+        builder.push(" (");
+        for (index, column) in values.keys().enumerate() {
+            if index > 0 {
+                builder.push(", ");
+            }
+            builder.push_identifier(column);
+        }
+        builder.push(") VALUES (");
+        for (index, value) in values.values().enumerate() {
+            if index > 0 {
+                builder.push(", ");
+            }
+            builder.push_value(value.as_deref());
+        }
+        builder.push(")");
+    }
+    builder.push(" RETURNING *");
+    builder.build()
+}
+
+/// Update columns of the row with the key, if it still has the original values
+pub fn update_statement(
+    schema: &str,
+    table: &str,
+    key: &BTreeMap<String, String>,
+    original: &BTreeMap<String, Option<String>>,
+    text_compared: &BTreeSet<String>,
+    changes: &BTreeMap<String, Option<String>>,
+) -> Result<SqlStatement, String> {
+    if key.is_empty() {
+        return Err(String::from("rows can only be updated by key"));
+    }
+    if original.is_empty() {
+        return Err(String::from(
+            "the values the row had when it was read are missing",
+        ));
+    }
+    if changes.is_empty() {
+        return Err(String::from("nothing to update"));
+    }
+    let mut builder = SqlBuilder::new();
+    builder
+        .push("UPDATE ")
+        .push_qualified_name(schema, table)
+        .push(" SET ");
+    for (index, (column, value)) in changes.iter().enumerate() {
+        if index > 0 {
+            builder.push(", ");
+        }
+        builder
+            .push_identifier(column)
+            .push(" = ")
+            .push_value(value.as_deref());
+    }
+    builder
+        .push_row_condition(key, original, text_compared)
+        .push(" RETURNING *");
+    Ok(builder.build())
+}
+
+/// Delete the row with the key, if it still has the original values
+///
+/// The deleted row is returned, so that a conflict shows as no row.
+pub fn delete_statement(
+    schema: &str,
+    table: &str,
+    key: &BTreeMap<String, String>,
+    original: &BTreeMap<String, Option<String>>,
+    text_compared: &BTreeSet<String>,
+) -> Result<SqlStatement, String> {
+    if key.is_empty() {
+        return Err(String::from("rows can only be deleted by key"));
+    }
+    if original.is_empty() {
+        return Err(String::from(
+            "the values the row had when it was read are missing",
+        ));
+    }
+    let mut builder = SqlBuilder::new();
+    builder
+        .push("DELETE FROM ")
+        .push_qualified_name(schema, table)
+        .push_row_condition(key, original, text_compared)
+        .push(" RETURNING *");
+    Ok(builder.build())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOSTILE_NAMES: &[&str] = &[
+        "",
+        "plain",
+        "with space",
+        "Mixed Case",
+        "quote\"inside",
+        "\"",
+        "\"\"",
+        "end\"",
+        "x\"; DROP TABLE students; --",
+        "single'quote",
+        "back\\slash",
+        "semi;colon",
+        "$1",
+        "new\nline",
+        "ünïcødé 表",
+    ];
+
+    /// Reverse of `quote_identifier`, fails for anything that is not a single identifier
+    fn unquote_identifier(quoted: &str) -> Option<String> {
+        let inner = quoted.strip_prefix('"')?.strip_suffix('"')?;
+        let mut name = String::new();
+        let mut chars = inner.chars();
+        while let Some(c) = chars.next() {
+            if c == '"' && chars.next() != Some('"') {
+                return None;
+            }
+            name.push(c);
+        }
+        Some(name)
+    }
+
+    #[test]
+    fn identifier_is_quoted() {
+        assert_eq!(quote_identifier("items"), "\"items\"");
+        assert_eq!(quote_identifier("we\"ird"), "\"we\"\"ird\"");
+        assert_eq!(quote_identifier(""), "\"\"");
+    }
+
+    #[test]
+    fn hostile_identifiers_stay_one_identifier() {
+        for name in HOSTILE_NAMES {
+            let quoted = quote_identifier(name);
+            assert_eq!(
+                unquote_identifier(&quoted).as_deref(),
+                Some(*name),
+                "{}",
+                quoted
+            );
+        }
+    }
+
+    #[test]
+    fn qualified_name_quotes_both_parts() {
+        assert_eq!(
+            qualified_name("Sales Data", "a.b"),
+            "\"Sales Data\".\"a.b\""
+        );
+        assert_eq!(
+            qualified_name("s\"", "t\"; DROP TABLE x; --"),
+            "\"s\"\"\".\"t\"\"; DROP TABLE x; --\""
+        );
+    }
+
+    #[test]
+    fn literal_is_quoted() {
+        assert_eq!(quote_literal("abc"), "'abc'");
+        assert_eq!(quote_literal("it's"), "'it''s'");
+        assert_eq!(
+            quote_literal("'; DROP TABLE x; --"),
+            "'''; DROP TABLE x; --'"
+        );
+        assert_eq!(quote_literal(""), "''");
+    }
+
+    #[test]
+    fn literal_with_backslash_uses_escape_syntax() {
+        assert_eq!(quote_literal("a\\b"), "E'a\\\\b'");
+        assert_eq!(quote_literal("\\'"), "E'\\\\'''");
+    }
+
+    #[test]
+    fn params_are_placeholders_in_text_and_literals_in_display() {
+        let mut builder = SqlBuilder::new();
+        builder
+            .push("SELECT * FROM ")
+            .push_qualified_name("public", "t")
+            .push(" WHERE (")
+            .push_identifiers(&[String::from("a"), String::from("b\"")])
+            .push(") > (")
+            .push_params(&[String::from("1"), String::from("x'; --")])
+            .push(")");
+        let statement = builder.build();
+        assert_eq!(
+            statement.text,
+            "SELECT * FROM \"public\".\"t\" WHERE (\"a\", \"b\"\"\") > ($1, $2)"
+        );
+        assert_eq!(
+            statement.params,
+            vec![String::from("1"), String::from("x'; --")]
+        );
+        assert_eq!(
+            statement.display,
+            "SELECT * FROM \"public\".\"t\" WHERE (\"a\", \"b\"\"\") > ('1', 'x''; --')"
+        );
+    }
+
+    #[test]
+    fn hostile_values_never_reach_the_sql_text() {
+        for value in HOSTILE_NAMES {
+            let mut builder = SqlBuilder::new();
+            builder.push("SELECT ").push_param(value);
+            let statement = builder.build();
+            assert_eq!(statement.text, "SELECT $1");
+            assert_eq!(statement.params, vec![String::from(*value)]);
+        }
+    }
+
+    fn table(schema: &str, table: &str, pagination: Option<Pagination>) -> DatabaseTable {
+        DatabaseTable {
+            database: None,
+            schema: String::from(schema),
+            table: String::from(table),
+            pagination,
+        }
+    }
+
+    #[test]
+    fn table_contents_of_hostile_table() {
+        let statement =
+            table_contents_query(&table("public", "x\"; DROP TABLE students; --", None));
+        assert_eq!(
+            statement.text,
+            "SELECT * FROM \"public\".\"x\"\"; DROP TABLE students; --\";"
+        );
+        assert!(statement.params.is_empty());
+    }
+
+    #[test]
+    fn table_contents_with_offset() {
+        let statement = table_contents_query(&table(
+            "Sales Data",
+            "Order's",
+            Some(Pagination::Offset {
+                limit: 100,
+                offset: 200,
+                order_by: Vec::new(),
+            }),
+        ));
+        assert_eq!(
+            statement.text,
+            "SELECT * FROM \"Sales Data\".\"Order's\" LIMIT 100 OFFSET 200;"
+        );
+    }
+
+    #[test]
+    fn table_contents_with_offset_in_order() {
+        let statement = table_contents_query(&table(
+            "public",
+            "orders",
+            Some(Pagination::Offset {
+                limit: 50,
+                offset: 0,
+                order_by: vec![String::from("region"), String::from("order id")],
+            }),
+        ));
+        assert_eq!(
+            statement.text,
+            "SELECT * FROM \"public\".\"orders\" ORDER BY \"region\", \"order id\" LIMIT 50 OFFSET 0;"
+        );
+    }
+
+    #[test]
+    fn table_contents_with_keyset_passes_values_as_params() {
+        let statement = table_contents_query(&table(
+            "public",
+            "t",
+            Some(Pagination::Keyset {
+                limit: 10,
+                key_columns: vec![String::from("id\""), String::from("name")],
+                after: Some(vec![String::from("5"), String::from("') OR 1=1 --")]),
+            }),
+        ));
+        assert_eq!(
+            statement.text,
+            "SELECT * FROM \"public\".\"t\" WHERE (\"id\"\"\", \"name\") > ($1, $2) ORDER BY \"id\"\"\", \"name\" LIMIT 10;"
+        );
+        assert_eq!(
+            statement.params,
+            vec![String::from("5"), String::from("') OR 1=1 --")]
+        );
+    }
+
+    #[test]
+    fn keyset_without_start_reads_first_page() {
+        let statement = table_contents_query(&table(
+            "public",
+            "t",
+            Some(Pagination::Keyset {
+                limit: 10,
+                key_columns: vec![String::from("id")],
+                after: None,
+            }),
+        ));
+        assert_eq!(
+            statement.text,
+            "SELECT * FROM \"public\".\"t\" ORDER BY \"id\" LIMIT 10;"
+        );
+    }
+
+    #[test]
+    fn tables_of_hostile_schema() {
+        for schema in HOSTILE_NAMES {
+            let statement = tables_query(schema);
+            assert_eq!(statement.text, tables_query("public").text);
+            assert_eq!(statement.params, vec![String::from(*schema)]);
+        }
+    }
+
+    #[test]
+    fn columns_of_hostile_table() {
+        for name in HOSTILE_NAMES {
+            let statement = table_columns_query(&table(name, name, None));
+            assert_eq!(
+                statement.text,
+                table_columns_query(&table("public", "t", None)).text
+            );
+            assert_eq!(statement.params, vec![qualified_name(name, name)]);
+        }
+    }
+
+    #[test]
+    fn verbatim_statement_has_no_params() {
+        let statement = SqlStatement::verbatim("SELECT '$1'");
+        assert_eq!(statement.text, "SELECT '$1'");
+        assert_eq!(statement.display, "SELECT '$1'");
+        assert!(statement.params.is_empty());
+    }
+
+    fn values(pairs: &[(&str, Option<&str>)]) -> BTreeMap<String, Option<String>> {
+        pairs
+            .iter()
+            .map(|(column, value)| (String::from(*column), value.map(String::from)))
+            .collect()
+    }
+
+    fn key(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs
+            .iter()
+            .map(|(column, value)| (String::from(*column), String::from(*value)))
+            .collect()
+    }
+
+    #[test]
+    fn insert_passes_values_as_params() {
+        let statement = insert_statement(
+            "s",
+            "t",
+            &values(&[("b", None), ("a", Some("x'); DROP TABLE t; --"))]),
+        );
+        assert_eq!(
+            statement.text,
+            "INSERT INTO \"s\".\"t\" (\"a\", \"b\") VALUES ($1, NULL) RETURNING *"
+        );
+        assert_eq!(
+            statement.params,
+            vec![String::from("x'); DROP TABLE t; --")]
+        );
+        assert_eq!(
+            insert_statement("s", "t", &values(&[])).text,
+            "INSERT INTO \"s\".\"t\" DEFAULT VALUES RETURNING *"
+        );
+    }
+
+    #[test]
+    fn update_checks_original_values() {
+        let statement = update_statement(
+            "s",
+            "t",
+            &key(&[("id", "7")]),
+            &values(&[("name", Some("old")), ("note", None)]),
+            &BTreeSet::new(),
+            &values(&[("name", Some("new")), ("note", None)]),
+        )
+        .unwrap();
+        assert_eq!(
+            statement.text,
+            "UPDATE \"s\".\"t\" SET \"name\" = $1, \"note\" = NULL \
+             WHERE \"id\" = $2 AND \"name\" IS NOT DISTINCT FROM $3 AND \"note\" IS NULL RETURNING *"
+        );
+        assert_eq!(
+            statement.params,
+            vec![String::from("new"), String::from("7"), String::from("old")]
+        );
+        let original = values(&[("a", Some("1"))]);
+        let none = BTreeSet::new();
+        assert!(update_statement(
+            "s",
+            "t",
+            &key(&[]),
+            &original,
+            &none,
+            &values(&[("a", None)])
+        )
+        .is_err());
+        assert!(update_statement(
+            "s",
+            "t",
+            &key(&[("id", "7")]),
+            &original,
+            &none,
+            &values(&[])
+        )
+        .is_err());
+        assert!(update_statement(
+            "s",
+            "t",
+            &key(&[("id", "7")]),
+            &values(&[]),
+            &none,
+            &values(&[("a", None)])
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn delete_needs_a_key() {
+        let statement = delete_statement(
+            "s",
+            "t",
+            &key(&[("a", "1"), ("b", "2")]),
+            &values(&[("a", Some("1")), ("b", Some("2"))]),
+            &BTreeSet::new(),
+        )
+        .unwrap();
+        assert_eq!(
+            statement.text,
+            "DELETE FROM \"s\".\"t\" WHERE \"a\" = $1 AND \"b\" = $2 RETURNING *"
+        );
+        let original = values(&[("a", Some("1"))]);
+        assert!(delete_statement("s", "t", &key(&[]), &original, &BTreeSet::new()).is_err());
+        assert!(delete_statement(
+            "s",
+            "t",
+            &key(&[("a", "1")]),
+            &values(&[]),
+            &BTreeSet::new()
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,183 @@
+//! Transactions kept open across queries, at most one per window
+//!
+//! Beginning a transaction takes a client from the pool and keeps it until
+//! the transaction is committed or rolled back. Meanwhile the custom queries
+//! of the window run on that client, one after the other. Navigation keeps
+//! using the pool, it does not see uncommitted changes.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use super::pool::{ConnectionPool, PooledClient};
+use super::script;
+use super::types::{DatabaseError, ErrorDetails, QueryLimits, SomeDatabase};
+use super::{execute_error, reset_statement_timeout, set_statement_timeout};
+
+/// State of the transaction of a window, as shown to the user
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct TransactionState {
+    pub open: bool,
+    /// A statement failed, postgres ignores everything but a rollback now
+    pub failed: bool,
+    pub database: Option<SomeDatabase>,
+}
+
+pub struct HeldTransaction {
+    /// None once the transaction has ended
+    client: Option<PooledClient>,
+    connection_str: String,
+    database: Option<SomeDatabase>,
+    limits: QueryLimits,
+    failed: bool,
+}
+
+impl HeldTransaction {
+    /// Client to run a query on, if the query is for the transaction's database
+    pub fn client(&mut self, connection_str: &str) -> Result<&mut PooledClient, DatabaseError> {
+        if self.connection_str != connection_str {
+            return Err(transaction_error(
+                "the open transaction is on another connection or database",
+            ));
+        }
+        self.client
+            .as_mut()
+            .ok_or_else(|| transaction_error("the transaction has ended"))
+    }
+
+    /// Remember whether the last statement failed
+    ///
+    /// After a failure only ROLLBACK (TO SAVEPOINT) succeeds, so any
+    /// success means the transaction is usable again.
+    pub fn record_outcome(&mut self, succeeded: bool) {
+        self.failed = !succeeded;
+    }
+
+    fn state(&self) -> TransactionState {
+        TransactionState {
+            open: self.client.is_some(),
+            failed: self.failed,
+            database: self.database.clone(),
+        }
+    }
+}
+
+fn transaction_error(message: &str) -> DatabaseError {
+    DatabaseError::Execute(ErrorDetails::from_message(String::from(message)))
+}
+
+/// Refuse statement that would begin or end the transaction behind its back
+///
+/// The state shown to the user would be wrong then, the transaction is ended by commit or rollback instead.
+pub fn refuse_transaction_control(statement: &str) -> Result<(), DatabaseError> {
+    if script::controls_transaction(statement) {
+        return Err(transaction_error(
+            "statements beginning or ending a transaction cannot run in the open transaction, \
+            commit or roll it back instead",
+        ));
+    }
+    Ok(())
+}
+
+/// Open transactions by window label
+#[derive(Default)]
+pub struct Transactions {
+    held: Mutex<HashMap<String, Arc<tokio::sync::Mutex<HeldTransaction>>>>,
+}
+
+impl Transactions {
+    /// Open transaction of the window
+    pub fn get(&self, window: &str) -> Option<Arc<tokio::sync::Mutex<HeldTransaction>>> {
+        self.held.lock().unwrap().get(window).cloned()
+    }
+
+    pub async fn state(&self, window: &str) -> TransactionState {
+        match self.get(window) {
+            Some(held) => held.lock().await.state(),
+            None => TransactionState::default(),
+        }
+    }
+
+    /// Take client from the pool and begin a transaction on it
+    ///
+    /// The client does not take one of the slots of the pool, so that open
+    /// transactions never block navigating the connection.
+    /// The statement timeout applies to every statement of the transaction.
+    pub async fn begin(
+        &self,
+        pool: &ConnectionPool,
+        window: &str,
+        connection_str: String,
+        database: Option<SomeDatabase>,
+        limits: QueryLimits,
+    ) -> Result<TransactionState, DatabaseError> {
+        if self.get(window).is_some() {
+            return Err(transaction_error("there is an open transaction already"));
+        }
+        let client = pool.acquire_unlimited(&connection_str).await?;
+        // set before BEGIN, so that a rollback does not undo it
+        set_statement_timeout(&client, &limits).await?;
+        client.batch_execute("BEGIN").await.map_err(execute_error)?;
+
+        let held = HeldTransaction {
+            client: Some(client),
+            connection_str,
+            database,
+            limits,
+            failed: false,
+        };
+        let state = held.state();
+        let mut transactions = self.held.lock().unwrap();
+        if transactions.contains_key(window) {
+            // began concurrently, the client is closed and its transaction with it
+            return Err(transaction_error("there is an open transaction already"));
+        }
+        transactions.insert(
+            String::from(window),
+            Arc::new(tokio::sync::Mutex::new(held)),
+        );
+        Ok(state)
+    }
+
+    /// Commit or roll back the transaction of the window, waiting for a running query
+    ///
+    /// Committing a failed transaction rolls it back, which is reported as error.
+    pub async fn end(
+        &self,
+        pool: &ConnectionPool,
+        window: &str,
+        commit: bool,
+    ) -> Result<TransactionState, DatabaseError> {
+        let held = self.held.lock().unwrap().remove(window);
+        let held = match held {
+            Some(held) => held,
+            None => return Err(transaction_error("there is no open transaction")),
+        };
+        let mut held = held.lock().await;
+        let mut client = match held.client.take() {
+            Some(client) => client,
+            None => return Err(transaction_error("the transaction has ended")),
+        };
+        let failed = held.failed;
+        let statement = if commit && !failed {
+            "COMMIT"
+        } else {
+            "ROLLBACK"
+        };
+        println!("Ending transaction of window \"{}\": {}", window, statement);
+        // on error the client is dropped, which closes its connection
+        client
+            .batch_execute(statement)
+            .await
+            .map_err(execute_error)?;
+        reset_statement_timeout(&client, &held.limits).await;
+        client.mark_session_changed();
+        pool.release(client).await;
+        if commit && failed {
+            return Err(transaction_error(
+                "the transaction failed and was rolled back",
+            ));
+        }
+        Ok(TransactionState::default())
+    }
+}
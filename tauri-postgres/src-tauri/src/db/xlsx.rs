@@ -0,0 +1,401 @@
+//! Minimal XLSX writer: a single worksheet, written row by row
+//!
+//! An XLSX file is a zip archive of XML parts. The worksheet is compressed
+//! while its rows arrive. The checksum and sizes of each part follow its
+//! data (zip data descriptors), so nothing is held back or rewritten.
+//!
+//! Strings are stored in their cells, there is no shared string table and
+//! no styling. Without zip64 the archive is limited to 4 GiB.
+
+use flate2::write::DeflateEncoder;
+use flate2::{Compression, Crc};
+use std::io::{self, Write};
+
+/// Most rows a worksheet can have
+pub const MAX_ROWS: u64 = 1_048_576;
+
+/// 1980-01-01, the earliest date zip can store
+const DOS_DATE: u16 = (1 << 5) | 1;
+/// Sizes and checksum follow the data
+const FLAG_DATA_DESCRIPTOR: u16 = 0x0008;
+const METHOD_DEFLATE: u16 = 8;
+const VERSION: u16 = 20;
+
+const CONTENT_TYPES: &str = concat!(
+    r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>"#,
+    r#"<Types xmlns="http://schemas.openxmlformats.org/package/2006/content-types">"#,
+    r#"<Default Extension="rels" ContentType="application/vnd.openxmlformats-package.relationships+xml"/>"#,
+    r#"<Default Extension="xml" ContentType="application/xml"/>"#,
+    r#"<Override PartName="/xl/workbook.xml" "#,
+    r#"ContentType="application/vnd.openxmlformats-officedocument.spreadsheetml.sheet.main+xml"/>"#,
+    r#"<Override PartName="/xl/worksheets/sheet1.xml" "#,
+    r#"ContentType="application/vnd.openxmlformats-officedocument.spreadsheetml.worksheet+xml"/>"#,
+    r#"</Types>"#
+);
+
+const ROOT_RELS: &str = concat!(
+    r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>"#,
+    r#"<Relationships xmlns="http://schemas.openxmlformats.org/package/2006/relationships">"#,
+    r#"<Relationship Id="rId1" "#,
+    r#"Type="http://schemas.openxmlformats.org/officeDocument/2006/relationships/officeDocument" "#,
+    r#"Target="xl/workbook.xml"/>"#,
+    r#"</Relationships>"#
+);
+
+const WORKBOOK: &str = concat!(
+    r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>"#,
+    r#"<workbook xmlns="http://schemas.openxmlformats.org/spreadsheetml/2006/main" "#,
+    r#"xmlns:r="http://schemas.openxmlformats.org/officeDocument/2006/relationships">"#,
+    r#"<sheets><sheet name="Export" sheetId="1" r:id="rId1"/></sheets>"#,
+    r#"</workbook>"#
+);
+
+const WORKBOOK_RELS: &str = concat!(
+    r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>"#,
+    r#"<Relationships xmlns="http://schemas.openxmlformats.org/package/2006/relationships">"#,
+    r#"<Relationship Id="rId1" "#,
+    r#"Type="http://schemas.openxmlformats.org/officeDocument/2006/relationships/worksheet" "#,
+    r#"Target="worksheets/sheet1.xml"/>"#,
+    r#"</Relationships>"#
+);
+
+const SHEET_START: &str = concat!(
+    r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>"#,
+    r#"<worksheet xmlns="http://schemas.openxmlformats.org/spreadsheetml/2006/main"><sheetData>"#
+);
+
+const SHEET_END: &str = "</sheetData></worksheet>";
+
+/// Counts the bytes written, for the offsets zip needs
+struct CountingWriter<W> {
+    inner: W,
+    count: u64,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.count += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn too_large() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Other,
+        "export exceeds the 4 GiB an XLSX file can have",
+    )
+}
+
+fn to_u32(value: u64) -> io::Result<u32> {
+    u32::try_from(value).map_err(|_| too_large())
+}
+
+struct ZipEntry {
+    name: &'static str,
+    crc: u32,
+    compressed_size: u32,
+    size: u32,
+    offset: u32,
+}
+
+struct OpenEntry<W: Write> {
+    name: &'static str,
+    offset: u64,
+    data_start: u64,
+    crc: Crc,
+    size: u64,
+    encoder: DeflateEncoder<CountingWriter<W>>,
+}
+
+/// Zip archive written front to back, one entry at a time
+struct ZipWriter<W: Write> {
+    /// None while an entry is open, its encoder has the writer then
+    out: Option<CountingWriter<W>>,
+    open: Option<OpenEntry<W>>,
+    entries: Vec<ZipEntry>,
+}
+
+impl<W: Write> ZipWriter<W> {
+    fn new(inner: W) -> ZipWriter<W> {
+        ZipWriter {
+            out: Some(CountingWriter { inner, count: 0 }),
+            open: None,
+            entries: Vec::new(),
+        }
+    }
+
+    fn start_entry(&mut self, name: &'static str) -> io::Result<()> {
+        let mut out = self.out.take().expect("previous entry was finished");
+        let offset = out.count;
+        out.write_all(&0x0403_4b50u32.to_le_bytes())?;
+        for field in [VERSION, FLAG_DATA_DESCRIPTOR, METHOD_DEFLATE, 0, DOS_DATE] {
+            out.write_all(&field.to_le_bytes())?;
+        }
+        // checksum and sizes follow the data
+        out.write_all(&[0; 12])?;
+        out.write_all(&(name.len() as u16).to_le_bytes())?;
+        out.write_all(&0u16.to_le_bytes())?;
+        out.write_all(name.as_bytes())?;
+        self.open = Some(OpenEntry {
+            name,
+            offset,
+            data_start: out.count,
+            crc: Crc::new(),
+            size: 0,
+            encoder: DeflateEncoder::new(out, Compression::default()),
+        });
+        Ok(())
+    }
+
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        let open = self.open.as_mut().expect("entry was started");
+        open.crc.update(data);
+        open.size += data.len() as u64;
+        open.encoder.write_all(data)
+    }
+
+    fn finish_entry(&mut self) -> io::Result<()> {
+        let open = self.open.take().expect("entry was started");
+        let mut out = open.encoder.finish()?;
+        let entry = ZipEntry {
+            name: open.name,
+            crc: open.crc.sum(),
+            compressed_size: to_u32(out.count - open.data_start)?,
+            size: to_u32(open.size)?,
+            offset: to_u32(open.offset)?,
+        };
+        out.write_all(&0x0807_4b50u32.to_le_bytes())?;
+        for field in [entry.crc, entry.compressed_size, entry.size] {
+            out.write_all(&field.to_le_bytes())?;
+        }
+        self.entries.push(entry);
+        self.out = Some(out);
+        Ok(())
+    }
+
+    fn add_entry(&mut self, name: &'static str, data: &str) -> io::Result<()> {
+        self.start_entry(name)?;
+        self.write(data.as_bytes())?;
+        self.finish_entry()
+    }
+
+    /// Write central directory, returns the underlying writer
+    fn finish(mut self) -> io::Result<W> {
+        let mut out = self.out.take().expect("last entry was finished");
+        let directory_start = out.count;
+        for entry in self.entries.iter() {
+            out.write_all(&0x0201_4b50u32.to_le_bytes())?;
+            for field in [
+                VERSION,
+                VERSION,
+                FLAG_DATA_DESCRIPTOR,
+                METHOD_DEFLATE,
+                0,
+                DOS_DATE,
+            ] {
+                out.write_all(&field.to_le_bytes())?;
+            }
+            for field in [entry.crc, entry.compressed_size, entry.size] {
+                out.write_all(&field.to_le_bytes())?;
+            }
+            // name length, extra field, comment, disk, internal attributes
+            for field in [entry.name.len() as u16, 0, 0, 0, 0] {
+                out.write_all(&field.to_le_bytes())?;
+            }
+            // external attributes
+            out.write_all(&0u32.to_le_bytes())?;
+            out.write_all(&entry.offset.to_le_bytes())?;
+            out.write_all(entry.name.as_bytes())?;
+        }
+        let directory_size = to_u32(out.count - directory_start)?;
+        let entries = self.entries.len() as u16;
+        out.write_all(&0x0605_4b50u32.to_le_bytes())?;
+        for field in [0, 0, entries, entries] {
+            out.write_all(&field.to_le_bytes())?;
+        }
+        out.write_all(&directory_size.to_le_bytes())?;
+        out.write_all(&to_u32(directory_start)?.to_le_bytes())?;
+        out.write_all(&0u16.to_le_bytes())?;
+        out.flush()?;
+        Ok(out.inner)
+    }
+}
+
+/// Cell of the worksheet
+pub enum Cell<'a> {
+    Empty,
+    /// Number in the form XML schema expects it (e.g. "-1.5", "2E10")
+    Number(&'a str),
+    Bool(bool),
+    Text(&'a str),
+}
+
+/// Column name as shown by spreadsheets: A ... Z, AA ... AZ, BA ...
+fn column_name(index: usize) -> String {
+    let mut name = Vec::new();
+    let mut rest = index + 1;
+    while rest > 0 {
+        rest -= 1;
+        name.push(b'A' + (rest % 26) as u8);
+        rest /= 26;
+    }
+    name.reverse();
+    String::from_utf8(name).expect("letters only")
+}
+
+/// Escape text for XML, characters XML does not allow are left out
+fn escape_xml(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\t' | '\n' | '\r' => escaped.push(c),
+            c if c < ' ' || c == '\u{fffe}' || c == '\u{ffff}' => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Workbook with a single worksheet, rows are written as they come
+pub struct XlsxWriter<W: Write> {
+    zip: ZipWriter<W>,
+    rows: u64,
+}
+
+impl<W: Write> XlsxWriter<W> {
+    pub fn new(out: W) -> io::Result<XlsxWriter<W>> {
+        let mut zip = ZipWriter::new(out);
+        zip.add_entry("[Content_Types].xml", CONTENT_TYPES)?;
+        zip.add_entry("_rels/.rels", ROOT_RELS)?;
+        zip.add_entry("xl/workbook.xml", WORKBOOK)?;
+        zip.add_entry("xl/_rels/workbook.xml.rels", WORKBOOK_RELS)?;
+        zip.start_entry("xl/worksheets/sheet1.xml")?;
+        zip.write(SHEET_START.as_bytes())?;
+        Ok(XlsxWriter { zip, rows: 0 })
+    }
+
+    pub fn write_row(&mut self, cells: &[Cell]) -> io::Result<()> {
+        if self.rows >= MAX_ROWS {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("a worksheet has at most {} rows", MAX_ROWS),
+            ));
+        }
+        self.rows += 1;
+        let mut xml = format!("<row r=\"{}\">", self.rows);
+        for (index, cell) in cells.iter().enumerate() {
+            let reference = format!("{}{}", column_name(index), self.rows);
+            match cell {
+                Cell::Empty => {}
+                Cell::Number(number) => {
+                    xml.push_str(&format!("<c r=\"{}\"><v>{}</v></c>", reference, number))
+                }
+                Cell::Bool(value) => xml.push_str(&format!(
+                    "<c r=\"{}\" t=\"b\"><v>{}</v></c>",
+                    reference, *value as u8
+                )),
+                Cell::Text(text) => xml.push_str(&format!(
+                    "<c r=\"{}\" t=\"inlineStr\"><is><t xml:space=\"preserve\">{}</t></is></c>",
+                    reference,
+                    escape_xml(text)
+                )),
+            }
+        }
+        xml.push_str("</row>");
+        self.zip.write(xml.as_bytes())
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        self.zip.write(SHEET_END.as_bytes())?;
+        self.zip.finish_entry()?;
+        self.zip.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::DeflateDecoder;
+    use std::io::Read;
+
+    #[test]
+    fn column_names() {
+        let names: Vec<String> = [0, 1, 25, 26, 27, 51, 52, 701, 702]
+            .iter()
+            .map(|i| column_name(*i))
+            .collect();
+        assert_eq!(
+            names,
+            vec!["A", "B", "Z", "AA", "AB", "AZ", "BA", "ZZ", "AAA"]
+        );
+    }
+
+    #[test]
+    fn xml_is_escaped() {
+        assert_eq!(
+            escape_xml("a<b & \"c\">\u{1}\td"),
+            "a&lt;b &amp; &quot;c&quot;&gt;\td"
+        );
+    }
+
+    fn u16_at(data: &[u8], at: usize) -> u16 {
+        u16::from_le_bytes([data[at], data[at + 1]])
+    }
+
+    fn u32_at(data: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]])
+    }
+
+    #[test]
+    fn worksheet_can_be_read_back() {
+        let mut writer = XlsxWriter::new(Vec::new()).unwrap();
+        writer
+            .write_row(&[Cell::Text("id"), Cell::Text("name")])
+            .unwrap();
+        writer
+            .write_row(&[Cell::Number("1"), Cell::Empty, Cell::Bool(true)])
+            .unwrap();
+        let archive = writer.finish().unwrap();
+
+        // end of central directory, then the last entry of the directory
+        let end = archive.len() - 22;
+        assert_eq!(u32_at(&archive, end), 0x0605_4b50);
+        assert_eq!(u16_at(&archive, end + 10), 5);
+        let directory = u32_at(&archive, end + 16) as usize;
+        let mut entry = directory;
+        for _ in 0..4 {
+            entry += 46 + u16_at(&archive, entry + 28) as usize;
+        }
+        assert_eq!(
+            &archive[entry + 46..entry + 46 + 24],
+            b"xl/worksheets/sheet1.xml"
+        );
+        let crc = u32_at(&archive, entry + 16);
+        let compressed_size = u32_at(&archive, entry + 20) as usize;
+        let offset = u32_at(&archive, entry + 42) as usize;
+
+        let data_start = offset + 30 + u16_at(&archive, offset + 26) as usize;
+        let mut sheet = String::new();
+        DeflateDecoder::new(&archive[data_start..data_start + compressed_size])
+            .read_to_string(&mut sheet)
+            .unwrap();
+        let mut check = Crc::new();
+        check.update(sheet.as_bytes());
+        assert_eq!(check.sum(), crc);
+        assert!(sheet.contains(concat!(
+            r#"<row r="2"><c r="A2"><v>1</v></c><c r="C2" t="b"><v>1</v></c></row>"#,
+            "</sheetData></worksheet>"
+        )));
+        assert!(sheet
+            .contains(r#"<c r="B1" t="inlineStr"><is><t xml:space="preserve">name</t></is></c>"#));
+    }
+}
@@ -3,6 +3,8 @@
 
 // See https://rfdonnelly.github.io/posts/tauri-async-rust-process/
 
+use std::sync::Arc;
+use tauri::WindowEvent;
 use tokio::sync::mpsc;
 
 mod db;
@@ -17,20 +19,64 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send>> {
     // The frontend function call will send a message on a channel,
     // and use the result.
 
-    let (channel_to_tauri_tx, channel_to_tauri_rx) =
-        mpsc::channel::<db::types::DatabaseQueryResult>(1);
-    let (channel_to_db_tx, channel_to_db_rx) = mpsc::channel::<db::types::FullQuery>(1);
+    // Each query carries its own reply channel, see db::types::DbRequest.
+    // Queries run concurrently and can be cancelled, see db::types::DbMessage.
 
-    tokio::spawn(db::db_task(channel_to_db_rx, channel_to_tauri_tx));
+    let (channel_to_db_tx, channel_to_db_rx) = mpsc::channel::<db::types::DbMessage>(1);
+
+    // Opened once the app's data directory is known, see setup below
+    let history = Arc::new(db::History::default());
+
+    tokio::spawn(db::db_task(channel_to_db_rx, history.clone()));
 
     tauri::async_runtime::set(tokio::runtime::Handle::current());
     let res = tauri::Builder::default()
         .manage(db::types::StateHalfpipeToDb::from(channel_to_db_tx))
-        .manage(db::types::StateHalfpipeToTauri::from(channel_to_tauri_rx))
+        .manage(db::Sessions::default())
+        .manage(history.clone())
+        .setup(move |app| {
+            if let Err(message) = db::history_dir(&app.handle()).and_then(|dir| history.open(&dir))
+            {
+                println!("Query history not available: {}", message);
+            }
+            Ok(())
+        })
+        .on_window_event(|event| {
+            if let WindowEvent::Destroyed = event.event() {
+                db::window_closed(event.window());
+            }
+        })
         .invoke_handler(tauri::generate_handler![
+            db::commands::begin_transaction,
+            db::commands::cancel_query,
+            db::commands::clear_history,
+            db::commands::commit_transaction,
+            db::commands::create_profile,
+            db::commands::default_next_action,
+            db::commands::delete_history_entries,
+            db::commands::delete_profile,
+            db::commands::describe_connection,
             db::commands::db_query,
+            db::commands::db_run_script,
+            db::commands::db_stream_query,
+            db::commands::duplicate_profile,
+            db::commands::edit_rows,
+            db::commands::export_query,
+            db::commands::get_session,
+            db::commands::get_transaction_state,
+            db::commands::import_file,
+            db::commands::list_history,
+            db::commands::list_profiles,
+            db::commands::navigate_to,
+            db::commands::prepare_query,
+            db::commands::preview_import,
+            db::commands::rerun_history_entry,
+            db::commands::rollback_transaction,
             db::commands::suggest_query,
-            db::commands::test_connection_string
+            db::commands::test_connection,
+            db::commands::test_connection_string,
+            db::commands::update_profile,
+            db::commands::use_profile
         ])
         .run(tauri::generate_context!());
 
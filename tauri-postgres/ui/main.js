@@ -3,12 +3,46 @@
  */
 
 const { invoke } = window.__TAURI__.tauri
+const { listen } = window.__TAURI__.event
+
+// Rows per page when browsing table contents
+const PAGE_SIZE = 100;
+// Rows per event when streaming table contents
+const STREAM_BATCH_SIZE = 500;
 
 let dbTable;
+let dbDetails;
 let breadcrumbs;
-let globalConnectionString;
+let globalConnection;
+// Profile the connection was taken from, null if entered directly
+let globalProfile = null;
+let globalQueryLimits = {};
 let customSqlQuery;
 let customDatabase;
+let paging;
+let currentPage = null;
+let streamCounter = 0;
+let exportCounter = 0;
+let importCounter = 0;
+let queryCounter = 0;
+// Ids of queries (and streams) that have not returned yet
+let runningQueryIds = new Set();
+let cancelButton;
+let editing;
+let transactionControls;
+let scriptControls;
+let paramControls;
+let exportControls;
+let importControls;
+let historyControls;
+// Preview of the file to import, see previewImport()
+let importPreview = null;
+// Query of the result shown, if its rows can be exported
+let exportableQuery = null;
+// Table contents shown, with the query that read them (null for other results)
+let shownTableContents = null;
+// Rows being edited, see startEditing()
+let editState = null;
 
 /* Hints for Debugging */
 
@@ -23,7 +57,7 @@ invoke("db_query",{ query: query})
 /* Query Types */
 
 // Query: backend request to database as defined by rust backend
-//        Examples: GetDatabases, GetTables, CustomQuery
+//        Examples: GetDatabases, GetSchemas, GetTables, CustomQuery
 //
 //        For instance:
 //        
@@ -40,40 +74,90 @@ invoke("db_query",{ query: query})
 //
 //        or
 //
-//        { "GetTables" : "mydatabase" }
+//        { "GetSchemas" : "mydatabase" }
+//
+//        or
+//
+//        { "GetTables" : { "database": "mydatabase", "schema": "public" } }
+//
+//        or
+//
+//        { "GetTableContents" : {
+//            "database": "mydatabase",
+//            "schema": "public",
+//            "table": "mytable",
+//            "pagination": { "Offset": { "limit": 100, "offset": 0 } }
+//        }}
 //
-// Path: [Database], [Path]
-//        Query can be created from this
+// Path: [Database], [Schema], [Table]
+//        Kept per window by the backend, which creates the query for it
+//        (see db/session.rs)
+//
+//       { "To": { "database": "mydatabase", "schema": "public", "table": null } }
 //
 // Connection: determines which database to connect to
-//        Currently is simply a string,
-//        contains (host, port, user, password)
+//        Either a string (keyword/value pairs or postgres:// URI)
 //
 //       {
 //         Stateless: connection_string
 //       }
 //
-// FullQuery: Query + Connection
+//        or single parameters, null ones are left out of the connection
+//
+//       {
+//         Params: {
+//           "host": "localhost", "port": "5432", "user": "me", "password": null,
+//           "dbname": null, "application_name": null, "sslmode": "prefer",
+//           "connect_timeout": 10, "extras": { "sslrootcert": "ca.crt" }
+//         }
+//       }
+//
+// Limits: enforced by the server, null means no limit
+//
+//       {
+//         "statement_timeout_ms": 30000,
+//         "max_rows": 10000
+//       }
+//
+// FullQuery: Query + Connection (+ Limits)
 //
 //       {
 //         "connection": <some connection>,
-//         "query": <query>
+//         "query": <query>,
+//         "limits": <limits>
 //       }
 //
 // Definitive: db.rs
 
-function getConnectionStringFromFullQuery(stateless_query) {
-  return stateless_query["connection"]["Stateless"];
+function getConnectionFromFullQuery(fullQuery) {
+  return fullQuery["connection"];
 }
 
 /* Common */
 
-function useConnectionString(connection_string) {
-  globalConnectionString = connection_string;
+function useConnection(connection) {
+  globalConnection = connection;
+}
+
+function getGlobalConnection() {
+  return globalConnection;
 }
 
-function getGlobalConnectionString() {
-  return globalConnectionString;
+function useProfile(profile) {
+  globalProfile = profile;
+}
+
+function getGlobalProfileName() {
+  return globalProfile ? globalProfile.name : null;
+}
+
+// Limits belong to the connection in use, they are set together with it
+function useQueryLimits(limits) {
+  globalQueryLimits = limits;
+}
+
+function getGlobalQueryLimits() {
+  return globalQueryLimits;
 }
 
 function onEnterRun(event, f) {
@@ -85,6 +169,25 @@ function onEnterRun(event, f) {
   }
 }
 
+// TLS state as defined by rust backend, e.g.
+//
+//   { sslmode: "verify-full", encrypted: true, version: "TLSv1.3", cipher: "TLS_AES_256_GCM_SHA384" }
+//
+function describeTls(tls) {
+  if (tls === null) {
+    return "TLS unknown";
+  }
+  let text;
+  if (tls.encrypted === true) {
+    text = "TLS " + tls.version + ", " + tls.cipher;
+  } else if (tls.encrypted === false) {
+    text = "unencrypted";
+  } else {
+    text = "encryption unknown";
+  }
+  return text + " (sslmode " + tls.sslmode + ")";
+}
+
 function InformStatus(message) {
   let s = document.querySelector("#statusbar");
   s.textContent = message;
@@ -92,59 +195,64 @@ function InformStatus(message) {
 
 /* DB Query (including custom query) */
 
+// Remember query as running, the cancel button is shown while any query runs
+function startRunningQuery(queryId) {
+  runningQueryIds.add(queryId);
+  cancelButton.classList.remove("hidden");
+}
+
+function stopRunningQuery(queryId) {
+  runningQueryIds.delete(queryId);
+  if (runningQueryIds.size === 0) {
+    cancelButton.classList.add("hidden");
+  }
+}
+
+function cancelRunningQueries() {
+  runningQueryIds.forEach((queryId) => {
+    invoke("cancel_query", { queryId: queryId })
+      .catch((error) => { console.log("Cancel failed: " + JSON.stringify(error)) });
+  });
+}
+
+function isCancelled(databaseError) {
+  return databaseError.hasOwnProperty("Cancelled");
+}
+
 function initVariablesDbQuery() {
   dbTable = document.querySelector("#db-table table");
+  dbDetails = document.querySelector("#db-details");
   breadcrumbs = document.querySelector("#breadcrumbs");
   customSqlQuery = document.querySelector(".custom-sql .sql-query");
   customDatabase = document.querySelector(".custom-sql .database");
+  paging = document.querySelector("#paging");
+  cancelButton = document.querySelector(".query-cancel");
+  editing = document.querySelector("#editing");
+  transactionControls = document.querySelector("#transaction");
+  scriptControls = document.querySelector("#script");
+  paramControls = document.querySelector("#query-params");
+  exportControls = document.querySelector("#export");
+  importControls = document.querySelector("#import");
+  historyControls = document.querySelector("#history");
 }
 
-function createCustomQuery(database, sqlQuery)
+function createCustomQuery(database, sqlQuery, params = [])
 {
   return {
     CustomQuery: {
       database: database, 
-      sql_query: sqlQuery
+      sql_query: sqlQuery,
+      params: params
     }
   }
 }
 
-function createQueryFromPathElements(database, table) {
-  /*
-    Possible values for "query":
-
-    "query": "GetDatabases"
-    "query": {"GetTables":"mydatabase"}
-    "query": {"GetTableContents":{
-        "database": "mydatabase"
-        "table": "mytable"
-      }}
-  */
-
-  let query;
-
-  if (table !== null) {
-    query = {
-      "GetTableContents":{
-        "database": database,
-        "table": table
-      }
-    }
-  } else if (database !== null) {
-    query = {"GetTables": database}
-  } else {
-    query = "GetDatabases";
-  }
-
-  return query;
-}
-
-function createFullQuery(connection_string, query) {
+function createFullQuery(connection, query) {
   return {
-    "connection": {
-      "Stateless": getGlobalConnectionString()
-    },
-    "query": query
+    "connection": connection,
+    "query": query,
+    "limits": getGlobalQueryLimits(),
+    "profile": getGlobalProfileName()
   }
 }
 
@@ -158,23 +266,233 @@ function initEventsForCustomQuery() {
   customDatabase.addEventListener("keyup", (event) => {
     onEnterRun(event, runCustomQuery);
   });
+  document.querySelector(".custom-sql .script-toggle").addEventListener("click", () => {
+    scriptControls.classList.toggle("hidden");
+  });
+  scriptControls.querySelector(".script-run").addEventListener("click", () => runScript());
+  document.querySelector(".custom-sql .history-toggle").addEventListener("click", () => {
+    historyControls.classList.toggle("hidden");
+    if (!historyControls.classList.contains("hidden")) {
+      showHistory();
+    }
+  });
+  historyControls.querySelector(".history-search").addEventListener("keyup", (event) => {
+    onEnterRun(event, showHistory);
+  });
+  historyControls.querySelector(".history-refresh").addEventListener("click", () => showHistory());
+  historyControls.querySelector(".history-clear").addEventListener("click", () => clearHistory());
+  document.querySelector(".custom-sql .query-params-prepare").addEventListener("click", () => prepareQueryParams());
+  document.querySelector(".custom-sql .explain").addEventListener("click", () => explainCustomQuery(false));
+  document.querySelector(".custom-sql .explain-analyze").addEventListener("click", () => explainCustomQuery(true));
+  transactionControls.querySelector(".transaction-begin").addEventListener("click", () => beginTransaction());
+  transactionControls.querySelector(".transaction-commit").addEventListener("click", () => endTransaction("commit_transaction"));
+  transactionControls.querySelector(".transaction-rollback").addEventListener("click", () => endTransaction("rollback_transaction"));
+}
+
+/* Transaction of this window (see db/transactions.rs) */
+
+// Show whether custom queries run in an open transaction
+function showTransactionState(state) {
+  transactionControls.querySelector(".transaction-begin").classList.toggle("hidden", state.open);
+  transactionControls.querySelector(".transaction-commit").classList.toggle("hidden", !state.open);
+  transactionControls.querySelector(".transaction-rollback").classList.toggle("hidden", !state.open);
+  let indicator = transactionControls.querySelector(".transaction-state");
+  indicator.textContent = !state.open ? "" : (state.failed ? "Transaktion fehlgeschlagen" : "Transaktion offen");
+  transactionControls.classList.toggle("transaction-open", state.open);
+  transactionControls.classList.toggle("transaction-failed", state.failed);
+}
+
+async function refreshTransactionState() {
+  await invoke("get_transaction_state", {})
+    .then(showTransactionState)
+    .catch((error) => {
+      InformStatus("Error: Call to get_transaction_state returned an error: " + JSON.stringify(error))
+    });
+}
+
+// Custom queries run in the transaction until it is committed or rolled back
+async function beginTransaction() {
+  await invoke("begin_transaction", {
+    connection: getGlobalConnection(),
+    database: customDatabase.value || null,
+    limits: getGlobalQueryLimits()
+  })
+    .then((state) => {
+      showTransactionState(state);
+      InformStatus("Transaction started, custom queries run in it until commit or rollback");
+    })
+    .catch((error) => { InformStatus("Error: Could not begin transaction: " + error) });
+}
+
+// command is "commit_transaction" or "rollback_transaction"
+async function endTransaction(command) {
+  await invoke(command, {})
+    .then((state) => {
+      showTransactionState(state);
+      InformStatus(command === "commit_transaction" ? "Transaction committed" : "Transaction rolled back");
+    })
+    .catch((error) => {
+      InformStatus("Error: " + error);
+      refreshTransactionState();
+    });
 }
 
 /* connection configuration and test */
 
 async function initEventsForConnectionConfig() {
   let suggested_query = await invoke("suggest_query", {});
-  let connection_string = suggested_query["connection"]["Stateless"];
-  
+
   let elem_con_string = document.querySelector(".connection-string");
+  let elem_con_uri = document.querySelector(".connection-uri");
+  let elem_con_error = document.querySelector(".connection-error");
+  let elems_con_param = document.querySelectorAll(".connection-param");
   let elem_con_test = document.querySelector(".connection-test")
   let elem_con_test_result = document.querySelector(".connection-result");
   let elem_con_select = document.querySelector(".connection-select");
   let elem_con_select_confirm = document.querySelector(".connection-select-confirm");
+  let elem_statement_timeout = document.querySelector(".statement-timeout");
+  let elem_max_rows = document.querySelector(".max-rows");
+  let elem_profile_select = document.querySelector(".profile-select");
+  let elem_profile_name = document.querySelector(".profile-name");
+  let elem_profile_color = document.querySelector(".profile-color");
+  let elem_profile_environment = document.querySelector(".profile-environment");
+  let elem_profile_save = document.querySelector(".profile-save");
+  let elem_profile_duplicate = document.querySelector(".profile-duplicate");
+  let elem_profile_delete = document.querySelector(".profile-delete");
+  let elem_profile_result = document.querySelector(".profile-result");
+
+  // Empty input means no limit
+  let limitFromInput = (elem) => (elem.value === "") ? null : Number(elem.value);
+  let limitToInput = (elem, limit) => { elem.value = (limit === null) ? "" : limit; };
+  let limitsFromForm = () => ({
+    "statement_timeout_ms": limitFromInput(elem_statement_timeout),
+    "max_rows": limitFromInput(elem_max_rows)
+  });
+  let limitsToForm = (limits) => {
+    limitToInput(elem_statement_timeout, limits["statement_timeout_ms"]);
+    limitToInput(elem_max_rows, limits["max_rows"]);
+  };
 
   const mark_okay = "&check; (okay)";
   const mark_fail = "&cross; (fail)";
 
+  // Options without an input of their own (e.g. sslrootcert) are kept as they are
+  let extras = {};
+
+  let paramsFromForm = () => {
+    let params = { "extras": extras };
+    elems_con_param.forEach((elem) => {
+      let value = elem.value;
+      if (value === "") {
+        value = null;
+      } else if (elem.type === "number") {
+        value = Number(value);
+      }
+      params[elem.dataset.param] = value;
+    });
+    return params;
+  };
+
+  let showConnection = (description, fromString) => {
+    elem_con_error.textContent = "";
+    elems_con_param.forEach((elem) => {
+      let value = description.params[elem.dataset.param];
+      elem.value = (value === null) ? "" : value;
+    });
+    extras = description.params.extras;
+    if (!fromString) {
+      elem_con_string.value = description.keywords;
+    }
+    elem_con_uri.value = description.uri;
+  };
+
+  // Form and string are kept in sync, the backend converts between them
+  let describe = (connection, fromString) => {
+    invoke("describe_connection", { "connection": connection })
+      .then((description) => showConnection(description, fromString))
+      .catch((error) => { elem_con_error.textContent = describeDatabaseError(error); });
+  };
+
+  /* profiles */
+
+  // Name of the profile shown in the form, null for a new one
+  let selectedProfile = null;
+
+  let profileFromForm = () => ({
+    "name": elem_profile_name.value,
+    "connection": paramsFromForm(),
+    "limits": limitsFromForm(),
+    "color": elem_profile_color.value,
+    "environment": (elem_profile_environment.value === "") ? null : elem_profile_environment.value
+  });
+
+  let showProfile = (profile) => {
+    selectedProfile = profile ? profile.name : null;
+    elem_profile_select.value = selectedProfile || "";
+    elem_profile_name.value = profile ? profile.name : "";
+    elem_profile_environment.value = (profile && profile.environment) || "";
+    if (profile) {
+      elem_profile_color.value = profile.color || "#ffffff";
+      describe({ "Params": profile.connection }, false);
+      limitsToForm(profile.limits);
+    }
+  };
+
+  // Fill list of profiles, then show the one named
+  let refreshProfiles = async (name) => {
+    let list = await invoke("list_profiles", {});
+    elem_profile_select.innerHTML = "";
+    let none = document.createElement("option");
+    none.value = "";
+    none.textContent = "(neues Profil)";
+    elem_profile_select.appendChild(none);
+    for (let profile of list.profiles) {
+      let option = document.createElement("option");
+      option.value = profile.name;
+      option.textContent = profile.environment ? profile.name + " [" + profile.environment + "]" : profile.name;
+      option.style.backgroundColor = profile.color || "";
+      elem_profile_select.appendChild(option);
+    }
+    let shown = (name === undefined) ? list.last_used : name;
+    showProfile(list.profiles.find((profile) => profile.name === shown) || null);
+    return list;
+  };
+
+  let profileAction = (action) => {
+    elem_profile_result.textContent = "";
+    action().catch((error) => { elem_profile_result.textContent = error; });
+  };
+
+  elem_profile_select.addEventListener("change", () => profileAction(() =>
+    refreshProfiles(elem_profile_select.value || null)
+  ));
+
+  // Saves changes to the profile shown, or creates a new one
+  elem_profile_save.addEventListener("click", () => profileAction(async () => {
+    let profile = profileFromForm();
+    if (selectedProfile === null) {
+      await invoke("create_profile", { "profile": profile });
+    } else {
+      await invoke("update_profile", { "name": selectedProfile, "profile": profile });
+    }
+    await refreshProfiles(profile.name);
+    elem_profile_result.innerHTML = mark_okay;
+  }));
+
+  elem_profile_duplicate.addEventListener("click", () => profileAction(async () => {
+    if (selectedProfile !== null) {
+      let copy = await invoke("duplicate_profile", { "name": selectedProfile, "newName": null });
+      await refreshProfiles(copy.name);
+    }
+  }));
+
+  elem_profile_delete.addEventListener("click", () => profileAction(async () => {
+    if (selectedProfile !== null) {
+      await invoke("delete_profile", { "name": selectedProfile });
+      await refreshProfiles(null);
+    }
+  }));
+
   let resetConcheck = () => {
     elem_con_test_result.innerHTML = "";
     elem_con_select_confirm.innerHTML = "";
@@ -182,22 +500,25 @@ async function initEventsForConnectionConfig() {
 
   let run_concheck = () => {
     resetConcheck();
-    invoke("test_connection_string",
-      { "connectionString": elem_con_string.value }
+    invoke("test_connection",
+      { "connection": { "Params": paramsFromForm() } }
     )
-    .then((bool_result) => {
-      if (bool_result) {
+    .then((check) => {
+      if (check.okay) {
         elem_con_test_result.innerHTML = mark_okay;
       } else {
         elem_con_test_result.innerHTML = mark_fail;
       }
+      elem_con_test_result.append(" " + describeTls(check.tls));
     })
     .catch((error) => {
-      elem_con_test_result.innerHTML = "error running test";
+      elem_con_test_result.textContent = describeDatabaseError(error);
     });
   };
 
-  elem_con_string.value = connection_string;
+  describe(getConnectionFromFullQuery(suggested_query), false);
+  limitsToForm(suggested_query["limits"]);
+  profileAction(() => refreshProfiles(undefined));
 
   // Pressing enter will run connection check
   elem_con_string.addEventListener("keyup", (event) => {
@@ -207,27 +528,49 @@ async function initEventsForConnectionConfig() {
   // Change to input will result in resetting 
   elem_con_string.addEventListener("input", () => {
     resetConcheck();
+    describe({ "Stateless": elem_con_string.value }, true);
   })
+
+  elems_con_param.forEach((elem) => {
+    elem.addEventListener("keyup", (event) => {
+      onEnterRun(event, run_concheck);
+    });
+    elem.addEventListener("input", () => {
+      resetConcheck();
+      describe({ "Params": paramsFromForm() }, false);
+    });
+  });
   
   // Click on "check" will run connection check
   elem_con_test.addEventListener("click", () => {
     run_concheck();
   });
 
-  // Click on "select" will use connection string for other requests
+  // Click on "select" will use connection for other requests
   //
   // See also: initialQuery()
   //
   // Please note and regret the inconsistent use of where
-  // the connection string is stored (inside query / global
-  // string).
+  // the connection is stored (inside query / global).
   //
   elem_con_select.addEventListener("click", () => {
     elem_con_select_confirm.innerHTML = "";
 
-    useConnectionString(elem_con_string.value);
+    useConnection({ "Params": paramsFromForm() });
+    useQueryLimits(limitsFromForm());
+    if (selectedProfile === null) {
+      useProfile(null);
+    } else {
+      useProfile(profileFromForm());
+      invoke("use_profile", { "name": selectedProfile })
+        .catch((error) => { elem_profile_result.textContent = error; });
+    }
     
-    dbFullRequest(createFullQuery(elem_con_string.value, createQueryFromPathElements(null, null)))
+    dbNavigate({ "Connect": {
+      "connection": getGlobalConnection(),
+      "limits": getGlobalQueryLimits(),
+      "profile": getGlobalProfileName()
+    } })
       .then((message) => { elem_con_select_confirm.innerHTML = mark_okay; selectComponent("db"); })
       .catch((error) => { elem_con_select_confirm.innerHTML = mark_fail })
     ;
@@ -243,6 +586,7 @@ function initVariables() {
 async function initEventFunctions() {
   await initEventsForConnectionConfig();
   await initEventsForCustomQuery();
+  initEventsForPaging();
 }
 
 /* Breadcrumbs */
@@ -264,11 +608,13 @@ function addTextLiTo(parent, className, textContent, linkFunction) {
 }
 
 // Add an element to the breadcrumbs including link
-function addBreadcrumbIfGiven(altLinkText, database, table) {
+function addBreadcrumbIfGiven(altLinkText, database, schema, table) {
 
   let lastPathElement;
   if (table) {
     lastPathElement = table;
+  } else if (schema) {
+    lastPathElement = schema;
   } else if (database) {
     lastPathElement = database;
   } else {
@@ -286,7 +632,7 @@ function addBreadcrumbIfGiven(altLinkText, database, table) {
       linkText = lastPathElement;
     }
 
-    addTextLiTo(breadcrumbs, "link", linkText, () => dbRequestFromPathElements(database, table));
+    addTextLiTo(breadcrumbs, "link", linkText, () => dbRequestFromPathElements(database, schema, table));
   }
 }
 
@@ -295,17 +641,49 @@ function updateBreadcrumbs(pathElements) {
   breadcrumbs.innerHTML = '';
 
   let database = pathElements["database"];
+  let schema = pathElements["schema"];
   let table = pathElements["table"];
 
-  addBreadcrumbIfGiven("home", null, null);
+  // connection -> database -> schema -> table
+  let connectionText = describeConnection(getGlobalConnection());
+  if (globalProfile && globalProfile.environment) {
+    connectionText = globalProfile.environment + ": " + connectionText;
+  }
+  addBreadcrumbIfGiven(connectionText, null, null, null);
+  if (globalProfile && globalProfile.color) {
+    breadcrumbs.lastChild.style.borderLeft = "4px solid " + globalProfile.color;
+  }
   if (database) {
-    addBreadcrumbIfGiven(null, database, null);
+    addBreadcrumbIfGiven(null, database, null, null);
+  }
+  if (schema) {
+    addBreadcrumbIfGiven(null, database, schema, null);
   }
   if (table) {
-    addBreadcrumbIfGiven(null, database, table);
+    addBreadcrumbIfGiven(null, database, schema, table);
   }
 }
 
+// Short text for the connection, e.g. "postgres@localhost"
+function describeConnection(connection) {
+  let user = null;
+  let host = null;
+  if ("Params" in connection) {
+    user = connection["Params"]["user"];
+    host = connection["Params"]["host"];
+  } else {
+    let connectionString = connection["Stateless"];
+    user = /(?:^|\s)user\s*=\s*(\S+)/.exec(connectionString);
+    host = /(?:^|\s)host\s*=\s*(\S+)/.exec(connectionString);
+    user = user && user[1];
+    host = host && host[1];
+  }
+  if (!user && !host) {
+    return "home";
+  }
+  return (user || "") + "@" + (host || "localhost");
+}
+
 function getTaskFromQuery(fullQuery) {
   let q = fullQuery["query"];
   return Object.keys(q)[0];
@@ -314,27 +692,38 @@ function getTaskFromQuery(fullQuery) {
 function toPathItems(fullQuery) {
   let q = fullQuery["query"];
   let database = null;
+  let schema = null;
   let table = null;
 
-  let task = getTaskFromQuery(fullQuery);  // "CustomQuery", "GetDatabases", "GetTables", "GetTableContents"
+  let task = getTaskFromQuery(fullQuery);  // "CustomQuery", "Explain", "GetDatabases", "GetSchemas", "GetTables", "GetTableContents", "DescribeTable"
   let task_info = q[task];
   switch (task) {
     case "CustomQuery":
       database = task_info["database"];
       break;
+    case "Explain":
+      database = task_info["query"]["database"];
+      break;
     case "GetDatabases":
       break;
-    case "GetTables":
+    case "GetSchemas":
       database = task_info;
       break;
+    case "GetTables":
+      database = task_info["database"];
+      schema = task_info["schema"];
+      break;
     case "GetTableContents":
+    case "DescribeTable":
       database = task_info["database"];
+      schema = task_info["schema"];
       table = task_info["table"];
       break;
     default:
   }
   let pathItems = {
     "database": database,
+    "schema": schema,
     "table": table
   }
 
@@ -343,41 +732,70 @@ function toPathItems(fullQuery) {
 
 /* Import-Table-Contents */
 
+// Text shown for a typed cell
+//
+// Unit variants (e.g. "Null") arrive as plain string, all others as
+// single-key object: { "Integer": 5 }, { "Text": "abc" }, ...
+function cellValueToText(cellType, cellValue) {
+  switch (cellType) {
+    case "Null":
+      return "NULL";
+    case "Json":
+      return JSON.stringify(cellValue);
+    case "ConversionFailure":
+      return "?";
+    default:
+      return String(cellValue);
+  }
+}
+
 function determineCellFunctionality(cellData, fullQuery) {
-  let cellType = Object.keys(cellData)[0];
-  let cellText = cellData[cellType];
-  let cellClass = cellText;
+  let cellType = (typeof cellData === "string") ? cellData : Object.keys(cellData)[0];
+  let cellValue = cellData[cellType];
+  let cellText = cellValueToText(cellType, cellValue);
+  let cellClass = "cell-" + cellType.toLowerCase();
+  let cellTitle = (cellType === "ConversionFailure") ? cellValue : null;
   let cellFunction = null;
   
   let pathItems = toPathItems(fullQuery);
   let lastDatabase = pathItems["database"];
+  let lastSchema = pathItems["schema"];
 
   switch (cellType) {
-    case "Text": {
-      cellFunction = null;
-    } break;
     case "Database": {
       cellClass += " link";
-      let database = cellText;
-      cellFunction = () => { dbRequestFromPathElements(database, null); }
+      let database = cellValue;
+      cellFunction = () => { dbRequestFromPathElements(database, null, null); }
+    } break;
+    case "Schema": {
+      cellClass += " link";
+      let database = lastDatabase;
+      let schema = cellValue;
+      cellFunction = () => { dbRequestFromPathElements(database, schema, null); }
     } break;
     case "Table": {
       cellClass += " link";
       let database = lastDatabase;
-      let table = cellText;
-      cellFunction = () => { dbRequestFromPathElements(database, table); }
+      let schema = lastSchema;
+      let table = cellValue;
+      cellFunction = () => { dbRequestFromPathElements(database, schema, table); }
     } break;
+    default: {
+      cellFunction = null;
+    }
   }
   
   return {
     "text": cellText,
+    "title": cellTitle,
     "cellClass": cellClass,
     "cellFunction": cellFunction
   }
 }
 
 function amendCell(domCell, text, cls, f) {
-  domCell.innerHTML = text;
+  // Values are data, never markup
+  domCell.textContent = text;
   if (cls !== null) {
     domCell.className = cls;
   }
@@ -388,6 +806,31 @@ function amendCell(domCell, text, cls, f) {
 
 function insertCellData(domCell, cellInfo) {
   amendCell(domCell, cellInfo["text"], cellInfo["cellClass"], cellInfo["cellFunction"]);
+  if (cellInfo["title"] !== null) {
+    domCell.title = cellInfo["title"];
+  }
+}
+
+function appendTableRows(fields, lastQuery) {
+  fields.forEach(function(row) {
+    let tr = dbTable.insertRow();
+
+    row.forEach(function(cellData) {
+      let cellInfo = determineCellFunctionality(cellData, lastQuery);
+        // regular row
+        var cell = tr.insertCell();
+        insertCellData(cell, cellInfo);
+    });
+  });
+}
+
+// Delete all rows, keeping the first `keep` rows (e.g. the header)
+function clearTableRows(keep) {
+  let num_rows = dbTable.rows.length;
+
+  for (let i = keep; i < num_rows; i++) {
+    dbTable.deleteRow(-1);
+  }
 }
 
 function replaceTableContents(table, lastQuery) {
@@ -395,82 +838,1028 @@ function replaceTableContents(table, lastQuery) {
 
   if (table) {
     // Clear old table contents
-    let num_rows = dbTable.rows.length;
-
-    for (let i = 0; i < num_rows; i++) {
-      dbTable.deleteRow(-1);
-    }
+    clearTableRows(0);
 
     // Insert table header
     let tr = dbTable.insertRow();
-    table.columns.forEach((column_name) => {
+    table.columns.forEach((column_name, index) => {
       let th = tr.appendChild(document.createElement("th"));
       amendCell(th, column_name, null, null);
+      if (index < table.column_types.length) {
+        th.title = table.column_types[index].name;
+      }
     })
 
     // Insert table contents
-    table.fields.forEach(function(row) {
-      let tr = dbTable.insertRow();
-
-      row.forEach(function(cellData) {
-        let cellInfo = determineCellFunctionality(cellData, lastQuery);
-          // regular row
-          var cell = tr.insertCell();
-          insertCellData(cell, cellInfo);
-      });
-    });
+    appendTableRows(table.fields, lastQuery);
   }
 }
 
-/** Will run a query to the database and process results (including DOM manipulation)
- * 
- * @param fullQuery containing connection and specific query
- */
-async function dbFullRequest(fullQuery) {
-  InformStatus("Running query: " + JSON.stringify(fullQuery));
-  invoke("db_query",{ query: fullQuery})
+/* Paging and streaming of table contents */
+
+function getOffsetFromQuery(fullQuery) {
+  let pagination = fullQuery["query"]["GetTableContents"]["pagination"];
+  if (pagination && pagination.hasOwnProperty("Offset")) {
+    return pagination.Offset.offset;
+  }
+  return 0;
+}
+
+function describeRowEstimate(rowEstimate) {
+  return (rowEstimate !== null) ? " of ~" + rowEstimate : "";
+}
+
+// Show paging controls for table contents, hide them for everything else
+function updatePaging(fullQuery, queryResult, numRows) {
+  if (getTaskFromQuery(fullQuery) !== "GetTableContents") {
+    currentPage = null;
+    paging.classList.add("hidden");
+    importControls.classList.add("hidden");
+    return;
+  }
+
+  let pathItems = toPathItems(fullQuery);
+  let offset = getOffsetFromQuery(fullQuery);
+  currentPage = {
+    "database": pathItems["database"],
+    "schema": pathItems["schema"],
+    "table": pathItems["table"],
+    "offset": offset,
+    "numRows": numRows
+  };
+
+  paging.querySelector(".page-info").textContent =
+    "Rows " + (numRows > 0 ? offset + 1 : offset) + "-" + (offset + numRows)
+    + describeRowEstimate(queryResult.row_estimate);
+  paging.querySelector(".page-prev").disabled = (offset === 0);
+  paging.querySelector(".page-next").disabled = (numRows < PAGE_SIZE);
+  paging.classList.remove("hidden");
+}
+
+async function dbRequestPage(direction) {
+  if (currentPage !== null) {
+    let offset = Math.max(0, currentPage.offset + direction * PAGE_SIZE);
+    // the position stays the same, only the page changes
+    await dbNextAction({ "Offset": { "limit": PAGE_SIZE, "offset": offset } });
+  }
+}
+
+// Load all rows of the current table, rows arrive batch by batch as "db-rows" events
+// Columns, constraints, indexes and triggers of the table shown
+async function describeCurrentTable() {
+  if (currentPage !== null) {
+    await dbFullRequest(createFullQuery(getGlobalConnection(), {
+      "DescribeTable": {
+        "database": currentPage.database,
+        "schema": currentPage.schema,
+        "table": currentPage.table
+      }
+    }));
+  }
+}
+
+function addDetailsSection(title, lines) {
+  if (lines.length === 0) {
+    return;
+  }
+  let heading = document.createElement("h3");
+  heading.textContent = title;
+  dbDetails.appendChild(heading);
+  let list = document.createElement("ul");
+  for (let line of lines) {
+    addTextLiTo(list, null, line, null);
+  }
+  dbDetails.appendChild(list);
+}
+
+function describeForeignKey(key) {
+  return key.name + ": " + key.from_schema + "." + key.from_table + " (" + key.from_columns.join(", ") + ")"
+    + " \u2192 " + key.to_schema + "." + key.to_table + " (" + key.to_columns.join(", ") + ")";
+}
+
+// One line per plan node, similar to the text format of EXPLAIN
+function describePlanNode(node) {
+  let text = node.node_type;
+  if (node.join_type !== null) {
+    text += " (" + node.join_type + ")";
+  }
+  if (node.relation_name !== null) {
+    text += " on " + node.relation_name;
+    if (node.alias !== null && node.alias !== node.relation_name) {
+      text += " " + node.alias;
+    }
+  }
+  if (node.index_name !== null) {
+    text += " using " + node.index_name;
+  }
+  text += "  (cost=" + node.startup_cost.toFixed(2) + ".." + node.total_cost.toFixed(2) + " rows=" + node.plan_rows + ")";
+  if (node.actual !== null) {
+    let actual = node.actual;
+    text += " (actual time=" + actual.startup_time_ms.toFixed(3) + ".." + actual.total_time_ms.toFixed(3)
+      + " rows=" + actual.rows + " loops=" + actual.loops + ")";
+  }
+  if (node.buffers !== null) {
+    text += " Buffers: shared hit=" + node.buffers.shared_hit + " read=" + node.buffers.shared_read;
+  }
+  if (node.most_expensive) {
+    text += " \u2013 teuerster Knoten";
+  }
+  if (node.slowest) {
+    text += " \u2013 langsamster Knoten";
+  }
+  if (node.misestimated) {
+    text += " \u2013 Zeilenschätzung daneben";
+  }
+  return text;
+}
+
+function addPlanNode(list, node) {
+  let li = document.createElement("li");
+  li.textContent = describePlanNode(node);
+  li.classList.toggle("plan-most-expensive", node.most_expensive);
+  li.classList.toggle("plan-slowest", node.slowest);
+  li.classList.toggle("plan-misestimated", node.misestimated);
+  // everything else (filters, sort keys, ...) when hovering
+  li.title = Object.entries(node.properties)
+    .map(([key, value]) => key + ": " + JSON.stringify(value))
+    .join("\n");
+  if (node.children.length > 0) {
+    let children = document.createElement("ul");
+    node.children.forEach((child) => addPlanNode(children, child));
+    li.appendChild(children);
+  }
+  list.appendChild(li);
+}
+
+// Plan tree of EXPLAIN (see db/explain.rs)
+function showPlan(plan) {
+  let heading = document.createElement("h3");
+  heading.textContent = "Ausführungsplan";
+  dbDetails.appendChild(heading);
+  let list = document.createElement("ul");
+  list.className = "plan";
+  addPlanNode(list, plan.root);
+  dbDetails.appendChild(list);
+  let times = [];
+  if (plan.planning_time_ms !== null) {
+    times.push("Planung " + plan.planning_time_ms.toFixed(3) + " ms");
+  }
+  if (plan.execution_time_ms !== null) {
+    times.push("Ausführung " + plan.execution_time_ms.toFixed(3) + " ms (zurückgerollt)");
+  }
+  plan.triggers.forEach((trigger) =>
+    times.push("Trigger " + trigger.name + ": " + trigger.time_ms.toFixed(3) + " ms, " + trigger.calls + " Aufrufe"));
+  addDetailsSection("Zeiten", times);
+}
+
+// Results besides the table, e.g. the structure of a table (see db/describe.rs)
+function showDetails(details) {
+  dbDetails.innerHTML = "";
+  if (details !== null && details.hasOwnProperty("Plan")) {
+    showPlan(details.Plan);
+    return;
+  }
+  if (details === null || !details.hasOwnProperty("TableStructure")) {
+    return;
+  }
+  let structure = details.TableStructure;
+  let describeConstraint = (constraint) => constraint.name + ": " + constraint.definition;
+  addDetailsSection("Primärschlüssel", structure.primary_key ? [describeConstraint(structure.primary_key)] : []);
+  addDetailsSection("Fremdschlüssel", structure.foreign_keys.map(describeForeignKey));
+  addDetailsSection("Referenziert von", structure.referenced_by.map(describeForeignKey));
+  addDetailsSection("Eindeutig", structure.unique_constraints.map(describeConstraint));
+  addDetailsSection("Prüfungen", structure.check_constraints.map(describeConstraint));
+  addDetailsSection("Indizes", structure.indexes.map((index) =>
+    index.definition + (index.valid ? "" : " (ungültig)")));
+  addDetailsSection("Trigger", structure.triggers.map((trigger) =>
+    trigger.definition + (trigger.enabled ? "" : " (deaktiviert)")));
+}
+
+async function streamTableContents() {
+  if (currentPage === null) {
+    return;
+  }
+
+  let fullQuery = createFullQuery(getGlobalConnection(), {
+    "GetTableContents": {
+      "database": currentPage.database,
+      "schema": currentPage.schema,
+      "table": currentPage.table
+    }
+  });
+  let streamId = "stream-" + (++streamCounter);
+  let numRows = 0;
+
+  // Header stays, the columns are the same as for the current page
+  stopEditing();
+  shownTableContents = null;
+  clearTableRows(1);
+  paging.classList.add("hidden");
+  InformStatus("Streaming rows of " + currentPage.table);
+
+  const unlisten = await listen("db-rows", (event) => {
+    let batch = event.payload;
+    if (batch.stream_id === streamId) {
+      appendTableRows(batch.fields, fullQuery);
+      numRows += batch.fields.length;
+      InformStatus("Streaming rows of " + currentPage.table + ": " + numRows + " rows so far");
+    }
+  });
+
+  startRunningQuery(streamId);
+  invoke("db_stream_query", { query: fullQuery, streamId: streamId, batchSize: STREAM_BATCH_SIZE })
     .then((queryResult) => {
       let tableResult = queryResult.table;
       if (tableResult.hasOwnProperty("Ok")) {
-        customSqlQuery.value = queryResult.sql_query.replace(/\s+/g,' ');
-        customSqlQuery.size = customSqlQuery.value.length;
-        if (queryResult.database !== null) {
-          customDatabase.value = queryResult.database;
-        }
-
-        InformStatus("Read " + tableResult.Ok.fields.length + " rows");
-        replaceTableContents(tableResult.Ok, fullQuery);
-        updateBreadcrumbs(toPathItems(fullQuery));  // just copy the query verbatim as current path
+        InformStatus("Streamed " + numRows + " rows" + describeRowEstimate(queryResult.row_estimate));
+      } else if (isCancelled(tableResult.Err)) {
+        InformStatus("Streaming cancelled after " + numRows + " rows");
       } else {
+        InformStatus(describeDatabaseError(tableResult.Err) + " - after " + numRows + " rows");
+      }
+    })
+    .catch((error) => {
+      InformStatus("Error: Call to db_stream_query returned an error: " + JSON.stringify(error))
+    })
+    .finally(() => { unlisten(); stopRunningQuery(streamId); });
+}
+
+/* Export of results to files (see db/export.rs) */
 
-        let err = "Error: No successful query-results for query '" + queryResult.sql_query + "'";
-        if (queryResult.database !== null) {
-          err += " on database '" + queryResult.database + "'";
-        }
-        InformStatus(err);
+// Offer export of all rows of the query, table contents without paging
+function showExport(fullQuery) {
+  exportableQuery = fullQuery;
+  if (fullQuery !== null && getTaskFromQuery(fullQuery) === "GetTableContents") {
+    let table = Object.assign({}, fullQuery.query.GetTableContents, { "pagination": null });
+    exportableQuery = Object.assign({}, fullQuery, { "query": { "GetTableContents": table } });
+  }
+  exportControls.classList.toggle("hidden", exportableQuery === null);
+}
+
+function exportOptionsFromForm() {
+  let table = exportControls.querySelector(".export-table").value.trim();
+  return {
+    "format": exportControls.querySelector(".export-format").value,
+    "delimiter": exportControls.querySelector(".export-delimiter").value || ",",
+    "header": exportControls.querySelector(".export-header").checked,
+    "null_text": exportControls.querySelector(".export-null").value,
+    "quoting": exportControls.querySelector(".export-quoting").value,
+    "table_name": (table === "") ? null : table,
+    "schema_name": null
+  };
+}
+
+// Write all rows to a file, progress arrives as "export-progress" events
+async function exportRows() {
+  let path = exportControls.querySelector(".export-path").value.trim();
+  if (exportableQuery === null || path === "") {
+    InformStatus("Export needs a result and a file name");
+    return;
+  }
+  let exportId = "export-" + (++exportCounter);
+  InformStatus("Exporting to " + path);
+
+  const unlisten = await listen("export-progress", (event) => {
+    let progress = event.payload;
+    if (progress.export_id === exportId) {
+      InformStatus("Exporting to " + path + ": " + progress.rows + " rows so far");
+    }
+  });
+
+  startRunningQuery(exportId);
+  invoke("export_query", {
+    query: exportableQuery,
+    path: path,
+    options: exportOptionsFromForm(),
+    exportId: exportId
+  })
+    .then((summary) => InformStatus("Exported " + summary.rows + " rows to " + summary.path))
+    .catch((error) => InformStatus("Export failed: " + error))
+    .finally(() => { unlisten(); stopRunningQuery(exportId); });
+}
+
+/* Import of CSV files into the current table (see db/import.rs) */
+
+// Guessed options and first rows of the file, with a column mapping to choose
+async function previewImport() {
+  let path = importControls.querySelector(".import-path").value.trim();
+  if (path === "") {
+    return;
+  }
+  await invoke("preview_import", { path: path, rows: null })
+    .then((preview) => {
+      importPreview = preview;
+      importControls.querySelector(".import-encoding").value = preview.encoding;
+      importControls.querySelector(".import-delimiter").value = preview.delimiter;
+      importControls.querySelector(".import-header").checked = preview.header;
+      showImportMapping(preview);
+      InformStatus("Preview of " + path + ": " + preview.columns.length + " columns");
+    })
+    .catch((error) => InformStatus("Preview failed: " + error));
+}
+
+// One row of selects (table column per file column), then the file's rows
+function showImportMapping(preview) {
+  let table = importControls.querySelector(".import-mapping");
+  table.innerHTML = "";
+  importControls.querySelector(".import-bad-rows").innerHTML = "";
+  let tableColumns = (shownTableContents !== null) ? shownTableContents.table.columns : [];
+  let mappingRow = table.insertRow();
+  preview.columns.forEach((name, index) => {
+    let select = document.createElement("select");
+    [""].concat(tableColumns).forEach((column) => {
+      let option = document.createElement("option");
+      option.value = column;
+      option.textContent = (column === "") ? "(überspringen)" : column;
+      select.appendChild(option);
+    });
+    let sameName = tableColumns.find((column) => column.toLowerCase() === name.toLowerCase());
+    select.value = preview.header ? (sameName || "") : (tableColumns[index] || "");
+    mappingRow.insertCell().appendChild(select);
+  });
+  let headerRow = table.insertRow();
+  preview.columns.forEach((name) => {
+    headerRow.appendChild(document.createElement("th")).textContent = name;
+  });
+  preview.rows.forEach((row) => {
+    let tr = table.insertRow();
+    row.forEach((text) => { tr.insertCell().textContent = text; });
+  });
+}
+
+function importMapping() {
+  return Array.from(importControls.querySelectorAll(".import-mapping select"))
+    .map((select) => (select.value === "") ? null : select.value);
+}
+
+// Load the file into the current table, progress arrives as "import-progress" events
+async function importFile() {
+  let path = importControls.querySelector(".import-path").value.trim();
+  if (currentPage === null || importPreview === null || path === "") {
+    InformStatus("Import needs a table and a preview of the file");
+    return;
+  }
+  let importId = "import-" + (++importCounter);
+  let badRows = importControls.querySelector(".import-bad-rows");
+  badRows.innerHTML = "";
+  InformStatus("Importing " + path + " into " + currentPage.table);
+
+  const unlisten = await listen("import-progress", (event) => {
+    let progress = event.payload;
+    if (progress.import_id === importId) {
+      let percent = (progress.total_bytes > 0) ? Math.floor(100 * progress.bytes / progress.total_bytes) : 100;
+      InformStatus("Importing " + path + ": " + progress.rows + " rows (" + percent + "%)");
+    }
+  });
+
+  let tableImport = {
+    "connection": getGlobalConnection(),
+    "table": { "database": currentPage.database, "schema": currentPage.schema, "table": currentPage.table },
+    "path": path,
+    "options": {
+      "encoding": importControls.querySelector(".import-encoding").value,
+      "delimiter": importControls.querySelector(".import-delimiter").value,
+      "header": importControls.querySelector(".import-header").checked,
+      "null_text": importControls.querySelector(".import-null").value,
+      "skip_bad_rows": importControls.querySelector(".import-skip-bad").checked
+    },
+    "mapping": importMapping()
+  };
+  startRunningQuery(importId);
+  await invoke("import_file", { import: tableImport, importId: importId })
+    .then((result) => {
+      result.bad_rows.forEach((bad) => addTextLiTo(badRows, "import-bad-row",
+        "Zeile " + bad.line + ": " + bad.reason, null));
+      let status = "Imported " + result.rows + " rows";
+      if (result.bad_row_count > 0) {
+        status += ", skipped " + result.bad_row_count + " bad rows";
       }
+      dbRequestPage(0).then(() => InformStatus(status));
+    })
+    .catch((error) => InformStatus("Import failed, nothing was imported: " + error))
+    .finally(() => { unlisten(); stopRunningQuery(importId); });
+}
+
+/* Query history (see db/history.rs) */
+
+function historyFilterFromForm() {
+  let status = historyControls.querySelector(".history-status").value;
+  return {
+    "search": historyControls.querySelector(".history-search").value || null,
+    "succeeded": (status === "") ? null : (status === "ok"),
+    "custom_only": historyControls.querySelector(".history-custom-only").checked,
+    "profile": historyControls.querySelector(".history-profile-only").checked ? getGlobalProfileName() : null
+  };
+}
+
+async function showHistory() {
+  let entries;
+  try {
+    entries = await invoke("list_history", { filter: historyFilterFromForm() });
+  } catch (error) {
+    InformStatus("Error: Call to list_history returned an error: " + JSON.stringify(error));
+    return;
+  }
+  let table = historyControls.querySelector(".history-entries");
+  table.innerHTML = "";
+  let headerRow = table.insertRow();
+  ["Zeit", "Profil", "Datenbank", "SQL", "Dauer", "Zeilen", "Status", ""].forEach((title) => {
+    headerRow.appendChild(document.createElement("th")).textContent = title;
+  });
+  entries.forEach((entry) => {
+    let tr = table.insertRow();
+    tr.classList.toggle("history-failed", entry.error !== null);
+    [
+      new Date(entry.executed_at).toLocaleString(),
+      entry.profile || "",
+      entry.database || "",
+      entry.sql,
+      entry.duration_ms + " ms",
+      (entry.rows === null) ? "" : entry.rows,
+      (entry.error === null) ? "OK" : entry.error
+    ].forEach((text) => { tr.insertCell().textContent = text; });
+    tr.title = entry.connection;
+    let actions = tr.insertCell();
+    addButtonTo(actions, "Ausführen", () => rerunHistoryEntry(entry));
+    if ("CustomQuery" in entry.query.query) {
+      addButtonTo(actions, "Übernehmen", () => {
+        customSqlQuery.value = entry.query.query.CustomQuery.sql_query;
+        customDatabase.value = entry.query.query.CustomQuery.database || "";
+      });
+    }
+    addButtonTo(actions, "Löschen", () => deleteHistoryEntries([entry.id]));
+  });
+  InformStatus("History: " + entries.length + " entries");
+}
+
+function addButtonTo(parent, text, onClick) {
+  let button = document.createElement("button");
+  button.type = "button";
+  button.textContent = text;
+  button.addEventListener("click", onClick);
+  parent.appendChild(button);
+}
+
+// The password is not kept in the history, the query runs on the current connection
+// if it was recorded for the profile in use
+async function rerunHistoryEntry(entry) {
+  let sameProfile = entry.profile !== null && entry.profile === getGlobalProfileName();
+  let queryId = "query-" + (++queryCounter);
+  InformStatus("Running query of history entry " + entry.id);
+  startRunningQuery(queryId);
+  await invoke("rerun_history_entry", {
+    id: entry.id,
+    connection: sameProfile ? getGlobalConnection() : null,
+    queryId: queryId
+  })
+    .then((action) => showQueryResult(action.query, action.result))
+    .catch((error) => {
+      InformStatus("Error: Call to rerun_history_entry returned an error: " + JSON.stringify(error))
     })
+    .finally(() => { stopRunningQuery(queryId); });
+}
+
+async function deleteHistoryEntries(ids) {
+  await invoke("delete_history_entries", { ids: ids })
+    .then(() => showHistory())
+    .catch((error) => InformStatus("Error: Call to delete_history_entries returned an error: " + JSON.stringify(error)));
+}
+
+async function clearHistory() {
+  await invoke("clear_history", {})
+    .then((count) => { showHistory().then(() => InformStatus("Removed " + count + " history entries")); })
+    .catch((error) => InformStatus("Error: Call to clear_history returned an error: " + JSON.stringify(error)));
+}
+
+/* Editing of table contents (see db/edit.rs) */
+
+// Values whose text the server reads back as they were (types without
+// equality operator, like json or point, are compared as text there)
+function isComparable(cellData) {
+  let cellType = (typeof cellData === "string") ? cellData : Object.keys(cellData)[0];
+  return ["Null", "Text", "Integer", "Bool", "Numeric", "Json"].includes(cellType);
+}
+
+// Value of a cell as sent to the backend, null for NULL
+function originalValue(cellData) {
+  if (cellData === "Null") {
+    return null;
+  }
+  let cellType = Object.keys(cellData)[0];
+  return cellValueToText(cellType, cellData[cellType]);
+}
+
+// Typing "NULL" into a cell sets it to NULL
+function editedValue(domCell) {
+  let text = domCell.textContent;
+  return (text === "NULL") ? null : text;
+}
+
+function makeCellEditable(domCell) {
+  domCell.contentEditable = "true";
+  domCell.title = "NULL eingeben für leeren Wert";
+  domCell.addEventListener("input", () => domCell.classList.add("cell-changed"));
+}
+
+function addDeleteToggle(tr) {
+  let cell = tr.insertCell();
+  amendCell(cell, "\u2715", "link", () => tr.classList.toggle("row-deleted"));
+  cell.title = "Zeile löschen";
+}
+
+// Make the rows shown editable, rows are identified by the primary key of the table
+async function startEditing() {
+  if (currentPage === null || shownTableContents === null || editState !== null) {
+    return;
+  }
+  let fullQuery = createFullQuery(getGlobalConnection(), {
+    "DescribeTable": {
+      "database": currentPage.database,
+      "schema": currentPage.schema,
+      "table": currentPage.table
+    }
+  });
+  let queryResult;
+  try {
+    queryResult = await invoke("db_query", { query: fullQuery });
+  } catch (error) {
+    InformStatus("Error: Call to db_query returned an error: " + JSON.stringify(error));
+    return;
+  }
+  if (queryResult.table.hasOwnProperty("Err")) {
+    InformStatus(describeDatabaseError(queryResult.table.Err));
+    return;
+  }
+  let primaryKey = queryResult.details.TableStructure.primary_key;
+  if (primaryKey === null) {
+    InformStatus("Table " + currentPage.table + " has no primary key, it cannot be edited");
+    return;
+  }
+
+  let table = shownTableContents.table;
+  let keyIndexes = primaryKey.columns.map((column) => table.columns.indexOf(column));
+  if (keyIndexes.includes(-1)) {
+    InformStatus("Not all columns of the primary key are shown, the table cannot be edited");
+    return;
+  }
+  editState = {
+    "table": { "database": currentPage.database, "schema": currentPage.schema, "table": currentPage.table },
+    "keyColumns": primaryKey.columns,
+    "rows": []
+  };
+  table.fields.forEach((fields, index) => {
+    let tr = dbTable.rows[index + 1];
+    Array.from(tr.cells).forEach(makeCellEditable);
+    addDeleteToggle(tr);
+    editState.rows.push({ "tr": tr, "fields": fields });
+  });
+  editing.classList.remove("hidden");
+  InformStatus("Editing " + currentPage.table + " by " + primaryKey.columns.join(", "));
+}
+
+function stopEditing() {
+  editState = null;
+  editing.classList.add("hidden");
+}
+
+function addEditRow() {
+  if (editState === null) {
+    return;
+  }
+  let tr = dbTable.insertRow();
+  shownTableContents.table.columns.forEach(() => makeCellEditable(tr.insertCell()));
+  addDeleteToggle(tr);
+  editState.rows.push({ "tr": tr, "fields": null });
+}
+
+// Edit for a row, null if it is unchanged
+function rowEdit(row, columns) {
+  let changed = {};
+  Array.from(row.tr.cells).slice(0, columns.length).forEach((cell, index) => {
+    if (cell.classList.contains("cell-changed")) {
+      changed[columns[index]] = editedValue(cell);
+    }
+  });
+  let deleted = row.tr.classList.contains("row-deleted");
+  if (row.fields === null) {
+    // new row, removed again before it was saved
+    return deleted ? null : { "Insert": { "values": changed } };
+  }
+
+  let key = {};
+  let original = {};
+  row.fields.forEach((cellData, index) => {
+    let column = columns[index];
+    if (editState.keyColumns.includes(column)) {
+      key[column] = originalValue(cellData);
+    }
+    if (isComparable(cellData)) {
+      original[column] = originalValue(cellData);
+    }
+  });
+  if (deleted) {
+    return { "Delete": { "key": key, "original": original } };
+  }
+  if (Object.keys(changed).length === 0) {
+    return null;
+  }
+  return { "Update": { "key": key, "original": original, "changes": changed } };
+}
+
+// Save all edits in one transaction, nothing is saved if any of them fails
+async function saveEdits() {
+  if (editState === null) {
+    return;
+  }
+  let table = shownTableContents.table;
+  let edits = [];
+  let editedRows = [];
+  for (let row of editState.rows) {
+    let edit = rowEdit(row, table.columns);
+    if (edit !== null) {
+      edits.push(edit);
+      editedRows.push(row);
+    }
+  }
+  if (edits.length === 0) {
+    InformStatus("Nothing to save");
+    return;
+  }
+
+  let result;
+  try {
+    result = await invoke("edit_rows", { edits: {
+      "connection": getGlobalConnection(),
+      "limits": getGlobalQueryLimits(),
+      "table": editState.table,
+      "edits": edits
+    }});
+  } catch (error) {
+    InformStatus("Error: Call to edit_rows returned an error: " + JSON.stringify(error));
+    return;
+  }
+  if (result.committed) {
+    InformStatus("Saved " + edits.length + " changed rows");
+    await dbRequestPage(0);
+    return;
+  }
+
+  let conflicts = 0;
+  let failures = 0;
+  result.rows.forEach((outcome, index) => {
+    let tr = editedRows[index].tr;
+    tr.classList.remove("edit-conflict", "edit-failed");
+    tr.title = "";
+    if (outcome === "Conflict") {
+      conflicts++;
+      tr.classList.add("edit-conflict");
+      tr.title = "Zeile wurde inzwischen geändert oder gelöscht";
+    } else if (outcome.hasOwnProperty("Failed")) {
+      failures++;
+      tr.classList.add("edit-failed");
+      tr.title = describeDatabaseError(outcome.Failed);
+    }
+  });
+  InformStatus("Nothing saved: " + conflicts + " rows changed in the meantime, " + failures + " rows failed");
+}
+
+function initEventsForPaging() {
+  paging.querySelector(".page-prev").addEventListener("click", () => dbRequestPage(-1));
+  paging.querySelector(".page-next").addEventListener("click", () => dbRequestPage(1));
+  paging.querySelector(".stream-all").addEventListener("click", () => streamTableContents());
+  paging.querySelector(".describe-table").addEventListener("click", () => describeCurrentTable());
+  paging.querySelector(".edit-table").addEventListener("click", () => startEditing());
+  editing.querySelector(".edit-add-row").addEventListener("click", () => addEditRow());
+  editing.querySelector(".edit-save").addEventListener("click", () => saveEdits());
+  editing.querySelector(".edit-discard").addEventListener("click", () => dbRequestPage(0));
+  cancelButton.addEventListener("click", () => cancelRunningQueries());
+  exportControls.querySelector(".export-run").addEventListener("click", () => exportRows());
+  paging.querySelector(".import-toggle").addEventListener("click", () => importControls.classList.toggle("hidden"));
+  importControls.querySelector(".import-preview").addEventListener("click", () => previewImport());
+  importControls.querySelector(".import-run").addEventListener("click", () => importFile());
+}
+
+/* Errors */
+
+// DatabaseError as defined by rust backend, e.g.
+//
+//   { "Parse": { code: "42P01", message: "...", detail: null, hint: null, position: 15 } }
+//
+function getErrorDetails(databaseError) {
+  let phase = Object.keys(databaseError)[0];
+  return [phase, databaseError[phase]];
+}
+
+function describeDatabaseError(databaseError) {
+  let [phase, details] = getErrorDetails(databaseError);
+  let text = "Error (" + phase + ")";
+  if (details.code !== null) {
+    text += " [" + details.code + "]";
+  }
+  text += ": " + details.message;
+  if (details.detail !== null) {
+    text += " Detail: " + details.detail;
+  }
+  if (details.hint !== null) {
+    text += " Hint: " + details.hint;
+  }
+  return text;
+}
+
+// Show failing sql and select the character the error points to
+function markErrorPosition(sqlQuery, databaseError) {
+  let [, details] = getErrorDetails(databaseError);
+  if (details.position !== null) {
+    customSqlQuery.value = sqlQuery;
+    customSqlQuery.size = customSqlQuery.value.length;
+    customSqlQuery.focus();
+    customSqlQuery.setSelectionRange(details.position - 1, details.position);
+  }
+}
+
+/** Will run a query to the database and process results (including DOM manipulation)
+ * 
+ * @param fullQuery containing connection and specific query
+ */
+/**
+ * Show result of a query in table, paging and breadcrumbs
+ *
+ * @param fullQuery the query that was run
+ * @param queryResult its result
+ */
+function showQueryResult(fullQuery, queryResult) {
+  let tableResult = queryResult.table;
+  // for EXPLAIN the query itself, not the statement that was run
+  let isExplain = (getTaskFromQuery(fullQuery) === "Explain");
+  let sqlQuery = isExplain ? fullQuery.query.Explain.query.sql_query : queryResult.sql_query;
+  if (tableResult.hasOwnProperty("Ok")) {
+    customSqlQuery.value = sqlQuery.replace(/\s+/g,' ');
+    customSqlQuery.size = customSqlQuery.value.length;
+    if (queryResult.database !== null) {
+      customDatabase.value = queryResult.database;
+    }
+
+    let status = queryResult.returns_rows
+      ? "Read " + tableResult.Ok.fields.length + " rows"
+      : "Done: " + (queryResult.command_tag || "statement executed");
+    if (isExplain) {
+      status = "Plan of the query";
+      // the plan is shown in the details, not as json in the table
+      tableResult.Ok.fields = [];
+    }
+    if (queryResult.truncated) {
+      status += " (truncated, more rows available than the limit of the connection)";
+    }
+    status += " - " + describeTls(queryResult.tls);
+    InformStatus(status);
+    stopEditing();
+    shownTableContents = (getTaskFromQuery(fullQuery) === "GetTableContents")
+      ? { "table": tableResult.Ok, "query": fullQuery } : null;
+    replaceTableContents(tableResult.Ok, fullQuery);
+    showDetails(queryResult.details);
+    updatePaging(fullQuery, queryResult, tableResult.Ok.fields.length);
+    showExport(queryResult.returns_rows && !isExplain ? fullQuery : null);
+    updateBreadcrumbs(toPathItems(fullQuery));  // just copy the query verbatim as current path
+  } else if (isCancelled(tableResult.Err)) {
+    InformStatus("Query cancelled: '" + sqlQuery + "'");
+  } else {
+
+    let err = describeDatabaseError(tableResult.Err);
+    err += " - query '" + sqlQuery + "'";
+    if (queryResult.database !== null) {
+      err += " on database '" + queryResult.database + "'";
+    }
+    InformStatus(err);
+    markErrorPosition(sqlQuery, tableResult.Err);
+  }
+}
+
+async function dbFullRequest(fullQuery) {
+  let queryId = "query-" + (++queryCounter);
+  InformStatus("Running query: " + JSON.stringify(fullQuery));
+  startRunningQuery(queryId);
+  await invoke("db_query",{ query: fullQuery, queryId: queryId})
+    .then((queryResult) => showQueryResult(fullQuery, queryResult))
     .catch((error) => { 
       InformStatus("Error: Call to db_query returned an error: " + JSON.stringify(error))
-    });
+    })
+    .finally(() => { stopRunningQuery(queryId); });
 }
 
-// Callback function that initiates a full request for a given database and table
+// Run the query the backend chooses for the position of this window
+async function dbNextAction(pagination = null) {
+  let queryId = "query-" + (++queryCounter);
+  InformStatus("Running default action");
+  startRunningQuery(queryId);
+  await invoke("default_next_action", { queryId: queryId, pagination: pagination })
+    .then((action) => showQueryResult(action.query, action.result))
+    .catch((error) => {
+      InformStatus("Error: Call to default_next_action returned an error: " + JSON.stringify(error))
+    })
+    .finally(() => { stopRunningQuery(queryId); });
+}
+
+// Change position of this window (see db/session.rs), then show what is there
+async function dbNavigate(navigation) {
+  try {
+    await invoke("navigate_to", { navigation: navigation });
+  } catch (error) {
+    InformStatus("Error: Call to navigate_to returned an error: " + JSON.stringify(error));
+    return;
+  }
+  await dbNextAction();
+}
+
+// Callback function that initiates a full request for a given database, schema and table
 //
-async function dbRequestFromPathElements(database, table) {
-  await dbFullRequest(createFullQuery(getGlobalConnectionString(), createQueryFromPathElements(database, table)));
+async function dbRequestFromPathElements(database, schema, table) {
+  await dbNavigate({ "To": { "database": database, "schema": schema, "table": table } });
+}
+
+
+/* Parameters of custom queries (see db/params.rs) */
+
+// SQL the shown parameter fields belong to, null if none are shown
+let paramsPreparedFor = null;
+
+function normalizeSql(sqlQuery) {
+  return sqlQuery.replace(/\s+/g, ' ').trim();
+}
+
+// One field per parameter, with its type and a checkbox for NULL
+function showQueryParams(sqlQuery, prepared) {
+  paramControls.replaceChildren();
+  prepared.param_types.forEach((paramType, index) => {
+    let label = document.createElement("label");
+    label.textContent = "$" + (index + 1) + " (" + paramType.name + ") ";
+    let input = document.createElement("input");
+    input.type = "text";
+    input.className = "param-value";
+    input.spellcheck = false;
+    input.addEventListener("keyup", (event) => onEnterRun(event, runCustomQuery));
+    let isNull = document.createElement("input");
+    isNull.type = "checkbox";
+    isNull.className = "param-null";
+    isNull.addEventListener("change", () => { input.disabled = isNull.checked; });
+    label.append(input, " NULL ", isNull);
+    paramControls.appendChild(label);
+  });
+  paramControls.classList.toggle("hidden", prepared.param_types.length === 0);
+  paramsPreparedFor = normalizeSql(sqlQuery);
 }
 
+// Returns whether the query could be prepared
+async function prepareQueryParams() {
+  let sqlQuery = customSqlQuery.value;
+  let fullQuery = createFullQuery(getGlobalConnection(), createCustomQuery(customDatabase.value, sqlQuery));
+  let prepared = await invoke("prepare_query", { query: fullQuery })
+    .then((prepared) => {
+      showQueryParams(sqlQuery, prepared);
+      InformStatus((prepared.param_types.length > 0)
+        ? "Enter values for the " + prepared.param_types.length + " parameters"
+        : "Query has no parameters");
+      return true;
+    })
+    .catch((error) => {
+      InformStatus("Error: Call to prepare_query returned an error: " + error);
+      return false;
+    });
+  await refreshTransactionState();
+  return prepared;
+}
+
+// Values of the parameter fields, null if they do not belong to the query
+function queryParamValues(sqlQuery) {
+  if (paramsPreparedFor !== normalizeSql(sqlQuery)) {
+    return null;
+  }
+  return Array.from(paramControls.querySelectorAll("label")).map((label) =>
+    label.querySelector(".param-null").checked ? null : label.querySelector(".param-value").value);
+}
+
+// Custom query with the values of its parameters, null if they have to be entered first
+async function customQueryWithParams() {
+  let sqlQuery = customSqlQuery.value;
+  let params = queryParamValues(sqlQuery);
+  if (params === null) {
+    paramControls.classList.add("hidden");
+    paramsPreparedFor = null;
+    // ask for values first, unless preparing fails: running the query shows the error
+    if (/\$[0-9]/.test(sqlQuery) && await prepareQueryParams()) {
+      return null;
+    }
+    params = [];
+  }
+  return createCustomQuery(customDatabase.value, sqlQuery, params);
+}
 
 async function runCustomQuery() {
-  await dbFullRequest(
-    createFullQuery(
-      getGlobalConnectionString(),
-      createCustomQuery(
-        customDatabase.value, 
-        customSqlQuery.value)
-    )
-  );
+  let customQuery = await customQueryWithParams();
+  if (customQuery === null) {
+    return;
+  }
+  await dbFullRequest(createFullQuery(getGlobalConnection(), customQuery));
+  await refreshTransactionState();
+}
+
+// Plan of the custom query, with ANALYZE the query runs and is rolled back
+async function explainCustomQuery(analyze) {
+  let customQuery = await customQueryWithParams();
+  if (customQuery === null) {
+    return;
+  }
+  let explain = {
+    "Explain": {
+      "query": customQuery.CustomQuery,
+      "analyze": analyze,
+      "buffers": document.querySelector(".custom-sql .explain-buffers").checked
+    }
+  };
+  await dbFullRequest(createFullQuery(getGlobalConnection(), explain));
+  await refreshTransactionState();
+}
+
+/* Scripts (see db/script.rs) */
+
+// Table of a statement's result, in the same form as the main table
+function createResultTable(table) {
+  let domTable = document.createElement("table");
+  let tr = domTable.insertRow();
+  table.columns.forEach((column_name) => {
+    amendCell(tr.appendChild(document.createElement("th")), column_name, null, null);
+  });
+  let fullQuery = createFullQuery(getGlobalConnection(), createCustomQuery(null, ""));
+  table.fields.forEach((row) => {
+    let tr = domTable.insertRow();
+    row.forEach((cellData) => insertCellData(tr.insertCell(), determineCellFunctionality(cellData, fullQuery)));
+  });
+  return domTable;
+}
+
+// Select the character an error points to, positions count within the statement
+function markScriptErrorPosition(statement) {
+  let [, details] = getErrorDetails(statement.table.Err);
+  let textarea = scriptControls.querySelector(".sql-script");
+  let start = (details.position !== null) ? statement.start + details.position - 1 : statement.start;
+  let end = (details.position !== null) ? start + 1 : statement.start + statement.sql.length;
+  textarea.focus();
+  textarea.setSelectionRange(start, end);
+}
+
+// Results of all statements, one after the other below the (emptied) main table
+function showScriptResult(result) {
+  stopEditing();
+  shownTableContents = null;
+  currentPage = null;
+  paging.classList.add("hidden");
+  showExport(null);
+  clearTableRows(0);
+  dbDetails.innerHTML = "";
+
+  let failed = 0;
+  for (let statement of result.statements) {
+    let heading = document.createElement("h3");
+    heading.textContent = statement.sql;
+    dbDetails.appendChild(heading);
+    let info = document.createElement("p");
+    info.className = "statement-info";
+    dbDetails.appendChild(info);
+    if (statement.table.hasOwnProperty("Ok")) {
+      info.textContent = statement.command_tag + " (" + statement.duration_ms + " ms)"
+        + (statement.truncated ? " - truncated" : "");
+      if (statement.returns_rows) {
+        dbDetails.appendChild(createResultTable(statement.table.Ok));
+      }
+    } else {
+      if (failed === 0) {
+        markScriptErrorPosition(statement);
+      }
+      failed++;
+      info.textContent = describeDatabaseError(statement.table.Err);
+    }
+  }
+  InformStatus("Ran " + result.statements.length + " statements, " + failed + " failed, "
+    + result.skipped + " skipped - " + describeTls(result.tls));
+}
+
+async function runScript() {
+  let queryId = "query-" + (++queryCounter);
+  let script = {
+    "connection": getGlobalConnection(),
+    "database": customDatabase.value || null,
+    "sql_script": scriptControls.querySelector(".sql-script").value,
+    "on_error": scriptControls.querySelector(".script-on-error").value,
+    "limits": getGlobalQueryLimits()
+  };
+  InformStatus("Running script");
+  startRunningQuery(queryId);
+  await invoke("db_run_script", { script: script, queryId: queryId })
+    .then(showScriptResult)
+    .catch((error) => {
+      InformStatus("Error: Call to db_run_script returned an error: " + JSON.stringify(error))
+    })
+    .finally(() => { stopRunningQuery(queryId); });
+  await refreshTransactionState();
 }
 
 /* Navigation */
@@ -509,10 +1898,29 @@ let initNavigation = () => {
 
 async function initialQuery(){
   selectComponent("db");
+  // A transaction stays open across reloads, until the window is closed
+  await refreshTransactionState();
+  // After a reload the backend still knows where this window was
+  let session = await invoke("get_session", {});
+  let profiles = await invoke("list_profiles", {}).catch(() => ({ "profiles": [], "last_used": null }));
+  if (session.connection !== null) {
+    useConnection(session.connection);
+    useQueryLimits(session.limits);
+    useProfile(profiles.profiles.find((profile) => profile.name === session.profile) || null);
+    await dbNextAction();
+    return;
+  }
   // Could split into "suggest_query" and "suggest_connection_string"
   let fullQuery = await invoke("suggest_query", {});
-  useConnectionString(getConnectionStringFromFullQuery(fullQuery));
-  await dbFullRequest(fullQuery);
+  useConnection(getConnectionFromFullQuery(fullQuery));
+  // the suggestion is the profile used last, if there is one
+  useProfile(profiles.profiles.find((profile) => profile.name === fullQuery["profile"]) || null);
+  useQueryLimits(fullQuery["limits"]);
+  await dbNavigate({ "Connect": {
+    "connection": fullQuery["connection"],
+    "limits": fullQuery["limits"],
+    "profile": fullQuery["profile"]
+  } });
 }
 
 window.addEventListener("DOMContentLoaded", () => {